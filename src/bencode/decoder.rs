@@ -1,24 +1,78 @@
-use crate::error::{BittorrentError, Result};
 use super::BencodeValue;
+use crate::error::{BittorrentError, Result};
 use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// Deepest list/dict nesting accepted, so hostile input can't overflow the
+/// stack through recursion
+pub const MAX_DEPTH: usize = 256;
 
 /// Decode bencoded data into a BencodeValue
 pub fn decode(data: &[u8]) -> Result<BencodeValue> {
     let mut pos = 0;
-    decode_value(data, &mut pos)
+    decode_value(data, &mut pos, 0)
+}
+
+/// Decode data that must already be canonical bencode
+///
+/// Unsorted or duplicate dict keys, padded integers, `-0` and trailing
+/// bytes are all rejected, since re-encoding wouldn't reproduce the input.
+#[allow(dead_code, reason = "only the tests check canonical input so far")]
+pub fn decode_canonical(data: &[u8]) -> Result<BencodeValue> {
+    let value = decode(data)?;
+    if value.canonical_encode() != data {
+        return Err(BittorrentError::BencodeError(
+            "Input is not canonical bencode".to_string(),
+        ));
+    }
+    Ok(value)
+}
+
+/// Find where a top-level dictionary value sits in `data`, so its exact
+/// bytes can be hashed or inspected
+///
+/// Only the keys before `key` need to be well-formed.
+pub fn find_dict_value(data: &[u8], key: &[u8]) -> Result<Range<usize>> {
+    if data.first() != Some(&b'd') {
+        return Err(BittorrentError::BencodeError(
+            "Top-level value is not a dictionary".to_string(),
+        ));
+    }
+
+    let mut pos = 1;
+    while pos < data.len() && data[pos] != b'e' {
+        let found = decode_string(data, &mut pos)?.as_bytes() == Some(key);
+        let start = pos;
+        decode_value(data, &mut pos, 1)?;
+
+        if found {
+            return Ok(start..pos);
+        }
+    }
+
+    Err(BittorrentError::BencodeError(format!(
+        "Key '{}' not found",
+        String::from_utf8_lossy(key)
+    )))
 }
 
-fn decode_value(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
+fn decode_value(data: &[u8], pos: &mut usize, depth: usize) -> Result<BencodeValue> {
     if *pos >= data.len() {
         return Err(BittorrentError::BencodeError(
             "Unexpected end of input".to_string(),
         ));
     }
+    if depth >= MAX_DEPTH && matches!(data[*pos], b'l' | b'd') {
+        return Err(BittorrentError::BencodeError(format!(
+            "Nesting deeper than {} levels",
+            MAX_DEPTH
+        )));
+    }
 
     match data[*pos] {
         b'i' => decode_integer(data, pos),
-        b'l' => decode_list(data, pos),
-        b'd' => decode_dict(data, pos),
+        b'l' => decode_list(data, pos, depth),
+        b'd' => decode_dict(data, pos, depth),
         b'0'..=b'9' => decode_string(data, pos),
         c => Err(BittorrentError::BencodeError(format!(
             "Invalid bencode token: {}",
@@ -74,7 +128,8 @@ fn decode_string(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
 
     *pos += 1; // Skip ':'
 
-    if *pos + len > data.len() {
+    // Compare without adding, so a huge length can't overflow
+    if len > data.len() - *pos {
         return Err(BittorrentError::BencodeError(
             "String length exceeds data".to_string(),
         ));
@@ -86,13 +141,13 @@ fn decode_string(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
     Ok(BencodeValue::String(string))
 }
 
-fn decode_list(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
+fn decode_list(data: &[u8], pos: &mut usize, depth: usize) -> Result<BencodeValue> {
     *pos += 1; // Skip 'l'
 
     let mut list = Vec::new();
 
     while *pos < data.len() && data[*pos] != b'e' {
-        list.push(decode_value(data, pos)?);
+        list.push(decode_value(data, pos, depth + 1)?);
     }
 
     if *pos >= data.len() {
@@ -106,7 +161,7 @@ fn decode_list(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
     Ok(BencodeValue::List(list))
 }
 
-fn decode_dict(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
+fn decode_dict(data: &[u8], pos: &mut usize, depth: usize) -> Result<BencodeValue> {
     *pos += 1; // Skip 'd'
 
     let mut dict = BTreeMap::new();
@@ -122,7 +177,7 @@ fn decode_dict(data: &[u8], pos: &mut usize) -> Result<BencodeValue> {
             }
         };
 
-        let value = decode_value(data, pos)?;
+        let value = decode_value(data, pos, depth + 1)?;
         dict.insert(key, value);
     }
 
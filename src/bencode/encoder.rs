@@ -1,6 +1,11 @@
 use super::BencodeValue;
 
 /// Encode a BencodeValue into its byte representation
+///
+/// The output is canonical bencode: dict keys are emitted in raw byte order
+/// (the `BTreeMap<Vec<u8>, _>` order, so "a" < "ab" < "b" and high bytes sort
+/// last) and integers have no leading zeros or negative zero. Re-encoding a
+/// decoded canonical input therefore reproduces it byte for byte.
 pub fn encode(value: &BencodeValue) -> Vec<u8> {
     let mut result = Vec::new();
     encode_into(value, &mut result);
@@ -2,12 +2,13 @@ mod decoder;
 mod encoder;
 mod value;
 
-pub use decoder::decode;
+pub use decoder::{decode, find_dict_value};
 pub use encoder::encode;
 pub use value::BencodeValue;
 
 #[cfg(test)]
 mod tests {
+    use super::decoder::decode_canonical;
     use super::*;
 
     #[test]
@@ -44,6 +45,92 @@ mod tests {
         assert_eq!(encoded, b"d3:bar4:spam3:fooi42ee");
     }
 
+    #[test]
+    fn test_dict_builder_matches_manual_construction() {
+        let mut info = std::collections::BTreeMap::new();
+        info.insert(b"name".to_vec(), BencodeValue::String(b"a.bin".to_vec()));
+        info.insert(b"piece length".to_vec(), BencodeValue::Integer(16384));
+        info.insert(b"pieces".to_vec(), BencodeValue::String(vec![0xff; 20]));
+        let mut root = std::collections::BTreeMap::new();
+        root.insert(
+            b"announce-list".to_vec(),
+            BencodeValue::List(vec![BencodeValue::List(vec![BencodeValue::String(
+                b"http://t".to_vec(),
+            )])]),
+        );
+        root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+        let manual = BencodeValue::Dict(root);
+
+        let built = BencodeValue::dict([
+            (
+                "info",
+                BencodeValue::dict([
+                    ("pieces", vec![0xffu8; 20].into()),
+                    ("piece length", 16384.into()),
+                    ("name", "a.bin".into()),
+                ]),
+            ),
+            (
+                "announce-list",
+                vec![BencodeValue::List(vec!["http://t".into()])].into(),
+            ),
+        ]);
+
+        assert_eq!(built, manual);
+        assert_eq!(encode(&built), encode(&manual));
+    }
+
+    #[test]
+    fn test_from_conversions() {
+        assert_eq!(BencodeValue::from(-3), BencodeValue::Integer(-3));
+        assert_eq!(
+            BencodeValue::from("ab"),
+            BencodeValue::String(b"ab".to_vec())
+        );
+        assert_eq!(
+            BencodeValue::from(String::from("ab")),
+            BencodeValue::from(&b"ab"[..])
+        );
+        assert_eq!(
+            BencodeValue::from(vec![BencodeValue::from(1)]),
+            BencodeValue::List(vec![BencodeValue::Integer(1)])
+        );
+    }
+
+    #[test]
+    fn test_random_input_never_panics() {
+        use crate::testutil::{mutate_bytes, random_bencode};
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let value = random_bencode(&mut rng, 4);
+            let encoded = encode(&value);
+            assert_eq!(decode(&encoded).unwrap(), value, "seed {}", seed);
+
+            let mut corrupted = encoded;
+            mutate_bytes(&mut rng, &mut corrupted);
+            let _ = decode(&corrupted);
+            let _ = decode_canonical(&corrupted);
+            let _ = find_dict_value(&corrupted, b"info");
+
+            let noise: Vec<u8> = (0..rng.gen_range(0..64)).map(|_| rng.gen()).collect();
+            let _ = decode(&noise);
+        }
+    }
+
+    #[test]
+    fn test_hostile_lengths_and_nesting_are_errors() {
+        assert!(decode(b"18446744073709551615:x").is_err());
+        assert!(decode(b"99999999999999999999999:x").is_err());
+        assert!(decode(b"i99999999999999999999e").is_err());
+
+        let deep = vec![b'l'; 100_000];
+        assert!(decode(&deep).is_err());
+        let nested = [vec![b'l'; 200], vec![b'e'; 200]].concat();
+        assert!(decode(&nested).is_ok());
+    }
+
     #[test]
     fn test_roundtrip() {
         let original = BencodeValue::List(vec![
@@ -54,4 +141,60 @@ mod tests {
         let decoded = decode(&encoded).unwrap();
         assert_eq!(original, decoded);
     }
+
+    #[test]
+    fn test_find_dict_value() {
+        // The info value holds bytes that look like bencode delimiters
+        let data = b"d8:announce3:url4:infod6:pieces3:elde4:zzzzi1ee";
+        let span = find_dict_value(data, b"info").unwrap();
+        assert_eq!(&data[span], b"d6:pieces3:elde");
+
+        assert!(find_dict_value(data, b"missing").is_err());
+        assert!(find_dict_value(b"li1ee", b"info").is_err());
+
+        // Garbage after the info dict doesn't matter
+        let data = b"d4:infodee5:junk!";
+        assert_eq!(&data[find_dict_value(data, b"info").unwrap()], b"de");
+    }
+
+    #[test]
+    fn test_dict_keys_sort_bytewise() {
+        let mut dict = std::collections::BTreeMap::new();
+        for key in [&b"b"[..], b"ab", b"a", b"\xff", b"\x7f", b"B"] {
+            dict.insert(key.to_vec(), BencodeValue::Integer(0));
+        }
+        let encoded = BencodeValue::Dict(dict).canonical_encode();
+        assert_eq!(
+            encoded,
+            b"d1:Bi0e1:ai0e2:abi0e1:bi0e1:\x7fi0e1:\xffi0ee".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_canonical_torrent_reencodes_identically() {
+        let torrent = crate::testutil::make_torrent(
+            "data.bin",
+            &crate::testutil::random_content(50_000),
+            16384,
+            "http://tracker/announce",
+        );
+        assert_eq!(
+            decode_canonical(&torrent).unwrap().canonical_encode(),
+            torrent
+        );
+    }
+
+    #[test]
+    fn test_non_canonical_input_is_rejected() {
+        for input in [
+            &b"d1:bi0e1:ai0ee"[..], // unsorted keys
+            b"d1:ai0e1:ai1ee",      // duplicate key
+            b"i03e",                // leading zero
+            b"i-0e",                // negative zero
+            b"i1etrailing",         // trailing data
+        ] {
+            assert!(decode(input).is_ok());
+            assert!(decode_canonical(input).is_err(), "{:?}", input);
+        }
+    }
 }
@@ -14,6 +14,24 @@ pub enum BencodeValue {
 }
 
 impl BencodeValue {
+    /// Build a dictionary from `(key, value)` pairs
+    ///
+    /// Keys may come in any order; they are sorted on encoding. A repeated
+    /// key keeps the last value.
+    pub fn dict<'a>(pairs: impl IntoIterator<Item = (&'a str, BencodeValue)>) -> Self {
+        BencodeValue::Dict(
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.as_bytes().to_vec(), value))
+                .collect(),
+        )
+    }
+
+    /// Encode as canonical bencode, see [`encode`](super::encode)
+    pub fn canonical_encode(&self) -> Vec<u8> {
+        super::encode(self)
+    }
+
     /// Try to get this value as an integer
     pub fn as_integer(&self) -> Option<i64> {
         match self {
@@ -32,8 +50,7 @@ impl BencodeValue {
 
     /// Try to get this value as a UTF-8 string
     pub fn as_str(&self) -> Option<&str> {
-        self.as_bytes()
-            .and_then(|b| std::str::from_utf8(b).ok())
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
     }
 
     /// Try to get this value as a list
@@ -53,17 +70,62 @@ impl BencodeValue {
     }
 
     /// Get a value from a dictionary by key
+    #[allow(dead_code, reason = "parsers match on the dict directly")]
     pub fn dict_get(&self, key: &[u8]) -> Option<&BencodeValue> {
         self.as_dict()?.get(key)
     }
 
     /// Get a string value from a dictionary by key
+    #[allow(dead_code, reason = "parsers match on the dict directly")]
     pub fn dict_get_str(&self, key: &[u8]) -> Option<&str> {
         self.dict_get(key)?.as_str()
     }
 
     /// Get an integer value from a dictionary by key
+    #[allow(dead_code, reason = "parsers match on the dict directly")]
     pub fn dict_get_int(&self, key: &[u8]) -> Option<i64> {
         self.dict_get(key)?.as_integer()
     }
 }
+
+impl From<i64> for BencodeValue {
+    fn from(value: i64) -> Self {
+        BencodeValue::Integer(value)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(value: &str) -> Self {
+        BencodeValue::String(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(value: String) -> Self {
+        BencodeValue::String(value.into_bytes())
+    }
+}
+
+impl From<&[u8]> for BencodeValue {
+    fn from(value: &[u8]) -> Self {
+        BencodeValue::String(value.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(value: Vec<u8>) -> Self {
+        BencodeValue::String(value)
+    }
+}
+
+impl From<Vec<BencodeValue>> for BencodeValue {
+    fn from(value: Vec<BencodeValue>) -> Self {
+        BencodeValue::List(value)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, BencodeValue>> for BencodeValue {
+    fn from(value: BTreeMap<Vec<u8>, BencodeValue>) -> Self {
+        BencodeValue::Dict(value)
+    }
+}
@@ -1,7 +1,24 @@
+use crate::bencode::find_dict_value;
+#[cfg(feature = "config-file")]
+use crate::client::FileConfig;
 use crate::client::{ClientConfig, TorrentClient};
-use crate::error::Result;
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use crate::error::{BittorrentError, Result};
+use crate::peer::{Blocklist, HandshakePolicy, PipelineLimits, ProvenFirst};
+use crate::piece::{PickStrategy, DEFAULT_ENDGAME_MIN_PIECES};
+use crate::storage::{SingleEntryLayout, StorageBackend, StorageManager};
+use crate::tracker::{
+    check_tracker_url, TrackerFilter, TrackerOverrides, TrackerTls, UdpTracker, DEFAULT_USER_AGENT,
+};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand};
+use sha1::{Digest, Sha1};
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::io::IsTerminal;
+use std::ops::Range;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::info;
 
 #[derive(Parser)]
 #[command(name = "bittorrent-rs")]
@@ -9,15 +26,21 @@ use std::path::PathBuf;
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Flags given on the command line rather than left at their defaults
+    #[arg(skip)]
+    explicit: HashSet<String>,
 }
 
+// Parsed once at startup, so the size of the Download variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Download a torrent file
     Download {
-        /// Path to the .torrent file
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
         #[arg(short, long)]
-        torrent: PathBuf,
+        torrent: String,
 
         /// Download directory
         #[arg(short, long, default_value = "./downloads")]
@@ -30,57 +53,565 @@ enum Commands {
         /// Maximum number of peers to connect to
         #[arg(short, long, default_value = "50")]
         max_peers: usize,
+
+        /// Start downloading once this many peers are connected
+        #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..))]
+        min_peers: u64,
+
+        /// Download and verify pieces without writing anything to disk
+        #[arg(long)]
+        dry_run: bool,
+
+        /// User-Agent sent to trackers
+        #[arg(long, default_value = DEFAULT_USER_AGENT)]
+        user_agent: String,
+
+        /// 8-character client identifier our peer ID starts with, e.g. "-RS0001-"
+        #[arg(long, value_parser = parse_peer_id_prefix, allow_hyphen_values = true)]
+        peer_id_prefix: Option<[u8; 8]>,
+
+        /// Extra tracker request header as "Name: value" (repeatable)
+        #[arg(long = "tracker-header", value_parser = parse_header)]
+        tracker_headers: Vec<(String, String)>,
+
+        /// PEM file with an extra root certificate to trust for HTTPS trackers (repeatable)
+        #[arg(long = "tracker-ca")]
+        tracker_ca: Vec<String>,
+
+        /// Only accept HTTPS trackers presenting this certificate, given as the
+        /// SHA-256 fingerprint of its DER encoding in hex (repeatable)
+        #[arg(long = "tracker-pin", value_parser = parse_fingerprint)]
+        tracker_pins: Vec<[u8; 32]>,
+
+        /// DANGEROUS: skip certificate checks for this tracker host (repeatable)
+        #[arg(long = "insecure-tracker")]
+        insecure_trackers: Vec<String>,
+
+        /// Only contact trackers whose host contains this text, or whose
+        /// announce URL is exactly this (repeatable)
+        #[arg(long = "tracker-allow")]
+        tracker_allow: Vec<String>,
+
+        /// Never contact trackers whose host contains this text, or whose
+        /// announce URL is exactly this (repeatable)
+        #[arg(long = "tracker-deny")]
+        tracker_deny: Vec<String>,
+
+        /// Also announce to this tracker, after the torrent's own (repeatable)
+        #[arg(long = "add-tracker", value_name = "URL", value_parser = parse_tracker_url)]
+        add_trackers: Vec<String>,
+
+        /// Announce only to the --add-tracker trackers, ignoring the torrent's
+        #[arg(long)]
+        replace_trackers: bool,
+
+        /// Seconds to wait for a tracker to answer before trying the next one
+        #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+        announce_timeout: u64,
+
+        /// Redirects to follow when announcing to an HTTP tracker (0 = treat
+        /// a redirect as a failed announce). Each one is logged so the torrent
+        /// can be updated
+        #[arg(long, default_value = "5")]
+        tracker_redirects: usize,
+
+        /// Don't retry an HTTP tracker with compact=0 when its compact
+        /// peer list is unreadable or empty
+        #[arg(long)]
+        no_compact_fallback: bool,
+
+        /// Never connect to peers in the IP ranges of this PeerGuardian
+        /// (.p2p) blocklist
+        #[arg(long)]
+        blocklist: Option<String>,
+
+        /// Mark peer traffic with this ToS byte (DSCP << 2) so a QoS-aware
+        /// router can deprioritize it, e.g. 32 for CS1 (lower effort)
+        #[arg(long, value_name = "TOS")]
+        socket_tos: Option<u8>,
+
+        /// Stop after downloading this many MiB in this session, leaving the
+        /// download partial
+        #[arg(long, value_name = "MIB")]
+        quota: Option<u64>,
+
+        /// Give up once this many different pieces keep failing their hash
+        /// check, as the torrent is likely corrupt (0 = never give up)
+        #[arg(long, default_value = "16")]
+        max_piece_failures: usize,
+
+        /// Maximum memory for in-progress piece buffers, in MiB (0 = unlimited)
+        #[arg(long, default_value = "256")]
+        max_inflight_mb: u64,
+
+        /// Most pieces downloaded at once, keeping disk writes close together
+        /// and the resume file small (0 = unlimited)
+        #[arg(long, default_value = "128")]
+        max_concurrent_pieces: usize,
+
+        /// Check piece hashes on a separate thread pool so hashing large
+        /// pieces doesn't hold up other peers
+        #[arg(long)]
+        offload_hashing: bool,
+
+        /// Seconds allowed to download one piece before re-picking it elsewhere
+        #[arg(long, default_value = "60")]
+        piece_timeout: u64,
+
+        /// Seconds to wait for a peer to unchoke us before moving on
+        #[arg(long, default_value = "30")]
+        unchoke_timeout: u64,
+
+        /// Seconds to wait for the next block from a peer before dropping it
+        #[arg(long, default_value = "30")]
+        block_timeout: u64,
+
+        /// Fewest block requests kept outstanding to each peer
+        #[arg(long, default_value = "2", value_parser = clap::value_parser!(u64).range(1..))]
+        min_pipeline_depth: u64,
+
+        /// Most block requests kept outstanding to each peer; the depth is
+        /// tuned between the two from each peer's response times
+        #[arg(long, default_value = "64", value_parser = clap::value_parser!(u64).range(1..))]
+        max_pipeline_depth: u64,
+
+        /// Seconds a connected peer may stay silent before it is dropped
+        #[arg(long, default_value = "180", value_parser = clap::value_parser!(u64).range(1..))]
+        peer_idle_timeout: u64,
+
+        /// Hex-dump the first N bytes of every peer message to the wire log
+        /// (shown with RUST_LOG=bittorrent_rs::wire=trace)
+        #[arg(long, value_name = "N")]
+        wire_dump: Option<usize>,
+
+        /// Piece order: sequential (for streaming), rarest, random-first
+        /// (random, then rarest once a few pieces are done) or random
+        #[arg(long, default_value = "random-first", value_parser = parse_pick_strategy)]
+        pick_strategy: PickStrategy,
+
+        /// Pieces fetched at random before random-first switches to rarest
+        #[arg(long, default_value = "4")]
+        random_first_pieces: usize,
+
+        /// Fetch the first and last piece of each file first, so media
+        /// players can open files while they download
+        #[arg(long)]
+        preview: bool,
+
+        /// Stream front to back: fetch pieces in order and flush each to
+        /// disk as soon as it verifies. Overrides --pick-strategy and
+        /// --write-buffer-kb, at some cost to overall speed
+        #[arg(long)]
+        sequential: bool,
+
+        /// Enter endgame (requesting the last pieces from several peers) once
+        /// this percentage of pieces is left, or 5 pieces if that is more
+        #[arg(long, default_value = "2", value_parser = parse_percent)]
+        endgame_percent: f64,
+
+        /// Seconds between progress updates
+        #[arg(long, default_value = "5", value_parser = clap::value_parser!(u64).range(1..))]
+        stats_interval: u64,
+
+        /// Log the median, 95th percentile and slowest peers of piece
+        /// download times when the download completes
+        #[arg(long)]
+        timing_summary: bool,
+
+        /// Refuse peers that don't support the extension protocol (BEP 10)
+        #[arg(long)]
+        require_extensions: bool,
+
+        /// Refuse unencrypted peers (stream encryption isn't implemented yet,
+        /// so this refuses every peer)
+        #[arg(long)]
+        require_encryption: bool,
+
+        /// Coalesce sequential disk writes into chunks of this many KiB (0 = write through)
+        #[arg(long, default_value = "1024")]
+        write_buffer_kb: usize,
+
+        /// Don't wait for written data to reach the disk. Faster, but a crash
+        /// can lose recently written pieces, which are then re-downloaded
+        #[arg(long)]
+        no_fsync: bool,
+
+        /// Where a multi-file torrent with only one file puts it: in a
+        /// directory named after the torrent (directory, what most clients
+        /// do), straight in the output directory (flat), or flat unless the
+        /// file sits in subdirectories (auto)
+        #[arg(long, default_value = "directory", value_parser = parse_single_entry_layout)]
+        single_entry_layout: SingleEntryLayout,
+
+        /// Command to run when the download completes; {name}, {path} and
+        /// {hash} are replaced. Split on whitespace and run without a shell
+        #[arg(long)]
+        exec_on_complete: Option<String>,
+
+        /// Seconds between saves of the resume file
+        #[arg(long, default_value = "30", value_parser = clap::value_parser!(u64).range(1..))]
+        resume_interval: u64,
+
+        /// TOML file with default settings, keyed by these flags' names with
+        /// underscores (e.g. max_peers = 80); flags given here override it
+        #[arg(long, value_name = "FILE")]
+        config: Option<String>,
     },
 
     /// Show information about a torrent file
     Info {
-        /// Path to the .torrent file
-        torrent: PathBuf,
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
+        torrent: String,
+    },
+
+    /// Check downloaded data against the torrent's piece hashes
+    Verify {
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
+        torrent: String,
+
+        /// Directory the torrent was downloaded to
+        #[arg(short, long, default_value = "./downloads")]
+        output: String,
+
+        /// Only check these pieces, e.g. "0,5,10-20" (0-based, ranges inclusive)
+        #[arg(long, value_delimiter = ',', value_parser = parse_piece_range)]
+        pieces: Vec<Range<usize>>,
+
+        /// Only check the pieces covering this file, numbered as in `info`
+        /// output. May be repeated
+        #[arg(long = "file")]
+        files: Vec<usize>,
+
+        /// Layout the download used for a multi-file torrent with only one
+        /// file (directory, flat or auto)
+        #[arg(long, default_value = "directory", value_parser = parse_single_entry_layout)]
+        single_entry_layout: SingleEntryLayout,
+    },
+
+    /// Serve already downloaded data to peers that connect to us
+    Seed {
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
+        torrent: String,
+
+        /// Directory the torrent was downloaded to
+        #[arg(short, long, default_value = "./downloads")]
+        output: String,
+
+        /// Port to listen on
+        #[arg(short, long, default_value = "6881")]
+        port: u16,
+
+        /// Maximum number of peers to serve at once
+        #[arg(short, long, default_value = "50")]
+        max_peers: usize,
+
+        /// Reveal pieces one at a time, a different one to each peer
+        /// (BEP 16), to spread a new torrent with fewer uploaded bytes
+        #[arg(long)]
+        super_seed: bool,
+
+        /// Layout the download used for a multi-file torrent with only one
+        /// file (directory, flat or auto)
+        #[arg(long, default_value = "directory", value_parser = parse_single_entry_layout)]
+        single_entry_layout: SingleEntryLayout,
+    },
+
+    /// Ask a torrent's trackers for seeder, leecher and download counts
+    Scrape {
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
+        torrent: String,
+    },
+
+    /// Dump the exact info dict bytes that get hashed, for comparing info
+    /// hashes with other clients
+    #[command(hide = true)]
+    DumpInfo {
+        /// Path or http(s) URL of the .torrent file ("-" reads stdin)
+        torrent: String,
+
+        /// Write the raw bytes to this file instead of hex-dumping them
+        #[arg(short, long)]
+        output: Option<String>,
     },
 }
 
 impl Cli {
     pub fn parse() -> Self {
-        <Self as Parser>::parse()
+        Self::try_parse_args(std::env::args_os()).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Parse arguments, remembering which flags were actually given so
+    /// they can override a config file
+    fn try_parse_args<I, T>(args: I) -> std::result::Result<Self, clap::Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<OsString> + Clone,
+    {
+        let matches = <Self as CommandFactory>::command().try_get_matches_from(args)?;
+        let mut cli = <Self as FromArgMatches>::from_arg_matches(&matches)?;
+        if let Some((_, command)) = matches.subcommand() {
+            cli.explicit = command
+                .ids()
+                .filter(|id| command.value_source(id.as_str()) == Some(ValueSource::CommandLine))
+                .map(|id| id.to_string())
+                .collect();
+        }
+        Ok(cli)
     }
 
     pub async fn run(&self) -> Result<()> {
         match &self.command {
-            Commands::Download {
+            Commands::Download { torrent, .. } => {
+                let config = self.download_config().await?;
+                let client = TorrentClient::new(config);
+                let result = client.download(torrent).await;
+                client.wait_for_completion_commands().await;
+                result?;
+            }
+
+            Commands::Info { torrent } => {
+                self.show_torrent_info(torrent).await?;
+            }
+
+            Commands::Verify {
+                torrent,
+                output,
+                pieces,
+                files,
+                single_entry_layout,
+            } => {
+                self.verify(torrent, output, pieces, files, *single_entry_layout)
+                    .await?;
+            }
+
+            Commands::Seed {
                 torrent,
                 output,
                 port,
                 max_peers,
+                super_seed,
+                single_entry_layout,
             } => {
                 let config = ClientConfig {
                     download_dir: output.clone(),
                     listen_port: *port,
                     max_peers: *max_peers,
+                    super_seed: *super_seed,
+                    single_entry_layout: *single_entry_layout,
+                    ..ClientConfig::default()
                 };
+                self.seed(torrent, config).await?;
+            }
 
-                let client = TorrentClient::new(config);
-                client.download(torrent).await?;
+            Commands::Scrape { torrent } => {
+                self.scrape(torrent).await?;
             }
 
-            Commands::Info { torrent } => {
-                self.show_torrent_info(torrent).await?;
+            Commands::DumpInfo { torrent, output } => {
+                self.dump_info(torrent, output.as_deref()).await?;
             }
         }
 
         Ok(())
     }
 
-    async fn show_torrent_info(&self, torrent_path: &PathBuf) -> Result<()> {
-        let metainfo = crate::torrent::load_torrent_file(torrent_path).await?;
+    /// Settings for `download`: the config file if one is given, overridden
+    /// by any flag set on the command line
+    async fn download_config(&self) -> Result<ClientConfig> {
+        let Commands::Download {
+            config,
+            output,
+            port,
+            max_peers,
+            min_peers,
+            dry_run,
+            user_agent,
+            peer_id_prefix,
+            tracker_headers,
+            tracker_ca,
+            tracker_pins,
+            insecure_trackers,
+            tracker_allow,
+            tracker_deny,
+            add_trackers,
+            replace_trackers,
+            announce_timeout,
+            tracker_redirects,
+            no_compact_fallback,
+            blocklist,
+            socket_tos,
+            quota,
+            max_piece_failures,
+            max_inflight_mb,
+            max_concurrent_pieces,
+            offload_hashing,
+            piece_timeout,
+            unchoke_timeout,
+            block_timeout,
+            min_pipeline_depth,
+            max_pipeline_depth,
+            peer_idle_timeout,
+            wire_dump,
+            pick_strategy,
+            random_first_pieces,
+            preview,
+            sequential,
+            endgame_percent,
+            stats_interval,
+            timing_summary,
+            require_extensions,
+            require_encryption,
+            write_buffer_kb,
+            no_fsync,
+            single_entry_layout,
+            exec_on_complete,
+            resume_interval,
+            ..
+        } = &self.command
+        else {
+            unreachable!("download_config called for another command");
+        };
+        let mut root_certificates = Vec::new();
+        for path in tracker_ca {
+            root_certificates.push(tokio::fs::read(path).await?);
+        }
+
+        let blocklist = match blocklist {
+            Some(path) => {
+                let blocklist = Blocklist::load(path).await?;
+                info!("Loaded {} blocklist ranges from {}", blocklist.len(), path);
+                Some(Arc::new(blocklist))
+            }
+            None => None,
+        };
+
+        let flags = ClientConfig {
+            download_dir: output.clone(),
+            listen_port: *port,
+            max_peers: *max_peers,
+            min_peers_to_start: *min_peers as usize,
+            dry_run: *dry_run,
+            user_agent: user_agent.clone(),
+            peer_id_prefix: *peer_id_prefix,
+            tracker_headers: tracker_headers.clone(),
+            tracker_tls: TrackerTls {
+                root_certificates,
+                pinned_certificates: tracker_pins.clone(),
+                insecure_hosts: insecure_trackers.clone(),
+            },
+            tracker_filter: TrackerFilter {
+                allow: tracker_allow.clone(),
+                deny: tracker_deny.clone(),
+            },
+            tracker_overrides: TrackerOverrides {
+                add: add_trackers.clone(),
+                replace: *replace_trackers,
+            },
+            announce_timeout: Duration::from_secs(*announce_timeout),
+            tracker_redirects: *tracker_redirects,
+            compact_fallback: !*no_compact_fallback,
+            blocklist,
+            dial_order: Arc::new(ProvenFirst),
+            socket_tos: *socket_tos,
+            download_quota: quota.map(|mib| mib * 1024 * 1024),
+            max_piece_failures: (*max_piece_failures > 0).then_some(*max_piece_failures),
+            max_inflight_bytes: (*max_inflight_mb > 0).then(|| max_inflight_mb * 1024 * 1024),
+            max_concurrent_pieces: (*max_concurrent_pieces > 0).then_some(*max_concurrent_pieces),
+            offload_hashing: *offload_hashing,
+            piece_timeout: Duration::from_secs(*piece_timeout),
+            unchoke_timeout: Duration::from_secs(*unchoke_timeout),
+            block_timeout: Duration::from_secs(*block_timeout),
+            pipeline: PipelineLimits {
+                min_depth: *min_pipeline_depth as usize,
+                max_depth: *max_pipeline_depth as usize,
+            },
+            peer_idle_timeout: Duration::from_secs(*peer_idle_timeout),
+            wire_dump: *wire_dump,
+            pick_strategy: *pick_strategy,
+            random_first_pieces: *random_first_pieces,
+            preview: *preview,
+            sequential: *sequential,
+            endgame_ratio: endgame_percent / 100.0,
+            endgame_min_pieces: DEFAULT_ENDGAME_MIN_PIECES,
+            stats_interval: Duration::from_secs(*stats_interval),
+            // Redraw one line on terminals; keep log lines when piped
+            progress_line: std::io::stdout().is_terminal(),
+            handshake_policy: HandshakePolicy {
+                require_extension_protocol: *require_extensions,
+                require_encryption: *require_encryption,
+            },
+            write_buffer_bytes: write_buffer_kb * 1024,
+            fsync: !*no_fsync,
+            single_entry_layout: *single_entry_layout,
+            resume_interval: Duration::from_secs(*resume_interval),
+            on_complete: exec_on_complete.clone(),
+            // Only `seed` serves peers
+            super_seed: false,
+            timing_summary: *timing_summary,
+        };
+
+        // Flags given on the command line win over the file
+        let mut settings = flags;
+        if let Some(path) = config {
+            self.apply_config_file(&mut settings, path).await?;
+        }
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Set what a config file gives, except settings whose flags were given
+    #[cfg(feature = "config-file")]
+    async fn apply_config_file(&self, settings: &mut ClientConfig, path: &str) -> Result<()> {
+        FileConfig::load(path, &self.explicit)
+            .await?
+            .apply(settings)
+    }
+
+    #[cfg(not(feature = "config-file"))]
+    async fn apply_config_file(&self, _settings: &mut ClientConfig, path: &str) -> Result<()> {
+        Err(BittorrentError::ConfigError(format!(
+            "can't read {}: built without the config-file feature",
+            path
+        )))
+    }
+
+    async fn show_torrent_info(&self, source: &str) -> Result<()> {
+        let metainfo = crate::torrent::load_torrent(source).await?;
 
         println!("Torrent Information");
         println!("==================");
         println!("Name: {}", metainfo.info.name);
-        println!("Tracker: {}", metainfo.announce);
+        println!(
+            "Tracker: {}",
+            metainfo.announce.as_deref().unwrap_or("(none)")
+        );
         println!("Total Size: {} bytes", metainfo.info.total_length);
         println!("Piece Length: {} bytes", metainfo.info.piece_length);
-        println!("Number of Pieces: {}", metainfo.info.pieces.len());
-        println!("Info Hash: {}", metainfo.info_hash_hex());
+        println!("Number of Pieces: {}", metainfo.info.piece_count());
+        let kind = if metainfo.info.is_hybrid() {
+            "hybrid (v1 + v2)"
+        } else if metainfo.info.is_v2_only() {
+            "v2"
+        } else {
+            "v1"
+        };
+        println!("Format: {}", kind);
+        let layout = if metainfo.info.is_single_file() {
+            "single file"
+        } else if metainfo.info.files.len() == 1 {
+            "multi-file with a single file (saved in a directory named after the torrent \
+             unless --single-entry-layout says otherwise)"
+        } else {
+            "multi-file (saved in a directory named after the torrent)"
+        };
+        println!("Layout: {}", layout);
+        if !metainfo.info.is_v2_only() {
+            println!("Info Hash (v1): {}", metainfo.info_hash_hex());
+        }
+        if let Some(v2_info_hash) = metainfo.v2_info_hash_hex() {
+            println!("Info Hash (v2): {}", v2_info_hash);
+        }
+        println!("Magnet: {}", metainfo.magnet_link());
         println!("\nFiles:");
 
         for (i, file) in metainfo.info.files.iter().enumerate() {
@@ -92,6 +623,42 @@ impl Cli {
             );
         }
 
+        if !metainfo.info.v2_files.is_empty() {
+            println!("\nv2 File Tree:");
+            for file in &metainfo.info.v2_files {
+                let layer = file
+                    .pieces_root
+                    .and_then(|root| metainfo.piece_layers.get(&root));
+                println!(
+                    "  - {} ({} bytes, {} piece hashes)",
+                    file.path.join("/"),
+                    file.length,
+                    layer.map_or(0, Vec::len)
+                );
+            }
+        }
+
+        if !metainfo.nodes.is_empty() {
+            println!("\nDHT Nodes:");
+            for node in &metainfo.nodes {
+                println!("  - {}", node);
+            }
+        }
+
+        if !metainfo.url_list.is_empty() {
+            println!("\nWeb Seeds:");
+            for url in &metainfo.url_list {
+                println!("  - {}", url);
+            }
+        }
+
+        if !metainfo.httpseeds.is_empty() {
+            println!("\nHTTP Seeds (BEP 17):");
+            for url in &metainfo.httpseeds {
+                println!("  - {}", url);
+            }
+        }
+
         if let Some(announce_list) = &metainfo.announce_list {
             println!("\nAdditional Trackers:");
             for (tier, trackers) in announce_list.iter().enumerate() {
@@ -104,4 +671,510 @@ impl Cli {
 
         Ok(())
     }
+
+    /// Rehash pieces on disk, all of them or only those selected by piece
+    /// ranges and files, and report on the selection
+    async fn seed(&self, source: &str, config: ClientConfig) -> Result<()> {
+        let metainfo = crate::torrent::load_torrent(source).await?;
+        let storage = StorageManager::new_with_layout(
+            &config.download_dir,
+            &metainfo.info,
+            config.single_entry_layout,
+        )
+        .await?;
+
+        let client = TorrentClient::new(config);
+        client.seed(&metainfo, Arc::new(storage)).await
+    }
+
+    async fn verify(
+        &self,
+        source: &str,
+        output: &str,
+        ranges: &[Range<usize>],
+        files: &[usize],
+        layout: SingleEntryLayout,
+    ) -> Result<()> {
+        let metainfo = crate::torrent::load_torrent(source).await?;
+        let info = &metainfo.info;
+        let num_pieces = info.piece_count();
+
+        let mut selected = Vec::new();
+        for range in ranges {
+            if range.end > num_pieces {
+                return Err(BittorrentError::ConfigError(format!(
+                    "piece {} is past the last piece ({})",
+                    range.end - 1,
+                    num_pieces.saturating_sub(1)
+                )));
+            }
+            selected.extend(range.clone());
+        }
+        for &file in files {
+            let range = file
+                .checked_sub(1)
+                .and_then(|index| info.file_piece_range(index))
+                .ok_or_else(|| {
+                    BittorrentError::ConfigError(format!(
+                        "no file {}; files are numbered 1 to {}",
+                        file,
+                        info.files.len()
+                    ))
+                })?;
+            selected.extend(range);
+        }
+        if ranges.is_empty() && files.is_empty() {
+            selected.extend(0..num_pieces);
+        }
+        selected.sort_unstable();
+        selected.dedup();
+
+        let storage = StorageManager::new_with_layout(output, info, layout).await?;
+        let results = storage.verify_pieces(&info.pieces, &selected).await?;
+        let failed: Vec<usize> = selected
+            .iter()
+            .zip(&results)
+            .filter(|(_, &ok)| !ok)
+            .map(|(&index, _)| index)
+            .collect();
+
+        for index in &failed {
+            println!("Piece {}: FAILED", index);
+        }
+        for &file in files {
+            let range = info.file_piece_range(file - 1).unwrap_or_default();
+            let bad = failed.iter().filter(|index| range.contains(index)).count();
+            let status = if bad == 0 {
+                "OK".to_string()
+            } else {
+                format!("{} of {} pieces failed", bad, range.len())
+            };
+            println!(
+                "File {} ({}): {}",
+                file,
+                info.files[file - 1].path.join("/"),
+                status
+            );
+        }
+        println!(
+            "Verified {} pieces: {} OK, {} failed",
+            selected.len(),
+            selected.len() - failed.len(),
+            failed.len()
+        );
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(BittorrentError::HashMismatch(format!(
+                "{} of {} pieces",
+                failed.len(),
+                selected.len()
+            )))
+        }
+    }
+
+    async fn scrape(&self, source: &str) -> Result<()> {
+        let metainfo = crate::torrent::load_torrent(source).await?;
+
+        let mut trackers: Vec<&str> = metainfo.announce.iter().map(String::as_str).collect();
+        for tracker in metainfo.announce_list.iter().flatten().flatten() {
+            if !trackers.contains(&tracker.as_str()) {
+                trackers.push(tracker);
+            }
+        }
+
+        for url in trackers {
+            if !url.starts_with("udp://") {
+                println!("{}: skipped (only udp:// trackers can be scraped)", url);
+                continue;
+            }
+
+            let result = match UdpTracker::connect(url).await {
+                Ok(mut tracker) => tracker.scrape(&[metainfo.info_hash]).await,
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(response) => {
+                    let stats = response
+                        .files
+                        .get(&metainfo.info_hash)
+                        .copied()
+                        .unwrap_or_default();
+                    println!(
+                        "{}: {} seeders, {} leechers, {} completed",
+                        url, stats.complete, stats.incomplete, stats.downloaded
+                    );
+                }
+                Err(e) => println!("{}: {}", url, e),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn dump_info(&self, source: &str, output: Option<&str>) -> Result<()> {
+        let data = crate::torrent::read_torrent_source(source).await?;
+        let span = find_dict_value(&data, b"info")?;
+        let info = &data[span.clone()];
+
+        println!(
+            "Info dict: bytes {}..{} ({} bytes)",
+            span.start,
+            span.end,
+            info.len()
+        );
+        println!("SHA1: {}", hex::encode(Sha1::digest(info)));
+
+        // Point out when the parser hashes something else
+        match crate::torrent::parse_torrent(&data) {
+            Ok(metainfo) if metainfo.info_hash[..] != Sha1::digest(info)[..] => {
+                println!("Parsed info hash: {} (differs!)", metainfo.info_hash_hex())
+            }
+            Ok(_) => {}
+            Err(e) => println!("Torrent doesn't fully parse: {}", e),
+        }
+
+        match output {
+            Some(path) => {
+                tokio::fs::write(path, info).await?;
+                println!("Wrote {} bytes to {}", info.len(), path);
+            }
+            None => print!("\n{}", hex_dump(info)),
+        }
+
+        Ok(())
+    }
+}
+
+/// Parse a SHA-256 fingerprint, with or without ':' separators
+fn parse_fingerprint(arg: &str) -> std::result::Result<[u8; 32], String> {
+    let bytes = hex::decode(arg.replace(':', ""))
+        .map_err(|e| format!("invalid fingerprint \"{}\": {}", arg, e))?;
+    bytes
+        .try_into()
+        .map_err(|_| format!("fingerprint \"{}\" is not 32 bytes", arg))
+}
+
+/// Parse a peer ID prefix, which must be exactly 8 bytes
+fn parse_peer_id_prefix(arg: &str) -> std::result::Result<[u8; 8], String> {
+    arg.as_bytes()
+        .try_into()
+        .map_err(|_| format!("peer ID prefix \"{}\" must be exactly 8 bytes", arg))
+}
+
+/// Parse a piece index or an inclusive range of them, like "10-20"
+fn parse_piece_range(arg: &str) -> std::result::Result<Range<usize>, String> {
+    let index = |s: &str| {
+        s.trim()
+            .parse::<usize>()
+            .map_err(|_| format!("invalid piece index \"{}\"", s))
+    };
+    let (first, last) = match arg.split_once('-') {
+        Some((first, last)) => (index(first)?, index(last)?),
+        None => (index(arg)?, index(arg)?),
+    };
+    if last < first {
+        return Err(format!("piece range \"{}\" is backwards", arg));
+    }
+    Ok(first..last + 1)
+}
+
+/// Parse a piece selection strategy name
+fn parse_pick_strategy(arg: &str) -> std::result::Result<PickStrategy, String> {
+    arg.parse()
+}
+
+fn parse_single_entry_layout(arg: &str) -> std::result::Result<SingleEntryLayout, String> {
+    arg.parse()
+}
+
+fn parse_tracker_url(arg: &str) -> std::result::Result<String, String> {
+    check_tracker_url(arg).map(|_| arg.to_string())
+}
+
+/// Parse a percentage between 0 and 100
+fn parse_percent(arg: &str) -> std::result::Result<f64, String> {
+    let percent: f64 = arg
+        .parse()
+        .map_err(|e| format!("invalid percentage \"{}\": {}", arg, e))?;
+    if !(0.0..=100.0).contains(&percent) {
+        return Err(format!("percentage {} is not between 0 and 100", percent));
+    }
+    Ok(percent)
+}
+
+/// Format bytes as offset, hex and printable ASCII columns, 16 per line
+fn hex_dump(data: &[u8]) -> String {
+    let mut out = String::new();
+
+    for (line, chunk) in data.chunks(16).enumerate() {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = chunk
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect();
+        out.push_str(&format!(
+            "{:08x}  {:<47}  |{}|\n",
+            line * 16,
+            hex.join(" "),
+            ascii
+        ));
+    }
+
+    out
+}
+
+/// Parse a "Name: value" header argument
+fn parse_header(arg: &str) -> std::result::Result<(String, String), String> {
+    let (name, value) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected \"Name: value\", got \"{}\"", arg))?;
+    Ok((name.trim().to_string(), value.trim().to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_id_prefix_flag() {
+        let cli = Cli::try_parse_from([
+            "bittorrent-rs",
+            "download",
+            "-t",
+            "x.torrent",
+            "--peer-id-prefix",
+            "-qB4650-",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Download { peer_id_prefix, .. } => {
+                assert_eq!(peer_id_prefix, Some(*b"-qB4650-"))
+            }
+            _ => unreachable!(),
+        }
+
+        assert!(parse_peer_id_prefix("-RS01-").is_err());
+        assert!(parse_peer_id_prefix("-RS000001-").is_err());
+    }
+
+    #[test]
+    fn test_seed_super_seed_flag() {
+        let cli =
+            Cli::try_parse_from(["bittorrent-rs", "seed", "x.torrent", "--super-seed"]).unwrap();
+        match cli.command {
+            Commands::Seed { super_seed, .. } => assert!(super_seed),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_piece_ranges() {
+        assert_eq!(parse_piece_range("7"), Ok(7..8));
+        assert_eq!(parse_piece_range("10-20"), Ok(10..21));
+        assert!(parse_piece_range("5-3").is_err());
+        assert!(parse_piece_range("a-3").is_err());
+
+        let cli = Cli::try_parse_from([
+            "bittorrent-rs",
+            "verify",
+            "x.torrent",
+            "--pieces",
+            "0,5,8-9",
+        ])
+        .unwrap();
+        match cli.command {
+            Commands::Verify { pieces, .. } => assert_eq!(pieces, vec![0..1, 5..6, 8..10]),
+            _ => unreachable!(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verify_single_file_of_multi_file_torrent() {
+        use crate::bencode::{encode, BencodeValue};
+
+        let dir = std::env::temp_dir().join(format!("cli-verify-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("album")).unwrap();
+
+        // Three 20000-byte files over 16 KiB pieces: file 1 lies in pieces
+        // 0-1, file 2 in 1-2 and file 3 in 2-3
+        let piece_length = 16384;
+        let content = crate::testutil::random_content(60_000);
+        let names = ["one.bin", "two.bin", "three.bin"];
+        for (name, data) in names.iter().zip(content.chunks(20_000)) {
+            std::fs::write(dir.join("album").join(name), data).unwrap();
+        }
+        let pieces: Vec<u8> = content
+            .chunks(piece_length)
+            .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+            .collect();
+        let files = names
+            .iter()
+            .map(|name| {
+                BencodeValue::dict([
+                    ("length", 20_000i64.into()),
+                    ("path", BencodeValue::List(vec![(*name).into()])),
+                ])
+            })
+            .collect();
+        let torrent = encode(&BencodeValue::dict([
+            ("announce", "http://tracker.example/announce".into()),
+            (
+                "info",
+                BencodeValue::dict([
+                    ("name", "album".into()),
+                    ("piece length", (piece_length as i64).into()),
+                    ("pieces", pieces.into()),
+                    ("files", BencodeValue::List(files)),
+                ]),
+            ),
+        ]));
+        let torrent_path = dir.join("album.torrent");
+        std::fs::write(&torrent_path, torrent).unwrap();
+
+        // Damage piece 3, which only file 3 covers
+        let mut three = std::fs::read(dir.join("album").join("three.bin")).unwrap();
+        three[15_000] ^= 0xff;
+        std::fs::write(dir.join("album").join("three.bin"), three).unwrap();
+
+        let verify = |selection: &[&str]| {
+            let mut args = vec![
+                "bittorrent-rs".to_string(),
+                "verify".to_string(),
+                torrent_path.display().to_string(),
+                "-o".to_string(),
+                dir.display().to_string(),
+            ];
+            args.extend(selection.iter().map(|arg| arg.to_string()));
+            Cli::try_parse_args(args).unwrap()
+        };
+
+        assert!(verify(&["--file", "1"]).run().await.is_ok());
+        assert!(verify(&["--file", "2"]).run().await.is_ok());
+        assert!(verify(&["--pieces", "0-2"]).run().await.is_ok());
+        assert!(matches!(
+            verify(&["--file", "3"]).run().await,
+            Err(BittorrentError::HashMismatch(_))
+        ));
+        assert!(verify(&[]).run().await.is_err());
+        assert!(matches!(
+            verify(&["--file", "4"]).run().await,
+            Err(BittorrentError::ConfigError(_))
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// `main` returns right after `run`, taking the runtime and any task
+    /// still on it along
+    #[tokio::test]
+    async fn test_download_runs_completion_command_before_returning() {
+        use crate::testutil::{make_torrent, random_content, MockPeer, MockTracker};
+        use crate::torrent::parse_torrent;
+
+        let dir = std::env::temp_dir().join(format!("cli-on-complete-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let piece_length = 16384;
+        let content = random_content(piece_length as usize * 2);
+        let info_hash = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap()
+        .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        let torrent_path = dir.join("data.torrent");
+        std::fs::write(
+            &torrent_path,
+            make_torrent("data.bin", &content, piece_length, &tracker.announce_url()),
+        )
+        .unwrap();
+
+        let cli = Cli::try_parse_args([
+            "bittorrent-rs".to_string(),
+            "download".to_string(),
+            "-t".to_string(),
+            torrent_path.display().to_string(),
+            "-o".to_string(),
+            dir.display().to_string(),
+            "--exec-on-complete".to_string(),
+            "touch {path}.done".to_string(),
+        ])
+        .unwrap();
+        tokio::time::timeout(Duration::from_secs(10), cli.run())
+            .await
+            .expect("download timed out")
+            .unwrap();
+
+        assert!(dir.join("data.bin.done").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(feature = "config-file")]
+    #[tokio::test]
+    async fn test_flags_override_config_file() {
+        let path = std::env::temp_dir().join(format!("cli-config-{}.toml", std::process::id()));
+        tokio::fs::write(&path, "port = 51413\nmax_peers = 80\nquota = 100\n")
+            .await
+            .unwrap();
+
+        let cli = Cli::try_parse_args([
+            "bittorrent-rs",
+            "download",
+            "-t",
+            "x.torrent",
+            "--config",
+            path.to_str().unwrap(),
+            "--max-peers",
+            "7",
+        ])
+        .unwrap();
+        let config = cli.download_config().await.unwrap();
+        assert_eq!(config.listen_port, 51413);
+        assert_eq!(config.max_peers, 7);
+        assert_eq!(config.download_quota, Some(100 * 1024 * 1024));
+        assert_eq!(config.piece_timeout, Duration::from_secs(60));
+
+        // A bad file is reported, not silently skipped
+        tokio::fs::write(&path, "port = \"http\"\n").await.unwrap();
+        let err = cli.download_config().await.err().unwrap().to_string();
+        assert!(err.contains("line 1") && err.contains("u16"), "{}", err);
+
+        tokio::fs::remove_file(&path).await.unwrap();
+    }
+
+    #[test]
+    fn test_parse_fingerprint() {
+        let hex = "ab".repeat(32);
+        assert_eq!(parse_fingerprint(&hex).unwrap(), [0xab; 32]);
+
+        let colons = vec!["ab"; 32].join(":");
+        assert_eq!(parse_fingerprint(&colons).unwrap(), [0xab; 32]);
+
+        assert!(parse_fingerprint("abcd").is_err());
+        assert!(parse_fingerprint(&"zz".repeat(32)).is_err());
+    }
+
+    #[test]
+    fn test_hex_dump() {
+        let dump = hex_dump(b"d4:name3:abc\x00\xffe and more");
+        assert_eq!(
+            dump,
+            "00000000  64 34 3a 6e 61 6d 65 33 3a 61 62 63 00 ff 65 20  |d4:name3:abc..e |\n\
+             00000010  61 6e 64 20 6d 6f 72 65                          |and more|\n"
+        );
+    }
 }
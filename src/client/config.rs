@@ -0,0 +1,429 @@
+//! Checking [`ClientConfig`] and loading it from a TOML file
+//!
+//! Config file keys are the `download` flag names with underscores, in the
+//! same units:
+//!
+//! ```toml
+//! output = "/srv/torrents"
+//! port = 51413
+//! max_peers = 80
+//! quota = 2048            # MiB
+//! piece_timeout = 90      # seconds
+//! tracker_deny = ["tracker.example.com"]
+//! ```
+//!
+//! Unknown keys are rejected, so a misspelled setting isn't silently left at
+//! its default.
+
+use super::ClientConfig;
+use crate::error::{BittorrentError, Result};
+#[cfg(feature = "config-file")]
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "config-file")]
+use std::collections::HashSet;
+#[cfg(feature = "config-file")]
+use std::path::Path;
+#[cfg(feature = "config-file")]
+use std::time::Duration;
+
+#[cfg(feature = "config-file")]
+const MIB: u64 = 1024 * 1024;
+
+impl ClientConfig {
+    /// Check settings that are fine on their own but not together, or out
+    /// of range
+    pub fn validate(&self) -> Result<()> {
+        let invalid = |reason: String| Err(BittorrentError::ConfigError(reason));
+
+        if self.listen_port == 0 {
+            return invalid("port must be between 1 and 65535".to_string());
+        }
+        if self.max_peers == 0 || self.min_peers_to_start == 0 {
+            return invalid("max_peers and min_peers must be at least 1".to_string());
+        }
+        if self.pipeline.min_depth == 0 || self.pipeline.min_depth > self.pipeline.max_depth {
+            return invalid(format!(
+                "pipeline depths must satisfy 1 <= min_pipeline_depth ({}) <= max_pipeline_depth ({})",
+                self.pipeline.min_depth, self.pipeline.max_depth
+            ));
+        }
+        self.tracker_overrides.validate()?;
+        if !(0.0..=1.0).contains(&self.endgame_ratio) {
+            return invalid("endgame_percent must be between 0 and 100".to_string());
+        }
+        let intervals = [
+            ("announce_timeout", self.announce_timeout),
+            ("piece_timeout", self.piece_timeout),
+            ("unchoke_timeout", self.unchoke_timeout),
+            ("block_timeout", self.block_timeout),
+            ("peer_idle_timeout", self.peer_idle_timeout),
+            ("stats_interval", self.stats_interval),
+            ("resume_interval", self.resume_interval),
+        ];
+        for (key, interval) in intervals {
+            if interval.is_zero() {
+                return invalid(format!("{} must be at least 1 second", key));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings a config file may hold; anything left out keeps its value
+#[cfg(feature = "config-file")]
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    pub output: Option<String>,
+    pub port: Option<u16>,
+    pub max_peers: Option<usize>,
+    pub min_peers: Option<usize>,
+    pub dry_run: Option<bool>,
+    pub user_agent: Option<String>,
+    pub peer_id_prefix: Option<String>,
+    /// `"Name: value"` strings
+    pub tracker_headers: Option<Vec<String>>,
+    pub insecure_trackers: Option<Vec<String>>,
+    pub tracker_allow: Option<Vec<String>>,
+    pub tracker_deny: Option<Vec<String>>,
+    pub add_trackers: Option<Vec<String>>,
+    pub replace_trackers: Option<bool>,
+    /// Seconds
+    pub announce_timeout: Option<u64>,
+    pub tracker_redirects: Option<usize>,
+    pub no_compact_fallback: Option<bool>,
+    pub socket_tos: Option<u8>,
+    /// MiB
+    pub quota: Option<u64>,
+    /// 0 = retry forever
+    pub max_piece_failures: Option<usize>,
+    /// MiB, 0 = unlimited
+    pub max_inflight_mb: Option<u64>,
+    /// 0 = unlimited
+    pub max_concurrent_pieces: Option<usize>,
+    pub offload_hashing: Option<bool>,
+    /// Seconds
+    pub piece_timeout: Option<u64>,
+    /// Seconds
+    pub unchoke_timeout: Option<u64>,
+    /// Seconds
+    pub block_timeout: Option<u64>,
+    pub min_pipeline_depth: Option<usize>,
+    pub max_pipeline_depth: Option<usize>,
+    /// Seconds
+    pub peer_idle_timeout: Option<u64>,
+    pub wire_dump: Option<usize>,
+    pub pick_strategy: Option<String>,
+    pub random_first_pieces: Option<usize>,
+    pub preview: Option<bool>,
+    pub sequential: Option<bool>,
+    pub endgame_percent: Option<f64>,
+    /// Seconds
+    pub stats_interval: Option<u64>,
+    pub timing_summary: Option<bool>,
+    pub require_extensions: Option<bool>,
+    pub require_encryption: Option<bool>,
+    pub write_buffer_kb: Option<usize>,
+    pub no_fsync: Option<bool>,
+    pub single_entry_layout: Option<String>,
+    pub exec_on_complete: Option<String>,
+    /// Seconds
+    pub resume_interval: Option<u64>,
+}
+
+#[cfg(feature = "config-file")]
+impl FileConfig {
+    /// Read a config file, leaving out the settings named in `overridden`
+    pub async fn load(path: impl AsRef<Path>, overridden: &HashSet<String>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = tokio::fs::read_to_string(path).await.map_err(|e| {
+            BittorrentError::ConfigError(format!("can't read {}: {}", path.display(), e))
+        })?;
+        Self::parse(&text, overridden)
+            .map_err(|e| BittorrentError::ConfigError(format!("{}: {}", path.display(), e)))
+    }
+
+    /// Parse a config document, leaving out the settings named in
+    /// `overridden`, such as flags given on the command line
+    ///
+    /// Every key is still checked, so a mistake is reported even when a
+    /// flag overrides it.
+    pub fn parse(text: &str, overridden: &HashSet<String>) -> Result<Self> {
+        let parse_error = |e: toml::de::Error| BittorrentError::ConfigError(e.to_string());
+        toml::from_str::<Self>(text).map_err(parse_error)?;
+
+        let mut table: toml::Table = toml::from_str(text).map_err(parse_error)?;
+        table.retain(|key, _| !overridden.contains(key));
+        toml::Value::Table(table).try_into().map_err(parse_error)
+    }
+
+    /// Set everything the file gives on `config`
+    pub fn apply(self, config: &mut ClientConfig) -> Result<()> {
+        let invalid = |key: &str, reason: String| {
+            BittorrentError::ConfigError(format!("{}: {}", key, reason))
+        };
+
+        if let Some(output) = self.output {
+            config.download_dir = output;
+        }
+        if let Some(port) = self.port {
+            config.listen_port = port;
+        }
+        if let Some(max_peers) = self.max_peers {
+            config.max_peers = max_peers;
+        }
+        if let Some(min_peers) = self.min_peers {
+            config.min_peers_to_start = min_peers;
+        }
+        if let Some(dry_run) = self.dry_run {
+            config.dry_run = dry_run;
+        }
+        if let Some(user_agent) = self.user_agent {
+            config.user_agent = user_agent;
+        }
+        if let Some(prefix) = self.peer_id_prefix {
+            config.peer_id_prefix = Some(prefix.as_bytes().try_into().map_err(|_| {
+                invalid(
+                    "peer_id_prefix",
+                    format!("\"{}\" must be exactly 8 bytes", prefix),
+                )
+            })?);
+        }
+        if let Some(headers) = self.tracker_headers {
+            config.tracker_headers = headers
+                .iter()
+                .map(|header| {
+                    header
+                        .split_once(':')
+                        .map(|(name, value)| (name.trim().to_string(), value.trim().to_string()))
+                        .ok_or_else(|| {
+                            invalid(
+                                "tracker_headers",
+                                format!("\"{}\" is not \"Name: value\"", header),
+                            )
+                        })
+                })
+                .collect::<Result<_>>()?;
+        }
+        if let Some(hosts) = self.insecure_trackers {
+            config.tracker_tls.insecure_hosts = hosts;
+        }
+        if let Some(allow) = self.tracker_allow {
+            config.tracker_filter.allow = allow;
+        }
+        if let Some(deny) = self.tracker_deny {
+            config.tracker_filter.deny = deny;
+        }
+        if let Some(add) = self.add_trackers {
+            config.tracker_overrides.add = add;
+        }
+        if let Some(replace) = self.replace_trackers {
+            config.tracker_overrides.replace = replace;
+        }
+        if let Some(secs) = self.announce_timeout {
+            config.announce_timeout = Duration::from_secs(secs);
+        }
+        if let Some(redirects) = self.tracker_redirects {
+            config.tracker_redirects = redirects;
+        }
+        if let Some(no_fallback) = self.no_compact_fallback {
+            config.compact_fallback = !no_fallback;
+        }
+        if let Some(tos) = self.socket_tos {
+            config.socket_tos = Some(tos);
+        }
+        if let Some(mib) = self.quota {
+            config.download_quota = Some(mib * MIB);
+        }
+        if let Some(max) = self.max_piece_failures {
+            config.max_piece_failures = (max > 0).then_some(max);
+        }
+        if let Some(mib) = self.max_inflight_mb {
+            config.max_inflight_bytes = (mib > 0).then_some(mib * MIB);
+        }
+        if let Some(max) = self.max_concurrent_pieces {
+            config.max_concurrent_pieces = (max > 0).then_some(max);
+        }
+        if let Some(offload) = self.offload_hashing {
+            config.offload_hashing = offload;
+        }
+        if let Some(secs) = self.piece_timeout {
+            config.piece_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.unchoke_timeout {
+            config.unchoke_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = self.block_timeout {
+            config.block_timeout = Duration::from_secs(secs);
+        }
+        if let Some(depth) = self.min_pipeline_depth {
+            config.pipeline.min_depth = depth;
+        }
+        if let Some(depth) = self.max_pipeline_depth {
+            config.pipeline.max_depth = depth;
+        }
+        if let Some(secs) = self.peer_idle_timeout {
+            config.peer_idle_timeout = Duration::from_secs(secs);
+        }
+        if let Some(limit) = self.wire_dump {
+            config.wire_dump = Some(limit);
+        }
+        if let Some(strategy) = self.pick_strategy {
+            config.pick_strategy = strategy.parse().map_err(|e| invalid("pick_strategy", e))?;
+        }
+        if let Some(count) = self.random_first_pieces {
+            config.random_first_pieces = count;
+        }
+        if let Some(preview) = self.preview {
+            config.preview = preview;
+        }
+        if let Some(sequential) = self.sequential {
+            config.sequential = sequential;
+        }
+        if let Some(percent) = self.endgame_percent {
+            config.endgame_ratio = percent / 100.0;
+        }
+        if let Some(secs) = self.stats_interval {
+            config.stats_interval = Duration::from_secs(secs);
+        }
+        if let Some(summary) = self.timing_summary {
+            config.timing_summary = summary;
+        }
+        if let Some(require) = self.require_extensions {
+            config.handshake_policy.require_extension_protocol = require;
+        }
+        if let Some(require) = self.require_encryption {
+            config.handshake_policy.require_encryption = require;
+        }
+        if let Some(kib) = self.write_buffer_kb {
+            config.write_buffer_bytes = kib * 1024;
+        }
+        if let Some(no_fsync) = self.no_fsync {
+            config.fsync = !no_fsync;
+        }
+        if let Some(layout) = self.single_entry_layout {
+            config.single_entry_layout = layout
+                .parse()
+                .map_err(|e| invalid("single_entry_layout", e))?;
+        }
+        if let Some(command) = self.exec_on_complete {
+            config.on_complete = Some(command);
+        }
+        if let Some(secs) = self.resume_interval {
+            config.resume_interval = Duration::from_secs(secs);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "config-file"))]
+mod tests {
+    use super::*;
+    use crate::piece::PickStrategy;
+
+    const SAMPLE: &str = r#"
+# Daemon settings
+output = "/srv/torrents"
+port = 51413
+max_peers = 80
+quota = 2_048            # MiB
+piece_timeout = 90
+endgame_percent = 5
+pick_strategy = 'sequential'
+no_fsync = true
+tracker_headers = ["X-Passkey: s3cret"]
+tracker_deny = [
+    "tracker.example.com",  # too slow
+    "udp://dead.example:80/announce",
+]
+exec_on_complete = "notify-send \"done\" {name}"
+"#;
+
+    fn load(text: &str) -> Result<ClientConfig> {
+        let mut config = ClientConfig::default();
+        FileConfig::parse(text, &HashSet::new())?.apply(&mut config)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_sample_config_roundtrips() {
+        let config = load(SAMPLE).unwrap();
+        assert_eq!(config.download_dir, "/srv/torrents");
+        assert_eq!(config.listen_port, 51413);
+        assert_eq!(config.max_peers, 80);
+        assert_eq!(config.download_quota, Some(2048 * MIB));
+        assert_eq!(config.piece_timeout, Duration::from_secs(90));
+        assert_eq!(config.endgame_ratio, 0.05);
+        assert_eq!(config.pick_strategy, PickStrategy::Sequential);
+        assert!(!config.fsync);
+        assert_eq!(
+            config.tracker_headers,
+            vec![("X-Passkey".to_string(), "s3cret".to_string())]
+        );
+        assert_eq!(config.tracker_filter.deny.len(), 2);
+        assert_eq!(
+            config.on_complete.as_deref(),
+            Some("notify-send \"done\" {name}")
+        );
+        // Anything not in the file keeps its default
+        assert_eq!(
+            config.unchoke_timeout,
+            ClientConfig::default().unchoke_timeout
+        );
+
+        let file = FileConfig::parse(SAMPLE, &HashSet::new()).unwrap();
+        let written = toml::to_string(&file).unwrap();
+        assert_eq!(FileConfig::parse(&written, &HashSet::new()).unwrap(), file);
+    }
+
+    #[test]
+    fn test_overridden_settings_are_left_out() {
+        let overridden = HashSet::from(["port".to_string()]);
+        let file = FileConfig::parse(SAMPLE, &overridden).unwrap();
+        assert_eq!(file.port, None);
+        assert_eq!(file.max_peers, Some(80));
+
+        // but still checked
+        assert!(FileConfig::parse("port = \"http\"", &overridden).is_err());
+    }
+
+    #[test]
+    fn test_malformed_config_is_rejected() {
+        let error = |text: &str| match load(text) {
+            Ok(_) => panic!("accepted {:?}", text),
+            Err(e) => e.to_string(),
+        };
+
+        assert!(error("port = 70000").contains("u16"));
+        assert!(error("port = 0").contains("between 1 and 65535"));
+        assert!(error("\n\nmax_peer = 5").contains("line 3"));
+        assert!(error("\n\nmax_peer = 5").contains("unknown field `max_peer`"));
+        assert!(error("max_peers = \"many\"").contains("invalid type"));
+        assert!(error("output = /srv").contains("line 1"));
+        assert!(error("[download]\nport = 1").contains("unknown field `download`"));
+        assert!(error("port = 1\nport = 2").contains("duplicate key"));
+        assert!(error("min_pipeline_depth = 10\nmax_pipeline_depth = 4").contains("pipeline"));
+        assert!(error("pick_strategy = \"fastest\"").contains("unknown piece strategy"));
+        assert!(error("peer_id_prefix = \"-RS01-\"").contains("exactly 8 bytes"));
+    }
+
+    #[test]
+    fn test_full_toml_syntax_is_accepted() {
+        let config = load(
+            r#"
+user_agent = """
+multi\tline"""
+exec_on_complete = 'C:\tools\done.exe {path}'
+tracker_allow = ["a\u00e9"]
+"#,
+        )
+        .unwrap();
+        assert_eq!(config.user_agent, "multi\tline");
+        assert_eq!(
+            config.on_complete.as_deref(),
+            Some("C:\\tools\\done.exe {path}")
+        );
+        assert_eq!(config.tracker_filter.allow, vec!["a\u{e9}".to_string()]);
+    }
+}
@@ -0,0 +1,113 @@
+//! External command run when a download finishes
+
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::task::JoinHandle;
+use tracing::{info, warn};
+
+/// Values substituted into a completion command
+pub struct HookContext {
+    /// Torrent name (`{name}`)
+    pub name: String,
+    /// Where the download was written (`{path}`)
+    pub path: String,
+    /// Info hash in hex (`{hash}`)
+    pub hash: String,
+}
+
+/// Split a command template into arguments and fill in placeholders
+///
+/// The template is split on whitespace and run without a shell, so a torrent
+/// name containing spaces or shell syntax stays a single, inert argument.
+pub fn expand_command(template: &str, context: &HookContext) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|arg| {
+            arg.replace("{name}", &context.name)
+                .replace("{path}", &context.path)
+                .replace("{hash}", &context.hash)
+        })
+        .collect()
+}
+
+/// Start the completion command in the background, logging how it went
+/// once it exits
+///
+/// The download doesn't wait for it, so a slow command (unpacking, moving
+/// files) doesn't hold up seeding or the resume save. The returned handle
+/// must be awaited before the runtime shuts down, or the command may never
+/// run.
+pub fn spawn_on_complete(template: String, context: HookContext) -> JoinHandle<()> {
+    tokio::spawn(async move { run_on_complete(&template, &context).await })
+}
+
+/// Run the completion command and log how it went
+async fn run_on_complete(template: &str, context: &HookContext) {
+    let args = expand_command(template, context);
+    let Some((program, args)) = args.split_first() else {
+        return;
+    };
+
+    info!("Running completion command: {}", program);
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await;
+
+    match output {
+        Ok(output) => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            if output.status.success() {
+                info!("Completion command finished: {}", output.status);
+            } else {
+                warn!("Completion command failed: {}", output.status);
+            }
+            if !stderr.trim().is_empty() {
+                warn!("Completion command stderr: {}", stderr.trim());
+            }
+        }
+        Err(e) => warn!("Failed to run completion command {}: {}", program, e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> HookContext {
+        HookContext {
+            name: "My Movie; rm -rf ~".to_string(),
+            path: "/downloads/My Movie; rm -rf ~".to_string(),
+            hash: "abcd".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_placeholders_stay_single_arguments() {
+        let args = expand_command("notify --file={path}  {hash}", &context());
+        assert_eq!(
+            args,
+            vec!["notify", "--file=/downloads/My Movie; rm -rf ~", "abcd"]
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_command_runs() {
+        let dir = std::env::temp_dir().join(format!("hook-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("My Movie; rm -rf ~");
+
+        let context = HookContext {
+            path: marker.to_string_lossy().into_owned(),
+            ..context()
+        };
+        spawn_on_complete("touch {path}".to_string(), context)
+            .await
+            .unwrap();
+        assert!(marker.exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,18 +1,203 @@
+mod config;
+mod hook;
+mod seed;
+mod stats;
+
+#[cfg(feature = "config-file")]
+pub use config::FileConfig;
+pub use stats::DownloadStats;
+pub use stats::FileProgress;
+pub use stats::PieceTiming;
+pub use stats::Stats;
+
+use stats::PieceTimings;
+
 use crate::error::{BittorrentError, Result};
-use crate::peer::{BlockInfo, PeerConnection, PeerMessage};
-use crate::piece::{PieceManager, PiecePicker};
-use crate::storage::StorageManager;
-use crate::tracker::{generate_peer_id, TrackerClient, TrackerRequest};
-use std::path::Path;
+use crate::peer::{
+    AvailabilityChange, BlockInfo, Blocklist, BufferPool, DialOrder, HandshakePolicy, HashFailures,
+    PeerConnection, PeerEvent, PeerMessage, PeerRegistry, PeerStats, PeerStatsTable,
+    PipelineLimits, ProvenFirst, DEFAULT_MAX_FAILED_PIECES,
+};
+use crate::piece::{
+    PickStrategy, PieceManager, PiecePicker, PieceState, DEFAULT_ENDGAME_MIN_PIECES,
+    DEFAULT_ENDGAME_RATIO, DEFAULT_MAX_CONCURRENT_PIECES, DEFAULT_RANDOM_FIRST_PIECES,
+};
+use crate::storage::{
+    content_path, resume_path, NullStorage, ResumeData, SingleEntryLayout, StorageBackend,
+    StorageManager,
+};
+use crate::torrent::{Metainfo, TorrentInfo};
+use crate::tracker::{
+    generate_peer_id_with_prefix, AnnounceSchedule, TrackerClient, TrackerEvent, TrackerFilter,
+    TrackerOptions, TrackerOverrides, TrackerRequest, TrackerTls, DEFAULT_ANNOUNCE_TIMEOUT,
+    DEFAULT_MAX_REDIRECTS, DEFAULT_PEER_ID_PREFIX, DEFAULT_USER_AGENT,
+};
+use crate::webseed::WebSeed;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::sync::Mutex;
-use tracing::{info, warn};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{mpsc, watch, Mutex, Notify, Semaphore};
+use tokio::task::{JoinHandle, JoinSet};
+use tokio::time::Instant;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// Most peer connections dialed at once, across every dialing task
+const MAX_CONCURRENT_DIALS: usize = 16;
+
+/// How often the number of connected peers is checked against `max_peers`
+/// when no peer has been lost meanwhile
+const REFILL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Time allowed for a peer to accept the connection and handshake, or for
+/// a web seed to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the connection pool is checked for dead peers
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time allowed for saying goodbye to pooled peers once a download ends
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// What every dial needs to handshake a peer for the current torrent
+#[derive(Clone)]
+struct DialParams {
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    policy: HandshakePolicy,
+    num_pieces: usize,
+    pipeline: PipelineLimits,
+    wire_dump: Option<usize>,
+    /// Longest a connection may wait for a complete message
+    read_timeout: Duration,
+    /// Shared by every connection for received block data
+    buffer_pool: BufferPool,
+    /// Where each new connection's stats are registered
+    peer_stats: PeerStatsTable,
+    /// ToS byte marking outgoing peer traffic
+    socket_tos: Option<u8>,
+    /// Half-open connection limit shared by every dialing task
+    half_open: Arc<Semaphore>,
+    /// Where each connection reports the pieces its peer offers
+    availability: mpsc::UnboundedSender<AvailabilityChange>,
+}
+
+/// How long a peer task waits at each step of fetching a piece
+#[derive(Debug, Clone, Copy)]
+struct PieceTimeouts {
+    /// For the peer to unchoke us
+    unchoke: Duration,
+    /// For every block of the piece, from the first request
+    piece: Duration,
+    /// For the next block while requests are outstanding
+    block: Duration,
+}
 
 /// Configuration for the BitTorrent client
 pub struct ClientConfig {
     pub download_dir: String,
     pub listen_port: u16,
     pub max_peers: usize,
+    /// Start downloading once this many peers are connected; more keep
+    /// connecting in the background
+    pub min_peers_to_start: usize,
+    /// Download and verify pieces without writing them to disk
+    pub dry_run: bool,
+    /// User-Agent sent to HTTP trackers
+    pub user_agent: String,
+    /// Client identifier at the start of our peer ID (None = `-RS0001-`)
+    pub peer_id_prefix: Option<[u8; 8]>,
+    /// Extra headers sent with every tracker announce
+    pub tracker_headers: Vec<(String, String)>,
+    /// Extra trust, pinning or (dangerously) no checks for HTTPS trackers
+    pub tracker_tls: TrackerTls,
+    /// Trackers to skip, or the only ones to use
+    pub tracker_filter: TrackerFilter,
+    /// Trackers to announce to besides, or instead of, the torrent's
+    pub tracker_overrides: TrackerOverrides,
+    /// How long to wait on one tracker before trying the next
+    pub announce_timeout: Duration,
+    /// HTTP redirects followed per announce (0 = none)
+    pub tracker_redirects: usize,
+    /// Ask HTTP trackers again with `compact=0` when a compact announce
+    /// gives no usable peer list
+    pub compact_fallback: bool,
+    /// Peer addresses never to contact, whatever source they come from
+    pub blocklist: Option<Arc<Blocklist>>,
+    /// Which known peers are dialed first when there are more than
+    /// `max_peers`
+    pub dial_order: Arc<dyn DialOrder>,
+    /// ToS byte (DSCP << 2) set on peer sockets so routers can deprioritize
+    /// the traffic (None = leave the system default)
+    pub socket_tos: Option<u8>,
+    /// Stop starting new pieces once this many bytes have been downloaded
+    /// this session (None = unlimited)
+    pub download_quota: Option<u64>,
+    /// Abort once this many different pieces keep failing verification
+    /// (None = retry forever)
+    pub max_piece_failures: Option<usize>,
+    /// Cap on memory used by in-progress piece buffers (None = unlimited)
+    pub max_inflight_bytes: Option<u64>,
+    /// Most pieces assembled at once, keeping writes local and resume
+    /// state small (None = unlimited)
+    pub max_concurrent_pieces: Option<usize>,
+    /// Hash completed pieces on the blocking thread pool instead of the
+    /// peer task's async worker
+    pub offload_hashing: bool,
+    /// Time allowed for a whole piece before it is abandoned and re-picked
+    pub piece_timeout: Duration,
+    /// Time to wait for a peer to unchoke us before trying another
+    pub unchoke_timeout: Duration,
+    /// Time to wait for the next block of a piece before dropping the peer
+    pub block_timeout: Duration,
+    /// Range the per-peer request pipeline is tuned within
+    pub pipeline: PipelineLimits,
+    /// Hex-dump up to this many bytes of each peer message to the wire log
+    pub wire_dump: Option<usize>,
+    /// Drop pooled connections that sent or received nothing for this long
+    pub peer_idle_timeout: Duration,
+    /// How the next piece to download is chosen
+    pub pick_strategy: PickStrategy,
+    /// Pieces fetched at random before rarest-first takes over, with
+    /// [`PickStrategy::RandomFirstThenRarest`]
+    pub random_first_pieces: usize,
+    /// Fetch the first and last piece of every file before the rest, so
+    /// media files can be opened early
+    pub preview: bool,
+    /// Stream-friendly mode: pick pieces in order and flush each one to
+    /// storage as soon as it verifies, so files become readable front to
+    /// back. Overrides `pick_strategy` and `write_buffer_bytes`
+    pub sequential: bool,
+    /// Enter endgame once this share of pieces, or
+    /// `endgame_min_pieces` if more, is left
+    pub endgame_ratio: f64,
+    /// Remaining piece count that always triggers endgame
+    pub endgame_min_pieces: usize,
+    /// How often download statistics are reported
+    pub stats_interval: Duration,
+    /// Redraw a single progress line on stdout instead of logging progress
+    pub progress_line: bool,
+    /// Which peers to refuse based on their handshake
+    pub handshake_policy: HandshakePolicy,
+    /// Coalesce sequential disk writes up to this many bytes (0 = write through)
+    pub write_buffer_bytes: usize,
+    /// Sync written data to disk, see [`StorageManager::with_fsync`]
+    pub fsync: bool,
+    /// Where the file of a multi-file torrent with a single entry goes
+    pub single_entry_layout: SingleEntryLayout,
+    /// How often session state is saved to the resume file
+    pub resume_interval: Duration,
+    /// Command run once when a download completes, with `{name}`, `{path}`
+    /// and `{hash}` filled in
+    pub on_complete: Option<String>,
+    /// Advertise pieces one peer at a time when seeding (BEP 16), see
+    /// [`SuperSeeder`](crate::piece::SuperSeeder)
+    pub super_seed: bool,
+    /// Log median, p95 and slowest peers of piece download times when a
+    /// download completes
+    pub timing_summary: bool,
 }
 
 impl Default for ClientConfig {
@@ -21,6 +206,47 @@ impl Default for ClientConfig {
             download_dir: "./downloads".to_string(),
             listen_port: 6881,
             max_peers: 50,
+            min_peers_to_start: 1,
+            dry_run: false,
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            peer_id_prefix: None,
+            tracker_headers: Vec::new(),
+            tracker_tls: TrackerTls::default(),
+            tracker_filter: TrackerFilter::default(),
+            tracker_overrides: TrackerOverrides::default(),
+            announce_timeout: DEFAULT_ANNOUNCE_TIMEOUT,
+            tracker_redirects: DEFAULT_MAX_REDIRECTS,
+            compact_fallback: true,
+            blocklist: None,
+            dial_order: Arc::new(ProvenFirst),
+            socket_tos: None,
+            download_quota: None,
+            max_piece_failures: Some(DEFAULT_MAX_FAILED_PIECES),
+            max_inflight_bytes: Some(256 * 1024 * 1024),
+            max_concurrent_pieces: Some(DEFAULT_MAX_CONCURRENT_PIECES),
+            offload_hashing: false,
+            piece_timeout: Duration::from_secs(60),
+            unchoke_timeout: Duration::from_secs(30),
+            block_timeout: Duration::from_secs(30),
+            pipeline: PipelineLimits::default(),
+            wire_dump: None,
+            peer_idle_timeout: Duration::from_secs(180),
+            pick_strategy: PickStrategy::default(),
+            random_first_pieces: DEFAULT_RANDOM_FIRST_PIECES,
+            preview: false,
+            sequential: false,
+            endgame_ratio: DEFAULT_ENDGAME_RATIO,
+            endgame_min_pieces: DEFAULT_ENDGAME_MIN_PIECES,
+            stats_interval: Duration::from_secs(5),
+            progress_line: false,
+            handshake_policy: HandshakePolicy::default(),
+            write_buffer_bytes: 1024 * 1024,
+            fsync: true,
+            single_entry_layout: SingleEntryLayout::Directory,
+            resume_interval: Duration::from_secs(30),
+            on_complete: None,
+            super_seed: false,
+            timing_summary: false,
         }
     }
 }
@@ -29,39 +255,254 @@ impl Default for ClientConfig {
 pub struct TorrentClient {
     config: ClientConfig,
     peer_id: [u8; 20],
+    /// Statistics for the current download
+    stats: Arc<Mutex<Stats>>,
+    /// Counters the download tasks update without locking
+    counters: Arc<DownloadStats>,
+    /// Stats of every open peer connection
+    peers: PeerStatsTable,
+    /// How long each piece of the current download took
+    timings: Arc<std::sync::Mutex<PieceTimings>>,
+    /// Whether downloads are paused; sessions follow changes as they run
+    paused: watch::Sender<bool>,
+    /// Completion commands still running
+    completion_commands: std::sync::Mutex<Vec<JoinHandle<()>>>,
 }
 
 impl TorrentClient {
     pub fn new(config: ClientConfig) -> Self {
-        let peer_id = generate_peer_id();
+        let prefix = config.peer_id_prefix.unwrap_or(DEFAULT_PEER_ID_PREFIX);
+        let peer_id = generate_peer_id_with_prefix(&prefix);
         info!("Client initialized with peer_id: {}", hex::encode(peer_id));
 
-        Self { config, peer_id }
+        Self {
+            config,
+            peer_id,
+            stats: Arc::new(Mutex::new(Stats::default())),
+            counters: Arc::new(DownloadStats::new()),
+            peers: PeerStatsTable::new(),
+            timings: Arc::new(std::sync::Mutex::new(PieceTimings::new())),
+            paused: watch::channel(false).0,
+            completion_commands: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Stop starting pieces until [`TorrentClient::resume`]
+    ///
+    /// Pieces already being downloaded are finished, then idle peers are
+    /// told we're not interested and the tracker that we've stopped.
+    /// Connections and every piece's state are kept.
+    #[allow(
+        dead_code,
+        reason = "control for embedders; the CLI has no way to pause"
+    )]
+    pub fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("Download paused");
+        }
+    }
+
+    /// Pick up a paused download where it stopped, announcing to the
+    /// tracker again
+    #[allow(dead_code, reason = "counterpart of `pause`")]
+    pub fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("Download resumed");
+        }
+    }
+
+    #[allow(dead_code, reason = "counterpart of `pause`")]
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Wait until the completion commands of finished downloads exit
+    ///
+    /// Downloads return without waiting for them; a caller about to shut
+    /// the runtime down waits here, or the commands never get to run.
+    pub async fn wait_for_completion_commands(&self) {
+        let commands = std::mem::take(&mut *self.completion_commands.lock().unwrap());
+        for command in commands {
+            let _ = command.await;
+        }
+    }
+
+    /// Get a snapshot of the current download's statistics
+    #[allow(dead_code, reason = "the CLI logs stats from inside the download loop")]
+    pub async fn stats(&self) -> Stats {
+        let mut stats = self.stats.lock().await.clone();
+        stats.pieces_complete = self.counters.pieces_completed();
+        stats.downloaded_bytes = self.counters.bytes_downloaded();
+        stats.connected_peers = self.counters.active_peers();
+        stats.piece_timing = self.timings.lock().unwrap().summary();
+        stats
+    }
+
+    /// Per-peer transfer counts, choke/interest state and client names of
+    /// every open connection
+    #[allow(
+        dead_code,
+        reason = "for embedders; the CLI logs per-peer stats itself"
+    )]
+    pub fn peer_stats(&self) -> Vec<PeerStats> {
+        self.peers.snapshot()
+    }
+
+    /// Lock-free counters of the current download
+    #[allow(dead_code, reason = "for progress readers running beside the download")]
+    pub fn counters(&self) -> Arc<DownloadStats> {
+        self.counters.clone()
     }
 
     /// Download a torrent
-    pub async fn download(&self, torrent_path: &Path) -> Result<()> {
-        info!("Starting download for: {}", torrent_path.display());
+    ///
+    /// `source` is a path, an http(s) URL, or `-` for stdin.
+    pub async fn download(&self, source: &str) -> Result<()> {
+        info!("Starting download for: {}", source);
 
         // Load torrent file
-        let metainfo = crate::torrent::load_torrent_file(torrent_path).await?;
+        let metainfo = crate::torrent::load_torrent(source).await?;
 
         info!("Torrent: {}", metainfo.info.name);
         info!("Total size: {} bytes", metainfo.info.total_length);
-        info!("Pieces: {}", metainfo.info.pieces.len());
+        info!("Pieces: {}", metainfo.info.piece_count());
         info!("Info hash: {}", metainfo.info_hash_hex());
 
+        let storage: Arc<dyn StorageBackend> = if self.config.dry_run {
+            info!("Dry run enabled: pieces will be verified but not written to disk");
+            Arc::new(NullStorage::new())
+        } else {
+            Arc::new(
+                StorageManager::new_with_layout(
+                    &self.config.download_dir,
+                    &metainfo.info,
+                    self.config.single_entry_layout,
+                )
+                .await?
+                .with_write_buffer(self.write_buffer_bytes())
+                .with_fsync(self.config.fsync),
+            )
+        };
+
+        // Dry runs write nothing, so there is nothing to resume
+        let resume = (!self.config.dry_run)
+            .then(|| resume_path(&self.config.download_dir, &metainfo.info.name));
+
+        self.download_session(&metainfo, storage, resume).await
+    }
+
+    /// Disk write coalescing, which sequential mode turns off so pieces
+    /// reach the disk in the order they verify
+    fn write_buffer_bytes(&self) -> usize {
+        if self.config.sequential {
+            0
+        } else {
+            self.config.write_buffer_bytes
+        }
+    }
+
+    /// Trackers this client announces to for a torrent: its own, adjusted
+    /// by [`ClientConfig::tracker_overrides`]
+    pub fn announce_tiers(&self, metainfo: &Metainfo) -> Vec<Vec<String>> {
+        self.config
+            .tracker_overrides
+            .apply(&metainfo.announce_tiers())
+    }
+
+    /// Download a parsed torrent into the given storage backend
+    #[allow(dead_code, reason = "the CLI picks its backend through `download`")]
+    pub async fn download_with_storage(
+        &self,
+        metainfo: &Metainfo,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<()> {
+        self.download_session(metainfo, storage, None).await
+    }
+
+    /// Download a parsed torrent, saving session state to `resume` if given
+    #[tracing::instrument(name = "torrent", skip_all, fields(hash = %&metainfo.info_hash_hex()[..8]))]
+    async fn download_session(
+        &self,
+        metainfo: &Metainfo,
+        storage: Arc<dyn StorageBackend>,
+        resume: Option<PathBuf>,
+    ) -> Result<()> {
+        if metainfo.info.is_v2_only() {
+            return Err(BittorrentError::InvalidTorrent(
+                "BitTorrent v2-only torrents can't be downloaded yet".to_string(),
+            ));
+        }
+
         // Initialize components
-        let storage = StorageManager::new(&self.config.download_dir, &metainfo.info).await?;
-        let piece_manager = Arc::new(Mutex::new(PieceManager::new(
-            metainfo.info.piece_length,
-            metainfo.info.total_length,
-            &metainfo.info.pieces,
-        )));
-        let piece_picker = Arc::new(Mutex::new(PiecePicker::new(metainfo.info.pieces.len())));
+        let piece_manager = Arc::new(Mutex::new(
+            PieceManager::new(&metainfo.info)
+                .with_max_inflight_bytes(self.config.max_inflight_bytes)
+                .with_download_quota(self.config.download_quota)
+                .with_offload_hashing(self.config.offload_hashing),
+        ));
+        let strategy = if self.config.sequential {
+            PickStrategy::Sequential
+        } else {
+            self.config.pick_strategy
+        };
+        let mut picker = PiecePicker::new(metainfo.info.piece_count())
+            .with_strategy(strategy, self.config.random_first_pieces)
+            .with_endgame_threshold(self.config.endgame_ratio, self.config.endgame_min_pieces)
+            .with_max_concurrent_pieces(self.config.max_concurrent_pieces);
+        if self.config.preview {
+            picker.boost_pieces(metainfo.info.file_boundary_pieces());
+        }
+        let mut paused = self.paused.subscribe();
+        picker.set_paused(*paused.borrow_and_update());
+        let piece_picker = Arc::new(Mutex::new(picker));
+
+        // Pick up where a previous session left off
+        let resume_base = match &resume {
+            Some(path) => {
+                Self::restore_session(
+                    path,
+                    metainfo,
+                    storage.as_ref(),
+                    &piece_manager,
+                    &piece_picker,
+                )
+                .await
+            }
+            None => ResumeData::new(metainfo.info_hash, metainfo.info.piece_count()),
+        };
+        let initial_completed = piece_manager.lock().await.completed_bytes();
+        self.counters.reset(
+            piece_manager.lock().await.complete_count(),
+            initial_completed,
+        );
+
+        if piece_manager.lock().await.is_complete() {
+            info!("All pieces already verified on disk, nothing to download");
+            if let Some(path) = &resume {
+                let snapshot = Self::resume_snapshot(
+                    &resume_base,
+                    initial_completed,
+                    &piece_manager,
+                    &piece_picker,
+                    storage.as_ref(),
+                )
+                .await;
+                if let Err(e) = snapshot.save(path).await {
+                    warn!("Failed to save resume data: {}", e);
+                }
+            }
+            return Ok(());
+        }
 
         // Contact tracker
-        let tracker_client = TrackerClient::new();
+        let tracker_client = TrackerClient::with_options(&TrackerOptions {
+            user_agent: self.config.user_agent.clone(),
+            headers: self.config.tracker_headers.clone(),
+            tls: self.config.tracker_tls.clone(),
+            timeout: self.config.announce_timeout,
+            max_redirects: self.config.tracker_redirects,
+            compact_fallback: self.config.compact_fallback,
+        })?;
         let request = TrackerRequest::new(
             metainfo.info_hash,
             self.peer_id,
@@ -69,203 +510,952 @@ impl TorrentClient {
             metainfo.info.total_length,
         );
 
-        let tracker_response = tracker_client
-            .announce(&metainfo.announce, &request)
-            .await?;
+        let all_trackers = self.announce_tiers(metainfo);
+        let trackers: Vec<String> = self
+            .config
+            .tracker_filter
+            .apply(&all_trackers)
+            .into_iter()
+            .flatten()
+            .collect();
+        if !all_trackers.is_empty() && trackers.is_empty() {
+            return Err(BittorrentError::TrackerError(format!(
+                "All {} trackers are excluded by the tracker allow/deny lists",
+                all_trackers.iter().flatten().count()
+            )));
+        }
+        if trackers.is_empty() && metainfo.web_seed_count() == 0 {
+            return Err(BittorrentError::TrackerError(
+                "Torrent has no trackers or web seeds and DHT is not supported".to_string(),
+            ));
+        }
 
-        info!(
-            "Received {} peers from tracker",
-            tracker_response.peers.len()
-        );
+        // Files finished in an earlier session start out complete
+        let file_bytes = piece_manager
+            .lock()
+            .await
+            .file_completed_bytes(&metainfo.info.files);
+        *self.stats.lock().await = Stats {
+            pieces_total: metainfo.info.piece_count(),
+            total_bytes: metainfo.info.total_length,
+            files: metainfo
+                .info
+                .files
+                .iter()
+                .zip(file_bytes)
+                .map(|(file, completed_bytes)| FileProgress {
+                    path: file.path.join("/"),
+                    length: file.length,
+                    completed_bytes,
+                })
+                .collect(),
+            ..Default::default()
+        };
+        *self.timings.lock().unwrap() = PieceTimings::new();
+
+        // Use the first tracker in announce order that answers. Dead
+        // trackers are survivable when web seeds can serve the content.
+        let mut tracker_url = None;
+        let mut tracker_response = None;
+        let mut schedule = None;
+        let mut last_error = None;
+        for url in &trackers {
+            match tracker_client.announce(url, &request).await {
+                Ok(response) => {
+                    tracker_url = Some(url.clone());
+                    schedule = Some(AnnounceSchedule::new(&response, Instant::now()));
+                    tracker_response = Some(response);
+                    break;
+                }
+                Err(e) => {
+                    warn!("Announce to {} failed: {}", url, e);
+                    last_error = Some(e);
+                }
+            }
+        }
+        if let (None, Some(e)) = (&tracker_response, last_error) {
+            if metainfo.web_seed_count() == 0 {
+                return Err(e);
+            }
+            warn!("No tracker answered, relying on web seeds");
+        }
+        // Every peer source feeds one registry so no address is dialed twice
+        let registry = PeerRegistry::new()
+            .with_blocklist(self.config.blocklist.clone())
+            .with_dial_order(self.config.dial_order.clone());
+        if let Some(response) = &tracker_response {
+            info!("Received {} peers from tracker", response.peers.len());
+            self.stats.lock().await.update_swarm(
+                response.peers.len(),
+                response.complete,
+                response.incomplete,
+            );
+            registry.add_all(response.peers.iter().map(|peer| peer.addr));
+        }
 
         // Try to connect to peers and download
-        if tracker_response.peers.is_empty() {
+        if registry.is_empty() && metainfo.web_seed_count() == 0 {
             return Err(BittorrentError::TrackerError(
                 "No peers available".to_string(),
             ));
         }
 
-        // Storage를 Arc로 감싸서 공유
-        let storage = Arc::new(storage);
-
-        // Try to connect to multiple peers
-        let mut peer_connections = Vec::new();
-        let max_connections = std::cmp::min(self.config.max_peers, tracker_response.peers.len());
-
+        // Dial peers in the background, handing over each one that connects
+        let max_connections = std::cmp::min(self.config.max_peers, registry.len());
         info!("Attempting to connect to up to {} peers", max_connections);
 
-        for peer_info in tracker_response.peers.iter().take(max_connections * 2) {
-            if peer_connections.len() >= max_connections {
-                break;
+        // Count each peer's pieces as it announces them, and stop counting
+        // them once it's gone
+        let (availability_tx, mut availability_rx) = mpsc::unbounded_channel();
+        let availability_piece_picker = piece_picker.clone();
+        let availability_task = tokio::spawn(
+            async move {
+                while let Some(change) = availability_rx.recv().await {
+                    let mut picker = availability_piece_picker.lock().await;
+                    let lost = match change {
+                        AvailabilityChange::Gained(bitfield) => {
+                            picker.update_peer_pieces(&bitfield);
+                            continue;
+                        }
+                        AvailabilityChange::Have(piece_index) => {
+                            picker.add_peer_piece(piece_index);
+                            continue;
+                        }
+                        AvailabilityChange::Lost(bitfield) => picker.remove_peer_pieces(&bitfield),
+                    };
+
+                    for piece_index in lost.iter().take(5) {
+                        warn!(
+                            "Piece {} no longer available from any connected peer",
+                            piece_index
+                        );
+                    }
+                    if lost.len() > 5 {
+                        warn!(
+                            "{} more pieces no longer available from any connected peer",
+                            lost.len() - 5
+                        );
+                    }
+                }
             }
+            .in_current_span(),
+        );
 
-            match tokio::time::timeout(
-                tokio::time::Duration::from_secs(5),
-                PeerConnection::connect(peer_info.addr, metainfo.info_hash, self.peer_id),
+        let buffer_pool = BufferPool::default();
+        let dial_params = DialParams {
+            info_hash: metainfo.info_hash,
+            peer_id: self.peer_id,
+            policy: self.config.handshake_policy,
+            num_pieces: metainfo.info.piece_count(),
+            pipeline: self.config.pipeline,
+            wire_dump: self.config.wire_dump,
+            read_timeout: self.config.peer_idle_timeout,
+            buffer_pool: buffer_pool.clone(),
+            peer_stats: self.peers.clone(),
+            socket_tos: self.config.socket_tos,
+            half_open: Arc::new(Semaphore::new(MAX_CONCURRENT_DIALS)),
+            availability: availability_tx,
+        };
+        let (connected_tx, mut connected_rx) = mpsc::channel(max_connections.max(1));
+        let mut connector = tokio::spawn(
+            Self::connect_peers(
+                registry.clone(),
+                dial_params.clone(),
+                None,
+                max_connections,
+                connected_tx.clone(),
             )
-            .await
-            {
-                Ok(Ok(conn)) => {
-                    info!("Successfully connected to peer: {}", peer_info.addr);
-                    peer_connections.push(conn);
-                }
-                Ok(Err(e)) => {
-                    warn!("Failed to connect to peer {}: {}", peer_info.addr, e);
-                }
-                Err(_) => {
-                    warn!("Connection timeout to peer: {}", peer_info.addr);
+            .in_current_span(),
+        );
+
+        // Start as soon as a few peers are up; the rest join while downloading
+        let mut peer_connections = Vec::new();
+        let mut connector_done = false;
+        while peer_connections.len() < self.config.min_peers_to_start {
+            tokio::select! {
+                Some(conn) = connected_rx.recv() => peer_connections.push(conn),
+                _ = &mut connector => {
+                    connector_done = true;
+                    while let Ok(conn) = connected_rx.try_recv() {
+                        peer_connections.push(conn);
+                    }
+                    break;
                 }
             }
         }
 
-        if peer_connections.is_empty() {
+        if peer_connections.is_empty() && metainfo.web_seed_count() == 0 {
             return Err(BittorrentError::PeerError(
                 "Could not connect to any peers".to_string(),
             ));
         }
 
         info!(
-            "Connected to {} peers, starting download",
-            peer_connections.len()
+            "Connected to {} peers and {} web seeds, starting download",
+            peer_connections.len(),
+            metainfo.web_seed_count()
         );
 
+        for _ in &peer_connections {
+            self.counters.peer_connected();
+        }
+
         // Download pieces concurrently using multiple peers
+        let initial_peers = peer_connections.len();
         let peer_connections = Arc::new(Mutex::new(peer_connections));
 
+        // Follow pause() and resume(): the picker stops handing out pieces,
+        // and idle peers learn we don't want anything for now
+        let pause_task = {
+            let piece_picker = piece_picker.clone();
+            let peer_connections = peer_connections.clone();
+
+            tokio::spawn(
+                async move {
+                    while paused.changed().await.is_ok() {
+                        let now_paused = *paused.borrow_and_update();
+                        piece_picker.lock().await.set_paused(now_paused);
+                        if !now_paused {
+                            continue;
+                        }
+                        for conn in peer_connections.lock().await.iter_mut() {
+                            if conn.state().am_interested {
+                                if let Err(e) = conn.send_message(&PeerMessage::NotInterested).await
+                                {
+                                    debug!("Failed to tell {} we paused: {}", conn.addr(), e);
+                                }
+                            }
+                        }
+                    }
+                }
+                .in_current_span(),
+            )
+        };
+
+        // Keep up to max_peers connected for the whole download: peers that
+        // drop out are replaced from the registry
+        let peers_lost = Arc::new(Notify::new());
+        let (round_tx, mut refill_rounds) = mpsc::channel(1);
+        let maintain_task = tokio::spawn(
+            Self::maintain_peers(
+                registry.clone(),
+                dial_params.clone(),
+                self.config.max_peers,
+                connected_tx.clone(),
+                peers_lost.clone(),
+                round_tx,
+            )
+            .in_current_span(),
+        );
+
+        // Periodically re-announce to keep the swarm counts fresh and dial
+        // any peers the tracker hasn't told us about before. Losing a peer
+        // asks for an early one, which the schedule holds to `min interval`.
+        let need_peers = Arc::new(Notify::new());
+        let reannounce_task = tracker_url
+            .clone()
+            .zip(schedule)
+            .map(|(url, mut schedule)| {
+                let need_peers = need_peers.clone();
+                let tracker_client = tracker_client.clone();
+                let stats = self.stats.clone();
+                let registry = registry.clone();
+                let connected_tx = connected_tx.clone();
+                let max_peers = self.config.max_peers;
+                let tracker_url = url;
+                let mut request = request.clone();
+                request.event = None;
+                let counters = self.counters.clone();
+                let total_length = metainfo.info.total_length;
+                let mut paused = self.paused.subscribe();
+
+                tokio::spawn(
+                    async move {
+                        // Trackers expect verified payload only, so wasted
+                        // bytes don't inflate our ratio
+                        let update_totals = |request: &mut TrackerRequest| {
+                            let completed = counters.bytes_downloaded();
+                            request.downloaded = completed.saturating_sub(initial_completed);
+                            request.uploaded = counters.bytes_uploaded();
+                            request.left = total_length.saturating_sub(completed);
+                        };
+
+                        let mut forced_at = None;
+                        loop {
+                            let due = forced_at
+                                .unwrap_or_else(|| schedule.next_regular())
+                                .min(schedule.next_regular());
+                            tokio::select! {
+                                _ = tokio::time::sleep_until(due) => {}
+                                _ = need_peers.notified(), if forced_at.is_none() => {
+                                    forced_at = Some(schedule.next_forced(Instant::now()));
+                                    continue;
+                                }
+                                _ = async { paused.wait_for(|paused| *paused).await.is_ok() } => {
+                                    // Leave the swarm while paused and rejoin
+                                    // it with the next announce
+                                    let mut stopped = request.clone();
+                                    stopped.event = Some(TrackerEvent::Stopped);
+                                    update_totals(&mut stopped);
+                                    let result =
+                                        tracker_client.announce(&tracker_url, &stopped).await;
+                                    if let Err(e) = result {
+                                        warn!(
+                                            "Failed to send stopped event to {}: {}",
+                                            tracker_url, e
+                                        );
+                                    }
+                                    if paused.wait_for(|paused| !*paused).await.is_err() {
+                                        break;
+                                    }
+                                    request.event = Some(TrackerEvent::Started);
+                                }
+                            }
+                            forced_at = None;
+
+                            update_totals(&mut request);
+                            let result = tracker_client.announce(&tracker_url, &request).await;
+                            request.event = None;
+                            match result {
+                                Ok(response) => {
+                                    schedule.record(&response, Instant::now());
+                                    let new_peers =
+                                        registry.merge(response.peers.iter().map(|peer| peer.addr));
+                                    debug!("Re-announce found {} new peers", new_peers.len());
+                                    stats.lock().await.update_swarm(
+                                        response.peers.len(),
+                                        response.complete,
+                                        response.incomplete,
+                                    );
+
+                                    let room = max_peers.saturating_sub(registry.active_count());
+                                    if !new_peers.is_empty() && room > 0 {
+                                        let max_connections = room.min(new_peers.len());
+                                        tokio::spawn(
+                                            Self::connect_peers(
+                                                registry.clone(),
+                                                dial_params.clone(),
+                                                Some(new_peers),
+                                                max_connections,
+                                                connected_tx.clone(),
+                                            )
+                                            .in_current_span(),
+                                        );
+                                    }
+                                }
+                                Err(e) => {
+                                    schedule.record_failure(Instant::now());
+                                    warn!("Re-announce to {} failed: {}", tracker_url, e);
+                                }
+                            }
+                        }
+                    }
+                    .in_current_span(),
+                )
+            });
+
+        // Only re-announces can find more peers once the first dials are done
+        drop(connected_tx);
+
+        // Periodically save session state so a crash loses little progress
+        let resume_task = resume.clone().map(|path| {
+            let resume_base = resume_base.clone();
+            let piece_manager = piece_manager.clone();
+            let piece_picker = piece_picker.clone();
+            let storage = storage.clone();
+            let resume_interval = self.config.resume_interval;
+
+            tokio::spawn(
+                async move {
+                    loop {
+                        tokio::time::sleep(resume_interval).await;
+
+                        // Only claim pieces whose data has reached the disk
+                        if let Err(e) = storage.flush().await {
+                            warn!("Failed to flush storage: {}", e);
+                            continue;
+                        }
+                        let snapshot = Self::resume_snapshot(
+                            &resume_base,
+                            initial_completed,
+                            &piece_manager,
+                            &piece_picker,
+                            storage.as_ref(),
+                        )
+                        .await;
+                        if let Err(e) = snapshot.save(&path).await {
+                            warn!("Failed to save resume data: {}", e);
+                        }
+                    }
+                }
+                .in_current_span(),
+            )
+        });
+
         // Create progress monitoring task
         let progress_piece_manager = piece_manager.clone();
-        let progress_task = tokio::spawn(async move {
-            let mut last_progress = 0.0;
-            loop {
-                tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-
-                let (complete, progress, complete_count, total) = {
-                    let pm = progress_piece_manager.lock().await;
-                    (
-                        pm.is_complete(),
-                        pm.progress(),
-                        pm.complete_count(),
-                        pm.piece_count(),
-                    )
-                };
+        let progress_piece_picker = piece_picker.clone();
+        let progress_stats = self.stats.clone();
+        let progress_counters = self.counters.clone();
+        let total = metainfo.info.piece_count();
+        let stats_interval = self.config.stats_interval;
+        let progress_line = self.config.progress_line;
+        let files = metainfo.info.files.clone();
+        let multi_file = metainfo.info.multi_file;
+        let progress_task = tokio::spawn(
+            async move {
+                let mut last_progress = 0.0;
+                let mut last_downloaded = 0;
+                loop {
+                    tokio::time::sleep(stats_interval).await;
 
-                if complete {
-                    break;
-                }
+                    let complete_count = progress_counters.pieces_completed();
+                    let downloaded = progress_counters.bytes_downloaded();
+                    if complete_count >= total {
+                        break;
+                    }
+                    let progress = complete_count as f64 / total as f64 * 100.0;
 
-                if (progress - last_progress).abs() > 0.1 {
-                    info!(
-                        "Download progress: {:.1}% ({}/{})",
-                        progress, complete_count, total
+                    // Only splitting progress across files needs the piece states
+                    let (file_bytes, received, wasted) = {
+                        let pm = progress_piece_manager.lock().await;
+                        let file_bytes = if multi_file {
+                            pm.file_completed_bytes(&files)
+                        } else {
+                            vec![downloaded]
+                        };
+                        (file_bytes, pm.received_bytes(), pm.wasted_bytes())
+                    };
+
+                    let (stats, finished) = {
+                        let mut stats = progress_stats.lock().await;
+                        stats.pieces_complete = complete_count;
+                        stats.downloaded_bytes = downloaded;
+                        stats.received_bytes = received;
+                        stats.wasted_bytes = wasted;
+                        stats.connected_peers = progress_counters.active_peers();
+                        let finished = stats.update_files(&file_bytes);
+                        (stats.clone(), finished)
+                    };
+                    if multi_file {
+                        for path in finished {
+                            info!("Finished file {}", path);
+                        }
+                    }
+                    let rate = (downloaded - last_downloaded) as f64 / stats_interval.as_secs_f64();
+                    last_downloaded = downloaded;
+
+                    if progress_line {
+                        // Logs go to stderr, so they don't clobber this line
+                        print!("\r{:<60}", stats.progress_line(rate));
+                        let _ = std::io::Write::flush(&mut std::io::stdout());
+                    } else if (progress - last_progress).abs() > 0.1 {
+                        info!(
+                            "Download progress: {:.1}% ({}/{}) - {}",
+                            progress, complete_count, total, stats
+                        );
+                        last_progress = progress;
+                    }
+
+                    // Pieces that lose their last holder are warned about as
+                    // it happens; this is the overall picture
+                    let (min_availability, zero_count, copies) =
+                        progress_piece_picker.lock().await.availability_summary();
+                    debug!(
+                        "Swarm availability: {:.2} distributed copies, min {} for missing pieces, {} unavailable",
+                        copies, min_availability, zero_count
                     );
-                    last_progress = progress;
                 }
             }
-        });
+            .in_current_span(),
+        );
 
-        // Create tasks for each peer
-        let mut tasks = Vec::new();
-        let num_peers = {
-            let conns = peer_connections.lock().await;
-            conns.len()
-        };
+        // One download task per connected peer, plus one per web seed
+        let mut tasks = JoinSet::new();
 
-        for _ in 0..num_peers {
+        // Who sent data for pieces that failed their hash check
+        let hash_failures = Arc::new(Mutex::new(
+            HashFailures::new().with_max_failed_pieces(self.config.max_piece_failures),
+        ));
+
+        let spawn_peer_task = |tasks: &mut JoinSet<Result<()>>| {
+            let counters = self.counters.clone();
+            let hash_failures = hash_failures.clone();
+            let registry = registry.clone();
             let piece_picker_clone = piece_picker.clone();
             let piece_manager_clone = piece_manager.clone();
             let storage_clone = storage.clone();
             let peer_connections_clone = peer_connections.clone();
-            let total_pieces = metainfo.info.pieces.len();
+            let total_pieces = metainfo.info.piece_count();
+            let timeouts = PieceTimeouts {
+                unchoke: self.config.unchoke_timeout,
+                piece: self.config.piece_timeout,
+                block: self.config.block_timeout,
+            };
+            let flush_pieces = self.config.sequential;
+            let timings = self.timings.clone();
 
-            let task = tokio::spawn(async move {
-                loop {
-                    // Get next piece to download
-                    let piece_index = {
-                        let mut picker = piece_picker_clone.lock().await;
-                        let pm = piece_manager_clone.lock().await;
-                        picker.pick_piece(&pm)
-                    };
+            tasks.spawn(
+                async move {
+                    loop {
+                        // Get next piece to download, unless the quota is used up
+                        let (piece_index, wait) = {
+                            let mut picker = piece_picker_clone.lock().await;
+                            let pm = piece_manager_clone.lock().await;
+                            if pm.quota_reached() {
+                                (None, false)
+                            } else {
+                                (picker.pick_piece(&pm), picker.must_wait(&pm))
+                            }
+                        };
 
-                    let piece_index = match piece_index {
-                        Some(idx) => idx,
-                        None => {
-                            // No more pieces to download
-                            break;
+                        let piece_index = match piece_index {
+                            Some(idx) => idx,
+                            // Wait until resumed, or for a piece in progress
+                            // to free its slot
+                            None if wait => {
+                                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                                continue;
+                            }
+                            None => {
+                                // No more pieces to download
+                                break;
+                            }
+                        };
+
+                        // Get a peer connection, skipping peers that already
+                        // sent bad data for this piece
+                        let peer = {
+                            let mut conns = peer_connections_clone.lock().await;
+                            if conns.is_empty() {
+                                drop(conns);
+                                piece_picker_clone.lock().await.mark_missing(piece_index);
+                                break;
+                            }
+                            let failures = hash_failures.lock().await;
+                            Self::take_peer_for_piece(&mut conns, piece_index, &failures)
+                        };
+                        let Some(mut peer) = peer else {
+                            debug!(
+                                "Every pooled peer failed piece {} before, waiting for another",
+                                piece_index
+                            );
+                            piece_picker_clone.lock().await.mark_missing(piece_index);
+                            tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                            continue;
+                        };
+
+                        // Check if peer has this piece. A peer whose bitfield we
+                        // haven't read yet gets a chance; it's checked again once
+                        // the peer has unchoked us.
+                        if peer.has_bitfield() && !peer.has_piece(piece_index) {
+                            // Return peer to pool and skip
+                            let mut conns = peer_connections_clone.lock().await;
+                            conns.push(peer);
+                            drop(conns);
+                            piece_picker_clone.lock().await.defer_piece(piece_index);
+                            continue;
                         }
-                    };
 
-                    // Get a peer connection
-                    let mut peer = {
-                        let mut conns = peer_connections_clone.lock().await;
-                        if conns.is_empty() {
-                            break;
+                        debug!(
+                            "Downloading piece {}/{} from peer {}",
+                            piece_index + 1,
+                            total_pieces,
+                            peer.addr()
+                        );
+
+                        // Download the piece
+                        let span = info_span!("peer", addr = %peer.addr());
+                        let result = Self::download_piece_from_peer(
+                            &mut peer,
+                            piece_index,
+                            piece_manager_clone.clone(),
+                            storage_clone.clone(),
+                            timeouts,
+                        )
+                        .instrument(span)
+                        .await;
+
+                        // Settle who gets blamed for the piece's data
+                        let (offenders, hopeless) = {
+                            let mut failures = hash_failures.lock().await;
+                            let offenders = match &result {
+                                Ok(_) => failures.record_success(piece_index),
+                                Err(BittorrentError::HashMismatch(_)) => {
+                                    failures.record_failure(piece_index, &[peer.addr()])
+                                }
+                                Err(_) => Vec::new(),
+                            };
+                            (offenders, failures.hopeless())
+                        };
+                        if !offenders.is_empty() {
+                            for addr in &offenders {
+                                warn!("Banning peer {} for sending corrupt data", addr);
+                                registry.ban(*addr);
+                            }
+                            let mut conns = peer_connections_clone.lock().await;
+                            let pooled = conns.len();
+                            conns.retain(|conn| !offenders.contains(&conn.addr()));
+                            counters.peers_disconnected(pooled - conns.len());
                         }
-                        conns.pop().unwrap()
-                    };
 
-                    // Check if peer has this piece
-                    if !peer.has_piece(piece_index) {
-                        // Return peer to pool and skip
-                        let mut conns = peer_connections_clone.lock().await;
-                        conns.push(peer);
-                        continue;
-                    }
+                        let peer_addr = peer.addr();
 
-                    info!(
-                        "Downloading piece {}/{} from peer {}",
-                        piece_index + 1,
-                        total_pieces,
-                        peer.addr()
-                    );
+                        // Return peer to pool, unless it broke the protocol,
+                        // got banned or the connection died
+                        if let Err(BittorrentError::ProtocolViolation(reason)) = &result {
+                            warn!("Disconnecting peer {}: {}", peer.addr(), reason);
+                            registry.ban(peer.addr());
+                            counters.peers_disconnected(1);
+                        } else if registry.is_banned(peer.addr()) {
+                            counters.peers_disconnected(1);
+                        } else if !peer.is_alive() {
+                            info!("Dropping dead connection to peer {}", peer.addr());
+                            registry.mark_failed(peer.addr());
+                            counters.peers_disconnected(1);
+                        } else {
+                            let mut conns = peer_connections_clone.lock().await;
+                            conns.push(peer);
+                        }
 
-                    // Download the piece
-                    let result = Self::download_piece_from_peer(
-                        &mut peer,
-                        piece_index,
-                        piece_manager_clone.clone(),
-                        storage_clone.clone(),
-                    )
-                    .await;
+                        match result {
+                            Ok(length) => {
+                                info!("Successfully downloaded piece {}", piece_index);
+                                counters.record_piece(length);
+                                registry.record_served(peer_addr, length);
+                                piece_picker_clone.lock().await.mark_complete(piece_index);
+                                let duration =
+                                    piece_manager_clone.lock().await.download_time(piece_index);
+                                if let Some(duration) = duration {
+                                    timings.lock().unwrap().record(PieceTiming {
+                                        piece_index,
+                                        duration,
+                                        peer: peer_addr,
+                                    });
+                                }
+                                // Make the piece readable before fetching the next
+                                if flush_pieces {
+                                    if let Err(e) = storage_clone.flush().await {
+                                        warn!("Failed to flush piece {}: {}", piece_index, e);
+                                    }
+                                }
+                            }
+                            Err(BittorrentError::MemoryBudgetExceeded(reason)) => {
+                                // Backpressure: let in-flight pieces drain, then retry
+                                debug!("Deferring piece {}: {}", piece_index, reason);
+                                piece_picker_clone.lock().await.mark_missing(piece_index);
+                                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                            }
+                            Err(e @ BittorrentError::StorageError(_)) => {
+                                // Nothing more can be saved; the session stops
+                                piece_picker_clone.lock().await.mark_missing(piece_index);
+                                return Err(e);
+                            }
+                            Err(e) => {
+                                warn!("Failed to download piece {}: {}", piece_index, e);
+                                // Mark piece as available again
+                                let mut picker = piece_picker_clone.lock().await;
+                                picker.mark_missing(piece_index);
+                            }
+                        }
 
-                    // Return peer to pool
-                    {
-                        let mut conns = peer_connections_clone.lock().await;
-                        conns.push(peer);
+                        // Stop wasting bandwidth on a torrent that can't verify
+                        if hopeless {
+                            break;
+                        }
                     }
+                    Ok(())
+                }
+                .in_current_span(),
+            );
+        };
+
+        for _ in 0..initial_peers {
+            spawn_peer_task(&mut tasks);
+        }
 
-                    match result {
-                        Ok(_) => {
-                            info!("Successfully downloaded piece {}", piece_index);
+        // Drop pooled connections that went quiet or broke; the registry
+        // keeps them as candidates for a later dial
+        let reap_task = {
+            let peer_connections = peer_connections.clone();
+            let peers_lost = peers_lost.clone();
+            let registry = registry.clone();
+            let counters = self.counters.clone();
+            let idle_timeout = self.config.peer_idle_timeout;
+
+            tokio::spawn(
+                async move {
+                    loop {
+                        tokio::time::sleep(REAP_INTERVAL).await;
+
+                        let reaped = Self::reap_dead_peers(
+                            &mut *peer_connections.lock().await,
+                            idle_timeout,
+                        );
+                        counters.peers_disconnected(reaped.len());
+                        if !reaped.is_empty() {
+                            peers_lost.notify_one();
+                        }
+                        for conn in reaped {
+                            info!("Dropping idle or dead peer {}", conn.addr());
+                            registry.mark_failed(conn.addr());
+                            if let Err(e) = conn.close().await {
+                                debug!("Error closing connection: {}", e);
+                            }
                         }
+                    }
+                }
+                .in_current_span(),
+            )
+        };
+
+        // SIGUSR1 dumps who holds each missing piece, to explain a stall
+        #[cfg(unix)]
+        let dump_task = {
+            let peer_connections = peer_connections.clone();
+            let piece_manager = piece_manager.clone();
+            let counters = self.counters.clone();
+
+            tokio::spawn(
+                async move {
+                    use tokio::signal::unix::{signal, SignalKind};
+
+                    let mut signals = match signal(SignalKind::user_defined1()) {
+                        Ok(signals) => signals,
                         Err(e) => {
-                            warn!("Failed to download piece {}: {}", piece_index, e);
-                            // Mark piece as available again
-                            let mut picker = piece_picker_clone.lock().await;
-                            picker.mark_missing(piece_index);
+                            debug!("Piece holder dump on SIGUSR1 unavailable: {}", e);
+                            return;
                         }
+                    };
+                    while signals.recv().await.is_some() {
+                        let connected_peers = counters.active_peers();
+                        Self::dump_piece_holders(
+                            &piece_manager,
+                            &peer_connections,
+                            connected_peers,
+                        )
+                        .await;
                     }
                 }
-            });
+                .in_current_span(),
+            )
+        };
 
-            tasks.push(task);
+        // Web seeds of both kinds pull pieces alongside the peers
+        if metainfo.web_seed_count() > 0 {
+            let http_client = reqwest::Client::builder()
+                .user_agent(self.config.user_agent.as_str())
+                .connect_timeout(CONNECT_TIMEOUT)
+                .build()?;
+
+            let webseeds = metainfo
+                .url_list
+                .iter()
+                .map(|url| WebSeed::new(url.clone(), http_client.clone()))
+                .chain(metainfo.httpseeds.iter().map(|url| {
+                    WebSeed::http_seed(url.clone(), metainfo.info_hash, http_client.clone())
+                }));
+            for webseed in webseeds {
+                let url = webseed.url().to_string();
+                let info = metainfo.info.clone();
+                let piece_picker = piece_picker.clone();
+                let piece_manager = piece_manager.clone();
+                let storage = storage.clone();
+                let piece_timeout = self.config.piece_timeout;
+
+                let span = info_span!("webseed", url = %url);
+                tasks.spawn(
+                    Self::run_webseed(
+                        webseed,
+                        info,
+                        piece_picker,
+                        piece_manager,
+                        storage,
+                        self.counters.clone(),
+                        piece_timeout,
+                    )
+                    .instrument(span),
+                );
+            }
         }
 
-        // Wait for all download tasks to complete
-        for task in tasks {
-            let _ = task.await;
+        // Put late connections to work until the download finishes or every
+        // source is exhausted. Re-announces and peer replacement keep the
+        // channel open, so the session ends once the initial dials are done,
+        // no task is left and no known peer can still be dialed.
+        let mut storage_failure = None;
+        loop {
+            tokio::select! {
+                Some(conn) = connected_rx.recv() => {
+                    peer_connections.lock().await.push(conn);
+                    self.counters.peer_connected();
+                    spawn_peer_task(&mut tasks);
+                }
+                _ = &mut connector, if !connector_done => {
+                    connector_done = true;
+                    while let Ok(conn) = connected_rx.try_recv() {
+                        peer_connections.lock().await.push(conn);
+                        self.counters.peer_connected();
+                        spawn_peer_task(&mut tasks);
+                    }
+                    if tasks.is_empty() {
+                        if !registry.has_candidates() {
+                            break;
+                        }
+                        peers_lost.notify_one();
+                    }
+                }
+                Some(result) = tasks.join_next() => {
+                    // A disk that refuses writes ends the session for everyone
+                    if let Ok(Err(e)) = result {
+                        tasks.abort_all();
+                        storage_failure = Some(e);
+                        break;
+                    }
+                    let finished = {
+                        let pm = piece_manager.lock().await;
+                        pm.is_complete() || pm.quota_reached()
+                    };
+                    if hash_failures.lock().await.hopeless() {
+                        tasks.abort_all();
+                        break;
+                    }
+                    if !finished {
+                        need_peers.notify_one();
+                        peers_lost.notify_one();
+                    }
+                    if tasks.is_empty()
+                        && (finished || (connector_done && !registry.has_candidates()))
+                    {
+                        break;
+                    }
+                }
+                Some(()) = refill_rounds.recv() => {
+                    // Without peers, keep dialing until no candidate is left
+                    if tasks.is_empty() && connector_done {
+                        if !registry.has_candidates() {
+                            break;
+                        }
+                        peers_lost.notify_one();
+                    }
+                }
+                else => break,
+            }
         }
+        connector.abort();
+        maintain_task.abort();
+        // Peers closed from here on aren't leaving a download in progress
+        availability_task.abort();
+        reap_task.abort();
+        pause_task.abort();
+        #[cfg(unix)]
+        dump_task.abort();
+        // Peers still out with a download task were dropped with it
+        tasks.abort_all();
+        Self::close_pool(&peer_connections).await;
 
-        // Stop progress monitoring
+        // Stop progress monitoring and re-announcing
         progress_task.abort();
+        if self.config.progress_line {
+            println!();
+        }
+        if let Some(task) = reannounce_task {
+            task.abort();
+        }
+
+        // Flush buffered writes and save a final snapshot on the way out
+        if let Some(task) = resume_task {
+            task.abort();
+        }
+        // A full or read-only disk will likely refuse the flush as well;
+        // the original failure is the one worth reporting
+        match storage.flush().await {
+            Err(e) if storage_failure.is_some() => warn!("Failed to flush storage: {}", e),
+            result => result?,
+        }
+        if let Some(path) = &resume {
+            let snapshot = Self::resume_snapshot(
+                &resume_base,
+                initial_completed,
+                &piece_manager,
+                &piece_picker,
+                storage.as_ref(),
+            )
+            .await;
+            if let Err(e) = snapshot.save(path).await {
+                warn!("Failed to save resume data: {}", e);
+            }
+        }
 
         // Check if download is complete
-        let (complete, progress) = {
+        let (complete, progress, finished, quota_reached, session_bytes) = {
             let pm = piece_manager.lock().await;
-            (pm.is_complete(), pm.progress())
+            let mut stats = self.stats.lock().await;
+            stats.pieces_complete = pm.complete_count();
+            stats.downloaded_bytes = pm.completed_bytes();
+            stats.received_bytes = pm.received_bytes();
+            stats.wasted_bytes = pm.wasted_bytes();
+            if pm.wasted_bytes() > 0 {
+                info!(
+                    "Received {} bytes, {} of them wasted on duplicate, corrupt or abandoned blocks",
+                    pm.received_bytes(),
+                    pm.wasted_bytes()
+                );
+            }
+            let finished = stats.update_files(&pm.file_completed_bytes(&metainfo.info.files));
+            (
+                pm.is_complete(),
+                pm.progress(),
+                finished,
+                pm.quota_reached(),
+                pm.session_bytes(),
+            )
         };
+        if metainfo.info.multi_file {
+            for path in finished {
+                info!("Finished file {}", path);
+            }
+        }
+
+        let failures = hash_failures.lock().await;
+        if !complete && failures.hopeless() {
+            return Err(BittorrentError::HashMismatch(format!(
+                "{} different pieces keep failing verification; the torrent may be \
+                 corrupt or not match the data peers are sharing",
+                failures.failed_pieces()
+            )));
+        }
+        drop(failures);
 
         if complete {
             info!("Download complete! All pieces downloaded and verified.");
+            if self.config.timing_summary {
+                if let Some(summary) = self.timings.lock().unwrap().summary() {
+                    info!("Piece timing: {}", summary);
+                }
+            }
+
+            if let Some(template) = &self.config.on_complete {
+                let path = content_path(
+                    Path::new(&self.config.download_dir),
+                    &metainfo.info,
+                    self.config.single_entry_layout,
+                );
+                let context = hook::HookContext {
+                    name: metainfo.info.name.clone(),
+                    path: path.to_string_lossy().into_owned(),
+                    hash: metainfo.info_hash_hex(),
+                };
+                let command = hook::spawn_on_complete(template.clone(), context);
+                self.completion_commands.lock().unwrap().push(command);
+            }
+        } else if let Some(e) = &storage_failure {
+            warn!("Stopping at {:.1}% complete: {}", progress, e);
+        } else if quota_reached {
+            warn!(
+                "Download quota reached after {} bytes; stopping at {:.1}% complete",
+                session_bytes, progress
+            );
         } else {
             warn!(
                 "Download incomplete. Progress: {:.1}%. Some pieces may be missing.",
@@ -273,123 +1463,817 @@ impl TorrentClient {
             );
         }
 
-        Ok(())
-    }
+        let pool = buffer_pool.stats();
+        debug!(
+            "Block buffers: {} allocated, {} reused",
+            pool.allocations, pool.reuses
+        );
+
+        // Let the tracker know we're leaving the swarm
+        if !complete && (quota_reached || storage_failure.is_some()) {
+            if let Some(url) = &tracker_url {
+                let mut request = request.clone();
+                let completed = piece_manager.lock().await.completed_bytes();
+                request.event = Some(TrackerEvent::Stopped);
+                request.downloaded = completed.saturating_sub(initial_completed);
+                request.uploaded = self.counters.bytes_uploaded();
+                request.left = metainfo.info.total_length.saturating_sub(completed);
+                if let Err(e) = tracker_client.announce(url, &request).await {
+                    warn!("Failed to send stopped event to {}: {}", url, e);
+                }
+            }
+        }
+
+        match storage_failure {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    /// Take the most recently pooled peer that hasn't already sent bad data
+    /// for `piece_index`
+    fn take_peer_for_piece<S: AsyncRead + AsyncWrite + Unpin>(
+        pool: &mut Vec<PeerConnection<S>>,
+        piece_index: usize,
+        failures: &HashFailures,
+    ) -> Option<PeerConnection<S>> {
+        let position = pool
+            .iter()
+            .rposition(|conn| !failures.is_excluded(piece_index, conn.addr()))?;
+        Some(pool.remove(position))
+    }
+
+    /// For each piece still missing, the pooled peers that have it
+    ///
+    /// A piece with no holders can't be finished with the peers we have,
+    /// which is what leaves a download stuck near the end. Peers checked out
+    /// of the pool for a download aren't included.
+    fn missing_piece_holders<S: AsyncRead + AsyncWrite + Unpin>(
+        piece_manager: &PieceManager,
+        pool: &[PeerConnection<S>],
+    ) -> Vec<(usize, Vec<SocketAddr>)> {
+        (0..piece_manager.piece_count())
+            .filter(|&idx| piece_manager.get_piece_state(idx) != Some(PieceState::Complete))
+            .map(|idx| {
+                let holders = pool
+                    .iter()
+                    .filter(|conn| conn.has_piece(idx))
+                    .map(|conn| conn.addr())
+                    .collect();
+                (idx, holders)
+            })
+            .collect()
+    }
+
+    /// Log which pooled peers hold each missing piece
+    async fn dump_piece_holders(
+        piece_manager: &Mutex<PieceManager>,
+        pool: &Mutex<Vec<PeerConnection>>,
+        connected_peers: usize,
+    ) {
+        let pool = pool.lock().await;
+        let holders = Self::missing_piece_holders(&*piece_manager.lock().await, &pool);
+        info!(
+            "{} pieces missing; showing holders among {} idle peers ({} busy downloading)",
+            holders.len(),
+            pool.len(),
+            connected_peers.saturating_sub(pool.len())
+        );
+        for (piece_index, peers) in &holders {
+            if peers.is_empty() {
+                warn!("Piece {}: no connected peer has it", piece_index);
+            } else {
+                let peers: Vec<String> = peers.iter().map(SocketAddr::to_string).collect();
+                info!("Piece {}: {}", piece_index, peers.join(", "));
+            }
+        }
+    }
+
+    /// Remove connections from the pool that failed or have been idle for
+    /// longer than `idle_timeout`, returning them for closing
+    fn reap_dead_peers<S: AsyncRead + AsyncWrite + Unpin>(
+        pool: &mut Vec<PeerConnection<S>>,
+        idle_timeout: Duration,
+    ) -> Vec<PeerConnection<S>> {
+        let (keep, reaped) = std::mem::take(pool)
+            .into_iter()
+            .partition(|conn| conn.is_alive() && conn.idle_time() < idle_timeout);
+        *pool = keep;
+        reaped
+    }
+
+    /// Close every pooled connection, giving up on slow ones after
+    /// `CLOSE_TIMEOUT`
+    async fn close_pool<S: AsyncRead + AsyncWrite + Unpin>(pool: &Mutex<Vec<PeerConnection<S>>>) {
+        let conns = std::mem::take(&mut *pool.lock().await);
+        let closing = async {
+            for conn in conns {
+                if let Err(e) = conn.close().await {
+                    debug!("Error closing connection: {}", e);
+                }
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closing).await.is_err() {
+            debug!("Gave up closing peer connections after {:?}", CLOSE_TIMEOUT);
+        }
+    }
+
+    /// Dial candidates from the registry a few at a time, sending each peer
+    /// that completes the handshake to `connected`
+    ///
+    /// With `candidates` only those addresses are dialed, and only if they
+    /// have never been dialed before. Stops once `max_connections` peers are
+    /// up or twice that many dials have been tried.
+    async fn connect_peers(
+        registry: PeerRegistry,
+        params: DialParams,
+        candidates: Option<Vec<SocketAddr>>,
+        max_connections: usize,
+        connected: mpsc::Sender<PeerConnection>,
+    ) {
+        let DialParams {
+            info_hash,
+            peer_id,
+            policy,
+            num_pieces,
+            pipeline,
+            wire_dump,
+            read_timeout,
+            buffer_pool,
+            peer_stats,
+            socket_tos,
+            half_open,
+            availability,
+        } = params;
+        let mut dials = JoinSet::new();
+        let mut established = 0;
+        let mut attempts = 0;
+
+        loop {
+            while dials.len() < MAX_CONCURRENT_DIALS
+                && established + dials.len() < max_connections
+                && attempts < max_connections * 2
+            {
+                let next = match &candidates {
+                    Some(addrs) => registry.claim_unconnected(addrs),
+                    None => registry.next_candidate(),
+                };
+                let Some(addr) = next else {
+                    break;
+                };
+                attempts += 1;
+
+                let half_open = half_open.clone();
+                dials.spawn(
+                    async move {
+                        let _permit = half_open.acquire_owned().await;
+                        let connect = PeerConnection::connect_with_policy(
+                            addr, info_hash, peer_id, &policy, socket_tos,
+                        );
+                        (addr, tokio::time::timeout(CONNECT_TIMEOUT, connect).await)
+                    }
+                    .instrument(info_span!("peer", addr = %addr)),
+                );
+            }
+
+            let Some(Ok((addr, result))) = dials.join_next().await else {
+                break;
+            };
+
+            match result {
+                Ok(Ok(mut conn)) => {
+                    info!("Successfully connected to peer: {}", addr);
+                    registry.mark_connected(addr);
+                    conn.set_piece_count(num_pieces);
+                    conn.set_pipeline_limits(pipeline);
+                    conn.set_wire_dump(wire_dump);
+                    conn.set_read_timeout(read_timeout);
+                    conn.set_buffer_pool(buffer_pool.clone());
+                    conn.set_availability_sink(availability.clone());
+                    peer_stats.register(conn.shared_stats());
+                    established += 1;
+                    if connected.send(conn).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(Err(e)) => {
+                    warn!("Failed to connect to peer {}: {}", addr, e);
+                    registry.mark_failed(addr);
+                }
+                Err(_) => {
+                    warn!("Connection timeout to peer: {}", addr);
+                    registry.mark_failed(addr);
+                }
+            }
+        }
+    }
+
+    /// Dial registry candidates whenever fewer than `max_peers` peers are
+    /// connected or being dialed
+    ///
+    /// A round runs after every `peers_lost` notification, and every
+    /// [`REFILL_INTERVAL`] otherwise; each finished round is reported on
+    /// `rounds`. Banned peers and peers that failed too often are never
+    /// candidates.
+    async fn maintain_peers(
+        registry: PeerRegistry,
+        params: DialParams,
+        max_peers: usize,
+        connected: mpsc::Sender<PeerConnection>,
+        peers_lost: Arc<Notify>,
+        rounds: mpsc::Sender<()>,
+    ) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(REFILL_INTERVAL) => {}
+                _ = peers_lost.notified() => {}
+            }
+
+            let room = max_peers.saturating_sub(registry.active_count());
+            if room > 0 && registry.has_candidates() {
+                debug!("{} peer slots free, dialing replacements", room);
+                Self::connect_peers(
+                    registry.clone(),
+                    params.clone(),
+                    None,
+                    room,
+                    connected.clone(),
+                )
+                .await;
+            }
+            let _ = rounds.try_send(());
+        }
+    }
+
+    /// Mark pieces finished by an earlier session as complete
+    ///
+    /// Pieces listed in the resume file are re-hashed from storage before
+    /// being trusted. Without a usable resume file everything on disk is
+    /// verified instead.
+    async fn restore_session(
+        path: &Path,
+        metainfo: &Metainfo,
+        storage: &dyn StorageBackend,
+        piece_manager: &Mutex<PieceManager>,
+        piece_picker: &Mutex<PiecePicker>,
+    ) -> ResumeData {
+        let num_pieces = metainfo.info.piece_count();
+        let pieces = &metainfo.info.pieces;
+
+        let (resume, mut verified) =
+            match ResumeData::load(path, metainfo.info_hash, num_pieces).await {
+                Some(resume) => {
+                    let mut verified = vec![false; num_pieces];
+                    for index in (0..num_pieces).filter(|&i| resume.completed[i]) {
+                        verified[index] = match storage.read_piece(index).await {
+                            Ok(data) => pieces.verify_hash(index, &data),
+                            Err(_) => false,
+                        };
+                    }
+                    (resume, verified)
+                }
+                None => {
+                    let verified = storage
+                        .verify_existing(pieces)
+                        .await
+                        .unwrap_or_else(|_| vec![false; num_pieces]);
+                    (ResumeData::new(metainfo.info_hash, num_pieces), verified)
+                }
+            };
+
+        // Blocks of pieces left half-done; the piece hash covers them once
+        // the rest arrives, or right away if they turn out to be all of it
+        let mut partial = Vec::new();
+        for (index, received) in &resume.partial {
+            if verified[*index] {
+                continue;
+            }
+            if let Ok(data) = storage.read_piece(*index).await {
+                if pieces.verify_hash(*index, &data) {
+                    verified[*index] = true;
+                } else if !received.iter().all(|&r| r) {
+                    partial.push((*index, received.clone(), data));
+                }
+            }
+        }
+
+        let mut pm = piece_manager.lock().await;
+        let mut picker = piece_picker.lock().await;
+        for index in (0..num_pieces).filter(|&i| verified[i]) {
+            pm.mark_verified(index);
+            picker.mark_complete(index);
+        }
+        picker.restore_availability(&resume.availability);
+
+        let mut resumed_blocks = 0;
+        for (index, received, data) in partial {
+            let count = received.iter().filter(|&&r| r).count();
+            match pm.restore_partial(index, received, data) {
+                Ok(()) => resumed_blocks += count,
+                Err(e) => warn!("Discarding saved blocks: {}", e),
+            }
+        }
+        if resumed_blocks > 0 {
+            info!("Resuming {} blocks of unfinished pieces", resumed_blocks);
+        }
+
+        if pm.complete_count() > 0 {
+            info!(
+                "Resuming with {}/{} pieces already verified",
+                pm.complete_count(),
+                num_pieces
+            );
+        }
+
+        resume
+    }
+
+    /// Capture the current session on top of what earlier sessions saved
+    ///
+    /// Blocks of half-downloaded pieces are written in place so a restart
+    /// can pick up mid-piece. The piece manager stays locked meanwhile, so
+    /// a piece can't complete and have its verified data overwritten by
+    /// this partial copy.
+    async fn resume_snapshot(
+        base: &ResumeData,
+        initial_completed: u64,
+        piece_manager: &Mutex<PieceManager>,
+        piece_picker: &Mutex<PiecePicker>,
+        storage: &dyn StorageBackend,
+    ) -> ResumeData {
+        let pm = piece_manager.lock().await;
+        let picker = piece_picker.lock().await;
+
+        let mut partial = Vec::new();
+        for (index, received, data) in pm.partial_pieces() {
+            match storage.write_piece(index, &data).await {
+                Ok(()) => partial.push((index, received)),
+                Err(e) => warn!("Failed to save blocks of piece {}: {}", index, e),
+            }
+        }
+        if let Err(e) = storage.flush().await {
+            warn!("Failed to flush saved blocks: {}", e);
+            partial.clear();
+        }
+
+        ResumeData {
+            completed: pm.completed_pieces(),
+            availability: picker.availability().to_vec(),
+            downloaded: base.downloaded + pm.completed_bytes().saturating_sub(initial_completed),
+            partial,
+            ..base.clone()
+        }
+    }
+
+    /// Download pieces from a web seed until none are left or it fails
+    /// too many times in a row. Only a storage failure is returned, since
+    /// it stops the whole download.
+    async fn run_webseed(
+        mut webseed: WebSeed,
+        info: TorrentInfo,
+        piece_picker: Arc<Mutex<PiecePicker>>,
+        piece_manager: Arc<Mutex<PieceManager>>,
+        storage: Arc<dyn StorageBackend>,
+        counters: Arc<DownloadStats>,
+        piece_timeout: Duration,
+    ) -> Result<()> {
+        loop {
+            let (piece_index, wait) = {
+                let mut picker = piece_picker.lock().await;
+                let pm = piece_manager.lock().await;
+                if pm.quota_reached() {
+                    (None, false)
+                } else {
+                    (picker.pick_piece(&pm), picker.must_wait(&pm))
+                }
+            };
+
+            let piece_index = match piece_index {
+                Some(idx) => idx,
+                None if wait => {
+                    tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                    continue;
+                }
+                None => break,
+            };
+
+            if let Err(e) = piece_manager.lock().await.start_piece(piece_index) {
+                debug!("Web seed deferring piece {}: {}", piece_index, e);
+                piece_picker.lock().await.mark_missing(piece_index);
+                tokio::time::sleep(tokio::time::Duration::from_millis(100)).await;
+                continue;
+            }
+
+            let result = Self::download_piece_from_webseed(
+                &mut webseed,
+                &info,
+                piece_index,
+                &piece_manager,
+                storage.as_ref(),
+                piece_timeout,
+            )
+            .await;
+
+            match result {
+                Ok(()) => {
+                    info!(
+                        "Downloaded piece {} from web seed {}",
+                        piece_index,
+                        webseed.url()
+                    );
+                    webseed.record_success();
+                    counters.record_piece(info.piece_size(piece_index));
+                    piece_picker.lock().await.mark_complete(piece_index);
+                }
+                Err(e @ BittorrentError::StorageError(_)) => {
+                    piece_picker.lock().await.mark_missing(piece_index);
+                    return Err(e);
+                }
+                Err(e) => {
+                    warn!(
+                        "Web seed {} failed piece {}: {}",
+                        webseed.url(),
+                        piece_index,
+                        e
+                    );
+                    piece_manager.lock().await.reset_piece(piece_index);
+                    piece_picker.lock().await.mark_missing(piece_index);
+
+                    // A busy seed isn't failing; peers carry on meanwhile
+                    if let Some(delay) = webseed.take_retry_after() {
+                        info!("Web seed {} is busy, waiting {:?}", webseed.url(), delay);
+                        let deadline = Instant::now() + delay;
+                        while Instant::now() < deadline {
+                            tokio::time::sleep(Duration::from_secs(1).min(delay)).await;
+                            let pm = piece_manager.lock().await;
+                            if pm.is_complete() || pm.quota_reached() {
+                                return Ok(());
+                            }
+                        }
+                        continue;
+                    }
+                    webseed.record_failure();
+                    if webseed.is_dead() {
+                        warn!("Giving up on web seed {}", webseed.url());
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fetch a started piece from a web seed, verify it and store it
+    async fn download_piece_from_webseed(
+        webseed: &mut WebSeed,
+        info: &TorrentInfo,
+        piece_index: usize,
+        piece_manager: &Mutex<PieceManager>,
+        storage: &dyn StorageBackend,
+        piece_timeout: Duration,
+    ) -> Result<()> {
+        let data = tokio::time::timeout(piece_timeout, webseed.fetch_piece(info, piece_index))
+            .await
+            .map_err(|_| {
+                BittorrentError::PieceTimeout(format!(
+                    "piece {} not completed within {:?}",
+                    piece_index, piece_timeout
+                ))
+            })??;
+
+        piece_manager
+            .lock()
+            .await
+            .add_block(piece_index, 0, &data)?;
+        let piece_data = Self::verify_piece(piece_manager, piece_index).await?;
+
+        Self::store_piece(piece_manager, storage, piece_index, &piece_data).await
+    }
 
-    /// Download a piece from a peer
+    /// Download a piece from a peer, returning its length once stored
     async fn download_piece_from_peer(
         peer: &mut PeerConnection,
         piece_index: usize,
         piece_manager: Arc<Mutex<PieceManager>>,
-        storage: Arc<StorageManager>,
+        storage: Arc<dyn StorageBackend>,
+        timeouts: PieceTimeouts,
+    ) -> Result<u64> {
+        let fetched = Self::fetch_piece(peer, piece_index, &piece_manager, timeouts).await;
+        // The connection may go back to the pool; whoever uses it next sets
+        // their own deadline
+        peer.set_deadline(None);
+        fetched?;
+
+        // Complete and verify piece
+        let piece_data = Self::verify_piece(&piece_manager, piece_index).await?;
+
+        // Write to storage
+        Self::store_piece(&piece_manager, storage.as_ref(), piece_index, &piece_data).await?;
+
+        Ok(piece_data.len() as u64)
+    }
+
+    /// Get unchoked by the peer and receive every block of a piece
+    ///
+    /// Every wait is a deadline on the connection, so a peer that misses
+    /// one is left marked dead rather than mid-message.
+    async fn fetch_piece(
+        peer: &mut PeerConnection,
+        piece_index: usize,
+        piece_manager: &Mutex<PieceManager>,
+        timeouts: PieceTimeouts,
     ) -> Result<()> {
+        // Send interested message if we're not already interested, then wait
+        // for unchoke unless the peer already unchoked us. Bitfield and Have
+        // messages seen meanwhile are applied by the connection, so
+        // `has_piece` below reflects everything the peer announced.
+        peer.set_deadline(Some(Instant::now() + timeouts.unchoke));
+        if !peer.state().am_interested {
+            peer.send_message(&PeerMessage::Interested).await?;
+        }
+        Self::wait_for_unchoke(peer, timeouts.unchoke, None).await?;
+        debug!("Peer unchoked us, ready to download piece {}", piece_index);
+
+        if !peer.has_piece(piece_index) {
+            return Err(BittorrentError::PeerError(format!(
+                "Peer does not have piece {}",
+                piece_index
+            )));
+        }
+
         // Start the piece
         {
             let mut pm = piece_manager.lock().await;
             pm.start_piece(piece_index)?;
         }
 
-        // Send interested message if we're not already interested
-        if !peer.state().am_interested {
-            peer.send_message(&PeerMessage::Interested).await?;
+        // Request blocks, giving up if the whole piece takes too long. Any
+        // failure abandons the piece so its buffer doesn't linger.
+        let deadline = Instant::now() + timeouts.piece;
+        let result =
+            match Self::download_blocks(peer, piece_index, piece_manager, timeouts, deadline).await
+            {
+                Err(BittorrentError::Timeout(_)) if Instant::now() >= deadline => {
+                    Err(BittorrentError::PieceTimeout(format!(
+                        "piece {} not completed within {:?}",
+                        piece_index, timeouts.piece
+                    )))
+                }
+                result => result,
+            };
+        if result.is_err() {
+            piece_manager.lock().await.reset_piece(piece_index);
         }
+        result
+    }
 
-        // Wait for unchoke (with timeout)
-        let unchoke_result = tokio::time::timeout(tokio::time::Duration::from_secs(30), async {
-            loop {
-                let msg = peer.receive_message().await?;
-                match msg {
-                    PeerMessage::Unchoke => {
-                        info!("Peer unchoked us, ready to download piece {}", piece_index);
-                        break;
-                    }
-                    PeerMessage::Choke => {
-                        warn!("Peer choked us while waiting for unchoke");
-                        return Err(BittorrentError::PeerError("Peer choked us".to_string()));
-                    }
-                    PeerMessage::KeepAlive => {
-                        // Just continue waiting
-                    }
-                    _ => {
-                        // Handle other messages but keep waiting
-                    }
-                }
+    /// Write a verified piece, marking it missing again if the write fails
+    /// so it isn't counted as downloaded
+    async fn store_piece(
+        piece_manager: &Mutex<PieceManager>,
+        storage: &dyn StorageBackend,
+        piece_index: usize,
+        data: &[u8],
+    ) -> Result<()> {
+        let result = storage.write_piece(piece_index, data).await;
+        if result.is_err() {
+            piece_manager.lock().await.forget_piece(piece_index);
+        }
+        result
+    }
+
+    /// Verify a fully downloaded piece and mark it complete
+    ///
+    /// With offloading on, the manager is only locked to take the piece out
+    /// and to record the result; hashing runs on the blocking pool so other
+    /// peer tasks keep running meanwhile.
+    async fn verify_piece(
+        piece_manager: &Mutex<PieceManager>,
+        piece_index: usize,
+    ) -> Result<Vec<u8>> {
+        let pending = {
+            let mut pm = piece_manager.lock().await;
+            if !pm.offloads_hashing() {
+                return pm.complete_piece(piece_index);
             }
-            Ok::<(), BittorrentError>(())
-        })
-        .await;
+            pm.take_piece(piece_index)?
+        };
 
-        match unchoke_result {
-            Ok(Ok(_)) => {}
-            Ok(Err(e)) => return Err(e),
-            Err(_) => {
-                return Err(BittorrentError::PeerError(
-                    "Timeout waiting for unchoke".to_string(),
-                ))
+        match tokio::task::spawn_blocking(move || pending.hash()).await {
+            Ok(hashed) => piece_manager.lock().await.finish_piece(hashed),
+            Err(e) => {
+                piece_manager.lock().await.abandon_piece(piece_index);
+                Err(BittorrentError::PieceError(format!(
+                    "hashing piece {} failed: {}",
+                    piece_index, e
+                )))
+            }
+        }
+    }
+
+    /// Read messages until the peer unchokes us, giving up after
+    /// `unchoke_timeout` or at `deadline`, whichever comes first
+    async fn wait_for_unchoke(
+        peer: &mut PeerConnection,
+        unchoke_timeout: Duration,
+        deadline: Option<Instant>,
+    ) -> Result<()> {
+        let unchoke_by = Instant::now() + unchoke_timeout;
+        peer.set_deadline(Some(deadline.map_or(unchoke_by, |d| d.min(unchoke_by))));
+
+        while peer.state().peer_choking {
+            match peer.receive_message().await {
+                Ok(PeerMessage::Piece { data, .. }) => peer.recycle(data),
+                Ok(_) => {}
+                Err(BittorrentError::Timeout(_)) if Instant::now() >= unchoke_by => {
+                    return Err(BittorrentError::Timeout(format!(
+                        "peer did not unchoke us within {:?}",
+                        unchoke_timeout
+                    )))
+                }
+                Err(e) => return Err(e),
             }
         }
+        Ok(())
+    }
 
-        // Request blocks
-        let num_blocks = {
+    /// Request every block of a started piece and store the replies
+    ///
+    /// Up to the peer's pipeline depth of requests are kept outstanding at
+    /// once. Requests are only sent while the peer has us unchoked. A choke
+    /// mid-piece makes the peer drop every outstanding request, so we wait
+    /// to be unchoked and ask for them again.
+    ///
+    /// Replies are matched by offset rather than by block: a peer may answer
+    /// with messages that span several blocks or only part of one, as long
+    /// as every requested byte eventually arrives.
+    ///
+    /// Nothing is waited on past `deadline`; missing it fails with the
+    /// connection's own `Timeout`.
+    async fn download_blocks(
+        peer: &mut PeerConnection,
+        piece_index: usize,
+        piece_manager: &Mutex<PieceManager>,
+        timeouts: PieceTimeouts,
+        deadline: Instant,
+    ) -> Result<()> {
+        let (blocks, mut filled): (Vec<(u32, u32)>, Vec<u32>) = {
             let pm = piece_manager.lock().await;
-            pm.blocks_in_piece(piece_index)
+            let blocks: Vec<(u32, u32)> = (0..pm.blocks_in_piece(piece_index))
+                .map(|block_index| pm.get_block_info(piece_index, block_index))
+                .collect::<Option<_>>()
+                .ok_or_else(|| BittorrentError::PieceError("Invalid block".to_string()))?;
+            // Bytes received so far from the start of each block; blocks
+            // saved by a previous session count as already delivered
+            let filled = match pm.received_blocks(piece_index) {
+                Some(saved) => blocks
+                    .iter()
+                    .zip(saved)
+                    .map(|(&(_, length), &have)| if have { length } else { 0 })
+                    .collect(),
+                None => vec![0; blocks.len()],
+            };
+            (blocks, filled)
         };
+        let num_blocks = blocks.len();
 
-        for block_index in 0..num_blocks {
-            let (offset, length) = {
-                let pm = piece_manager.lock().await;
-                pm.get_block_info(piece_index, block_index)
-                    .ok_or_else(|| BittorrentError::PieceError("Invalid block".to_string()))?
-            };
+        let mut next_block = 0;
+        let mut received = blocks
+            .iter()
+            .zip(&filled)
+            .filter(|(&(_, length), &got)| got == length)
+            .count();
+        let mut outstanding: HashSet<BlockInfo> = HashSet::new();
 
-            let block = BlockInfo::new(piece_index as u32, offset, length);
-            peer.send_message(&PeerMessage::Request { block }).await?;
+        while received < num_blocks {
+            // Top up the pipeline
+            while next_block < num_blocks && outstanding.len() < peer.pipeline_depth() {
+                let (offset, length) = blocks[next_block];
+                if filled[next_block] == length {
+                    // Already delivered as part of a larger message
+                    next_block += 1;
+                    continue;
+                }
 
-            // Receive piece (with timeout)
-            let receive_result =
-                tokio::time::timeout(tokio::time::Duration::from_secs(30), peer.receive_message())
-                    .await;
+                if peer.state().peer_choking {
+                    Self::wait_for_unchoke(peer, timeouts.unchoke, Some(deadline)).await?;
+                }
+                peer.set_deadline(Some(deadline));
+                let block = peer
+                    .request_block(piece_index as u32, offset, length)
+                    .await?;
+                outstanding.insert(block);
+                next_block += 1;
+            }
 
-            match receive_result {
-                Ok(Ok(PeerMessage::Piece {
+            // Receive piece (with timeout). Unsolicited blocks are already
+            // filtered by the connection; anything else left over is a stale
+            // answer to an earlier request, so keep waiting for ours.
+            peer.set_deadline(Some(deadline.min(Instant::now() + timeouts.block)));
+            match peer.read_block().await {
+                Ok(PeerEvent::Block {
                     piece_index: received_index,
-                    offset: received_offset,
+                    offset,
                     data,
-                })) => {
-                    if received_index as usize == piece_index && received_offset == offset {
-                        let mut pm = piece_manager.lock().await;
-                        pm.add_block(piece_index, offset, &data)?;
+                }) => {
+                    // The block this data starts in, which must be one we
+                    // asked for and must continue where its data left off
+                    let first = blocks
+                        .partition_point(|&(start, _)| start <= offset)
+                        .checked_sub(1)
+                        .filter(|&i| {
+                            let (start, length) = blocks[i];
+                            received_index as usize == piece_index
+                                && outstanding.contains(&BlockInfo::new(
+                                    received_index,
+                                    start,
+                                    length,
+                                ))
+                                && offset <= start + filled[i]
+                        });
+
+                    if let Some(first) = first {
+                        let added =
+                            piece_manager
+                                .lock()
+                                .await
+                                .add_block(piece_index, offset, &data);
+                        if let Err(e) = added {
+                            peer.recycle(data);
+                            return Err(e);
+                        }
+
+                        let end = offset + data.len() as u32;
+                        for (i, &(start, length)) in blocks.iter().enumerate().skip(first) {
+                            if start >= end {
+                                break;
+                            }
+                            let reached = end.min(start + length) - start;
+                            if reached > filled[i] {
+                                filled[i] = reached;
+                                if reached == length {
+                                    outstanding.remove(&BlockInfo::new(
+                                        received_index,
+                                        start,
+                                        length,
+                                    ));
+                                    received += 1;
+                                }
+                            }
+                        }
                     } else {
                         warn!(
-                            "Received unexpected piece data: expected piece {}, offset {}, got piece {}, offset {}",
-                            piece_index, offset, received_index, received_offset
+                            "Received stale piece data: piece {}, offset {} while downloading piece {}",
+                            received_index, offset, piece_index
                         );
                     }
+                    peer.recycle(data);
+                }
+                Ok(PeerEvent::Choked) => {
+                    debug!(
+                        "Choked with {} requests for piece {} outstanding, re-requesting after unchoke",
+                        outstanding.len(),
+                        piece_index
+                    );
+                    Self::wait_for_unchoke(peer, timeouts.unchoke, Some(deadline)).await?;
+                    peer.set_deadline(Some(deadline));
+                    for block in &outstanding {
+                        peer.request_block(block.piece_index, block.offset, block.length)
+                            .await?;
+                    }
                 }
-                Ok(Ok(other_msg)) => {
-                    return Err(BittorrentError::PeerError(format!(
-                        "Expected Piece message, got {:?}",
-                        other_msg
-                    )));
+                Ok(PeerEvent::Other(PeerMessage::Request { block })) => {
+                    // We never unchoke peers yet, so there is nothing to serve
+                    debug!(
+                        "Ignoring request from {} for piece {} offset {}",
+                        peer.addr(),
+                        block.piece_index,
+                        block.offset
+                    );
                 }
-                Ok(Err(e)) => return Err(e),
-                Err(_) => {
-                    return Err(BittorrentError::PeerError(
-                        "Timeout receiving block".to_string(),
-                    ))
+                Ok(PeerEvent::Unchoked) => {}
+                Ok(PeerEvent::Other(other_msg)) => {
+                    // Have, Interested and the like were already applied to
+                    // the connection state; keep waiting for our blocks
+                    debug!(
+                        "Received {} from {} while waiting for piece {}",
+                        other_msg,
+                        peer.addr(),
+                        piece_index
+                    );
                 }
+                // Past the piece deadline the caller reports the timeout
+                Err(BittorrentError::Timeout(_)) if Instant::now() < deadline => {
+                    return Err(BittorrentError::Timeout(format!(
+                        "no block received for piece {} ({} requests outstanding)",
+                        piece_index,
+                        outstanding.len()
+                    )))
+                }
+                Err(e) => return Err(e),
             }
         }
 
-        // Complete and verify piece
-        let piece_data = {
-            let mut pm = piece_manager.lock().await;
-            pm.complete_piece(piece_index)?
-        };
-
-        // Write to storage
-        storage.write_piece(piece_index, &piece_data).await?;
-
         Ok(())
     }
 }
@@ -399,3 +2283,1587 @@ impl Default for TorrentClient {
         Self::new(ClientConfig::default())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::PeerEntryState;
+    use crate::piece::BLOCK_SIZE;
+    use crate::storage::MemoryStorage;
+    use crate::testutil::{
+        make_info, make_torrent, random_content, MockBehavior, MockHttpSeed, MockPeer, MockTracker,
+        MockWebSeed, PieceFixture,
+    };
+    use crate::torrent::parse_torrent;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// Timeouts no test should hit
+    const PATIENT: PieceTimeouts = PieceTimeouts {
+        unchoke: Duration::from_secs(30),
+        piece: Duration::from_secs(60),
+        block: Duration::from_secs(30),
+    };
+
+    /// Download `piece_index` from the fixture's peer with timeouts no
+    /// test should hit
+    async fn download_fixture_piece(fixture: &mut PieceFixture, piece_index: usize) -> Result<u64> {
+        TorrentClient::download_piece_from_peer(
+            &mut fixture.peer,
+            piece_index,
+            fixture.piece_manager.clone(),
+            fixture.storage.clone(),
+            PATIENT,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_unsolicited_piece_is_not_stored() {
+        let content = b"hello world!".to_vec();
+        let behavior = MockBehavior {
+            extra_before_block: vec![(
+                0,
+                PeerMessage::Piece {
+                    piece_index: 0,
+                    offset: 4,
+                    data: b"junk".to_vec(),
+                },
+            )],
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert_eq!(fixture.peer.pending_request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unaligned_piece_messages_complete_piece() {
+        let content = random_content(40_000);
+        // Block boundaries are at 16384 and 32768; none of the replies
+        // start or end on one
+        let behavior = MockBehavior {
+            batch_requests: 3,
+            split_batch_at: vec![20_000, 33_000],
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+        fixture.peer.set_pipeline_limits(PipelineLimits {
+            min_depth: 3,
+            max_depth: 3,
+        });
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert_eq!(fixture.peer.pending_request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_short_last_block_is_requested_and_accepted() {
+        let content = random_content(16_384 * 3 + 1000);
+        let mut fixture = PieceFixture::single_piece(&content, MockBehavior::default()).await;
+        fixture.peer.set_pipeline_limits(PipelineLimits {
+            min_depth: 4,
+            max_depth: 4,
+        });
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert_eq!(
+            fixture.seed.requested_blocks(),
+            vec![
+                BlockInfo::new(0, 0, 16_384),
+                BlockInfo::new(0, 16_384, 16_384),
+                BlockInfo::new(0, 32_768, 16_384),
+                BlockInfo::new(0, 49_152, 1000),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_control_messages_between_blocks_are_tolerated() {
+        let content = random_content(32_768);
+        let request = PeerMessage::Request {
+            block: BlockInfo::new(0, 0, 16_384),
+        };
+        let behavior = MockBehavior {
+            extra_before_block: [
+                PeerMessage::Have { piece_index: 0 },
+                PeerMessage::Interested,
+                request,
+                PeerMessage::KeepAlive,
+            ]
+            .into_iter()
+            .map(|message| (1, message))
+            .collect(),
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+        fixture.peer.set_pipeline_limits(PipelineLimits {
+            min_depth: 2,
+            max_depth: 2,
+        });
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert!(fixture.peer.state().peer_interested);
+    }
+
+    #[tokio::test]
+    async fn test_failed_piece_is_retried_from_another_peer() {
+        let content = random_content(32_768);
+        let bad_block = MockBehavior {
+            corrupt_block: Some(1),
+            ..Default::default()
+        };
+        let PieceFixture {
+            metainfo,
+            seed: bad_seed,
+            peer,
+            piece_manager,
+            storage,
+        } = PieceFixture::single_piece(&content, bad_block).await;
+        let info_hash = metainfo.info_hash;
+        let good_seed = MockPeer::spawn(info_hash, content.clone(), 32_768).await;
+        let mut failures = HashFailures::new();
+
+        let mut pool = vec![peer];
+        let mut peer = TorrentClient::take_peer_for_piece(&mut pool, 0, &failures).unwrap();
+        let result = TorrentClient::download_piece_from_peer(
+            &mut peer,
+            0,
+            piece_manager.clone(),
+            storage.clone(),
+            PATIENT,
+        )
+        .await;
+        assert!(matches!(result, Err(BittorrentError::HashMismatch(_))));
+        assert!(failures.record_failure(0, &[peer.addr()]).is_empty());
+
+        // The bad seed is back in the pool but isn't handed the piece again
+        pool.push(peer);
+        assert!(TorrentClient::take_peer_for_piece(&mut pool, 0, &failures).is_none());
+
+        let good = PeerConnection::connect(good_seed.addr(), info_hash, [1u8; 20])
+            .await
+            .unwrap();
+        pool.insert(0, good);
+        let mut peer = TorrentClient::take_peer_for_piece(&mut pool, 0, &failures).unwrap();
+        assert_eq!(peer.addr(), good_seed.addr());
+
+        TorrentClient::download_piece_from_peer(
+            &mut peer,
+            0,
+            piece_manager,
+            storage.clone(),
+            PATIENT,
+        )
+        .await
+        .unwrap();
+        assert_eq!(storage.contents(), content);
+
+        // Success proves the first copy was bad
+        assert_eq!(failures.record_success(0), vec![bad_seed.addr()]);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_mid_piece_abandons_it() {
+        let behavior = MockBehavior {
+            disconnect_after_blocks: Some(0),
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&random_content(12), behavior).await;
+
+        let result = download_fixture_piece(&mut fixture, 0).await;
+        assert!(result.is_err());
+
+        let pm = fixture.piece_manager.lock().await;
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+        assert_eq!(pm.inflight_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_have_before_unchoke_is_applied() {
+        let content = b"hello world!".to_vec();
+        // Starts without the piece and announces it before unchoking
+        let behavior = MockBehavior {
+            announce_with_have: true,
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert!(fixture.peer.has_piece(0));
+        assert_eq!(fixture.storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_peer_that_never_unchokes_times_out() {
+        let behavior = MockBehavior {
+            never_unchoke: true,
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&random_content(12), behavior).await;
+
+        let result = TorrentClient::download_piece_from_peer(
+            &mut fixture.peer,
+            0,
+            fixture.piece_manager.clone(),
+            fixture.storage.clone(),
+            PieceTimeouts {
+                unchoke: Duration::from_millis(200),
+                ..PATIENT
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(BittorrentError::Timeout(_))));
+        let pm = fixture.piece_manager.lock().await;
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+        assert_eq!(pm.inflight_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_restart_resumes_half_downloaded_piece() {
+        let content = random_content(16_384 * 4);
+        let mut fixture = PieceFixture::single_piece(&content, MockBehavior::default()).await;
+        let dir = std::env::temp_dir().join(format!("resume-partial-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = resume_path(&dir, "data.bin");
+
+        // First session gets blocks 0 and 2, then stops
+        {
+            let piece_manager = Mutex::new(PieceManager::new(&fixture.metainfo.info));
+            let piece_picker = Mutex::new(PiecePicker::new(1));
+            {
+                let mut pm = piece_manager.lock().await;
+                pm.start_piece(0).unwrap();
+                pm.add_block(0, 0, &content[..16_384]).unwrap();
+                pm.add_block(0, 32_768, &content[32_768..49_152]).unwrap();
+            }
+            let base = ResumeData::new(fixture.metainfo.info_hash, 1);
+            let snapshot = TorrentClient::resume_snapshot(
+                &base,
+                0,
+                &piece_manager,
+                &piece_picker,
+                fixture.storage.as_ref(),
+            )
+            .await;
+            assert_eq!(snapshot.partial, vec![(0, vec![true, false, true, false])]);
+            snapshot.save(&path).await.unwrap();
+        }
+
+        // After a restart only the missing blocks are requested
+        let piece_picker = Mutex::new(PiecePicker::new(1));
+        TorrentClient::restore_session(
+            &path,
+            &fixture.metainfo,
+            fixture.storage.as_ref(),
+            &fixture.piece_manager,
+            &piece_picker,
+        )
+        .await;
+        assert_eq!(fixture.piece_manager.lock().await.complete_count(), 0);
+
+        fixture.peer.set_pipeline_limits(PipelineLimits {
+            min_depth: 4,
+            max_depth: 4,
+        });
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert_eq!(fixture.piece_manager.lock().await.complete_count(), 1);
+
+        let offsets: Vec<u32> = fixture
+            .seed
+            .requested_blocks()
+            .iter()
+            .map(|block| block.offset)
+            .collect();
+        assert_eq!(offsets, vec![16_384, 49_152]);
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download_from_mock_peer() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+
+        // The info hash doesn't depend on the announce URL
+        let info_hash = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap()
+        .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &tracker.announce_url());
+        let metainfo = parse_torrent(&torrent).unwrap();
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_denied_tracker_is_never_announced_to() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let (denied, denied_hits) = counting_listener("127.0.0.1").await;
+        let denied_url = format!("http://{}/announce", denied);
+
+        let info_hash = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            &denied_url,
+        ))
+        .unwrap()
+        .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &denied_url);
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+        metainfo.announce_list = Some(vec![vec![denied_url.clone()], vec![tracker.announce_url()]]);
+
+        let client = TorrentClient::new(ClientConfig {
+            tracker_filter: TrackerFilter {
+                allow: Vec::new(),
+                deny: vec![denied_url.clone()],
+            },
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+        assert_eq!(denied_hits.load(std::sync::atomic::Ordering::SeqCst), 0);
+
+        // Filtering out every tracker is an error, not a silent no-op
+        let client = TorrentClient::new(ClientConfig {
+            tracker_filter: TrackerFilter {
+                allow: vec!["tracker.example".to_string()],
+                deny: Vec::new(),
+            },
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        let err = client
+            .download_with_storage(&metainfo, storage)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("All 2 trackers"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn test_download_one_piece_at_a_time() {
+        let piece_length = 16 * 1024;
+        let content = random_content(piece_length as usize * 6);
+        let torrent = make_torrent("data.bin", &content, piece_length, "http://x");
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+
+        let mut peers = Vec::new();
+        for _ in 0..3 {
+            peers.push(MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await);
+        }
+        let tracker = MockTracker::spawn(peers.iter().map(MockPeer::addr).collect()).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        // Peers without a free slot wait their turn instead of giving up
+        let client = TorrentClient::new(ClientConfig {
+            max_concurrent_pieces: Some(1),
+            min_peers_to_start: 3,
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_completion_command_does_not_block_download() {
+        let piece_length = 16 * 1024;
+        let content = random_content(piece_length as usize * 2);
+        let torrent = make_torrent("data.bin", &content, piece_length, "http://x");
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig {
+            on_complete: Some("sleep 30".to_string()),
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download waited for the completion command")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_download_with_offloaded_hashing() {
+        let piece_length = 16 * 1024;
+        let content = random_content(piece_length as usize * 8);
+        let torrent = make_torrent("data.bin", &content, piece_length, "http://x");
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+
+        // Corrupt pieces are caught off the runtime just the same
+        let good = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let bad = MockPeer::spawn(
+            metainfo.info_hash,
+            random_content(content.len()),
+            piece_length,
+        )
+        .await;
+        let tracker = MockTracker::spawn(vec![bad.addr(), good.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig {
+            offload_hashing: true,
+            min_peers_to_start: 2,
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    /// Download `content` from `peers` mock seeds and time it
+    async fn timed_swarm_download(content: &[u8], peers: usize, offload_hashing: bool) -> Duration {
+        let piece_length = 1024 * 1024;
+        let torrent = make_torrent("data.bin", content, piece_length, "http://x");
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+        let mut seeds = Vec::new();
+        for _ in 0..peers {
+            seeds.push(MockPeer::spawn(metainfo.info_hash, content.to_vec(), piece_length).await);
+        }
+        let tracker = MockTracker::spawn(seeds.iter().map(MockPeer::addr).collect()).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig {
+            offload_hashing,
+            min_peers_to_start: peers,
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        let started = std::time::Instant::now();
+        client
+            .download_with_storage(&metainfo, storage.clone())
+            .await
+            .unwrap();
+        assert_eq!(storage.contents(), content);
+        started.elapsed()
+    }
+
+    /// Swarm throughput with piece hashing on and off the runtime threads,
+    /// downloading 128 MiB in 1 MiB pieces from 32 loopback seeds. Run with
+    /// `cargo test --release offload_throughput -- --ignored --nocapture`
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    #[ignore = "measurement, not a check"]
+    async fn measure_offload_throughput() {
+        let content = random_content(128 * 1024 * 1024);
+        let mebibytes = content.len() as f64 / (1024.0 * 1024.0);
+
+        for offload_hashing in [false, true] {
+            let elapsed = timed_swarm_download(&content, 32, offload_hashing).await;
+            println!(
+                "offload_hashing={offload_hashing}: {elapsed:?}, {:.1} MiB/s",
+                mebibytes / elapsed.as_secs_f64()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_download_aborts_when_pieces_keep_failing() {
+        let piece_length = 16 * 1024;
+        let content = random_content(piece_length as usize * 8);
+        let torrent = make_torrent("data.bin", &content, piece_length, "http://x");
+        let info_hash = parse_torrent(&torrent).unwrap().info_hash;
+
+        // The peer serves data that matches none of the torrent's hashes
+        let garbage = random_content(content.len());
+        let peer = MockPeer::spawn(info_hash, garbage, piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &tracker.announce_url());
+        let metainfo = parse_torrent(&torrent).unwrap();
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::new(ClientConfig {
+            max_piece_failures: Some(2),
+            ..Default::default()
+        });
+        let result = tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage),
+        )
+        .await
+        .expect("download should give up instead of retrying forever");
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, BittorrentError::HashMismatch(_)), "{err}");
+        assert!(err.to_string().contains("may be corrupt"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn test_quota_stops_download() {
+        let piece_length = 16 * 1024;
+        let content = random_content(piece_length as usize * 8);
+        let info_hash = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap()
+        .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &tracker.announce_url());
+        let metainfo = parse_torrent(&torrent).unwrap();
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::new(ClientConfig {
+            download_quota: Some(piece_length * 3),
+            ..Default::default()
+        });
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        // One peer means one piece in flight at a time
+        let stats = client.stats().await;
+        assert_eq!(stats.pieces_complete, 3);
+        assert_eq!(stats.downloaded_bytes, piece_length * 3);
+    }
+
+    #[tokio::test]
+    async fn test_download_starts_without_waiting_for_slow_dials() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let info_hash = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap()
+        .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+
+        // Unroutable addresses that only fail after the connect timeout
+        let mut peers: Vec<std::net::SocketAddr> = (1..=5)
+            .map(|i| format!("10.255.255.{}:6881", i).parse().unwrap())
+            .collect();
+        peers.push(peer.addr());
+        let tracker = MockTracker::spawn(peers).await;
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &tracker.announce_url());
+        let metainfo = parse_torrent(&torrent).unwrap();
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            CONNECT_TIMEOUT - Duration::from_secs(1),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download waited on dead peers")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    /// Listen on a loopback port, counting and dropping every connection
+    async fn counting_listener(ip: &str) -> (SocketAddr, Arc<std::sync::atomic::AtomicUsize>) {
+        let listener = tokio::net::TcpListener::bind((ip, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                drop(stream);
+            }
+        });
+        (addr, accepted)
+    }
+
+    #[tokio::test]
+    async fn test_reannounce_dials_only_new_peers() {
+        let mut listeners = Vec::new();
+        for _ in 0..3 {
+            listeners.push(counting_listener("127.0.0.1").await);
+        }
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|(addr, _)| *addr).collect();
+
+        // The first peer is already connected from the initial announce
+        let registry = PeerRegistry::new();
+        registry.add(addrs[0]);
+        registry.next_candidate();
+        registry.mark_connected(addrs[0]);
+
+        let new_peers = registry.merge(addrs.clone());
+        assert_eq!(new_peers, addrs[1..]);
+
+        let (tx, _rx) = mpsc::channel(4);
+        let params = DialParams {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            policy: HandshakePolicy::default(),
+            num_pieces: 1,
+            pipeline: PipelineLimits::default(),
+            wire_dump: None,
+            read_timeout: Duration::from_secs(5),
+            buffer_pool: BufferPool::default(),
+            peer_stats: PeerStatsTable::new(),
+            socket_tos: None,
+            half_open: Arc::new(Semaphore::new(MAX_CONCURRENT_DIALS)),
+            availability: mpsc::unbounded_channel().0,
+        };
+        TorrentClient::connect_peers(registry, params, Some(new_peers), 10, tx).await;
+
+        let counts: Vec<usize> = listeners
+            .iter()
+            .map(|(_, accepted)| accepted.load(std::sync::atomic::Ordering::SeqCst))
+            .collect();
+        assert_eq!(counts, vec![0, 1, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_lost_peer_is_replaced_from_registry() {
+        let info_hash = [7u8; 20];
+        let lost = MockPeer::spawn(info_hash, vec![0; 16384], 16384).await;
+        let spare = MockPeer::spawn(info_hash, vec![0; 16384], 16384).await;
+
+        // One slot, taken by the first peer
+        let registry = PeerRegistry::new();
+        registry.add_all([lost.addr(), spare.addr()]);
+        registry.claim_unconnected(&[lost.addr()]);
+        registry.mark_connected(lost.addr());
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let (round_tx, mut rounds) = mpsc::channel(1);
+        let peers_lost = Arc::new(Notify::new());
+        let params = DialParams {
+            info_hash,
+            peer_id: [0; 20],
+            policy: HandshakePolicy::default(),
+            num_pieces: 1,
+            pipeline: PipelineLimits::default(),
+            wire_dump: None,
+            read_timeout: Duration::from_secs(5),
+            buffer_pool: BufferPool::default(),
+            peer_stats: PeerStatsTable::new(),
+            socket_tos: None,
+            half_open: Arc::new(Semaphore::new(MAX_CONCURRENT_DIALS)),
+            availability: mpsc::unbounded_channel().0,
+        };
+        let task = tokio::spawn(TorrentClient::maintain_peers(
+            registry.clone(),
+            params,
+            1,
+            tx,
+            peers_lost.clone(),
+            round_tx,
+        ));
+
+        // A full pool needs nothing dialed
+        peers_lost.notify_one();
+        rounds.recv().await.unwrap();
+        assert!(rx.try_recv().is_err());
+
+        // The peer goes away for good and its slot is filled again
+        let lost_addr = lost.addr();
+        drop(lost);
+        registry.mark_failed(lost_addr);
+        peers_lost.notify_one();
+        let replacement = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .expect("no replacement was dialed")
+            .unwrap();
+        assert_eq!(replacement.addr(), spare.addr());
+        assert_eq!(
+            registry.state(spare.addr()),
+            Some(PeerEntryState::Connected)
+        );
+
+        task.abort();
+    }
+
+    #[tokio::test]
+    async fn test_blocklisted_peers_are_never_dialed() {
+        let (allowed, allowed_count) = counting_listener("127.0.0.1").await;
+        let (blocked, blocked_count) = counting_listener("127.0.0.2").await;
+
+        let blocklist = Blocklist::parse_p2p("Test range:127.0.0.2-127.0.0.2");
+        let registry = PeerRegistry::new().with_blocklist(Some(Arc::new(blocklist)));
+        registry.add_all([allowed, blocked]);
+
+        let (tx, _rx) = mpsc::channel(4);
+        let params = DialParams {
+            info_hash: [0; 20],
+            peer_id: [0; 20],
+            policy: HandshakePolicy::default(),
+            num_pieces: 1,
+            pipeline: PipelineLimits::default(),
+            wire_dump: None,
+            read_timeout: Duration::from_secs(5),
+            buffer_pool: BufferPool::default(),
+            peer_stats: PeerStatsTable::new(),
+            socket_tos: None,
+            half_open: Arc::new(Semaphore::new(MAX_CONCURRENT_DIALS)),
+            availability: mpsc::unbounded_channel().0,
+        };
+        TorrentClient::connect_peers(registry, params, None, 10, tx).await;
+
+        // The allowed peer fails the handshake and is retried a few times
+        assert!(allowed_count.load(std::sync::atomic::Ordering::SeqCst) > 0);
+        assert_eq!(blocked_count.load(std::sync::atomic::Ordering::SeqCst), 0);
+    }
+
+    /// A connection over an in-memory pipe, plus the remote end
+    async fn piped_peer(
+        port: u16,
+    ) -> (
+        PeerConnection<tokio::io::DuplexStream>,
+        tokio::io::DuplexStream,
+    ) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (local, mut remote) = tokio::io::duplex(1024);
+        remote
+            .write_all(&crate::peer::Handshake::new([0; 20], [9; 20]).to_bytes())
+            .await
+            .unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let conn =
+            PeerConnection::handshake(local, addr, [0; 20], [1; 20], &HandshakePolicy::default())
+                .await
+                .unwrap();
+        remote.read_exact(&mut [0u8; 68]).await.unwrap();
+        (conn, remote)
+    }
+
+    #[tokio::test]
+    async fn test_idle_and_broken_peers_are_reaped() {
+        let (mut active, _active_remote) = piped_peer(1).await;
+        let (idle, _idle_remote) = piped_peer(2).await;
+        let (mut broken, broken_remote) = piped_peer(3).await;
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        active.send_message(&PeerMessage::KeepAlive).await.unwrap();
+        drop(broken_remote);
+        assert!(broken.receive_message().await.is_err());
+        assert!(!broken.is_alive());
+
+        let mut pool = vec![active, idle, broken];
+        let reaped = TorrentClient::reap_dead_peers(&mut pool, Duration::from_millis(50));
+
+        let port = |conn: &PeerConnection<_>| conn.addr().port();
+        assert_eq!(reaped.iter().map(port).collect::<Vec<_>>(), vec![2, 3]);
+        assert_eq!(pool.len(), 1);
+        assert_eq!(pool[0].addr().port(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_missing_piece_holders() {
+        let (mut first, mut first_remote) = piped_peer(1).await;
+        let (second, _second_remote) = piped_peer(2).await;
+
+        // The first peer has pieces 0 and 1; the second never says
+        let bitfield = PeerMessage::Bitfield {
+            bitfield: vec![0b1100_0000],
+        };
+        first_remote.write_all(&bitfield.to_bytes()).await.unwrap();
+        first.receive_message().await.unwrap();
+
+        let mut pm = PieceManager::new(&make_info(16384, 16384 * 3));
+        pm.mark_verified(0);
+
+        let holders = TorrentClient::missing_piece_holders(&pm, &[first, second]);
+        let addr = SocketAddr::from(([127, 0, 0, 1], 1));
+        assert_eq!(holders, vec![(1, vec![addr]), (2, vec![])]);
+    }
+
+    /// Log sink for asserting on formatted output
+    #[derive(Clone, Default)]
+    struct LogCapture(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogCapture {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_logs_carry_torrent_and_peer_spans() {
+        let logs = LogCapture::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(move || writer.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::INFO)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let content = random_content(40_000);
+        let piece_length = 16 * 1024;
+        let info_hash = parse_torrent(&make_torrent("log.bin", &content, piece_length, "http://x"))
+            .unwrap()
+            .info_hash;
+        let peer = MockPeer::spawn(info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        let metainfo = parse_torrent(&make_torrent(
+            "log.bin",
+            &content,
+            piece_length,
+            &tracker.announce_url(),
+        ))
+        .unwrap();
+
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        TorrentClient::default()
+            .download_with_storage(&metainfo, storage)
+            .await
+            .unwrap();
+
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let torrent_span = format!("torrent{{hash={}}}", &metainfo.info_hash_hex()[..8]);
+        let peer_span = format!("{}:peer{{addr={}}}", torrent_span, peer.addr());
+        assert!(output.contains(&torrent_span), "{}", output);
+        assert!(output.contains(&peer_span), "{}", output);
+        assert!(!output.contains("Added block"), "{}", output);
+    }
+
+    #[tokio::test]
+    async fn test_silent_tracker_times_out_and_next_one_is_used() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+
+        // Accepts announces and never answers them
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_url = format!("http://{}/announce", silent.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((socket, _)) = silent.accept().await {
+                held.push(socket);
+            }
+        });
+
+        let torrent = make_torrent("data.bin", &content, piece_length, &silent_url);
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce_list = Some(vec![vec![silent_url, tracker.announce_url()]]);
+
+        let client = TorrentClient::new(ClientConfig {
+            announce_timeout: Duration::from_millis(300),
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_scheme_tier_falls_through_to_udp_tracker() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+
+        // Nothing listens on these, and wss:// isn't supported at all
+        let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let closed_addr = closed.local_addr().unwrap();
+        drop(closed);
+
+        let torrent = make_torrent("data.bin", &content, piece_length, "http://x");
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn_udp(vec![peer.addr()]).await;
+        metainfo.announce = None;
+        metainfo.announce_list = Some(vec![vec![
+            format!("http://{}/announce", closed_addr),
+            format!("https://{}/announce", closed_addr),
+            format!("wss://{}/announce", closed_addr),
+            tracker.announce_url(),
+        ]]);
+
+        let client = TorrentClient::new(ClientConfig {
+            announce_timeout: Duration::from_secs(2),
+            ..Default::default()
+        });
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_peer_stats_during_download() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let behavior = MockBehavior {
+            block_delay: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let peer =
+            MockPeer::spawn_with(metainfo.info_hash, content.clone(), piece_length, behavior).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::default();
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        let download = client.download_with_storage(&metainfo, storage.clone());
+        let watch = async {
+            loop {
+                let peers = client.peer_stats();
+                if peers.iter().any(|p| p.downloaded_bytes > 0) {
+                    return peers;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+
+        let (result, peers) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(download, watch)
+        })
+        .await
+        .expect("download timed out");
+        result.unwrap();
+
+        assert_eq!(peers.len(), 1);
+        let stats = &peers[0];
+        assert_eq!(stats.addr, peer.addr());
+        assert_eq!(stats.client.as_deref(), Some("MK 0.0.0.1"));
+        assert_eq!(stats.pieces, metainfo.info.piece_count());
+        assert!(stats.state.am_interested);
+        assert_eq!(stats.uploaded_bytes, 0);
+
+        // Closed connections drop out once the aborted tasks let go of them
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !client.peer_stats().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("connection outlived the download");
+    }
+
+    /// Memory storage that logs every write and flush
+    struct RecordingStorage {
+        inner: MemoryStorage,
+        events: std::sync::Mutex<Vec<StorageEvent>>,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum StorageEvent {
+        Write(usize),
+        Flush,
+    }
+
+    #[async_trait::async_trait]
+    impl StorageBackend for RecordingStorage {
+        async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+            self.events
+                .lock()
+                .unwrap()
+                .push(StorageEvent::Write(piece_index));
+            self.inner.write_piece(piece_index, data).await
+        }
+
+        async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+            self.inner.read_piece(piece_index).await
+        }
+
+        async fn flush(&self) -> Result<()> {
+            self.events.lock().unwrap().push(StorageEvent::Flush);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sequential_download_flushes_pieces_in_order() {
+        let content = random_content(300_000);
+        let piece_length = 16 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "movie.mkv",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig {
+            sequential: true,
+            ..Default::default()
+        });
+        let storage = Arc::new(RecordingStorage {
+            inner: MemoryStorage::new(piece_length, content.len() as u64),
+            events: Default::default(),
+        });
+        tokio::time::timeout(
+            Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        // Every piece is written and flushed before the next one is written
+        let events = storage.events.lock().unwrap().clone();
+        let per_piece: Vec<StorageEvent> = (0..metainfo.info.piece_count())
+            .flat_map(|index| [StorageEvent::Write(index), StorageEvent::Flush])
+            .collect();
+        assert_eq!(&events[..per_piece.len()], &per_piece[..]);
+        assert!(events[per_piece.len()..]
+            .iter()
+            .all(|event| *event == StorageEvent::Flush));
+        assert_eq!(storage.inner.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_pause_stops_requests_until_resumed() {
+        let content = random_content(320 * 1024);
+        let piece_length = 16 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let peer = MockPeer::spawn_with(
+            metainfo.info_hash,
+            content.clone(),
+            piece_length,
+            MockBehavior {
+                block_delay: Duration::from_millis(20),
+                ..Default::default()
+            },
+        )
+        .await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig::default());
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        let control = async {
+            while client.counters().pieces_completed() < 3 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+            client.pause();
+            assert!(client.is_paused());
+
+            // Let the piece in flight finish, then nothing more is asked for
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let requested = peer.requests();
+            let completed = client.counters().pieces_completed();
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            assert_eq!(peer.requests(), requested);
+            assert!(completed < metainfo.info.piece_count());
+
+            client.resume();
+        };
+        let (result, ()) = tokio::time::timeout(Duration::from_secs(10), async {
+            tokio::join!(
+                client.download_with_storage(&metainfo, storage.clone()),
+                control
+            )
+        })
+        .await
+        .expect("download didn't finish after resuming");
+        result.unwrap();
+
+        // Pieces from before the pause were kept, not fetched again
+        assert_eq!(storage.contents(), content);
+        assert_eq!(peer.requests(), metainfo.info.piece_count());
+    }
+
+    /// Memory storage that runs out of space after a number of pieces
+    struct FullDiskStorage {
+        inner: MemoryStorage,
+        room_for: usize,
+        writes: std::sync::atomic::AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl StorageBackend for FullDiskStorage {
+        async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+            let writes = self
+                .writes
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            if writes >= self.room_for {
+                return Err(BittorrentError::StorageError(
+                    "no space left on the device holding ./downloads".to_string(),
+                ));
+            }
+            self.inner.write_piece(piece_index, data).await
+        }
+
+        async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+            self.inner.read_piece(piece_index).await
+        }
+    }
+
+    #[tokio::test]
+    async fn test_full_disk_stops_download() {
+        let content = random_content(300_000);
+        let piece_length = 16 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+
+        let client = TorrentClient::new(ClientConfig::default());
+        let storage = Arc::new(FullDiskStorage {
+            inner: MemoryStorage::new(piece_length, content.len() as u64),
+            room_for: 3,
+            writes: Default::default(),
+        });
+        let result = tokio::time::timeout(
+            Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download kept going after the disk filled up");
+
+        // The first refused write ends the download with the reason
+        match result {
+            Err(BittorrentError::StorageError(reason)) => assert!(reason.contains("no space")),
+            other => panic!("expected a storage error, got {:?}", other),
+        }
+        assert_eq!(storage.writes.load(std::sync::atomic::Ordering::SeqCst), 4);
+
+        // The piece that couldn't be written isn't counted as downloaded
+        assert_eq!(client.stats().await.pieces_complete, 3);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_webseed_without_peers() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let webseed = MockWebSeed::spawn(vec![("/data.bin", content.clone())]).await;
+
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        metainfo.announce = None;
+        metainfo.url_list = vec![format!("{}missing/", webseed.url()), webseed.url()];
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_download_from_http_seed_without_peers() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let httpseed = MockHttpSeed::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        metainfo.announce = None;
+        metainfo.httpseeds = vec![httpseed.url()];
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    #[tokio::test]
+    async fn test_busy_http_seed_leaves_pieces_to_peers() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        let httpseed = MockHttpSeed::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        httpseed.set_busy(Some(60));
+        let peer = MockPeer::spawn(metainfo.info_hash, content.clone(), piece_length).await;
+        let tracker = MockTracker::spawn(vec![peer.addr()]).await;
+        metainfo.announce = Some(tracker.announce_url());
+        metainfo.httpseeds = vec![httpseed.url()];
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        // Waiting out the seed's minute would time this out
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_with_storage(&metainfo, storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+    }
+
+    fn resume_test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_resume_file_falls_back_to_verify() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://127.0.0.1:1/announce",
+        ))
+        .unwrap();
+
+        // Everything is already on disk, so the dead tracker is never needed
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        for (index, chunk) in content.chunks(piece_length as usize).enumerate() {
+            storage.write_piece(index, chunk).await.unwrap();
+        }
+
+        let dir = resume_test_dir("corrupt-resume");
+        let path = dir.join("data.bin.resume");
+        std::fs::write(&path, b"not bencode").unwrap();
+
+        let client = TorrentClient::default();
+        client
+            .download_session(&metainfo, storage, Some(path.clone()))
+            .await
+            .unwrap();
+
+        let saved = ResumeData::load(&path, metainfo.info_hash, 4)
+            .await
+            .unwrap();
+        assert_eq!(saved.completed, vec![true; 4]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resumed_pieces_are_rechecked() {
+        let content = random_content(100_000);
+        let piece_length = 32 * 1024;
+        let webseed = MockWebSeed::spawn(vec![("/data.bin", content.clone())]).await;
+
+        let mut metainfo = parse_torrent(&make_torrent(
+            "data.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+        metainfo.announce = None;
+        metainfo.url_list = vec![webseed.url()];
+
+        // The resume file claims every piece but the last never hit storage
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        for (index, chunk) in content.chunks(piece_length as usize).enumerate().take(3) {
+            storage.write_piece(index, chunk).await.unwrap();
+        }
+
+        let dir = resume_test_dir("stale-resume");
+        let path = dir.join("data.bin.resume");
+        let mut stale = ResumeData::new(metainfo.info_hash, 4);
+        stale.completed = vec![true; 4];
+        stale.downloaded = 1000;
+        stale.save(&path).await.unwrap();
+
+        let client = TorrentClient::default();
+        tokio::time::timeout(
+            tokio::time::Duration::from_secs(10),
+            client.download_session(&metainfo, storage.clone(), Some(path.clone())),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(storage.contents(), content);
+        let saved = ResumeData::load(&path, metainfo.info_hash, 4)
+            .await
+            .unwrap();
+        assert_eq!(saved.completed, vec![true; 4]);
+        assert_eq!(saved.downloaded, 1000 + metainfo.info.piece_size(3));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_slow_piece_is_abandoned() {
+        let behavior = MockBehavior {
+            block_delay: Duration::from_millis(150),
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&random_content(64 * 1024), behavior).await;
+
+        // Four blocks at 150ms each can't finish within 300ms
+        let result = TorrentClient::download_piece_from_peer(
+            &mut fixture.peer,
+            0,
+            fixture.piece_manager.clone(),
+            fixture.storage.clone(),
+            PieceTimeouts {
+                piece: Duration::from_millis(300),
+                ..PATIENT
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(BittorrentError::PieceTimeout(_))));
+        let pm = fixture.piece_manager.lock().await;
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+        assert_eq!(pm.inflight_bytes(), 0);
+        // The read was cut off mid-stream, so the peer isn't pooled again
+        assert!(!fixture.peer.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_peer_stalling_between_blocks_is_dropped() {
+        let behavior = MockBehavior {
+            block_delay: Duration::from_millis(500),
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&random_content(32 * 1024), behavior).await;
+
+        let result = TorrentClient::download_piece_from_peer(
+            &mut fixture.peer,
+            0,
+            fixture.piece_manager.clone(),
+            fixture.storage.clone(),
+            PieceTimeouts {
+                block: Duration::from_millis(100),
+                ..PATIENT
+            },
+        )
+        .await;
+
+        assert!(matches!(result, Err(BittorrentError::Timeout(_))));
+        assert!(!fixture.peer.is_alive());
+        let pm = fixture.piece_manager.lock().await;
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+    }
+
+    #[tokio::test]
+    async fn test_choke_mid_piece_waits_and_re_requests() {
+        let content = random_content(64 * 1024);
+        let behavior = MockBehavior {
+            choke_after_blocks: Some(1),
+            choke_for: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert!(!fixture.peer.state().peer_choking);
+    }
+
+    #[tokio::test]
+    async fn test_block_buffers_are_recycled() {
+        let piece_length = 64 * 1024;
+        let content = random_content(piece_length as usize * 4);
+        let mut fixture = PieceFixture::new(&content, piece_length, MockBehavior::default()).await;
+        let pool = BufferPool::new(8);
+        fixture.peer.set_buffer_pool(pool.clone());
+
+        for piece_index in 0..4 {
+            download_fixture_piece(&mut fixture, piece_index)
+                .await
+                .unwrap();
+        }
+        assert_eq!(fixture.storage.contents(), content);
+
+        // 16 blocks arrived, but each buffer was back in the pool before
+        // the next block was read
+        let stats = pool.stats();
+        assert_eq!(stats.allocations + stats.reuses, 16);
+        assert_eq!(stats.allocations, 1);
+    }
+
+    /// Download every piece of `content` from a mock peer over one
+    /// connection, reading blocks from `pool` if given
+    async fn timed_download(content: &[u8], pool: Option<BufferPool>) -> Duration {
+        let mut fixture = PieceFixture::new(content, 256 * 1024, MockBehavior::default()).await;
+        if let Some(pool) = pool {
+            fixture.peer.set_buffer_pool(pool);
+        }
+
+        let started = std::time::Instant::now();
+        for piece_index in 0..fixture.metainfo.info.piece_count() {
+            download_fixture_piece(&mut fixture, piece_index)
+                .await
+                .unwrap();
+        }
+        started.elapsed()
+    }
+
+    /// Block-buffer allocations with and without a pool over a 64 MiB
+    /// loopback download. Run with
+    /// `cargo test --release buffer_pool_allocations -- --ignored --nocapture`
+    #[tokio::test]
+    #[ignore = "measurement, not a check"]
+    async fn measure_buffer_pool_allocations() {
+        let content = random_content(64 * 1024 * 1024);
+        let blocks = content.len() / BLOCK_SIZE as usize;
+
+        let unpooled = timed_download(&content, None).await;
+        println!("without pool: {blocks} allocations, {unpooled:?}");
+
+        let pool = BufferPool::default();
+        let pooled = timed_download(&content, Some(pool.clone())).await;
+        let stats = pool.stats();
+        println!(
+            "with pool:    {} allocations, {} reuses, {pooled:?}",
+            stats.allocations, stats.reuses
+        );
+        assert_eq!(stats.allocations + stats.reuses, blocks as u64);
+    }
+
+    /// Download one 4-block piece from a mock peer and report the pipeline
+    /// depth the connection settled on
+    async fn pipeline_depth_after_piece(behavior: MockBehavior) -> usize {
+        let content = random_content(64 * 1024);
+        let mut fixture = PieceFixture::single_piece(&content, behavior).await;
+
+        download_fixture_piece(&mut fixture, 0).await.unwrap();
+
+        assert_eq!(fixture.storage.contents(), content);
+        assert!(fixture.peer.rtt().is_some());
+        fixture.peer.pipeline_depth()
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_adapts_to_peer_speed() {
+        let min_depth = PipelineLimits::default().min_depth;
+
+        // Every fast answer adds one more outstanding request
+        let fast = pipeline_depth_after_piece(MockBehavior::default()).await;
+        assert_eq!(fast, min_depth + 4);
+
+        // Answers slower than the target latency keep the depth at the floor
+        let slow = pipeline_depth_after_piece(MockBehavior {
+            block_delay: Duration::from_millis(400),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(slow, min_depth);
+    }
+}
@@ -0,0 +1,420 @@
+//! Serving a torrent we have to peers that connect to us
+
+use super::{DownloadStats, TorrentClient};
+use crate::error::{BittorrentError, Result};
+use crate::peer::{set_socket_tos, HandshakePolicy, PeerConnection, PeerMessage, PeerStatsTable};
+use crate::piece::SuperSeeder;
+use crate::storage::StorageBackend;
+use crate::torrent::Metainfo;
+use socket2::SockRef;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tracing::{debug, info, info_span, warn, Instrument};
+
+/// Largest block a peer may request; 16 KiB is usual, clients don't go
+/// past 128 KiB
+const MAX_REQUEST_LENGTH: u32 = 128 * 1024;
+
+/// What every connection needs to serve the torrent
+#[derive(Clone)]
+struct SeedContext {
+    /// Peers may name the torrent by its v1 or truncated v2 info hash
+    metainfo: Arc<Metainfo>,
+    peer_id: [u8; 20],
+    storage: Arc<dyn StorageBackend>,
+    /// Verified pieces, the only ones offered
+    have: Arc<Vec<bool>>,
+    peer_stats: PeerStatsTable,
+    /// Where served bytes are counted, for the tracker's `uploaded`
+    counters: Arc<DownloadStats>,
+    /// Which piece each peer is shown, when super-seeding
+    super_seeder: Option<Arc<Mutex<SuperSeeder>>>,
+}
+
+impl TorrentClient {
+    /// Seed a torrent from `storage` to peers connecting on the listen port
+    pub async fn seed(&self, metainfo: &Metainfo, storage: Arc<dyn StorageBackend>) -> Result<()> {
+        let listener = TcpListener::bind(("0.0.0.0", self.config.listen_port)).await?;
+        if let Some(tos) = self.config.socket_tos {
+            if let Err(e) = set_socket_tos(SockRef::from(&listener), tos) {
+                warn!("Failed to set ToS {:#04x} on the listen socket: {}", tos, e);
+            }
+        }
+        self.seed_on(listener, metainfo, storage).await
+    }
+
+    /// Seed a torrent from `storage` to peers connecting on `listener`
+    ///
+    /// Only pieces that pass their hash check are offered. Every peer that
+    /// asks is unchoked, up to `max_peers` at once. Runs until accepting
+    /// fails.
+    ///
+    /// With [`ClientConfig::super_seed`](super::ClientConfig::super_seed) and
+    /// every piece present, peers get an empty bitfield and are then shown
+    /// one piece at a time, see [`SuperSeeder`].
+    pub async fn seed_on(
+        &self,
+        listener: TcpListener,
+        metainfo: &Metainfo,
+        storage: Arc<dyn StorageBackend>,
+    ) -> Result<()> {
+        let have = storage.verify_existing(&metainfo.info.pieces).await?;
+        info!(
+            "Seeding {} of {} pieces on {}",
+            have.iter().filter(|&&verified| verified).count(),
+            have.len(),
+            listener.local_addr()?
+        );
+
+        let super_seeder = if !self.config.super_seed {
+            None
+        } else if have.iter().all(|&verified| verified) {
+            info!("Super-seeding: revealing one piece at a time to each peer");
+            Some(Arc::new(Mutex::new(SuperSeeder::new(have.len()))))
+        } else {
+            warn!("Not super-seeding: only a complete torrent can be super-seeded");
+            None
+        };
+
+        let context = SeedContext {
+            metainfo: Arc::new(metainfo.clone()),
+            peer_id: self.peer_id,
+            storage,
+            have: Arc::new(have),
+            peer_stats: self.peers.clone(),
+            counters: self.counters.clone(),
+            super_seeder,
+        };
+        let mut peers = JoinSet::new();
+
+        loop {
+            let (stream, addr) = listener.accept().await?;
+            // Most systems copy the listener's ToS to accepted sockets, but
+            // a listener passed in by the caller may not have it
+            if let Some(tos) = self.config.socket_tos {
+                if let Err(e) = set_socket_tos(SockRef::from(&stream), tos) {
+                    debug!("Failed to set ToS {:#04x} for peer {}: {}", tos, addr, e);
+                }
+            }
+            while peers.try_join_next().is_some() {}
+
+            if let Some(blocklist) = &self.config.blocklist {
+                if blocklist.contains(addr.ip()) {
+                    debug!("Refusing blocklisted peer {}", addr);
+                    continue;
+                }
+            }
+            if peers.len() >= self.config.max_peers {
+                debug!("Refusing peer {}: already serving {}", addr, peers.len());
+                continue;
+            }
+
+            let context = context.clone();
+            let policy = self.config.handshake_policy;
+            let read_timeout = self.config.peer_idle_timeout;
+            peers.spawn(
+                async move {
+                    match Self::serve_peer(stream, addr, &context, &policy, read_timeout).await {
+                        Ok(()) => debug!("Peer {} disconnected", addr),
+                        Err(e) => debug!("Stopped serving peer {}: {}", addr, e),
+                    }
+                    if let Some(seeder) = &context.super_seeder {
+                        seeder.lock().unwrap().remove_peer(addr);
+                    }
+                }
+                .instrument(info_span!("peer", addr = %addr)),
+            );
+        }
+    }
+
+    /// Answer one peer's requests until it hangs up
+    async fn serve_peer(
+        stream: TcpStream,
+        addr: SocketAddr,
+        context: &SeedContext,
+        policy: &HandshakePolicy,
+        read_timeout: Duration,
+    ) -> Result<()> {
+        let mut conn = PeerConnection::accept(
+            stream,
+            addr,
+            |info_hash| context.metainfo.matches_info_hash(info_hash),
+            context.peer_id,
+            policy,
+        )
+        .await?;
+        conn.set_piece_count(context.have.len());
+        conn.set_read_timeout(read_timeout);
+        context.peer_stats.register(conn.shared_stats());
+
+        // A super-seeder claims to have nothing, then reveals pieces with Have
+        let bitfield = match context.super_seeder {
+            Some(_) => vec![0u8; context.have.len().div_ceil(8)],
+            None => to_bitfield(&context.have),
+        };
+        conn.send_message(&PeerMessage::Bitfield { bitfield })
+            .await?;
+        Self::advertise_next_piece(&mut conn, context, None).await?;
+
+        // Blocks are usually requested in order, so one piece read from
+        // storage serves several requests
+        let mut cached: Option<(usize, Vec<u8>)> = None;
+        loop {
+            let message = match conn.receive_message().await {
+                Ok(message) => message,
+                Err(BittorrentError::IoError(_)) if !conn.is_alive() => return Ok(()),
+                Err(e) => return Err(e),
+            };
+
+            Self::advertise_next_piece(&mut conn, context, Some(&message)).await?;
+
+            match message {
+                PeerMessage::Interested if conn.state().am_choking => {
+                    conn.send_message(&PeerMessage::Unchoke).await?;
+                }
+                PeerMessage::Request { block } => {
+                    // Requests made before the unchoke reached the peer
+                    if conn.state().am_choking {
+                        continue;
+                    }
+                    let index = block.piece_index as usize;
+                    let piece_size = context.metainfo.info.piece_size(index);
+                    let end = block.offset as u64 + block.length as u64;
+                    if !context.have.get(index).copied().unwrap_or(false)
+                        || block.length > MAX_REQUEST_LENGTH
+                        || end > piece_size
+                    {
+                        warn!("Peer {} requested an invalid block {:?}", addr, block);
+                        return Err(BittorrentError::ProtocolViolation(format!(
+                            "Peer {} requested piece {} bytes {}..{}, which we can't serve",
+                            addr, index, block.offset, end
+                        )));
+                    }
+
+                    if cached.as_ref().map(|(cached, _)| *cached) != Some(index) {
+                        cached = Some((index, context.storage.read_piece(index).await?));
+                    }
+                    let (_, piece) = cached.as_ref().expect("piece was just cached");
+                    conn.send_message(&PeerMessage::Piece {
+                        piece_index: block.piece_index,
+                        offset: block.offset,
+                        data: piece[block.offset as usize..end as usize].to_vec(),
+                    })
+                    .await?;
+                    context.counters.record_upload(block.length as u64);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// When super-seeding, record the pieces `message` announces, then show
+    /// the peer a new piece if it's due one
+    ///
+    /// A peer only moves on once the piece it was shown turns up on another
+    /// peer. That is noticed the next time this peer sends anything,
+    /// usually a Have for a piece it got elsewhere in the swarm.
+    async fn advertise_next_piece(
+        conn: &mut PeerConnection,
+        context: &SeedContext,
+        message: Option<&PeerMessage>,
+    ) -> Result<()> {
+        let Some(seeder) = &context.super_seeder else {
+            return Ok(());
+        };
+        let addr = conn.addr();
+        let next = {
+            let mut seeder = seeder.lock().unwrap();
+            match message {
+                Some(PeerMessage::Have { piece_index }) => {
+                    seeder.on_have(addr, *piece_index as usize);
+                }
+                Some(PeerMessage::Bitfield { .. }) => {
+                    for index in (0..context.have.len()).filter(|&i| conn.has_piece(i)) {
+                        seeder.on_have(addr, index);
+                    }
+                }
+                _ => {}
+            }
+            seeder.next_piece(addr)
+        };
+
+        if let Some(piece_index) = next {
+            debug!("Super-seeding piece {} to {}", piece_index, addr);
+            conn.send_message(&PeerMessage::Have {
+                piece_index: piece_index as u32,
+            })
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Pack per-piece flags into a wire bitfield, high bit first
+fn to_bitfield(have: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; have.len().div_ceil(8)];
+    for (index, _) in have.iter().enumerate().filter(|(_, &has)| has) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    bitfield
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::ClientConfig;
+    use crate::storage::MemoryStorage;
+    use crate::testutil::{make_torrent, random_content, MockTracker};
+    use crate::torrent::parse_torrent;
+
+    #[test]
+    fn test_to_bitfield() {
+        let have = [true, false, true, false, false, false, false, true, true];
+        assert_eq!(to_bitfield(&have), vec![0b1010_0001, 0b1000_0000]);
+    }
+
+    /// One instance seeds a generated torrent, another downloads it from
+    /// the first over loopback
+    #[tokio::test]
+    async fn test_super_seeding_shows_leechers_different_pieces() {
+        let content = random_content(4 * 16384);
+        let piece_length = 16384;
+        let metainfo = parse_torrent(&make_torrent(
+            "shared.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        for (index, chunk) in content.chunks(piece_length as usize).enumerate() {
+            storage.write_piece(index, chunk).await.unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder_addr = listener.local_addr().unwrap();
+        let seeding = {
+            let metainfo = metainfo.clone();
+            tokio::spawn(async move {
+                let seeder = TorrentClient::new(ClientConfig {
+                    super_seed: true,
+                    ..ClientConfig::default()
+                });
+                seeder.seed_on(listener, &metainfo, storage).await
+            })
+        };
+
+        let mut offered = Vec::new();
+        let mut leechers = Vec::new();
+        for peer in 0..2u8 {
+            let mut conn = PeerConnection::connect(seeder_addr, metainfo.info_hash, [peer; 20])
+                .await
+                .unwrap();
+            conn.set_piece_count(metainfo.info.piece_count());
+
+            let bitfield = conn.receive_message().await.unwrap();
+            assert_eq!(
+                bitfield,
+                PeerMessage::Bitfield {
+                    bitfield: vec![0u8]
+                }
+            );
+            match conn.receive_message().await.unwrap() {
+                PeerMessage::Have { piece_index } => offered.push(piece_index),
+                other => panic!("expected a Have, got {:?}", other),
+            }
+            // Stay connected so the first offer isn't forgotten
+            leechers.push(conn);
+        }
+
+        assert_ne!(offered[0], offered[1]);
+        seeding.abort();
+    }
+
+    /// Peers of a hybrid torrent's v2 swarm send the truncated v2 hash
+    #[tokio::test]
+    async fn test_seeder_accepts_v2_info_hash() {
+        let content = random_content(16384);
+        let mut metainfo =
+            parse_torrent(&make_torrent("hybrid.bin", &content, 16384, "http://x")).unwrap();
+        metainfo.v2_info_hash = Some([5u8; 32]);
+        let v2_hash = metainfo.v2_info_hash_truncated().unwrap();
+
+        let storage = Arc::new(MemoryStorage::new(16384, content.len() as u64));
+        storage.write_piece(0, &content).await.unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder_addr = listener.local_addr().unwrap();
+        let seeding = {
+            let metainfo = metainfo.clone();
+            tokio::spawn(async move {
+                let seeder = TorrentClient::new(ClientConfig::default());
+                seeder.seed_on(listener, &metainfo, storage).await
+            })
+        };
+
+        for info_hash in [metainfo.info_hash, v2_hash] {
+            let mut conn = PeerConnection::connect(seeder_addr, info_hash, [1u8; 20])
+                .await
+                .unwrap();
+            conn.set_piece_count(1);
+            assert!(matches!(
+                conn.receive_message().await.unwrap(),
+                PeerMessage::Bitfield { .. }
+            ));
+        }
+        assert!(PeerConnection::connect(seeder_addr, [9u8; 20], [1u8; 20])
+            .await
+            .is_err());
+        seeding.abort();
+    }
+
+    #[tokio::test]
+    async fn test_seeder_to_leecher_over_loopback() {
+        let content = random_content(300_000);
+        let piece_length = 32 * 1024;
+        let mut metainfo = parse_torrent(&make_torrent(
+            "shared.bin",
+            &content,
+            piece_length,
+            "http://x",
+        ))
+        .unwrap();
+
+        let seeder_storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        for (index, chunk) in content.chunks(piece_length as usize).enumerate() {
+            seeder_storage.write_piece(index, chunk).await.unwrap();
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seeder_addr = listener.local_addr().unwrap();
+        let seeder = TorrentClient::default();
+        let uploads = seeder.counters();
+        let seeding = {
+            let metainfo = metainfo.clone();
+            tokio::spawn(async move { seeder.seed_on(listener, &metainfo, seeder_storage).await })
+        };
+
+        let tracker = MockTracker::spawn(vec![seeder_addr]).await;
+        metainfo.announce = Some(tracker.announce_url());
+        let leecher = TorrentClient::default();
+        let leecher_storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+        tokio::time::timeout(
+            Duration::from_secs(20),
+            leecher.download_with_storage(&metainfo, leecher_storage.clone()),
+        )
+        .await
+        .expect("download timed out")
+        .unwrap();
+
+        assert_eq!(leecher_storage.contents(), content);
+        assert!(uploads.bytes_uploaded() >= content.len() as u64);
+        assert!(
+            !seeding.is_finished(),
+            "seeder stopped: {:?}",
+            seeding.await
+        );
+        seeding.abort();
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::Duration;
+
+/// Peers listed as slowest in a [`TimingSummary`]
+const SLOWEST_PEERS: usize = 3;
+
+/// Snapshot of a download's progress and swarm health
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Pieces downloaded and verified
+    pub pieces_complete: usize,
+    /// Pieces in the torrent
+    pub pieces_total: usize,
+    /// Bytes in verified pieces
+    pub downloaded_bytes: u64,
+    /// Block data received from peers and web seeds this session, including
+    /// data that was thrown away
+    pub received_bytes: u64,
+    /// Received bytes that never counted toward completion: duplicates,
+    /// pieces that failed verification and abandoned pieces
+    pub wasted_bytes: u64,
+    /// Bytes in the torrent
+    pub total_bytes: u64,
+    /// Peers we currently hold a connection to
+    pub connected_peers: usize,
+    /// Peers reported by the tracker
+    pub known_peers: usize,
+    /// Seeders reported by the tracker (`complete`)
+    pub seeders: Option<u64>,
+    /// Leechers reported by the tracker (`incomplete`)
+    pub leechers: Option<u64>,
+    /// Progress of each file in the torrent
+    pub files: Vec<FileProgress>,
+    /// How long pieces took, once any completed this session
+    pub piece_timing: Option<TimingSummary>,
+}
+
+/// Counters updated by every peer and web seed task
+///
+/// Plain atomics, so tasks can bump them and the progress monitor can read
+/// them without taking the `PieceManager` lock. Each counter is exact on its
+/// own, but a reader may see one updated slightly before another.
+#[derive(Debug, Default)]
+pub struct DownloadStats {
+    pieces_completed: AtomicUsize,
+    bytes_downloaded: AtomicU64,
+    bytes_uploaded: AtomicU64,
+    active_peers: AtomicUsize,
+}
+
+impl DownloadStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start over from what an earlier session already verified
+    pub fn reset(&self, pieces_completed: usize, bytes_downloaded: u64) {
+        self.pieces_completed
+            .store(pieces_completed, Ordering::Relaxed);
+        self.bytes_downloaded
+            .store(bytes_downloaded, Ordering::Relaxed);
+        self.bytes_uploaded.store(0, Ordering::Relaxed);
+        self.active_peers.store(0, Ordering::Relaxed);
+    }
+
+    /// Count a piece of `length` bytes that was verified and stored
+    pub fn record_piece(&self, length: u64) {
+        self.pieces_completed.fetch_add(1, Ordering::Relaxed);
+        self.bytes_downloaded.fetch_add(length, Ordering::Relaxed);
+    }
+
+    pub fn record_upload(&self, bytes: u64) {
+        self.bytes_uploaded.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn peer_connected(&self) {
+        self.active_peers.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count `count` peers that went away, never dropping below zero
+    pub fn peers_disconnected(&self, count: usize) {
+        let _ = self
+            .active_peers
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                Some(active.saturating_sub(count))
+            });
+    }
+
+    pub fn pieces_completed(&self) -> usize {
+        self.pieces_completed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_downloaded(&self) -> u64 {
+        self.bytes_downloaded.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_uploaded(&self) -> u64 {
+        self.bytes_uploaded.load(Ordering::Relaxed)
+    }
+
+    pub fn active_peers(&self) -> usize {
+        self.active_peers.load(Ordering::Relaxed)
+    }
+}
+
+/// How long one verified piece took, and who sent it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PieceTiming {
+    pub piece_index: usize,
+    /// From starting the piece to it passing verification
+    pub duration: Duration,
+    pub peer: SocketAddr,
+}
+
+/// Timing of every piece completed this session
+#[derive(Debug, Clone, Default)]
+pub struct PieceTimings {
+    samples: Vec<PieceTiming>,
+}
+
+impl PieceTimings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, timing: PieceTiming) {
+        self.samples.push(timing);
+    }
+
+    /// Median, 95th percentile and slowest peers, or None before any piece
+    /// completed
+    pub fn summary(&self) -> Option<TimingSummary> {
+        let mut durations: Vec<Duration> = self.samples.iter().map(|t| t.duration).collect();
+        durations.sort_unstable();
+        // Nearest rank: the smallest duration at least `share` of pieces are under
+        let percentile = |share: f64| {
+            let rank = (share * durations.len() as f64).ceil() as usize;
+            durations[rank.clamp(1, durations.len()) - 1]
+        };
+        let slowest = *durations.last()?;
+
+        let mut by_peer: HashMap<SocketAddr, (usize, Duration)> = HashMap::new();
+        for timing in &self.samples {
+            let (pieces, total) = by_peer.entry(timing.peer).or_default();
+            *pieces += 1;
+            *total += timing.duration;
+        }
+        let mut peers: Vec<PeerTiming> = by_peer
+            .into_iter()
+            .map(|(addr, (pieces, total))| PeerTiming {
+                addr,
+                pieces,
+                mean: total / pieces as u32,
+            })
+            .collect();
+        peers.sort_by(|a, b| b.mean.cmp(&a.mean).then(a.addr.cmp(&b.addr)));
+        peers.truncate(SLOWEST_PEERS);
+
+        Some(TimingSummary {
+            pieces: durations.len(),
+            median: percentile(0.5),
+            p95: percentile(0.95),
+            slowest,
+            slowest_peers: peers,
+        })
+    }
+}
+
+/// Distribution of piece times
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimingSummary {
+    /// Pieces timed
+    pub pieces: usize,
+    pub median: Duration,
+    pub p95: Duration,
+    pub slowest: Duration,
+    /// Peers with the longest mean piece time, slowest first
+    pub slowest_peers: Vec<PeerTiming>,
+}
+
+/// Mean piece time of one peer
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerTiming {
+    pub addr: SocketAddr,
+    pub pieces: usize,
+    pub mean: Duration,
+}
+
+impl fmt::Display for TimingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} pieces timed: median {:.2}s, p95 {:.2}s, slowest {:.2}s",
+            self.pieces,
+            self.median.as_secs_f64(),
+            self.p95.as_secs_f64(),
+            self.slowest.as_secs_f64()
+        )?;
+        if !self.slowest_peers.is_empty() {
+            let peers: Vec<String> = self
+                .slowest_peers
+                .iter()
+                .map(|peer| {
+                    format!(
+                        "{} ({:.2}s over {} pieces)",
+                        peer.addr,
+                        peer.mean.as_secs_f64(),
+                        peer.pieces
+                    )
+                })
+                .collect();
+            write!(f, "; slowest peers: {}", peers.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// How much of one file has been downloaded and verified
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileProgress {
+    /// Path within the torrent, '/'-separated
+    pub path: String,
+    pub length: u64,
+    /// Bytes of the file inside verified pieces
+    pub completed_bytes: u64,
+}
+
+impl FileProgress {
+    #[allow(dead_code, reason = "the CLI only prints whole-torrent progress")]
+    pub fn percent_complete(&self) -> f64 {
+        if self.length == 0 {
+            return 100.0;
+        }
+        self.completed_bytes as f64 / self.length as f64 * 100.0
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed_bytes == self.length
+    }
+}
+
+impl Stats {
+    /// Update the swarm counts from the latest tracker response
+    pub fn update_swarm(
+        &mut self,
+        known_peers: usize,
+        seeders: Option<u64>,
+        leechers: Option<u64>,
+    ) {
+        self.known_peers = known_peers;
+        // Keep the previous figure if the tracker stopped reporting it
+        self.seeders = seeders.or(self.seeders);
+        self.leechers = leechers.or(self.leechers);
+    }
+
+    /// Update per-file progress, returning the paths of files that just
+    /// became complete
+    pub fn update_files(&mut self, completed_bytes: &[u64]) -> Vec<String> {
+        let mut finished = Vec::new();
+        for (file, &completed) in self.files.iter_mut().zip(completed_bytes) {
+            let was_complete = file.is_complete();
+            file.completed_bytes = completed;
+            if file.is_complete() && !was_complete {
+                finished.push(file.path.clone());
+            }
+        }
+        finished
+    }
+}
+
+impl Stats {
+    /// Percentage of pieces complete
+    pub fn percent_complete(&self) -> f64 {
+        if self.pieces_total == 0 {
+            return 0.0;
+        }
+        self.pieces_complete as f64 / self.pieces_total as f64 * 100.0
+    }
+
+    /// Compact status for an interactive terminal, e.g.
+    /// `45.2% | 3.1 MiB/s | 8 peers | ETA 00:04:12`
+    pub fn progress_line(&self, bytes_per_sec: f64) -> String {
+        let remaining = self.total_bytes.saturating_sub(self.downloaded_bytes);
+        let eta = if bytes_per_sec > 0.0 {
+            format_eta(remaining as f64 / bytes_per_sec)
+        } else {
+            "--:--:--".to_string()
+        };
+
+        format!(
+            "{:.1}% | {} | {} peers | ETA {}",
+            self.percent_complete(),
+            format_rate(bytes_per_sec),
+            self.connected_peers,
+            eta
+        )
+    }
+}
+
+/// Format a transfer rate with binary units
+fn format_rate(bytes_per_sec: f64) -> String {
+    const UNITS: [&str; 4] = ["B/s", "KiB/s", "MiB/s", "GiB/s"];
+
+    let mut value = bytes_per_sec;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+/// Format a duration in seconds as `HH:MM:SS`
+fn format_eta(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "connected {}/{} peers",
+            self.connected_peers, self.known_peers
+        )?;
+
+        match (self.seeders, self.leechers) {
+            (Some(seeders), Some(leechers)) => {
+                write!(f, ", {} seeders / {} leechers", seeders, leechers)
+            }
+            (Some(seeders), None) => write!(f, ", {} seeders", seeders),
+            (None, Some(leechers)) => write!(f, ", {} leechers", leechers),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_piece_timing_summary() {
+        let fast: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+        let slow: SocketAddr = "10.0.0.2:6881".parse().unwrap();
+        let mut timings = PieceTimings::new();
+        assert_eq!(timings.summary(), None);
+
+        // Nineteen quick pieces and one slow one
+        for piece_index in 0..20 {
+            let (peer, millis) = match piece_index {
+                7 => (slow, 5000),
+                _ => (fast, 100 + piece_index as u64 * 10),
+            };
+            timings.record(PieceTiming {
+                piece_index,
+                duration: Duration::from_millis(millis),
+                peer,
+            });
+        }
+
+        let summary = timings.summary().unwrap();
+        assert_eq!(summary.pieces, 20);
+        assert_eq!(summary.median, Duration::from_millis(200));
+        assert_eq!(summary.p95, Duration::from_millis(290));
+        assert_eq!(summary.slowest, Duration::from_millis(5000));
+        assert_eq!(summary.slowest_peers.len(), 2);
+        assert_eq!(summary.slowest_peers[0].addr, slow);
+        assert_eq!(summary.slowest_peers[0].pieces, 1);
+        assert_eq!(summary.slowest_peers[1].pieces, 19);
+        assert!(summary.to_string().starts_with(
+            "20 pieces timed: median 0.20s, p95 0.29s, slowest 5.00s; slowest peers: 10.0.0.2:6881"
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_download_stats_from_many_tasks() {
+        let stats = std::sync::Arc::new(DownloadStats::new());
+        stats.reset(2, 2000);
+
+        let tasks: Vec<_> = (0..16)
+            .map(|_| {
+                let stats = stats.clone();
+                tokio::spawn(async move {
+                    for _ in 0..1000 {
+                        stats.peer_connected();
+                        stats.record_piece(100);
+                        stats.record_upload(10);
+                        tokio::task::yield_now().await;
+                        stats.peers_disconnected(1);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(stats.pieces_completed(), 2 + 16_000);
+        assert_eq!(stats.bytes_downloaded(), 2000 + 1_600_000);
+        assert_eq!(stats.bytes_uploaded(), 160_000);
+        assert_eq!(stats.active_peers(), 0);
+
+        stats.peers_disconnected(3);
+        assert_eq!(stats.active_peers(), 0);
+    }
+
+    #[test]
+    fn test_display_with_swarm_counts() {
+        let mut stats = Stats {
+            connected_peers: 8,
+            ..Default::default()
+        };
+        stats.update_swarm(42, Some(15), Some(120));
+        assert_eq!(
+            stats.to_string(),
+            "connected 8/42 peers, 15 seeders / 120 leechers"
+        );
+
+        // A later response without counts keeps the last known values
+        stats.update_swarm(40, None, None);
+        assert_eq!(
+            stats.to_string(),
+            "connected 8/40 peers, 15 seeders / 120 leechers"
+        );
+    }
+
+    #[test]
+    fn test_progress_line() {
+        let stats = Stats {
+            pieces_complete: 452,
+            pieces_total: 1000,
+            downloaded_bytes: 1024 * 1024,
+            total_bytes: 1024 * 1024 + 3 * 1024 * 1024 * 252,
+            connected_peers: 8,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            stats.progress_line(3.0 * 1024.0 * 1024.0),
+            "45.2% | 3.0 MiB/s | 8 peers | ETA 00:04:12"
+        );
+        assert_eq!(
+            stats.progress_line(0.0),
+            "45.2% | 0.0 B/s | 8 peers | ETA --:--:--"
+        );
+    }
+
+    #[test]
+    fn test_update_files_reports_newly_finished() {
+        let mut stats = Stats {
+            files: vec![
+                FileProgress {
+                    path: "a".to_string(),
+                    length: 10,
+                    completed_bytes: 0,
+                },
+                FileProgress {
+                    path: "b".to_string(),
+                    length: 20,
+                    completed_bytes: 0,
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(stats.update_files(&[10, 5]), vec!["a"]);
+        assert_eq!(stats.files[1].percent_complete(), 25.0);
+        assert!(stats.update_files(&[10, 5]).is_empty());
+        assert_eq!(stats.update_files(&[10, 20]), vec!["b"]);
+    }
+
+    #[test]
+    fn test_display_without_swarm_counts() {
+        let stats = Stats {
+            connected_peers: 1,
+            known_peers: 3,
+            ..Default::default()
+        };
+        assert_eq!(stats.to_string(), "connected 1/3 peers");
+    }
+}
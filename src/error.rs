@@ -8,15 +8,45 @@ pub enum BittorrentError {
     #[error("Invalid torrent file: {0}")]
     InvalidTorrent(String),
 
+    #[error("Invalid magnet link: {0}")]
+    InvalidMagnet(String),
+
+    #[error("Failed to fetch torrent: {0}")]
+    FetchError(String),
+
     #[error("Tracker error: {0}")]
     TrackerError(String),
 
     #[error("Peer connection error: {0}")]
     PeerError(String),
 
+    #[error("Web seed error: {0}")]
+    WebSeedError(String),
+
+    #[error("Handshake rejected: {0}")]
+    HandshakeRejected(String),
+
+    #[error("Peer protocol violation: {0}")]
+    ProtocolViolation(String),
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
+    #[error("Piece download too slow: {0}")]
+    PieceTimeout(String),
+
     #[error("Piece validation failed: {0}")]
     PieceError(String),
 
+    #[error("Piece failed its hash check: {0}")]
+    HashMismatch(String),
+
+    #[error("Piece buffer memory budget exceeded: {0}")]
+    MemoryBudgetExceeded(String),
+
+    #[error("Invalid configuration: {0}")]
+    ConfigError(String),
+
     #[error("Storage error: {0}")]
     StorageError(String),
 
@@ -7,6 +7,10 @@ mod piece;
 mod storage;
 mod torrent;
 mod tracker;
+mod webseed;
+
+#[cfg(test)]
+mod testutil;
 
 use anyhow::Result;
 use cli::Cli;
@@ -14,7 +18,9 @@ use cli::Cli;
 #[tokio::main]
 async fn main() -> Result<()> {
     // Initialize tracing
+    // Logs go to stderr so stdout stays free for the progress line
     tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
         .with_env_filter(
             tracing_subscriber::EnvFilter::from_default_env()
                 .add_directive(tracing::Level::INFO.into()),
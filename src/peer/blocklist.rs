@@ -0,0 +1,142 @@
+use crate::error::Result;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+use tracing::warn;
+
+/// IPv4 ranges that must never be contacted, e.g. from a PeerGuardian list
+///
+/// Ranges are kept sorted and merged so a lookup is one binary search.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    /// Inclusive, non-overlapping `(first, last)` ranges in ascending order
+    ranges: Vec<(u32, u32)>,
+}
+
+impl Blocklist {
+    /// Load a blocklist file in the PeerGuardian `.p2p` format
+    pub async fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let text = tokio::fs::read(path).await?;
+        Ok(Self::parse_p2p(&String::from_utf8_lossy(&text)))
+    }
+
+    /// Parse `description:first-last` lines
+    ///
+    /// Blank lines and `#` comments are ignored. Published lists often carry
+    /// a few broken lines, so those are skipped with a warning instead of
+    /// rejecting the whole list.
+    pub fn parse_p2p(text: &str) -> Self {
+        let mut ranges = Vec::new();
+        let mut skipped = 0;
+
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match parse_line(line) {
+                Some(range) => ranges.push(range),
+                None => skipped += 1,
+            }
+        }
+
+        if skipped > 0 {
+            warn!("Skipped {} malformed blocklist lines", skipped);
+        }
+
+        Self::from_ranges(ranges)
+    }
+
+    /// Build a blocklist from inclusive ranges in any order
+    pub fn from_ranges(mut ranges: Vec<(u32, u32)>) -> Self {
+        ranges.sort_unstable();
+
+        let mut merged: Vec<(u32, u32)> = Vec::with_capacity(ranges.len());
+        for (first, last) in ranges {
+            match merged.last_mut() {
+                Some(prev) if first <= prev.1.saturating_add(1) => prev.1 = prev.1.max(last),
+                _ => merged.push((first, last)),
+            }
+        }
+
+        Self { ranges: merged }
+    }
+
+    /// Whether an address falls in a blocked range
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V4(ip) => ip,
+            IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+                Some(ip) => ip,
+                None => return false,
+            },
+        };
+        let ip = u32::from(ip);
+
+        // The only candidate is the last range starting at or below `ip`
+        let index = self.ranges.partition_point(|&(first, _)| first <= ip);
+        index > 0 && ip <= self.ranges[index - 1].1
+    }
+
+    /// Number of disjoint ranges after merging
+    pub fn len(&self) -> usize {
+        self.ranges.len()
+    }
+
+    #[allow(dead_code, reason = "clippy::len_without_is_empty pairs it with `len`")]
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+}
+
+/// Parse one `description:first-last` line; the description may itself
+/// contain colons
+fn parse_line(line: &str) -> Option<(u32, u32)> {
+    let (_, range) = line.rsplit_once(':')?;
+    let (first, last) = range.split_once('-')?;
+    let first: Ipv4Addr = first.trim().parse().ok()?;
+    let last: Ipv4Addr = last.trim().parse().ok()?;
+
+    let (first, last) = (u32::from(first), u32::from(last));
+    (first <= last).then_some((first, last))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_p2p() {
+        let list = Blocklist::parse_p2p(
+            "# comment\n\
+             Some org:1.2.3.0-1.2.3.255\n\
+             Name: with colons:10.0.0.5-10.0.0.5\n\
+             \n\
+             broken line\n\
+             Backwards:9.9.9.9-9.9.9.1\n",
+        );
+
+        assert_eq!(list.len(), 2);
+        assert!(list.contains(ip("1.2.3.0")));
+        assert!(list.contains(ip("1.2.3.255")));
+        assert!(list.contains(ip("10.0.0.5")));
+        assert!(list.contains(ip("::ffff:1.2.3.4")));
+        assert!(!list.contains(ip("1.2.4.0")));
+        assert!(!list.contains(ip("10.0.0.6")));
+        assert!(!list.contains(ip("9.9.9.5")));
+        assert!(!list.contains(ip("::1")));
+    }
+
+    #[test]
+    fn test_overlapping_ranges_are_merged() {
+        let list = Blocklist::from_ranges(vec![(20, 30), (5, 10), (11, 12), (25, 40), (50, 50)]);
+        assert_eq!(list.ranges, vec![(5, 12), (20, 40), (50, 50)]);
+
+        let list = Blocklist::from_ranges(vec![(0, u32::MAX), (7, 7)]);
+        assert!(list.contains(ip("255.255.255.255")));
+    }
+}
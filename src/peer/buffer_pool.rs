@@ -0,0 +1,170 @@
+use crate::piece::BLOCK_SIZE;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+
+/// Buffers handed out or kept idle at once by default: 4 MiB of blocks
+pub const DEFAULT_MAX_POOLED_BUFFERS: usize = 256;
+
+/// Allocation counters of a [`BufferPool`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    /// Buffers that had to be freshly allocated
+    pub allocations: u64,
+    /// Buffers handed out again after being recycled
+    pub reuses: u64,
+}
+
+#[derive(Debug)]
+struct PoolState {
+    free: Vec<Vec<u8>>,
+    stats: BufferPoolStats,
+}
+
+/// Recycles block-sized receive buffers so the many 16 KiB Piece payloads
+/// arriving every second don't each hit the allocator
+///
+/// At most `max_buffers` buffers are out at once; [`acquire`](Self::acquire)
+/// waits for one to be released beyond that, so received block data never
+/// takes more than `max_buffers` blocks of memory. Released buffers are kept
+/// for reuse, except anything larger than a block, which is freed. Cloning
+/// shares the pool.
+#[derive(Debug, Clone)]
+pub struct BufferPool {
+    state: Arc<Mutex<PoolState>>,
+    /// One permit per buffer that may still be handed out
+    permits: Arc<Semaphore>,
+}
+
+impl BufferPool {
+    pub fn new(max_buffers: usize) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(PoolState {
+                free: Vec::new(),
+                stats: BufferPoolStats::default(),
+            })),
+            permits: Arc::new(Semaphore::new(max_buffers)),
+        }
+    }
+
+    /// An empty buffer with room for `len` bytes, recycled if one is idle
+    ///
+    /// Waits while `max_buffers` buffers are out. The buffer must be
+    /// handed back with [`release`](Self::release), or its slot with
+    /// [`reclaim`](Self::reclaim) if it was dropped instead.
+    pub async fn acquire(&self, len: usize) -> Vec<u8> {
+        self.permits
+            .acquire()
+            .await
+            .expect("buffer pool semaphore is never closed")
+            .forget();
+
+        let mut state = self.state.lock().unwrap();
+        if len <= BLOCK_SIZE as usize {
+            if let Some(buffer) = state.free.pop() {
+                state.stats.reuses += 1;
+                return buffer;
+            }
+        }
+
+        // Short final blocks still get room for a full one, so they can be
+        // recycled for any later block
+        state.stats.allocations += 1;
+        Vec::with_capacity(len.max(BLOCK_SIZE as usize))
+    }
+
+    /// Hand a buffer back once its contents have been copied out
+    pub fn release(&self, mut buffer: Vec<u8>) {
+        let block = BLOCK_SIZE as usize;
+        if (block..=2 * block).contains(&buffer.capacity()) {
+            // Only the length is reset; the next reader overwrites the bytes
+            buffer.clear();
+            self.state.lock().unwrap().free.push(buffer);
+        }
+        self.permits.add_permits(1);
+    }
+
+    /// Free the slots of `count` buffers that were dropped rather than
+    /// released
+    pub fn reclaim(&self, count: usize) {
+        self.permits.add_permits(count);
+    }
+
+    /// Number of idle buffers waiting to be reused
+    #[cfg(test)]
+    pub fn idle(&self) -> usize {
+        self.state.lock().unwrap().free.len()
+    }
+
+    /// Number of further buffers that can be handed out without waiting
+    #[cfg(test)]
+    pub fn available(&self) -> usize {
+        self.permits.available_permits()
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        self.state.lock().unwrap().stats
+    }
+}
+
+impl Default for BufferPool {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_POOLED_BUFFERS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_released_buffers_are_reused() {
+        let pool = BufferPool::new(3);
+        let first = pool.acquire(BLOCK_SIZE as usize).await;
+        let second = pool.acquire(100).await;
+        let third = pool.acquire(BLOCK_SIZE as usize).await;
+        pool.release(first);
+        pool.release(second);
+        pool.release(third);
+        assert_eq!(pool.idle(), 3);
+
+        let reused = pool.acquire(7).await;
+        assert!(reused.is_empty() && reused.capacity() >= 7);
+        assert_eq!(
+            pool.stats(),
+            BufferPoolStats {
+                allocations: 3,
+                reuses: 1
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_buffers_are_not_pooled() {
+        let pool = BufferPool::new(4);
+        let big = pool.acquire(BLOCK_SIZE as usize * 4).await;
+        pool.release(big);
+        assert_eq!(pool.idle(), 0);
+        assert_eq!(pool.available(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_at_the_cap() {
+        let pool = BufferPool::new(2);
+        let first = pool.acquire(BLOCK_SIZE as usize).await;
+        let _second = pool.acquire(BLOCK_SIZE as usize).await;
+        assert_eq!(pool.available(), 0);
+
+        let waiting = tokio::time::timeout(Duration::from_millis(50), pool.acquire(1)).await;
+        assert!(waiting.is_err(), "acquired a third buffer past the cap");
+
+        pool.release(first);
+        let third = tokio::time::timeout(Duration::from_secs(1), pool.acquire(1)).await;
+        assert!(third.is_ok());
+
+        // A dropped buffer's slot comes back through reclaim
+        drop(third);
+        pool.reclaim(1);
+        assert_eq!(pool.available(), 1);
+    }
+}
@@ -0,0 +1,183 @@
+//! Best-effort client identification from peer IDs
+//!
+//! Most clients put their name and version at the start of the peer ID,
+//! in one of a few conventions:
+//! - Azureus style: `-qB4500-...`, a two-letter client code and four
+//!   version characters between dashes
+//! - Shadow style: `S58B-----...`, one client letter followed by up to five
+//!   version characters and dash padding
+//! - Mainline style: `M4-3-6--...`, dash-separated version numbers
+
+/// Azureus-style client codes
+const AZUREUS_CLIENTS: &[(&str, &str)] = &[
+    ("AG", "Ares"),
+    ("AZ", "Vuze"),
+    ("BC", "BitComet"),
+    ("BI", "BiglyBT"),
+    ("BT", "BitTorrent"),
+    ("DE", "Deluge"),
+    ("FD", "Free Download Manager"),
+    ("FW", "FrostWire"),
+    ("KT", "KTorrent"),
+    ("LT", "libtorrent"),
+    ("lt", "rTorrent"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("RS", "bittorrent-rs"),
+    ("SD", "Thunder"),
+    ("TR", "Transmission"),
+    ("UM", "µTorrent for Mac"),
+    ("UT", "µTorrent"),
+    ("UW", "µTorrent Web"),
+    ("WW", "WebTorrent"),
+    ("XL", "Xunlei"),
+];
+
+/// Shadow-style client letters
+const SHADOW_CLIENTS: &[(u8, &str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+/// Name and version of the client that generated `peer_id`, if it follows
+/// a known convention
+///
+/// Unknown Azureus-style codes are reported as the raw code with its
+/// version, e.g. `ZZ 1.2.3`.
+pub fn client_name(peer_id: &[u8; 20]) -> Option<String> {
+    azureus_style(peer_id)
+        .or_else(|| mainline_style(peer_id))
+        .or_else(|| shadow_style(peer_id))
+}
+
+fn azureus_style(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'-' || peer_id[7] != b'-' {
+        return None;
+    }
+    let code = std::str::from_utf8(&peer_id[1..3]).ok()?;
+    let version = &peer_id[3..7];
+    if !code.bytes().all(|b| b.is_ascii_alphabetic())
+        || !version.iter().all(|b| b.is_ascii_alphanumeric())
+    {
+        return None;
+    }
+
+    let name = AZUREUS_CLIENTS
+        .iter()
+        .find(|(known, _)| *known == code)
+        .map_or(code, |(_, name)| name);
+    let digit = |b: u8| (b as char).to_digit(36).unwrap_or(0);
+
+    let version = match code {
+        // Major version, then a two-digit minor: -TR2940- is 2.94
+        "TR" => format!(
+            "{}.{}{}",
+            digit(version[0]),
+            digit(version[1]),
+            digit(version[2])
+        ),
+        _ => {
+            let mut parts: Vec<String> =
+                version[..3].iter().map(|&b| digit(b).to_string()).collect();
+            // A fourth digit is a build number; letters mark betas
+            if version[3].is_ascii_digit() && version[3] != b'0' {
+                parts.push(digit(version[3]).to_string());
+            }
+            parts.join(".")
+        }
+    };
+
+    Some(format!("{} {}", name, version))
+}
+
+fn mainline_style(peer_id: &[u8; 20]) -> Option<String> {
+    if peer_id[0] != b'M' {
+        return None;
+    }
+    let prefix = std::str::from_utf8(&peer_id[1..8]).ok()?;
+    let parts: Vec<&str> = prefix.split('-').filter(|part| !part.is_empty()).collect();
+    if parts.len() != 3
+        || !parts
+            .iter()
+            .all(|part| part.bytes().all(|b| b.is_ascii_digit()))
+    {
+        return None;
+    }
+
+    Some(format!("Mainline {}", parts.join(".")))
+}
+
+fn shadow_style(peer_id: &[u8; 20]) -> Option<String> {
+    let (_, name) = SHADOW_CLIENTS
+        .iter()
+        .find(|(letter, _)| *letter == peer_id[0])?;
+    if &peer_id[6..9] != b"---" {
+        return None;
+    }
+
+    let mut parts = Vec::new();
+    for &b in peer_id[1..6].iter().take_while(|&&b| b != b'-') {
+        let value = match b {
+            b'0'..=b'9' => b - b'0',
+            b'A'..=b'Z' => b - b'A' + 10,
+            b'a'..=b'z' => b - b'a' + 36,
+            b'.' => 62,
+            _ => return None,
+        };
+        parts.push(value.to_string());
+    }
+    if parts.is_empty() {
+        return None;
+    }
+
+    Some(format!("{} {}", name, parts.join(".")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(prefix: &[u8]) -> [u8; 20] {
+        let mut peer_id = [b'x'; 20];
+        peer_id[..prefix.len()].copy_from_slice(prefix);
+        peer_id
+    }
+
+    #[test]
+    fn test_known_clients() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"-qB4500-", "qBittorrent 4.5.0"),
+            (b"-TR2940-", "Transmission 2.94"),
+            (b"-UT3550-", "µTorrent 3.5.5"),
+            (b"-DE13F0-", "Deluge 1.3.15"),
+            (b"-LT1204-", "libtorrent 1.2.0.4"),
+            (b"-RS0001-", "bittorrent-rs 0.0.0.1"),
+            (b"-ZZ1230-", "ZZ 1.2.3"),
+            (b"M4-3-6--", "Mainline 4.3.6"),
+            (b"M7-10-2-", "Mainline 7.10.2"),
+            (b"S58B-----", "Shadow 5.8.11"),
+            (b"T03I-----", "BitTornado 0.3.18"),
+        ];
+        for (prefix, expected) in cases {
+            assert_eq!(
+                client_name(&id(prefix)).as_deref(),
+                Some(*expected),
+                "{}",
+                String::from_utf8_lossy(prefix)
+            );
+        }
+    }
+
+    #[test]
+    fn test_unrecognized_ids() {
+        assert_eq!(client_name(&[0u8; 20]), None);
+        assert_eq!(client_name(&id(b"-qB45")), None);
+        assert_eq!(client_name(&id(b"Sxxxxxxxxx")), None);
+        assert_eq!(client_name(&id(b"Mabc-def")), None);
+    }
+}
@@ -1,65 +1,287 @@
-use super::{Handshake, PeerMessage, PeerState};
+use super::{
+    set_socket_tos, BlockInfo, BufferPool, Handshake, HandshakePolicy, PeerMessage, PeerState,
+    PeerStats,
+};
 use crate::error::{BittorrentError, Result};
+use bytes::BufMut;
+use socket2::SockRef;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
-use tracing::{debug, info, warn};
+use tokio::sync::mpsc;
+use tracing::{debug, info, trace, warn, Level};
+
+/// Number of unsolicited Piece messages tolerated before dropping the peer
+const MAX_UNSOLICITED_PIECES: u32 = 8;
+
+/// Largest message accepted from a peer: room for a 16M-piece bitfield and
+/// far more than any sane block
+const MAX_MESSAGE_LENGTH: usize = 2 * 1024 * 1024;
+
+/// How long a peer may take to send a complete message. Peers send a
+/// keep-alive at least every two minutes, so silence beyond that means
+/// the connection is dead
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(180);
+
+/// Round trip under which a peer is sent more requests at once; slower
+/// answers mean requests are queueing up, so the depth shrinks again
+const PIPELINE_TARGET_LATENCY: Duration = Duration::from_millis(300);
+
+/// Log target of the per-message wire log, enabled with e.g.
+/// `RUST_LOG=bittorrent_rs::wire=trace`
+pub const WIRE_LOG_TARGET: &str = "bittorrent_rs::wire";
+
+/// Bounds on how many block requests may be outstanding to one peer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PipelineLimits {
+    /// Depth every peer starts at and never goes below
+    pub min_depth: usize,
+    /// Depth no peer goes beyond, however fast
+    pub max_depth: usize,
+}
+
+impl Default for PipelineLimits {
+    fn default() -> Self {
+        Self {
+            min_depth: 2,
+            max_depth: 64,
+        }
+    }
+}
+
+/// What a message from the peer means to a download, from
+/// [`PeerConnection::read_block`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerEvent {
+    /// Data answering one or more of our requests, possibly only part of a
+    /// block or several neighbouring blocks at once
+    Block {
+        piece_index: u32,
+        offset: u32,
+        data: Vec<u8>,
+    },
+    /// The peer choked us and dropped every request we had outstanding
+    Choked,
+    /// The peer unchoked us, so requests will be answered
+    Unchoked,
+    /// Any other message, already applied to the connection's state
+    Other(PeerMessage),
+}
+
+/// A change in the pieces one peer offers, sent to the channel given to
+/// [`PeerConnection::set_availability_sink`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvailabilityChange {
+    /// The peer sent its bitfield
+    Gained(Vec<u8>),
+    /// The peer announced one more piece
+    Have(usize),
+    /// The peer no longer offers these pieces: it replaced its bitfield or
+    /// the connection is gone
+    Lost(Vec<u8>),
+}
 
 /// Manages a connection to a peer
-pub struct PeerConnection {
+///
+/// Generic over the byte stream so the message layer can be driven through
+/// in-memory pipes in tests; real connections use [`TcpStream`].
+pub struct PeerConnection<S: AsyncWrite + Unpin = TcpStream> {
     addr: SocketAddr,
-    stream: TcpStream,
+    stream: S,
     state: PeerState,
     peer_id: Option<[u8; 20]>,
+    /// The peer's handshake, whose reserved bytes say what it supports
+    peer_handshake: Handshake,
+    /// UDP port of the peer's DHT node, once it sent a Port message
+    dht_port: Option<u16>,
     bitfield: Option<Vec<u8>>,
+    /// Number of pieces in the torrent, used to validate the bitfield
+    num_pieces: Option<usize>,
+    /// Blocks we have requested and not yet received, with when we asked
+    pending_requests: HashMap<BlockInfo, Instant>,
+    /// Smoothed round trip time of block requests
+    rtt: Option<Duration>,
+    /// How many requests may be outstanding right now
+    pipeline_depth: usize,
+    pipeline_limits: PipelineLimits,
+    /// Piece messages received that matched no outstanding request
+    unsolicited_pieces: u32,
+    /// When a message was last sent or received successfully
+    last_activity: Instant,
+    /// Set once reading or writing the stream failed
+    broken: bool,
+    /// Longest `receive_message` waits for a complete message
+    read_timeout: Duration,
+    /// When the caller stops waiting on this peer, however long the read
+    /// timeout
+    deadline: Option<tokio::time::Instant>,
+    /// Hex-dump up to this many raw bytes of every message to the wire log
+    wire_dump: Option<usize>,
+    /// Where received block data is allocated from, if shared
+    buffer_pool: Option<BufferPool>,
+    /// Pooled buffers taken for received blocks and not yet recycled
+    pooled_buffers: usize,
+    /// Where changes in the pieces this peer offers are reported, if anyone
+    /// is counting
+    availability: Option<mpsc::UnboundedSender<AvailabilityChange>>,
+    /// Transfer counts and state, readable while another task owns the
+    /// connection
+    stats: Arc<Mutex<PeerStats>>,
 }
 
 impl PeerConnection {
     /// Connect to a peer and perform handshake
+    #[allow(dead_code, reason = "the client dials with `connect_with_policy`")]
     pub async fn connect(
         addr: SocketAddr,
         info_hash: [u8; 20],
         our_peer_id: [u8; 20],
+    ) -> Result<Self> {
+        Self::connect_with_policy(
+            addr,
+            info_hash,
+            our_peer_id,
+            &HandshakePolicy::default(),
+            None,
+        )
+        .await
+    }
+
+    /// Connect to a peer, refusing it if its handshake breaks `policy`, and
+    /// mark the connection's packets with `socket_tos` if set
+    pub async fn connect_with_policy(
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: &HandshakePolicy,
+        socket_tos: Option<u8>,
     ) -> Result<Self> {
         info!("Connecting to peer: {}", addr);
 
         // Connect to peer
-        let mut stream = TcpStream::connect(addr).await.map_err(|e| {
+        let stream = TcpStream::connect(addr).await.map_err(|e| {
             BittorrentError::PeerError(format!("Failed to connect to {}: {}", addr, e))
         })?;
+        if let Some(tos) = socket_tos {
+            if let Err(e) = set_socket_tos(SockRef::from(&stream), tos) {
+                warn!(
+                    "Failed to set ToS {:#04x} on connection to {}: {}",
+                    tos, addr, e
+                );
+            }
+        }
 
+        Self::handshake(stream, addr, info_hash, our_peer_id, policy).await
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PeerConnection<S> {
+    /// Perform the handshake over a stream we opened
+    pub async fn handshake(
+        mut stream: S,
+        addr: SocketAddr,
+        info_hash: [u8; 20],
+        our_peer_id: [u8; 20],
+        policy: &HandshakePolicy,
+    ) -> Result<Self> {
         // Send handshake
         let handshake = Handshake::new(info_hash, our_peer_id);
         stream.write_all(&handshake.to_bytes()).await?;
 
         debug!("Sent handshake to {}", addr);
 
-        // Receive handshake
-        let mut handshake_buf = vec![0u8; 68];
-        stream.read_exact(&mut handshake_buf).await?;
+        let peer_handshake = read_handshake(&mut stream, |hash| *hash == info_hash).await?;
+        policy.check(&peer_handshake)?;
 
-        let peer_handshake = Handshake::from_bytes(&handshake_buf)?;
+        info!("Successfully connected to peer: {}", addr);
 
-        // Verify info hash
-        if peer_handshake.info_hash != info_hash {
-            return Err(BittorrentError::PeerError("Info hash mismatch".to_string()));
-        }
+        Ok(Self::new(stream, addr, &peer_handshake))
+    }
 
-        info!("Successfully connected to peer: {}", addr);
+    /// Answer the handshake of a peer that connected to us
+    ///
+    /// The peer's info hash is accepted if `is_ours` says so, and our
+    /// handshake repeats it, so a hybrid torrent can be served under its v1
+    /// and its v2 hash. A peer rejected by `policy` never sees our handshake.
+    pub async fn accept(
+        mut stream: S,
+        addr: SocketAddr,
+        is_ours: impl Fn(&[u8; 20]) -> bool,
+        our_peer_id: [u8; 20],
+        policy: &HandshakePolicy,
+    ) -> Result<Self> {
+        let peer_handshake = read_handshake(&mut stream, is_ours).await?;
+        policy.check(&peer_handshake)?;
+
+        let handshake = Handshake::new(peer_handshake.info_hash, our_peer_id);
+        stream.write_all(&handshake.to_bytes()).await?;
 
-        Ok(Self {
+        info!("Accepted peer: {}", addr);
+
+        Ok(Self::new(stream, addr, &peer_handshake))
+    }
+
+    fn new(stream: S, addr: SocketAddr, peer_handshake: &Handshake) -> Self {
+        Self {
             addr,
             stream,
             state: PeerState::default(),
             peer_id: Some(peer_handshake.peer_id),
+            peer_handshake: peer_handshake.clone(),
+            dht_port: None,
             bitfield: None,
-        })
+            num_pieces: None,
+            pending_requests: HashMap::new(),
+            rtt: None,
+            pipeline_depth: PipelineLimits::default().min_depth,
+            pipeline_limits: PipelineLimits::default(),
+            unsolicited_pieces: 0,
+            last_activity: Instant::now(),
+            broken: false,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+            deadline: None,
+            wire_dump: None,
+            buffer_pool: None,
+            pooled_buffers: 0,
+            availability: None,
+            stats: Arc::new(Mutex::new(PeerStats::new(
+                addr,
+                Some(peer_handshake.peer_id),
+            ))),
+        }
     }
 
     /// Send a message to the peer
+    ///
+    /// A write still blocked at the deadline fails with `Timeout` and marks
+    /// the connection dead, as part of the message may have been sent.
     pub async fn send_message(&mut self, message: &PeerMessage) -> Result<()> {
         let bytes = message.to_bytes();
-        self.stream.write_all(&bytes).await?;
+        let write = self.stream.write_all(&bytes);
+        let written = match self.deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, write).await,
+            None => Ok(write.await),
+        };
+        match written {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                self.broken = true;
+                return Err(e.into());
+            }
+            Err(_) => {
+                self.broken = true;
+                return Err(BittorrentError::Timeout(format!(
+                    "peer {} did not take our {} by the deadline",
+                    self.addr, message
+                )));
+            }
+        }
+        self.last_activity = Instant::now();
 
         // Update our state based on what we sent
         match message {
@@ -67,57 +289,290 @@ impl PeerConnection {
             PeerMessage::Unchoke => self.state.am_choking = false,
             PeerMessage::Interested => self.state.am_interested = true,
             PeerMessage::NotInterested => self.state.am_interested = false,
+            PeerMessage::Request { block } => {
+                self.pending_requests.insert(*block, Instant::now());
+            }
+            PeerMessage::Cancel { block } => {
+                // Also drops the tail of a request that was partly answered
+                let end = block.offset + block.length;
+                self.pending_requests.retain(|pending, _| {
+                    pending.piece_index != block.piece_index
+                        || pending.offset < block.offset
+                        || pending.offset >= end
+                });
+            }
             _ => {}
         }
 
-        debug!("Sent message to {}: {:?}", self.addr, message);
+        let state = self.state;
+        self.update_stats(|stats| {
+            stats.state = state;
+            if let PeerMessage::Piece { data, .. } = message {
+                stats.uploaded_bytes += data.len() as u64;
+            }
+        });
+
+        self.log_wire("->", message);
         Ok(())
     }
 
     /// Receive a message from the peer
+    ///
+    /// Piece messages that don't answer one of our outstanding requests are
+    /// dropped here; a peer that keeps sending them is disconnected.
+    ///
+    /// Each message must arrive in full within the read timeout, and before
+    /// the deadline if one is set. A peer that misses either, even halfway
+    /// through a message, fails with `Timeout` and the connection is marked
+    /// dead, as the stream may be left mid-message. Callers bound their
+    /// waits with [`set_deadline`](Self::set_deadline) rather than by
+    /// cancelling this future, which would leave the stream in the same
+    /// state without marking it.
     pub async fn receive_message(&mut self) -> Result<PeerMessage> {
+        loop {
+            let wait = match self.deadline {
+                Some(deadline) => self
+                    .read_timeout
+                    .min(deadline.saturating_duration_since(tokio::time::Instant::now())),
+                None => self.read_timeout,
+            };
+            let read = tokio::time::timeout(wait, self.read_message()).await;
+            let message = match read {
+                Ok(Ok(message)) => message,
+                Ok(Err(e)) => {
+                    if matches!(e, BittorrentError::IoError(_)) {
+                        self.broken = true;
+                    }
+                    return Err(e);
+                }
+                Err(_) => {
+                    self.broken = true;
+                    return Err(BittorrentError::Timeout(format!(
+                        "peer {} sent no complete message within {:?}",
+                        self.addr, wait
+                    )));
+                }
+            };
+            self.last_activity = Instant::now();
+
+            if let PeerMessage::Piece {
+                piece_index,
+                offset,
+                data,
+            } = &message
+            {
+                if !self.match_pending(*piece_index, *offset, data.len() as u32) {
+                    self.unsolicited_pieces += 1;
+                    warn!(
+                        "Dropping unsolicited piece {} offset {} ({} bytes) from {}",
+                        piece_index,
+                        offset,
+                        data.len(),
+                        self.addr
+                    );
+
+                    if let PeerMessage::Piece { data, .. } = message {
+                        self.recycle(data);
+                    }
+                    if self.unsolicited_pieces > MAX_UNSOLICITED_PIECES {
+                        return Err(BittorrentError::ProtocolViolation(format!(
+                            "Peer {} sent too many unsolicited pieces",
+                            self.addr
+                        )));
+                    }
+                    continue;
+                }
+            }
+
+            let state = self.state;
+            self.update_stats(|stats| {
+                stats.state = state;
+                if let PeerMessage::Piece { data, .. } = &message {
+                    stats.downloaded_bytes += data.len() as u64;
+                }
+            });
+            return Ok(message);
+        }
+    }
+
+    /// Ask the peer for `length` bytes at `offset` of a piece
+    ///
+    /// The request stays pending until [`read_block`](Self::read_block)
+    /// returns the data for it.
+    pub async fn request_block(
+        &mut self,
+        piece_index: u32,
+        offset: u32,
+        length: u32,
+    ) -> Result<BlockInfo> {
+        let block = BlockInfo::new(piece_index, offset, length);
+        self.send_message(&PeerMessage::Request { block }).await?;
+        Ok(block)
+    }
+
+    /// Wait for the next block data or change in choke state
+    ///
+    /// Keep-alives are skipped. Data nobody asked for is dropped as in
+    /// [`receive_message`](Self::receive_message), so every `Block` answers
+    /// an earlier [`request_block`](Self::request_block).
+    pub async fn read_block(&mut self) -> Result<PeerEvent> {
+        loop {
+            let event = match self.receive_message().await? {
+                PeerMessage::Piece {
+                    piece_index,
+                    offset,
+                    data,
+                } => PeerEvent::Block {
+                    piece_index,
+                    offset,
+                    data,
+                },
+                PeerMessage::Choke => PeerEvent::Choked,
+                PeerMessage::Unchoke => PeerEvent::Unchoked,
+                PeerMessage::KeepAlive => continue,
+                other => PeerEvent::Other(other),
+            };
+            return Ok(event);
+        }
+    }
+
+    /// Read and parse the next message from the wire
+    async fn read_message(&mut self) -> Result<PeerMessage> {
         // Read length prefix (4 bytes)
         let mut length_buf = [0u8; 4];
         self.stream.read_exact(&mut length_buf).await?;
 
         let length = u32::from_be_bytes(length_buf) as usize;
 
+        if length > MAX_MESSAGE_LENGTH {
+            return Err(BittorrentError::ProtocolViolation(format!(
+                "Peer {} sent a {} byte message",
+                self.addr, length
+            )));
+        }
+
         // Handle keep-alive
         if length == 0 {
+            self.log_wire("<-", &PeerMessage::KeepAlive);
             return Ok(PeerMessage::KeepAlive);
         }
 
-        // Read message payload
-        let mut message_buf = vec![0u8; length];
-        self.stream.read_exact(&mut message_buf).await?;
+        let mut id = [0u8; 1];
+        self.stream.read_exact(&mut id).await?;
 
-        // Reconstruct full message for parsing
-        let mut full_message = Vec::with_capacity(4 + length);
-        full_message.extend_from_slice(&length_buf);
-        full_message.extend_from_slice(&message_buf);
+        let message = match &self.buffer_pool {
+            // Block data is read straight into a recycled buffer
+            Some(pool) if id[0] == PeerMessage::PIECE && length >= 9 => {
+                let mut header = [0u8; 8];
+                self.stream.read_exact(&mut header).await?;
+                let mut data = pool.acquire(length - 9).await;
+                self.pooled_buffers += 1;
+                if let Err(e) = read_into(&mut self.stream, &mut data, length - 9).await {
+                    self.pooled_buffers -= 1;
+                    pool.release(data);
+                    return Err(e.into());
+                }
 
-        let message = PeerMessage::from_bytes(&full_message)?;
+                PeerMessage::Piece {
+                    piece_index: u32::from_be_bytes(header[..4].try_into().unwrap()),
+                    offset: u32::from_be_bytes(header[4..].try_into().unwrap()),
+                    data,
+                }
+            }
+            _ => {
+                // Reconstruct the full message for parsing
+                let mut full_message = vec![0u8; 4 + length];
+                full_message[..4].copy_from_slice(&length_buf);
+                full_message[4] = id[0];
+                self.stream.read_exact(&mut full_message[5..]).await?;
+                PeerMessage::from_bytes(&full_message)?
+            }
+        };
+        self.log_wire("<-", &message);
 
         // Update state based on message
-        self.handle_message(&message);
-
-        debug!("Received message from {}: {:?}", self.addr, message);
+        self.handle_message(&message)?;
 
         Ok(message)
     }
 
     /// Handle incoming message and update state
-    fn handle_message(&mut self, message: &PeerMessage) {
+    fn handle_message(&mut self, message: &PeerMessage) -> Result<()> {
         match message {
-            PeerMessage::Choke => self.state.peer_choking = true,
+            PeerMessage::Choke => {
+                // A choking peer discards everything we asked for
+                if !self.pending_requests.is_empty() {
+                    debug!(
+                        "Peer {} choked us with {} requests outstanding",
+                        self.addr,
+                        self.pending_requests.len()
+                    );
+                    self.pending_requests.clear();
+                }
+                self.state.peer_choking = true;
+            }
             PeerMessage::Unchoke => self.state.peer_choking = false,
             PeerMessage::Interested => self.state.peer_interested = true,
             PeerMessage::NotInterested => self.state.peer_interested = false,
             PeerMessage::Bitfield { bitfield } => {
-                self.bitfield = Some(bitfield.clone());
+                if let Some(num_pieces) = self.num_pieces {
+                    validate_bitfield(bitfield, num_pieces)?;
+                }
+                // Anything announced before is replaced, not added to
+                if let Some(old) = self.bitfield.replace(bitfield.clone()) {
+                    self.report_availability(AvailabilityChange::Lost(old));
+                }
+                self.report_availability(AvailabilityChange::Gained(bitfield.clone()));
+                let pieces = bitfield.iter().map(|b| b.count_ones() as usize).sum();
+                self.update_stats(|stats| stats.pieces = pieces);
+            }
+            PeerMessage::Have { piece_index } => self.set_have(*piece_index as usize)?,
+            PeerMessage::Port { port } => {
+                debug!("Peer {} runs a DHT node on port {}", self.addr, port);
+                self.dht_port = Some(*port);
             }
             _ => {}
         }
+        Ok(())
+    }
+
+    /// Record a piece announced with Have, creating the bitfield if the
+    /// peer never sent one
+    fn set_have(&mut self, piece_index: usize) -> Result<()> {
+        if let Some(num_pieces) = self.num_pieces {
+            if piece_index >= num_pieces {
+                return Err(BittorrentError::ProtocolViolation(format!(
+                    "Have for piece {} but torrent has {} pieces",
+                    piece_index, num_pieces
+                )));
+            }
+        }
+
+        if self.has_piece(piece_index) {
+            return Ok(());
+        }
+        let len = self.num_pieces.unwrap_or(piece_index + 1).div_ceil(8);
+        let bitfield = self.bitfield.get_or_insert_with(Vec::new);
+        if bitfield.len() < len {
+            bitfield.resize(len, 0);
+        }
+        bitfield[piece_index / 8] |= 0x80 >> (piece_index % 8);
+        self.update_stats(|stats| stats.pieces += 1);
+        self.report_availability(AvailabilityChange::Have(piece_index));
+        Ok(())
+    }
+
+    /// Tell whoever counts piece availability what this peer now offers
+    fn report_availability(&mut self, change: AvailabilityChange) {
+        if let Some(availability) = &self.availability {
+            // A closed channel just means nobody is counting any more
+            let _ = availability.send(change);
+        }
+    }
+
+    /// Set the torrent's piece count so incoming bitfields can be validated
+    pub fn set_piece_count(&mut self, num_pieces: usize) {
+        self.num_pieces = Some(num_pieces);
     }
 
     /// Check if peer has a specific piece
@@ -133,6 +588,224 @@ impl PeerConnection {
         false
     }
 
+    /// Bound the request pipeline, restarting at the minimum depth
+    pub fn set_pipeline_limits(&mut self, limits: PipelineLimits) {
+        let min_depth = limits.min_depth.max(1);
+        self.pipeline_limits = PipelineLimits {
+            min_depth,
+            max_depth: limits.max_depth.max(min_depth),
+        };
+        self.pipeline_depth = min_depth;
+    }
+
+    /// How many block requests to keep outstanding to this peer
+    pub fn pipeline_depth(&self) -> usize {
+        self.pipeline_depth
+    }
+
+    /// Smoothed round trip time of block requests, once one was answered
+    #[cfg(test)]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.rtt
+    }
+
+    /// Bound how long `receive_message` waits for a complete message
+    pub fn set_read_timeout(&mut self, timeout: Duration) {
+        self.read_timeout = timeout;
+    }
+
+    /// Fail reads and writes that haven't finished by `deadline`, or lift
+    /// the bound with `None`
+    ///
+    /// Stays in force until changed, so a caller that sets one clears it
+    /// once done with the connection.
+    pub fn set_deadline(&mut self, deadline: Option<tokio::time::Instant>) {
+        self.deadline = deadline;
+    }
+
+    /// Report every piece this peer announces to `availability`, and all
+    /// of them as lost once the connection is dropped
+    pub fn set_availability_sink(
+        &mut self,
+        availability: mpsc::UnboundedSender<AvailabilityChange>,
+    ) {
+        self.availability = Some(availability);
+    }
+
+    /// Hex-dump the first `limit` bytes of every message sent or received
+    /// to the wire log, or stop with `None`
+    ///
+    /// Payloads can be large and may hold the content being shared, so
+    /// this is off unless asked for.
+    pub fn set_wire_dump(&mut self, limit: Option<usize>) {
+        self.wire_dump = limit;
+    }
+
+    /// Allocate received block data from `pool` instead of afresh; hand
+    /// it back with [`recycle`](Self::recycle)
+    ///
+    /// Reading a block waits while the pool's buffers are all out. Buffers
+    /// dropped without being recycled are returned to the pool's count when
+    /// the connection is dropped.
+    pub fn set_buffer_pool(&mut self, pool: BufferPool) {
+        self.buffer_pool = Some(pool);
+    }
+
+    /// Snapshot of this connection's transfer counts and state
+    #[allow(
+        dead_code,
+        reason = "the client reads every connection through PeerStatsTable"
+    )]
+    pub fn stats(&self) -> PeerStats {
+        self.stats.lock().unwrap().clone()
+    }
+
+    /// The cell behind [`stats`](Self::stats), for tracking the connection
+    /// from other tasks
+    pub fn shared_stats(&self) -> &Arc<Mutex<PeerStats>> {
+        &self.stats
+    }
+
+    fn update_stats(&self, update: impl FnOnce(&mut PeerStats)) {
+        update(&mut self.stats.lock().unwrap());
+    }
+
+    /// Return a received block's buffer once its data has been copied out
+    pub fn recycle(&mut self, data: Vec<u8>) {
+        if let Some(pool) = &self.buffer_pool {
+            if self.pooled_buffers > 0 {
+                self.pooled_buffers -= 1;
+                pool.release(data);
+            }
+        }
+    }
+
+    /// Log one message in `direction` with its encoded size, plus a
+    /// truncated hex dump when enabled
+    fn log_wire(&self, direction: &str, message: &PeerMessage) {
+        if !tracing::enabled!(target: WIRE_LOG_TARGET, Level::TRACE) {
+            return;
+        }
+
+        let raw = message.to_bytes();
+        trace!(
+            target: WIRE_LOG_TARGET,
+            "{} {} {} [{} bytes]",
+            direction,
+            self.addr,
+            message,
+            raw.len()
+        );
+
+        if let Some(limit) = self.wire_dump {
+            let shown = &raw[..raw.len().min(limit)];
+            trace!(
+                target: WIRE_LOG_TARGET,
+                "{} {} {}{}",
+                direction,
+                self.addr,
+                hex::encode(shown),
+                if shown.len() < raw.len() { "..." } else { "" }
+            );
+        }
+    }
+
+    /// Retire the outstanding requests a Piece message answers
+    ///
+    /// Peers may split a block over several messages or coalesce
+    /// neighbouring blocks into one, but data always arrives in order. So a
+    /// message must start exactly where a pending request (or the unanswered
+    /// tail of one) starts; it then consumes as many consecutive requests as
+    /// it covers. Returns false if it doesn't answer any request.
+    fn match_pending(&mut self, piece_index: u32, offset: u32, length: u32) -> bool {
+        let end = offset.saturating_add(length);
+        let mut cursor = offset;
+        let mut matched = false;
+
+        while cursor < end {
+            let Some((&block, &sent)) = self
+                .pending_requests
+                .iter()
+                .find(|(block, _)| block.piece_index == piece_index && block.offset == cursor)
+            else {
+                break;
+            };
+
+            self.pending_requests.remove(&block);
+            matched = true;
+
+            let block_end = block.offset + block.length;
+            if block_end <= end {
+                self.record_round_trip(sent.elapsed());
+            } else {
+                // Keep waiting for the rest of a split block
+                self.pending_requests
+                    .insert(BlockInfo::new(piece_index, end, block_end - end), sent);
+            }
+            cursor = block_end;
+        }
+
+        matched
+    }
+
+    /// Fold in one request's round trip and grow or shrink the pipeline
+    ///
+    /// A fast answer means the peer could take more requests at once; a slow
+    /// one means ours are already waiting in its queue.
+    fn record_round_trip(&mut self, sample: Duration) {
+        self.rtt = Some(match self.rtt {
+            Some(rtt) => (rtt * 7 + sample) / 8,
+            None => sample,
+        });
+
+        let PipelineLimits {
+            min_depth,
+            max_depth,
+        } = self.pipeline_limits;
+        if sample < PIPELINE_TARGET_LATENCY {
+            self.pipeline_depth = (self.pipeline_depth + 1).min(max_depth);
+        } else {
+            self.pipeline_depth = self.pipeline_depth.saturating_sub(1).max(min_depth);
+        }
+    }
+
+    /// Hang up politely: tell the peer we're no longer interested, if we
+    /// were, then shut down our side of the stream so it sees a clean end
+    /// of stream rather than a reset
+    pub async fn close(mut self) -> Result<()> {
+        if self.broken {
+            return Ok(());
+        }
+        if self.state.am_interested {
+            self.send_message(&PeerMessage::NotInterested).await?;
+        }
+        self.stream.shutdown().await?;
+        debug!("Closed connection to peer {}", self.addr);
+        Ok(())
+    }
+
+    /// Whether the connection can still be used: false once a read or
+    /// write on it has failed
+    pub fn is_alive(&self) -> bool {
+        !self.broken
+    }
+
+    /// When a message was last sent or received successfully
+    #[allow(dead_code, reason = "the reaper only needs `idle_time`")]
+    pub fn last_activity(&self) -> Instant {
+        self.last_activity
+    }
+
+    /// Time since the last successful send or receive
+    pub fn idle_time(&self) -> Duration {
+        self.last_activity.elapsed()
+    }
+
+    /// Whether the peer has told us which pieces it has
+    pub fn has_bitfield(&self) -> bool {
+        self.bitfield.is_some()
+    }
+
     pub fn state(&self) -> &PeerState {
         &self.state
     }
@@ -141,7 +814,546 @@ impl PeerConnection {
         self.addr
     }
 
+    #[allow(dead_code, reason = "peer stats carry the ID for the client")]
     pub fn peer_id(&self) -> Option<&[u8; 20]> {
         self.peer_id.as_ref()
     }
+
+    /// The handshake the peer sent, e.g. to check what it supports
+    #[cfg(test)]
+    pub fn peer_handshake(&self) -> &Handshake {
+        &self.peer_handshake
+    }
+
+    /// Where the peer's DHT node listens, as told by its Port message
+    ///
+    /// Together with the peer's IP this is a node for a DHT routing table.
+    #[allow(
+        dead_code,
+        reason = "there is no DHT routing table to add nodes to yet"
+    )]
+    pub fn dht_node(&self) -> Option<SocketAddr> {
+        self.dht_port
+            .map(|port| SocketAddr::new(self.addr.ip(), port))
+    }
+
+    /// Tell the peer where our DHT node listens, if it runs one too
+    ///
+    /// Returns whether the Port message was sent.
+    #[allow(dead_code, reason = "we don't run a DHT node to announce yet")]
+    pub async fn announce_dht_port(&mut self, port: u16) -> Result<bool> {
+        if !self.peer_handshake.supports_dht() {
+            return Ok(false);
+        }
+        self.send_message(&PeerMessage::Port { port }).await?;
+        Ok(true)
+    }
+
+    /// Number of requests sent that haven't been answered yet
+    #[cfg(test)]
+    pub fn pending_request_count(&self) -> usize {
+        self.pending_requests.len()
+    }
+}
+
+/// Read the peer's handshake and check `is_ours` accepts its info hash
+async fn read_handshake<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    is_ours: impl Fn(&[u8; 20]) -> bool,
+) -> Result<Handshake> {
+    let mut handshake_buf = vec![0u8; 68];
+    stream.read_exact(&mut handshake_buf).await?;
+
+    let peer_handshake = Handshake::from_bytes(&handshake_buf)?;
+
+    // Verify info hash
+    if !is_ours(&peer_handshake.info_hash) {
+        return Err(BittorrentError::PeerError("Info hash mismatch".to_string()));
+    }
+
+    Ok(peer_handshake)
+}
+
+/// Connections dropped without [`PeerConnection::close`] can't send
+/// anything more, but whatever was already written is pushed out if the
+/// stream can take it right away
+impl<S: AsyncWrite + Unpin> Drop for PeerConnection<S> {
+    fn drop(&mut self) {
+        if let Some(pool) = &self.buffer_pool {
+            pool.reclaim(self.pooled_buffers);
+        }
+        if let (Some(availability), Some(bitfield)) = (&self.availability, self.bitfield.take()) {
+            let _ = availability.send(AvailabilityChange::Lost(bitfield));
+        }
+        if !self.broken {
+            let mut cx = Context::from_waker(Waker::noop());
+            let _ = Pin::new(&mut self.stream).poll_flush(&mut cx);
+        }
+    }
+}
+
+/// Read exactly `len` bytes into the spare capacity of an empty `buffer`,
+/// without zeroing it first
+async fn read_into<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+    len: usize,
+) -> std::io::Result<()> {
+    buffer.reserve(len);
+    while buffer.len() < len {
+        let remaining = len - buffer.len();
+        let mut rest = (&mut *buffer).limit(remaining);
+        if reader.read_buf(&mut rest).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+    Ok(())
+}
+
+/// Check that a bitfield has exactly one bit per piece and zeroed padding
+pub fn validate_bitfield(bitfield: &[u8], num_pieces: usize) -> Result<()> {
+    let expected_len = num_pieces.div_ceil(8);
+    if bitfield.len() != expected_len {
+        return Err(BittorrentError::ProtocolViolation(format!(
+            "Bitfield is {} bytes, expected {}",
+            bitfield.len(),
+            expected_len
+        )));
+    }
+
+    let spare_bits = expected_len * 8 - num_pieces;
+    if spare_bits > 0 {
+        let padding_mask = (1u8 << spare_bits) - 1;
+        if bitfield[expected_len - 1] & padding_mask != 0 {
+            return Err(BittorrentError::ProtocolViolation(
+                "Bitfield has spare bits set".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::peer::protocol::{DHT_BIT, EXTENSION_PROTOCOL_BIT};
+    use tokio::io::{duplex, DuplexStream};
+
+    const INFO_HASH: [u8; 20] = [7u8; 20];
+
+    /// A connection over an in-memory pipe, plus the remote end of the pipe
+    /// with the handshake already consumed
+    async fn piped_connection() -> (PeerConnection<DuplexStream>, DuplexStream) {
+        let (local, mut remote) = duplex(64 * 1024);
+        remote
+            .write_all(&Handshake::new(INFO_HASH, [9u8; 20]).to_bytes())
+            .await
+            .unwrap();
+
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let mut conn = PeerConnection::handshake(
+            local,
+            addr,
+            INFO_HASH,
+            [1u8; 20],
+            &HandshakePolicy::default(),
+        )
+        .await
+        .unwrap();
+        conn.set_piece_count(11);
+
+        let mut handshake = [0u8; 68];
+        remote.read_exact(&mut handshake).await.unwrap();
+        (conn, remote)
+    }
+
+    #[tokio::test]
+    async fn test_stalled_message_times_out() {
+        let (mut conn, mut remote) = piped_connection().await;
+        conn.set_read_timeout(Duration::from_millis(100));
+
+        // A length prefix for a Request, then nothing
+        remote.write_all(&[0, 0, 0, 13, 6]).await.unwrap();
+        let started = Instant::now();
+        let result = conn.receive_message().await;
+        assert!(
+            matches!(result, Err(BittorrentError::Timeout(_))),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!conn.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_deadline_cuts_read_short() {
+        let (mut conn, mut remote) = piped_connection().await;
+        conn.set_deadline(Some(
+            tokio::time::Instant::now() + Duration::from_millis(100),
+        ));
+
+        // Half a Request arrives, well within the read timeout
+        remote.write_all(&[0, 0, 0, 13, 6, 0, 0]).await.unwrap();
+        let started = Instant::now();
+        let result = conn.receive_message().await;
+        assert!(
+            matches!(result, Err(BittorrentError::Timeout(_))),
+            "{:?}",
+            result
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(!conn.is_alive());
+    }
+
+    #[tokio::test]
+    async fn test_close_sends_not_interested() {
+        let (mut conn, mut remote) = piped_connection().await;
+        conn.send_message(&PeerMessage::Interested).await.unwrap();
+        conn.close().await.unwrap();
+
+        let mut sent = Vec::new();
+        remote.read_to_end(&mut sent).await.unwrap();
+        let mut expected = PeerMessage::Interested.to_bytes();
+        expected.extend(PeerMessage::NotInterested.to_bytes());
+        assert_eq!(sent, expected);
+
+        // Nothing to take back from a peer we never asked for anything
+        let (conn, mut remote) = piped_connection().await;
+        conn.close().await.unwrap();
+        let mut sent = Vec::new();
+        remote.read_to_end(&mut sent).await.unwrap();
+        assert!(sent.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dht_port_exchange() {
+        // A peer without the DHT bit is never told our port
+        let (mut conn, _remote) = piped_connection().await;
+        assert!(!conn.announce_dht_port(6881).await.unwrap());
+
+        let (local, mut remote) = duplex(64 * 1024);
+        let dht_peer = Handshake::new(INFO_HASH, [9u8; 20]).with_bit(DHT_BIT);
+        remote.write_all(&dht_peer.to_bytes()).await.unwrap();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let mut conn = PeerConnection::handshake(
+            local,
+            addr,
+            INFO_HASH,
+            [1u8; 20],
+            &HandshakePolicy::default(),
+        )
+        .await
+        .unwrap();
+        remote.read_exact(&mut [0u8; 68]).await.unwrap();
+        assert!(conn.peer_handshake().supports_dht());
+
+        assert!(conn.announce_dht_port(7000).await.unwrap());
+        let mut port = [0u8; 7];
+        remote.read_exact(&mut port).await.unwrap();
+        assert_eq!(
+            PeerMessage::from_bytes(&port).unwrap(),
+            PeerMessage::Port { port: 7000 }
+        );
+
+        assert_eq!(conn.dht_node(), None);
+        remote
+            .write_all(&PeerMessage::Port { port: 51413 }.to_bytes())
+            .await
+            .unwrap();
+        conn.receive_message().await.unwrap();
+        assert_eq!(conn.dht_node(), Some("127.0.0.1:51413".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_handshake_over_pipe() {
+        let (conn, _remote) = piped_connection().await;
+        assert_eq!(conn.peer_id(), Some(&[9u8; 20]));
+        assert!(conn.state().peer_choking);
+    }
+
+    fn extension_handshake() -> Handshake {
+        Handshake::new(INFO_HASH, [9u8; 20]).with_bit(EXTENSION_PROTOCOL_BIT)
+    }
+
+    #[tokio::test]
+    async fn test_accept_checks_policy() {
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let require_extensions = HandshakePolicy {
+            require_extension_protocol: true,
+            ..Default::default()
+        };
+
+        for (remote_handshake, policy, accepted) in [
+            (
+                Handshake::new(INFO_HASH, [9u8; 20]),
+                HandshakePolicy::default(),
+                true,
+            ),
+            (
+                Handshake::new(INFO_HASH, [9u8; 20]),
+                require_extensions,
+                false,
+            ),
+            (extension_handshake(), require_extensions, true),
+        ] {
+            let (local, mut remote) = duplex(1024);
+            remote
+                .write_all(&remote_handshake.to_bytes())
+                .await
+                .unwrap();
+
+            let result =
+                PeerConnection::accept(local, addr, |hash| *hash == INFO_HASH, [1u8; 20], &policy)
+                    .await;
+            if accepted {
+                let mut reply = [0u8; 68];
+                remote.read_exact(&mut reply).await.unwrap();
+                assert_eq!(Handshake::from_bytes(&reply).unwrap().peer_id, [1u8; 20]);
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(BittorrentError::HandshakeRejected(_))));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_handshake_checks_policy() {
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        let require_encryption = HandshakePolicy {
+            require_encryption: true,
+            ..Default::default()
+        };
+
+        let (local, mut remote) = duplex(1024);
+        remote
+            .write_all(&extension_handshake().to_bytes())
+            .await
+            .unwrap();
+        let result =
+            PeerConnection::handshake(local, addr, INFO_HASH, [1u8; 20], &require_encryption).await;
+        assert!(matches!(result, Err(BittorrentError::HandshakeRejected(_))));
+
+        let (local, mut remote) = duplex(1024);
+        remote
+            .write_all(&extension_handshake().to_bytes())
+            .await
+            .unwrap();
+        let require_extensions = HandshakePolicy {
+            require_extension_protocol: true,
+            ..Default::default()
+        };
+        assert!(
+            PeerConnection::handshake(local, addr, INFO_HASH, [1u8; 20], &require_extensions)
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_depth_stays_within_limits() {
+        let (mut conn, _remote) = piped_connection().await;
+        conn.set_pipeline_limits(PipelineLimits {
+            min_depth: 2,
+            max_depth: 4,
+        });
+        assert_eq!(conn.pipeline_depth(), 2);
+
+        for _ in 0..10 {
+            conn.record_round_trip(Duration::from_millis(10));
+        }
+        assert_eq!(conn.pipeline_depth(), 4);
+
+        for _ in 0..10 {
+            conn.record_round_trip(PIPELINE_TARGET_LATENCY * 2);
+        }
+        assert_eq!(conn.pipeline_depth(), 2);
+        assert!(conn.rtt().unwrap() > PIPELINE_TARGET_LATENCY);
+
+        // Nonsensical limits still leave room for one request
+        conn.set_pipeline_limits(PipelineLimits {
+            min_depth: 0,
+            max_depth: 0,
+        });
+        assert_eq!(conn.pipeline_depth(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_message_round_trip() {
+        let (mut conn, mut remote) = piped_connection().await;
+
+        let block = BlockInfo::new(3, 0, 4);
+        conn.send_message(&PeerMessage::Request { block })
+            .await
+            .unwrap();
+        let mut request = [0u8; 17];
+        remote.read_exact(&mut request).await.unwrap();
+        assert_eq!(
+            PeerMessage::from_bytes(&request).unwrap(),
+            PeerMessage::Request { block }
+        );
+        assert_eq!(conn.pending_request_count(), 1);
+
+        let piece = PeerMessage::Piece {
+            piece_index: 3,
+            offset: 0,
+            data: b"data".to_vec(),
+        };
+        remote.write_all(&piece.to_bytes()).await.unwrap();
+        assert_eq!(conn.receive_message().await.unwrap(), piece);
+        assert_eq!(conn.pending_request_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_request_and_read_blocks() {
+        let (mut conn, mut remote) = piped_connection().await;
+
+        let first = conn.request_block(2, 0, 4).await.unwrap();
+        let second = conn.request_block(2, 4, 4).await.unwrap();
+        assert_eq!(second, BlockInfo::new(2, 4, 4));
+        assert_eq!(conn.pending_request_count(), 2);
+        let mut requests = [0u8; 34];
+        remote.read_exact(&mut requests).await.unwrap();
+        assert_eq!(
+            PeerMessage::from_bytes(&requests[..17]).unwrap(),
+            PeerMessage::Request { block: first }
+        );
+
+        // Control messages come through between blocks; keep-alives don't
+        for message in [
+            PeerMessage::KeepAlive,
+            PeerMessage::Unchoke,
+            PeerMessage::Have { piece_index: 5 },
+            PeerMessage::Piece {
+                piece_index: 2,
+                offset: 0,
+                data: b"abcd".to_vec(),
+            },
+            PeerMessage::Choke,
+        ] {
+            remote.write_all(&message.to_bytes()).await.unwrap();
+        }
+
+        assert_eq!(conn.read_block().await.unwrap(), PeerEvent::Unchoked);
+        assert_eq!(
+            conn.read_block().await.unwrap(),
+            PeerEvent::Other(PeerMessage::Have { piece_index: 5 })
+        );
+        assert_eq!(
+            conn.read_block().await.unwrap(),
+            PeerEvent::Block {
+                piece_index: 2,
+                offset: 0,
+                data: b"abcd".to_vec(),
+            }
+        );
+        assert_eq!(conn.pending_request_count(), 1);
+        assert_eq!(conn.read_block().await.unwrap(), PeerEvent::Choked);
+        assert_eq!(conn.pending_request_count(), 0);
+        assert!(conn.has_piece(5));
+    }
+
+    #[tokio::test]
+    async fn test_announced_pieces_are_reported() {
+        let (mut conn, mut remote) = piped_connection().await;
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        conn.set_availability_sink(tx);
+
+        let bitfield = PeerMessage::Bitfield {
+            bitfield: vec![0b1000_0000, 0],
+        };
+        remote.write_all(&bitfield.to_bytes()).await.unwrap();
+        // A repeated Have adds nothing
+        for _ in 0..2 {
+            remote
+                .write_all(&PeerMessage::Have { piece_index: 10 }.to_bytes())
+                .await
+                .unwrap();
+        }
+        for _ in 0..3 {
+            conn.receive_message().await.unwrap();
+        }
+        drop(conn);
+
+        let mut changes = Vec::new();
+        while let Some(change) = rx.recv().await {
+            changes.push(change);
+        }
+        assert_eq!(
+            changes,
+            vec![
+                AvailabilityChange::Gained(vec![0b1000_0000, 0]),
+                AvailabilityChange::Have(10),
+                AvailabilityChange::Lost(vec![0b1000_0000, 0b0010_0000]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bitfield_and_have_update_state() {
+        let (mut conn, mut remote) = piped_connection().await;
+
+        let bitfield = PeerMessage::Bitfield {
+            bitfield: vec![0b1000_0000, 0],
+        };
+        remote.write_all(&bitfield.to_bytes()).await.unwrap();
+        remote
+            .write_all(&PeerMessage::Have { piece_index: 10 }.to_bytes())
+            .await
+            .unwrap();
+        remote
+            .write_all(&PeerMessage::Unchoke.to_bytes())
+            .await
+            .unwrap();
+
+        for _ in 0..3 {
+            conn.receive_message().await.unwrap();
+        }
+        assert!(conn.has_piece(0));
+        assert!(conn.has_piece(10));
+        assert!(!conn.has_piece(1));
+        assert!(!conn.state().peer_choking);
+
+        // Out of range for an 11-piece torrent
+        remote
+            .write_all(&PeerMessage::Have { piece_index: 11 }.to_bytes())
+            .await
+            .unwrap();
+        assert!(matches!(
+            conn.receive_message().await,
+            Err(BittorrentError::ProtocolViolation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected() {
+        let (mut conn, mut remote) = piped_connection().await;
+
+        let length = (MAX_MESSAGE_LENGTH as u32 + 1).to_be_bytes();
+        remote.write_all(&length).await.unwrap();
+        assert!(matches!(
+            conn.receive_message().await,
+            Err(BittorrentError::ProtocolViolation(_))
+        ));
+    }
+
+    #[test]
+    fn test_valid_bitfield() {
+        assert!(validate_bitfield(&[0xff, 0b1110_0000], 11).is_ok());
+        assert!(validate_bitfield(&[0xff], 8).is_ok());
+    }
+
+    #[test]
+    fn test_wrong_length_bitfield() {
+        assert!(validate_bitfield(&[0xff], 11).is_err());
+        assert!(validate_bitfield(&[0xff, 0x00, 0x00], 11).is_err());
+    }
+
+    #[test]
+    fn test_bitfield_with_spare_bits_set() {
+        assert!(matches!(
+            validate_bitfield(&[0xff, 0b1111_0000], 11),
+            Err(BittorrentError::ProtocolViolation(_))
+        ));
+    }
 }
@@ -0,0 +1,204 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use tracing::warn;
+
+/// Failed pieces a peer may contribute to before it is banned
+pub const MAX_HASH_FAILURES: u32 = 3;
+
+/// Distinct unrecovered failing pieces after which a download is given up
+pub const DEFAULT_MAX_FAILED_PIECES: usize = 16;
+
+/// Failures from this many disjoint sets of peers suggest the piece hash in
+/// the torrent itself is wrong
+pub const SUSPECT_HASH_AFTER: usize = 2;
+
+/// Pieces that failed their hash check and the peers that sent them
+///
+/// Peers that contributed to a failed attempt are kept away from that
+/// piece's retries, so a bad peer can't keep poisoning the same piece.
+#[derive(Debug, Default)]
+pub struct HashFailures {
+    /// Contributors of each failed attempt, per piece
+    attempts: HashMap<usize, Vec<HashSet<SocketAddr>>>,
+    /// Number of failed pieces each peer contributed to
+    strikes: HashMap<SocketAddr, u32>,
+    /// Failing pieces after which the torrent is deemed hopeless
+    max_failed_pieces: Option<usize>,
+}
+
+impl HashFailures {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Give up once this many distinct pieces are failing at the same time
+    /// (None = never)
+    pub fn with_max_failed_pieces(mut self, max: Option<usize>) -> Self {
+        self.max_failed_pieces = max;
+        self
+    }
+
+    /// Record a failed attempt at a piece
+    ///
+    /// Returns the contributors that have now reached `MAX_HASH_FAILURES`
+    /// and should be banned.
+    pub fn record_failure(
+        &mut self,
+        piece_index: usize,
+        contributors: &[SocketAddr],
+    ) -> Vec<SocketAddr> {
+        let was_suspect = self.hash_suspect(piece_index);
+        self.attempts
+            .entry(piece_index)
+            .or_default()
+            .push(contributors.iter().copied().collect());
+
+        if !was_suspect && self.hash_suspect(piece_index) {
+            warn!(
+                "Piece {} failed its hash check with data from {} unrelated sets of peers; \
+                 the hash in the torrent may be bad",
+                piece_index, SUSPECT_HASH_AFTER
+            );
+        }
+
+        let mut to_ban = Vec::new();
+        for addr in contributors {
+            let strikes = self.strikes.entry(*addr).or_default();
+            *strikes += 1;
+            if *strikes == MAX_HASH_FAILURES {
+                to_ban.push(*addr);
+            }
+        }
+        to_ban
+    }
+
+    /// Record that a piece finally verified
+    ///
+    /// A good copy proves every earlier attempt carried bad data, so their
+    /// contributors are returned to be banned.
+    pub fn record_success(&mut self, piece_index: usize) -> Vec<SocketAddr> {
+        let mut offenders: Vec<SocketAddr> = self
+            .attempts
+            .remove(&piece_index)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .collect();
+        offenders.sort_unstable();
+        offenders.dedup();
+        offenders
+    }
+
+    /// Whether a peer already sent bad data for this piece
+    pub fn is_excluded(&self, piece_index: usize, addr: SocketAddr) -> bool {
+        self.attempts
+            .get(&piece_index)
+            .is_some_and(|attempts| attempts.iter().any(|peers| peers.contains(&addr)))
+    }
+
+    /// Whether the piece failed from enough disjoint sets of peers that the
+    /// expected hash is more likely wrong than every one of them
+    pub fn hash_suspect(&self, piece_index: usize) -> bool {
+        let Some(attempts) = self.attempts.get(&piece_index) else {
+            return false;
+        };
+
+        // Greedily count attempts sharing no peer with an earlier counted one
+        let mut seen = HashSet::new();
+        let mut disjoint = 0;
+        for peers in attempts {
+            if !peers.is_empty() && peers.is_disjoint(&seen) {
+                disjoint += 1;
+                seen.extend(peers.iter().copied());
+            }
+        }
+        disjoint >= SUSPECT_HASH_AFTER
+    }
+
+    /// Pieces that failed and haven't verified since
+    ///
+    /// One piece failing over and over counts once; a piece that later
+    /// verifies stops counting, so a flaky peer doesn't add up here.
+    pub fn failed_pieces(&self) -> usize {
+        self.attempts.len()
+    }
+
+    /// Whether so many different pieces fail that the torrent itself is
+    /// likely corrupt or doesn't match the data being shared
+    pub fn hopeless(&self) -> bool {
+        self.max_failed_pieces
+            .is_some_and(|max| self.failed_pieces() >= max)
+    }
+
+    /// Number of failed pieces a peer contributed to
+    #[cfg(test)]
+    pub fn strikes(&self, addr: SocketAddr) -> u32 {
+        self.strikes.get(&addr).copied().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_contributors_are_excluded_until_success() {
+        let mut failures = HashFailures::new();
+        assert!(failures.record_failure(3, &[addr(1)]).is_empty());
+
+        assert!(failures.is_excluded(3, addr(1)));
+        assert!(!failures.is_excluded(3, addr(2)));
+        assert!(!failures.is_excluded(4, addr(1)));
+
+        assert_eq!(failures.record_success(3), vec![addr(1)]);
+        assert!(!failures.is_excluded(3, addr(1)));
+        assert!(failures.record_success(3).is_empty());
+    }
+
+    #[test]
+    fn test_disjoint_failures_make_hash_suspect() {
+        let mut failures = HashFailures::new();
+        failures.record_failure(0, &[addr(1), addr(2)]);
+        failures.record_failure(0, &[addr(2), addr(3)]);
+        assert!(!failures.hash_suspect(0));
+
+        failures.record_failure(0, &[addr(4)]);
+        assert!(failures.hash_suspect(0));
+        assert!(!failures.hash_suspect(1));
+    }
+
+    #[test]
+    fn test_hopeless_after_distinct_pieces_fail() {
+        let mut failures = HashFailures::new().with_max_failed_pieces(Some(2));
+        failures.record_failure(0, &[addr(1)]);
+        failures.record_failure(0, &[addr(2)]);
+        failures.record_failure(0, &[addr(3)]);
+        assert!(!failures.hopeless());
+
+        failures.record_failure(1, &[addr(4)]);
+        assert!(failures.hopeless());
+
+        failures.record_success(1);
+        assert!(!failures.hopeless());
+        assert!(!HashFailures::new().hopeless());
+    }
+
+    #[test]
+    fn test_repeat_offender_is_banned_once() {
+        let mut failures = HashFailures::new();
+        for piece in 0..MAX_HASH_FAILURES as usize - 1 {
+            assert!(failures.record_failure(piece, &[addr(1)]).is_empty());
+        }
+        assert_eq!(
+            failures.record_failure(9, &[addr(1), addr(2)]),
+            vec![addr(1)]
+        );
+        assert!(failures.record_failure(10, &[addr(1)]).is_empty());
+        assert_eq!(failures.strikes(addr(1)), MAX_HASH_FAILURES + 1);
+        assert_eq!(failures.strikes(addr(2)), 1);
+    }
+}
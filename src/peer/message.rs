@@ -1,8 +1,9 @@
-use bytes::{Buf, BufMut, BytesMut};
 use crate::error::{BittorrentError, Result};
+use bytes::{Buf, BufMut, BytesMut};
+use std::fmt;
 
 /// Information about a block within a piece
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct BlockInfo {
     /// Piece index
     pub piece_index: u32,
@@ -49,6 +50,8 @@ pub enum PeerMessage {
     },
     /// Cancel a block request
     Cancel { block: BlockInfo },
+    /// UDP port of the sender's DHT node (BEP 5)
+    Port { port: u16 },
 }
 
 impl PeerMessage {
@@ -60,8 +63,9 @@ impl PeerMessage {
     const HAVE: u8 = 4;
     const BITFIELD: u8 = 5;
     const REQUEST: u8 = 6;
-    const PIECE: u8 = 7;
+    pub(crate) const PIECE: u8 = 7;
     const CANCEL: u8 = 8;
+    const PORT: u8 = 9;
 
     /// Serialize message to bytes
     /// Format: <length prefix><message ID><payload>
@@ -123,6 +127,11 @@ impl PeerMessage {
                 buf.put_u32(block.offset);
                 buf.put_u32(block.length);
             }
+            PeerMessage::Port { port } => {
+                buf.put_u32(3); // length = 1 + 2
+                buf.put_u8(Self::PORT);
+                buf.put_u16(*port);
+            }
         }
 
         buf.to_vec()
@@ -131,9 +140,7 @@ impl PeerMessage {
     /// Deserialize message from bytes
     pub fn from_bytes(mut data: &[u8]) -> Result<Self> {
         if data.len() < 4 {
-            return Err(BittorrentError::PeerError(
-                "Message too short".to_string(),
-            ));
+            return Err(BittorrentError::PeerError("Message too short".to_string()));
         }
 
         let length = data.get_u32() as usize;
@@ -143,9 +150,7 @@ impl PeerMessage {
         }
 
         if data.len() < length {
-            return Err(BittorrentError::PeerError(
-                "Incomplete message".to_string(),
-            ));
+            return Err(BittorrentError::PeerError("Incomplete message".to_string()));
         }
 
         let message_id = data.get_u8();
@@ -157,7 +162,9 @@ impl PeerMessage {
             Self::NOT_INTERESTED => Ok(PeerMessage::NotInterested),
             Self::HAVE => {
                 if data.len() < 4 {
-                    return Err(BittorrentError::PeerError("Invalid Have message".to_string()));
+                    return Err(BittorrentError::PeerError(
+                        "Invalid Have message".to_string(),
+                    ));
                 }
                 let piece_index = data.get_u32();
                 Ok(PeerMessage::Have { piece_index })
@@ -168,7 +175,9 @@ impl PeerMessage {
             }
             Self::REQUEST => {
                 if data.len() < 12 {
-                    return Err(BittorrentError::PeerError("Invalid Request message".to_string()));
+                    return Err(BittorrentError::PeerError(
+                        "Invalid Request message".to_string(),
+                    ));
                 }
                 let piece_index = data.get_u32();
                 let offset = data.get_u32();
@@ -179,7 +188,9 @@ impl PeerMessage {
             }
             Self::PIECE => {
                 if data.len() < 8 {
-                    return Err(BittorrentError::PeerError("Invalid Piece message".to_string()));
+                    return Err(BittorrentError::PeerError(
+                        "Invalid Piece message".to_string(),
+                    ));
                 }
                 let piece_index = data.get_u32();
                 let offset = data.get_u32();
@@ -192,7 +203,9 @@ impl PeerMessage {
             }
             Self::CANCEL => {
                 if data.len() < 12 {
-                    return Err(BittorrentError::PeerError("Invalid Cancel message".to_string()));
+                    return Err(BittorrentError::PeerError(
+                        "Invalid Cancel message".to_string(),
+                    ));
                 }
                 let piece_index = data.get_u32();
                 let offset = data.get_u32();
@@ -201,6 +214,16 @@ impl PeerMessage {
                     block: BlockInfo::new(piece_index, offset, length),
                 })
             }
+            Self::PORT => {
+                if data.len() < 2 {
+                    return Err(BittorrentError::PeerError(
+                        "Invalid Port message".to_string(),
+                    ));
+                }
+                Ok(PeerMessage::Port {
+                    port: data.get_u16(),
+                })
+            }
             _ => Err(BittorrentError::PeerError(format!(
                 "Unknown message ID: {}",
                 message_id
@@ -208,3 +231,77 @@ impl PeerMessage {
         }
     }
 }
+
+/// Compact form for logs: message type plus indices and sizes, never the
+/// payload itself
+impl fmt::Display for PeerMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerMessage::KeepAlive => write!(f, "keep-alive"),
+            PeerMessage::Choke => write!(f, "choke"),
+            PeerMessage::Unchoke => write!(f, "unchoke"),
+            PeerMessage::Interested => write!(f, "interested"),
+            PeerMessage::NotInterested => write!(f, "not interested"),
+            PeerMessage::Have { piece_index } => write!(f, "have piece {}", piece_index),
+            PeerMessage::Bitfield { bitfield } => write!(f, "bitfield ({} bytes)", bitfield.len()),
+            PeerMessage::Request { block } => write!(
+                f,
+                "request piece {} offset {} ({} bytes)",
+                block.piece_index, block.offset, block.length
+            ),
+            PeerMessage::Piece {
+                piece_index,
+                offset,
+                data,
+            } => write!(
+                f,
+                "piece {} offset {} ({} bytes)",
+                piece_index,
+                offset,
+                data.len()
+            ),
+            PeerMessage::Cancel { block } => write!(
+                f,
+                "cancel piece {} offset {} ({} bytes)",
+                block.piece_index, block.offset, block.length
+            ),
+            PeerMessage::Port { port } => write!(f, "dht port {}", port),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display_omits_payload() {
+        let piece = PeerMessage::Piece {
+            piece_index: 3,
+            offset: 16384,
+            data: vec![0xab; 16384],
+        };
+        assert_eq!(piece.to_string(), "piece 3 offset 16384 (16384 bytes)");
+
+        let bitfield = PeerMessage::Bitfield {
+            bitfield: vec![0xff; 40],
+        };
+        assert_eq!(bitfield.to_string(), "bitfield (40 bytes)");
+        assert_eq!(PeerMessage::NotInterested.to_string(), "not interested");
+        assert_eq!(
+            PeerMessage::Port { port: 6881 }.to_string(),
+            "dht port 6881"
+        );
+    }
+
+    #[test]
+    fn test_port_roundtrip() {
+        let port = PeerMessage::Port { port: 0xc8d5 };
+        let bytes = port.to_bytes();
+        assert_eq!(bytes, [0, 0, 0, 3, 9, 0xc8, 0xd5]);
+        assert_eq!(PeerMessage::from_bytes(&bytes).unwrap(), port);
+
+        // A Port message too short to hold the port is an error
+        assert!(PeerMessage::from_bytes(&[0, 0, 0, 2, 9, 0xc8]).is_err());
+    }
+}
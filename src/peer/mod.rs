@@ -1,10 +1,26 @@
+mod blocklist;
+mod buffer_pool;
+mod client_id;
 mod connection;
+mod hash_failures;
 mod message;
 mod protocol;
+mod registry;
+mod stats;
+mod tos;
 
-pub use connection::PeerConnection;
-pub use message::{PeerMessage, BlockInfo};
-pub use protocol::{Handshake, PROTOCOL_STRING};
+pub use blocklist::Blocklist;
+pub use buffer_pool::BufferPool;
+pub use client_id::client_name;
+pub use connection::{AvailabilityChange, PeerConnection, PeerEvent, PipelineLimits};
+pub use hash_failures::{HashFailures, DEFAULT_MAX_FAILED_PIECES};
+pub use message::{BlockInfo, PeerMessage};
+pub use protocol::{Handshake, HandshakePolicy};
+#[cfg(test)]
+pub use registry::PeerEntryState;
+pub use registry::{DialOrder, PeerRegistry, ProvenFirst};
+pub use stats::{PeerStats, PeerStatsTable};
+pub use tos::set_socket_tos;
 
 // Peer connection states
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
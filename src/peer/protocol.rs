@@ -2,10 +2,21 @@ use crate::error::{BittorrentError, Result};
 
 pub const PROTOCOL_STRING: &[u8] = b"BitTorrent protocol";
 
+/// Reserved bit advertising the extension protocol (BEP 10)
+pub const EXTENSION_PROTOCOL_BIT: (usize, u8) = (5, 0x10);
+
+/// Reserved bit advertising the fast extension (BEP 6)
+pub const FAST_EXTENSION_BIT: (usize, u8) = (7, 0x04);
+
+/// Reserved bit advertising a DHT node, whose port follows in a Port message (BEP 5)
+pub const DHT_BIT: (usize, u8) = (7, 0x01);
+
 /// Handshake message for peer wire protocol
 /// Format: <pstrlen><pstr><reserved><info_hash><peer_id>
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Handshake {
+    /// Feature bits advertised by the sender
+    pub reserved: [u8; 8],
     pub info_hash: [u8; 20],
     pub peer_id: [u8; 20],
 }
@@ -13,11 +24,46 @@ pub struct Handshake {
 impl Handshake {
     pub fn new(info_hash: [u8; 20], peer_id: [u8; 20]) -> Self {
         Self {
+            reserved: [0u8; 8],
             info_hash,
             peer_id,
         }
     }
 
+    /// Advertise the given reserved bytes instead of none
+    #[cfg(test)]
+    pub fn with_reserved(mut self, reserved: [u8; 8]) -> Self {
+        self.reserved = reserved;
+        self
+    }
+
+    /// Set one of the `*_BIT` feature bits
+    #[cfg(test)]
+    pub fn with_bit(mut self, (byte, mask): (usize, u8)) -> Self {
+        self.reserved[byte] |= mask;
+        self
+    }
+
+    fn has_bit(&self, (byte, mask): (usize, u8)) -> bool {
+        self.reserved[byte] & mask != 0
+    }
+
+    /// Whether the sender supports the extension protocol (BEP 10)
+    pub fn supports_extension_protocol(&self) -> bool {
+        self.has_bit(EXTENSION_PROTOCOL_BIT)
+    }
+
+    /// Whether the sender supports the fast extension (BEP 6)
+    #[allow(dead_code, reason = "no fast extension messages are handled yet")]
+    pub fn supports_fast(&self) -> bool {
+        self.has_bit(FAST_EXTENSION_BIT)
+    }
+
+    /// Whether the sender runs a DHT node (BEP 5)
+    pub fn supports_dht(&self) -> bool {
+        self.has_bit(DHT_BIT)
+    }
+
     /// Serialize handshake to bytes
     /// Format: <pstrlen><pstr><reserved><info_hash><peer_id>
     /// Total: 1 + 19 + 8 + 20 + 20 = 68 bytes
@@ -30,8 +76,8 @@ impl Handshake {
         // Protocol string
         buf.extend_from_slice(PROTOCOL_STRING);
 
-        // Reserved bytes (8 bytes, all zeros)
-        buf.extend_from_slice(&[0u8; 8]);
+        // Reserved bytes
+        buf.extend_from_slice(&self.reserved);
 
         // Info hash
         buf.extend_from_slice(&self.info_hash);
@@ -65,6 +111,9 @@ impl Handshake {
             ));
         }
 
+        let mut reserved = [0u8; 8];
+        reserved.copy_from_slice(&data[20..28]);
+
         // Extract info hash
         let mut info_hash = [0u8; 20];
         info_hash.copy_from_slice(&data[28..48]);
@@ -74,12 +123,44 @@ impl Handshake {
         peer_id.copy_from_slice(&data[48..68]);
 
         Ok(Handshake {
+            reserved,
             info_hash,
             peer_id,
         })
     }
 }
 
+/// Which peers to accept based on what their handshake advertises
+///
+/// The default accepts everyone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HandshakePolicy {
+    /// Refuse peers that don't advertise the extension protocol
+    pub require_extension_protocol: bool,
+    /// Refuse plaintext connections. Stream encryption isn't implemented,
+    /// so this refuses every peer on the plain BitTorrent handshake.
+    pub require_encryption: bool,
+}
+
+impl HandshakePolicy {
+    /// Check a peer's handshake against the policy
+    pub fn check(&self, handshake: &Handshake) -> Result<()> {
+        if self.require_encryption {
+            return Err(BittorrentError::HandshakeRejected(
+                "peer offered a plaintext connection but encryption is required".to_string(),
+            ));
+        }
+
+        if self.require_extension_protocol && !handshake.supports_extension_protocol() {
+            return Err(BittorrentError::HandshakeRejected(
+                "peer doesn't support the extension protocol".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,5 +179,54 @@ mod tests {
 
         let decoded = Handshake::from_bytes(&bytes).unwrap();
         assert_eq!(decoded, handshake);
+        assert!(!decoded.supports_extension_protocol());
+        assert!(!decoded.supports_fast());
+        assert!(!decoded.supports_dht());
+
+        // Reserved bytes survive the round trip and map to their features
+        let reserved = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        let handshake = Handshake::new(info_hash, peer_id).with_reserved(reserved);
+        let bytes = handshake.to_bytes();
+        assert_eq!(&bytes[20..28], &reserved);
+        let decoded = Handshake::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.reserved, reserved);
+        assert!(decoded.supports_extension_protocol());
+        assert!(decoded.supports_fast());
+        assert!(decoded.supports_dht());
+
+        let fast_only = Handshake::new(info_hash, peer_id).with_bit(FAST_EXTENSION_BIT);
+        assert_eq!(fast_only.reserved, [0, 0, 0, 0, 0, 0, 0, 0x04]);
+        assert!(fast_only.supports_fast() && !fast_only.supports_dht());
+    }
+
+    #[test]
+    fn test_policy() {
+        let plain = Handshake::new([1u8; 20], [2u8; 20]);
+        let extended = plain.clone().with_bit(EXTENSION_PROTOCOL_BIT);
+        let extended = Handshake::from_bytes(&extended.to_bytes()).unwrap();
+        assert!(extended.supports_extension_protocol());
+
+        let permissive = HandshakePolicy::default();
+        assert!(permissive.check(&plain).is_ok());
+        assert!(permissive.check(&extended).is_ok());
+
+        let require_extensions = HandshakePolicy {
+            require_extension_protocol: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            require_extensions.check(&plain),
+            Err(BittorrentError::HandshakeRejected(_))
+        ));
+        assert!(require_extensions.check(&extended).is_ok());
+
+        let require_encryption = HandshakePolicy {
+            require_encryption: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            require_encryption.check(&extended),
+            Err(BittorrentError::HandshakeRejected(_))
+        ));
     }
 }
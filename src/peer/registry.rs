@@ -0,0 +1,454 @@
+use super::Blocklist;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tracing::debug;
+
+/// Failed connection attempts after which a peer is no longer dialed
+pub const MAX_CONNECT_FAILURES: u32 = 3;
+
+/// Connection state of a known peer address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerEntryState {
+    /// Known but never dialed
+    #[default]
+    Unconnected,
+    /// A dial is in progress
+    Connecting,
+    /// Handshake completed
+    Connected,
+    /// The peer failed this many times in a row
+    Failed(u32),
+    /// The peer misbehaved and must not be contacted again
+    Banned,
+}
+
+/// Registry bookkeeping for one address
+#[derive(Debug, Clone, Copy, Default)]
+struct PeerEntry {
+    state: PeerEntryState,
+    /// Consecutive failures, kept across dial attempts
+    failures: u32,
+    /// Whether a handshake with the peer ever completed
+    connected_before: bool,
+    /// Bytes of verified pieces the peer sent us
+    served_bytes: u64,
+}
+
+impl PeerEntry {
+    /// Never dialed, or failed fewer than [`MAX_CONNECT_FAILURES`] times
+    fn is_dialable(&self) -> bool {
+        match self.state {
+            PeerEntryState::Unconnected => true,
+            PeerEntryState::Failed(n) => n < MAX_CONNECT_FAILURES,
+            _ => false,
+        }
+    }
+}
+
+/// What the registry knows about a peer it could dial
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DialCandidate {
+    pub addr: SocketAddr,
+    /// Consecutive failed dials or dropped connections
+    pub failures: u32,
+    /// Whether a handshake with the peer ever completed
+    pub connected_before: bool,
+    /// Bytes of verified pieces the peer sent us
+    pub served_bytes: u64,
+}
+
+/// Decides which known peers are dialed first when there are more of them
+/// than connection slots
+pub trait DialOrder: fmt::Debug + Send + Sync {
+    /// `Less` if `a` should be dialed before `b`
+    fn compare(&self, a: &DialCandidate, b: &DialCandidate) -> Ordering;
+}
+
+/// Dial the peers that failed least often first
+#[derive(Debug, Clone, Copy, Default)]
+#[allow(
+    dead_code,
+    reason = "an alternative `ClientConfig::dial_order` with no CLI flag"
+)]
+pub struct FewestFailuresFirst;
+
+impl DialOrder for FewestFailuresFirst {
+    fn compare(&self, a: &DialCandidate, b: &DialCandidate) -> Ordering {
+        a.failures.cmp(&b.failures)
+    }
+}
+
+/// Dial peers that already sent us data first, most data first, then
+/// the rest by fewest failures, preferring peers we once connected to
+///
+/// A peer that served pieces before most likely still has them, which
+/// matters once the swarm is larger than `max_peers`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProvenFirst;
+
+impl DialOrder for ProvenFirst {
+    fn compare(&self, a: &DialCandidate, b: &DialCandidate) -> Ordering {
+        b.served_bytes
+            .cmp(&a.served_bytes)
+            .then(a.failures.cmp(&b.failures))
+            .then(b.connected_before.cmp(&a.connected_before))
+    }
+}
+
+/// Peers known from every source (tracker, PEX, DHT, ...), deduplicated by
+/// address
+///
+/// Cloning the registry shares the same underlying table.
+#[derive(Debug, Clone)]
+pub struct PeerRegistry {
+    peers: Arc<Mutex<HashMap<SocketAddr, PeerEntry>>>,
+    /// Addresses that are never recorded, whatever their source
+    blocklist: Option<Arc<Blocklist>>,
+    /// Which candidates are dialed first
+    dial_order: Arc<dyn DialOrder>,
+}
+
+impl Default for PeerRegistry {
+    fn default() -> Self {
+        Self {
+            peers: Default::default(),
+            blocklist: None,
+            dial_order: Arc::new(ProvenFirst),
+        }
+    }
+}
+
+impl PeerRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rank dial candidates with `dial_order` instead of [`ProvenFirst`]
+    pub fn with_dial_order(mut self, dial_order: Arc<dyn DialOrder>) -> Self {
+        self.dial_order = dial_order;
+        self
+    }
+
+    /// Drop every address in `blocklist` instead of recording it
+    pub fn with_blocklist(mut self, blocklist: Option<Arc<Blocklist>>) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
+    /// Whether a peer is on the blocklist; inbound connections from such
+    /// peers must be refused before the handshake
+    pub fn is_blocked(&self, addr: SocketAddr) -> bool {
+        self.blocklist
+            .as_ref()
+            .is_some_and(|blocklist| blocklist.contains(addr.ip()))
+    }
+
+    /// Record a peer address, returning whether it was new
+    ///
+    /// Known peers keep their state, so re-announces don't resurrect
+    /// failed or banned peers. Blocked peers are never recorded.
+    pub fn add(&self, addr: SocketAddr) -> bool {
+        if self.is_blocked(addr) {
+            debug!("Ignoring blocklisted peer {}", addr);
+            return false;
+        }
+
+        let mut peers = self.peers.lock().unwrap();
+        if peers.contains_key(&addr) {
+            return false;
+        }
+        peers.insert(addr, PeerEntry::default());
+        true
+    }
+
+    /// Record several peer addresses, returning how many were new
+    pub fn add_all(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> usize {
+        self.merge(addrs).len()
+    }
+
+    /// Record a fresh peer list, such as a re-announce response, returning
+    /// only the addresses that weren't known before
+    pub fn merge(&self, addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        addrs.into_iter().filter(|&addr| self.add(addr)).collect()
+    }
+
+    /// Claim the best of `addrs` that has never been dialed and mark it as
+    /// connecting
+    pub fn claim_unconnected(&self, addrs: &[SocketAddr]) -> Option<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap();
+        let candidates = addrs.iter().filter_map(|addr| {
+            let entry = peers.get(addr)?;
+            (entry.state == PeerEntryState::Unconnected).then(|| Self::candidate(*addr, entry))
+        });
+        let addr = self.best(candidates)?;
+
+        if let Some(entry) = peers.get_mut(&addr) {
+            entry.state = PeerEntryState::Connecting;
+        }
+        Some(addr)
+    }
+
+    /// Number of peers that are connected or being dialed
+    pub fn active_count(&self) -> usize {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|entry| {
+                matches!(
+                    entry.state,
+                    PeerEntryState::Connecting | PeerEntryState::Connected
+                )
+            })
+            .count()
+    }
+
+    /// Claim the next peer to dial and mark it as connecting
+    ///
+    /// Candidates are never-dialed peers and peers that failed fewer than
+    /// [`MAX_CONNECT_FAILURES`] times, taken in the registry's dial order.
+    /// A peer is never handed out twice while a dial is in progress.
+    pub fn next_candidate(&self) -> Option<SocketAddr> {
+        let mut peers = self.peers.lock().unwrap();
+
+        let candidates = peers
+            .iter()
+            .filter(|(_, entry)| entry.is_dialable())
+            .map(|(&addr, entry)| Self::candidate(addr, entry));
+        let candidate = self.best(candidates)?;
+
+        if let Some(entry) = peers.get_mut(&candidate) {
+            entry.state = PeerEntryState::Connecting;
+        }
+        Some(candidate)
+    }
+
+    /// Mark a peer as connected
+    ///
+    /// Its failures are only forgotten once it sends us data, so a peer
+    /// that keeps connecting and dropping out still runs out of retries.
+    pub fn mark_connected(&self, addr: SocketAddr) {
+        self.transition(addr, |entry| {
+            entry.connected_before = true;
+            entry.state = PeerEntryState::Connected;
+        });
+    }
+
+    /// Credit a peer with a verified piece of `bytes` it sent us
+    pub fn record_served(&self, addr: SocketAddr, bytes: u64) {
+        self.transition(addr, |entry| {
+            entry.failures = 0;
+            entry.served_bytes += bytes;
+        });
+    }
+
+    /// Whether any known peer could still be dialed
+    pub fn has_candidates(&self) -> bool {
+        self.peers
+            .lock()
+            .unwrap()
+            .values()
+            .any(PeerEntry::is_dialable)
+    }
+
+    /// Record a failed dial or a dropped connection
+    pub fn mark_failed(&self, addr: SocketAddr) {
+        self.transition(addr, |entry| {
+            entry.failures += 1;
+            entry.state = PeerEntryState::Failed(entry.failures);
+        });
+    }
+
+    /// Ban a peer for good
+    pub fn ban(&self, addr: SocketAddr) {
+        let mut peers = self.peers.lock().unwrap();
+        peers.entry(addr).or_default().state = PeerEntryState::Banned;
+    }
+
+    /// Whether a peer has been banned
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.state(addr) == Some(PeerEntryState::Banned)
+    }
+
+    /// Current state of a peer
+    pub fn state(&self, addr: SocketAddr) -> Option<PeerEntryState> {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(&addr)
+            .map(|entry| entry.state)
+    }
+
+    /// Number of known peers
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn candidate(addr: SocketAddr, entry: &PeerEntry) -> DialCandidate {
+        DialCandidate {
+            addr,
+            failures: entry.failures,
+            connected_before: entry.connected_before,
+            served_bytes: entry.served_bytes,
+        }
+    }
+
+    /// The candidate to dial first, ties going to the lowest address so the
+    /// choice doesn't depend on hash order
+    fn best(&self, candidates: impl Iterator<Item = DialCandidate>) -> Option<SocketAddr> {
+        candidates
+            .min_by(|a, b| self.dial_order.compare(a, b).then(a.addr.cmp(&b.addr)))
+            .map(|candidate| candidate.addr)
+    }
+
+    /// Update a known, non-banned peer's state
+    fn transition(&self, addr: SocketAddr, update: impl FnOnce(&mut PeerEntry)) {
+        let mut peers = self.peers.lock().unwrap();
+        if let Some(entry) = peers.get_mut(&addr) {
+            if entry.state != PeerEntryState::Banned {
+                update(entry);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_duplicate_addresses_are_ignored() {
+        let registry = PeerRegistry::new();
+        assert_eq!(registry.add_all([addr(1), addr(2), addr(1)]), 2);
+        assert!(!registry.add(addr(2)));
+        assert_eq!(registry.len(), 2);
+
+        // A shared clone sees the same peers
+        let shared = registry.clone();
+        assert!(!shared.add(addr(1)));
+    }
+
+    #[test]
+    fn test_each_peer_is_dialed_once_at_a_time() {
+        let registry = PeerRegistry::new();
+        registry.add_all([addr(1), addr(2)]);
+
+        let first = registry.next_candidate().unwrap();
+        let second = registry.next_candidate().unwrap();
+        assert_ne!(first, second);
+        assert_eq!(registry.next_candidate(), None);
+        assert_eq!(registry.state(first), Some(PeerEntryState::Connecting));
+
+        // Re-adding a peer being dialed doesn't make it a candidate again
+        registry.add(first);
+        assert_eq!(registry.next_candidate(), None);
+    }
+
+    #[test]
+    fn test_merge_returns_only_new_peers() {
+        let registry = PeerRegistry::new();
+        registry.add_all([addr(1), addr(2)]);
+        registry.next_candidate();
+        registry.next_candidate();
+        registry.mark_connected(addr(1));
+
+        let new = registry.merge([addr(1), addr(2), addr(3), addr(4), addr(3)]);
+        assert_eq!(new, vec![addr(3), addr(4)]);
+        assert_eq!(registry.active_count(), 2);
+
+        // Only the new peers are handed out, once each
+        let mut dialed = vec![
+            registry.claim_unconnected(&new).unwrap(),
+            registry.claim_unconnected(&new).unwrap(),
+        ];
+        dialed.sort();
+        assert_eq!(dialed, new);
+        assert_eq!(registry.claim_unconnected(&new), None);
+        assert_eq!(registry.claim_unconnected(&[addr(1), addr(2)]), None);
+        assert_eq!(registry.active_count(), 4);
+    }
+
+    #[test]
+    fn test_blocked_peers_are_not_recorded() {
+        let blocklist = Blocklist::from_ranges(vec![(0x7f00_0002, 0x7f00_0002)]);
+        let registry = PeerRegistry::new().with_blocklist(Some(Arc::new(blocklist)));
+        let blocked = SocketAddr::from(([127, 0, 0, 2], 1));
+
+        assert!(registry.is_blocked(blocked));
+        assert!(!registry.add(blocked));
+        assert_eq!(registry.merge([blocked, addr(1)]), vec![addr(1)]);
+        assert_eq!(registry.next_candidate(), Some(addr(1)));
+        assert_eq!(registry.next_candidate(), None);
+    }
+
+    #[test]
+    fn test_failures_are_remembered() {
+        let registry = PeerRegistry::new();
+        registry.add(addr(1));
+
+        for attempt in 1..=MAX_CONNECT_FAILURES {
+            assert_eq!(registry.next_candidate(), Some(addr(1)));
+            registry.mark_failed(addr(1));
+            assert_eq!(
+                registry.state(addr(1)),
+                Some(PeerEntryState::Failed(attempt))
+            );
+        }
+        assert_eq!(registry.next_candidate(), None);
+    }
+
+    #[test]
+    fn test_peers_that_served_data_are_dialed_first() {
+        let registry = PeerRegistry::new();
+        registry.add_all([addr(1), addr(2), addr(3)]);
+
+        // addr(3) connected and sent a piece before its connection dropped
+        registry.claim_unconnected(&[addr(3)]);
+        registry.mark_connected(addr(3));
+        registry.record_served(addr(3), 16384);
+        registry.mark_failed(addr(3));
+
+        assert_eq!(registry.next_candidate(), Some(addr(3)));
+        assert_eq!(registry.next_candidate(), Some(addr(1)));
+    }
+
+    #[test]
+    fn test_dial_order_is_pluggable() {
+        let registry = PeerRegistry::new().with_dial_order(Arc::new(FewestFailuresFirst));
+        registry.add_all([addr(1), addr(2)]);
+        registry.claim_unconnected(&[addr(1)]);
+        registry.mark_connected(addr(1));
+        registry.record_served(addr(1), 16384);
+        registry.mark_failed(addr(1));
+
+        // Past service doesn't count, so the peer that never failed wins
+        assert_eq!(registry.next_candidate(), Some(addr(2)));
+        assert_eq!(registry.next_candidate(), Some(addr(1)));
+    }
+
+    #[test]
+    fn test_banned_peers_stay_banned() {
+        let registry = PeerRegistry::new();
+        registry.add(addr(1));
+        registry.next_candidate();
+        registry.mark_connected(addr(1));
+        assert_eq!(registry.state(addr(1)), Some(PeerEntryState::Connected));
+
+        registry.ban(addr(1));
+        registry.mark_failed(addr(1));
+        registry.add(addr(1));
+        assert_eq!(registry.state(addr(1)), Some(PeerEntryState::Banned));
+        assert_eq!(registry.next_candidate(), None);
+    }
+}
@@ -0,0 +1,67 @@
+use super::PeerState;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, Weak};
+
+/// What we know about one connected peer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerStats {
+    pub addr: SocketAddr,
+    /// Peer ID from the handshake
+    pub peer_id: Option<[u8; 20]>,
+    /// Client name and version decoded from the peer ID, when recognized
+    pub client: Option<String>,
+    /// Block data received from the peer
+    pub downloaded_bytes: u64,
+    /// Block data sent to the peer
+    pub uploaded_bytes: u64,
+    /// Choke and interest state in both directions
+    pub state: PeerState,
+    /// Pieces the peer has, from its bitfield and Have messages
+    pub pieces: usize,
+}
+
+impl PeerStats {
+    pub fn new(addr: SocketAddr, peer_id: Option<[u8; 20]>) -> Self {
+        Self {
+            addr,
+            peer_id,
+            client: peer_id.as_ref().and_then(super::client_name),
+            downloaded_bytes: 0,
+            uploaded_bytes: 0,
+            state: PeerState::default(),
+            pieces: 0,
+        }
+    }
+}
+
+/// Live stats of every registered connection
+///
+/// Connections are owned by whichever task is using them, so each keeps
+/// its stats in a shared cell and the table only holds weak references.
+/// Dropped connections fall out of the next snapshot on their own.
+#[derive(Debug, Clone, Default)]
+pub struct PeerStatsTable {
+    entries: Arc<Mutex<Vec<Weak<Mutex<PeerStats>>>>>,
+}
+
+impl PeerStatsTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track a connection's stats cell until the connection is dropped
+    pub fn register(&self, stats: &Arc<Mutex<PeerStats>>) {
+        self.entries.lock().unwrap().push(Arc::downgrade(stats));
+    }
+
+    /// Current stats of every connection still open
+    pub fn snapshot(&self) -> Vec<PeerStats> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|entry| entry.strong_count() > 0);
+        entries
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|stats| stats.lock().unwrap().clone())
+            .collect()
+    }
+}
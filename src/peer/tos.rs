@@ -0,0 +1,86 @@
+use socket2::SockRef;
+use std::io;
+use std::net::SocketAddr;
+
+/// Mark the packets a socket sends with a ToS byte (the DSCP value shifted
+/// left by two), so QoS-aware routers can give them a lower priority
+///
+/// IPv6 sockets get the same value as their traffic class.
+#[cfg(not(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+)))]
+pub fn set_socket_tos(socket: SockRef<'_>, tos: u8) -> io::Result<()> {
+    if let Some(SocketAddr::V6(_)) = socket.local_addr()?.as_socket() {
+        return set_traffic_class(socket, tos);
+    }
+    socket.set_tos_v4(tos as u32)
+}
+
+#[cfg(any(
+    target_os = "fuchsia",
+    target_os = "redox",
+    target_os = "solaris",
+    target_os = "illumos",
+    target_os = "haiku",
+))]
+pub fn set_socket_tos(_socket: SockRef<'_>, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the ToS byte isn't supported on this platform",
+    ))
+}
+
+#[cfg(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+))]
+fn set_traffic_class(socket: SockRef<'_>, tos: u8) -> io::Result<()> {
+    socket.set_tclass_v6(tos as u32)
+}
+
+#[cfg(not(any(
+    target_os = "android",
+    target_os = "dragonfly",
+    target_os = "freebsd",
+    target_os = "linux",
+    target_os = "macos",
+    target_os = "netbsd",
+    target_os = "openbsd",
+)))]
+fn set_traffic_class(_socket: SockRef<'_>, _tos: u8) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "setting the IPv6 traffic class isn't supported on this platform",
+    ))
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_tos_is_applied_to_listener_and_streams() {
+        // CS1, the "lower effort" class background traffic is meant to use
+        let tos = 8 << 2;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        set_socket_tos(SockRef::from(&listener), tos).unwrap();
+        assert_eq!(SockRef::from(&listener).tos_v4().unwrap(), tos as u32);
+
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        set_socket_tos(SockRef::from(&stream), tos).unwrap();
+        assert_eq!(SockRef::from(&stream).tos_v4().unwrap(), tos as u32);
+    }
+}
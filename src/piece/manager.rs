@@ -1,57 +1,214 @@
 use super::{PieceInfo, PieceState, BLOCK_SIZE};
 use crate::error::{BittorrentError, Result};
-use crate::torrent::Pieces;
+use crate::torrent::{FileInfo, TorrentInfo};
 use sha1::{Digest, Sha1};
 use std::collections::HashMap;
-use tracing::{debug, info, warn};
+use std::time::{Duration, Instant};
+use tracing::{debug, trace, warn};
+
+/// Data for a piece being downloaded, hashed as it arrives
+struct PieceBuffer {
+    data: Vec<u8>,
+    /// SHA1 of `data[..hashed]`, or None once a block arrived out of order
+    /// and the whole buffer has to be hashed at the end
+    hasher: Option<Sha1>,
+    hashed: usize,
+    /// Which blocks have been fully written, for resuming mid-piece
+    received: Vec<bool>,
+    /// When the piece was started
+    started: Instant,
+}
+
+impl PieceBuffer {
+    fn new(length: usize) -> Self {
+        Self {
+            data: vec![0u8; length],
+            hasher: Some(Sha1::new()),
+            hashed: 0,
+            received: vec![false; length.div_ceil(BLOCK_SIZE as usize)],
+            started: Instant::now(),
+        }
+    }
+
+    /// Store a block, feeding it to the rolling hash if it's the next one
+    fn write(&mut self, offset: usize, block: &[u8]) {
+        self.data[offset..offset + block.len()].copy_from_slice(block);
+
+        // Only blocks covered end to end count; a partial one is re-requested
+        let block_size = BLOCK_SIZE as usize;
+        let end = offset + block.len();
+        let first = offset.div_ceil(block_size);
+        for (index, received) in self.received.iter_mut().enumerate().skip(first) {
+            let block_end = ((index + 1) * block_size).min(self.data.len());
+            if block_end > end {
+                break;
+            }
+            *received = true;
+        }
+
+        match &mut self.hasher {
+            Some(hasher) if offset == self.hashed => {
+                hasher.update(block);
+                self.hashed += block.len();
+            }
+            Some(_) => {
+                trace!(
+                    "Block at offset {} arrived out of order, hashing at completion",
+                    offset
+                );
+                self.hasher = None;
+            }
+            None => {}
+        }
+    }
+
+    /// Bytes of `offset..offset + length` that lie in already received blocks
+    fn received_overlap(&self, offset: usize, length: usize) -> u64 {
+        let block_size = BLOCK_SIZE as usize;
+        let end = offset + length;
+        self.received
+            .iter()
+            .enumerate()
+            .filter(|(_, &received)| received)
+            .map(|(index, _)| {
+                let start = (index * block_size).max(offset);
+                let stop = ((index + 1) * block_size).min(self.data.len()).min(end);
+                stop.saturating_sub(start) as u64
+            })
+            .sum()
+    }
+
+    /// SHA1 of the whole piece, reusing the rolling hash when it covers it
+    fn digest(self) -> ([u8; 20], Vec<u8>) {
+        let digest = match self.hasher {
+            Some(hasher) if self.hashed == self.data.len() => hasher.finalize(),
+            _ => Sha1::digest(&self.data),
+        };
+        (digest.into(), self.data)
+    }
+}
+
+/// A downloaded piece taken out of the manager to be hashed, so the hash
+/// can run without holding the manager
+pub struct PendingPiece {
+    index: usize,
+    buffer: PieceBuffer,
+    expected: [u8; 20],
+    elapsed: Duration,
+}
+
+impl PendingPiece {
+    /// Hash the piece. CPU-bound for large pieces, so async callers may
+    /// want it on a blocking thread
+    pub fn hash(self) -> HashedPiece {
+        let (digest, data) = self.buffer.digest();
+        HashedPiece {
+            index: self.index,
+            valid: digest == self.expected,
+            data,
+            elapsed: self.elapsed,
+        }
+    }
+}
+
+/// A piece whose hash has been checked, for [`PieceManager::finish_piece`]
+#[derive(Debug)]
+pub struct HashedPiece {
+    index: usize,
+    valid: bool,
+    data: Vec<u8>,
+    elapsed: Duration,
+}
 
 /// Manages piece download and verification
 pub struct PieceManager {
     piece_length: u64,
+    #[allow(dead_code, reason = "piece lengths already account for the last one")]
     total_length: u64,
     pieces: Vec<PieceInfo>,
     /// In-progress piece data
-    downloading: HashMap<usize, Vec<u8>>,
+    downloading: HashMap<usize, PieceBuffer>,
+    /// Bytes currently allocated for in-progress pieces
+    inflight_bytes: u64,
+    /// Upper bound on `inflight_bytes` (None = unlimited)
+    max_inflight_bytes: Option<u64>,
+    /// Bytes of pieces downloaded this session, whether or not they verified
+    session_bytes: u64,
+    /// Stop starting pieces once this many bytes are downloaded or in flight
+    download_quota: Option<u64>,
+    /// Block data received this session, including data later thrown away
+    received_bytes: u64,
+    /// Received bytes that never counted toward completion: duplicate
+    /// blocks, pieces that failed verification and abandoned pieces
+    wasted_bytes: u64,
+    /// Blocks kept from a previous session, used when the piece next starts
+    restored: HashMap<usize, (Vec<bool>, Vec<u8>)>,
+    /// Time from starting to verifying each piece completed this session
+    download_times: HashMap<usize, Duration>,
+    /// Hash completed pieces on tokio's blocking pool
+    offload_hashing: bool,
 }
 
 impl PieceManager {
-    pub fn new(piece_length: u64, total_length: u64, piece_hashes: &Pieces) -> Self {
-        let num_pieces = piece_hashes.len();
-        let mut pieces = Vec::with_capacity(num_pieces);
-
-        for (index, hash) in piece_hashes.iter().enumerate() {
-            let length = if index == num_pieces - 1 {
-                // Last piece might be smaller
-                let remainder = total_length % piece_length;
-                if remainder == 0 {
-                    piece_length
-                } else {
-                    remainder
-                }
-            } else {
-                piece_length
-            };
-
-            pieces.push(PieceInfo {
+    pub fn new(info: &TorrentInfo) -> Self {
+        let pieces = info
+            .pieces
+            .iter()
+            .enumerate()
+            .map(|(index, hash)| PieceInfo {
                 index,
-                length,
+                length: info.piece_size(index),
                 state: PieceState::Missing,
-                hash: *hash.as_bytes(),
-            });
-        }
+                hash: *hash,
+            })
+            .collect();
 
         Self {
-            piece_length,
-            total_length,
+            piece_length: info.piece_length,
+            total_length: info.total_length,
             pieces,
             downloading: HashMap::new(),
+            inflight_bytes: 0,
+            max_inflight_bytes: None,
+            session_bytes: 0,
+            download_quota: None,
+            received_bytes: 0,
+            wasted_bytes: 0,
+            restored: HashMap::new(),
+            download_times: HashMap::new(),
+            offload_hashing: false,
         }
     }
 
+    /// Limit the memory used by in-progress piece buffers
+    pub fn with_max_inflight_bytes(mut self, max_inflight_bytes: Option<u64>) -> Self {
+        self.max_inflight_bytes = max_inflight_bytes;
+        self
+    }
+
+    /// Cap the bytes downloaded this session (None = unlimited)
+    pub fn with_download_quota(mut self, download_quota: Option<u64>) -> Self {
+        self.download_quota = download_quota;
+        self
+    }
+
+    /// Ask async callers to hash completed pieces on a blocking thread,
+    /// via [`PieceManager::take_piece`], instead of on their own task
+    pub fn with_offload_hashing(mut self, offload_hashing: bool) -> Self {
+        self.offload_hashing = offload_hashing;
+        self
+    }
+
+    pub fn offloads_hashing(&self) -> bool {
+        self.offload_hashing
+    }
+
     /// Start downloading a piece
     pub fn start_piece(&mut self, piece_index: usize) -> Result<()> {
         if piece_index >= self.pieces.len() {
-            return Err(BittorrentError::PieceError("Invalid piece index".to_string()));
+            return Err(BittorrentError::PieceError(
+                "Invalid piece index".to_string(),
+            ));
         }
 
         let piece = &mut self.pieces[piece_index];
@@ -61,8 +218,30 @@ impl PieceManager {
             ));
         }
 
+        // A single piece is always allowed so an undersized budget can't stall us
+        if let Some(max) = self.max_inflight_bytes {
+            if self.inflight_bytes > 0 && self.inflight_bytes + piece.length > max {
+                return Err(BittorrentError::MemoryBudgetExceeded(format!(
+                    "{} bytes in flight, piece {} needs {} more (limit {})",
+                    self.inflight_bytes, piece_index, piece.length, max
+                )));
+            }
+        }
+
         piece.state = PieceState::Downloading;
-        self.downloading.insert(piece_index, vec![0u8; piece.length as usize]);
+        self.inflight_bytes += piece.length;
+
+        let mut buffer = PieceBuffer::new(piece.length as usize);
+        if let Some((received, data)) = self.restored.remove(&piece_index) {
+            // In order, so the rolling hash still covers a leading run
+            for (block_index, _) in received.iter().enumerate().filter(|(_, r)| **r) {
+                let start = block_index * BLOCK_SIZE as usize;
+                let end = (start + BLOCK_SIZE as usize).min(data.len());
+                buffer.write(start, &data[start..end]);
+            }
+            debug!("Resuming piece {} from saved blocks", piece_index);
+        }
+        self.downloading.insert(piece_index, buffer);
 
         debug!("Started downloading piece {}", piece_index);
         Ok(())
@@ -70,18 +249,23 @@ impl PieceManager {
 
     /// Add a block to a piece
     pub fn add_block(&mut self, piece_index: usize, offset: u32, data: &[u8]) -> Result<()> {
-        let piece_data = self.downloading.get_mut(&piece_index).ok_or_else(|| {
-            BittorrentError::PieceError("Piece not being downloaded".to_string())
-        })?;
+        let buffer = self
+            .downloading
+            .get_mut(&piece_index)
+            .ok_or_else(|| BittorrentError::PieceError("Piece not being downloaded".to_string()))?;
 
         let offset = offset as usize;
-        if offset + data.len() > piece_data.len() {
-            return Err(BittorrentError::PieceError("Block exceeds piece size".to_string()));
+        if offset + data.len() > buffer.data.len() {
+            return Err(BittorrentError::PieceError(
+                "Block exceeds piece size".to_string(),
+            ));
         }
 
-        piece_data[offset..offset + data.len()].copy_from_slice(data);
+        self.received_bytes += data.len() as u64;
+        self.wasted_bytes += buffer.received_overlap(offset, data.len());
+        buffer.write(offset, data);
 
-        debug!(
+        trace!(
             "Added block to piece {} at offset {} ({} bytes)",
             piece_index,
             offset,
@@ -93,29 +277,184 @@ impl PieceManager {
 
     /// Verify and complete a piece
     pub fn complete_piece(&mut self, piece_index: usize) -> Result<Vec<u8>> {
-        let piece_data = self.downloading.remove(&piece_index).ok_or_else(|| {
-            BittorrentError::PieceError("Piece not being downloaded".to_string())
-        })?;
-
-        let piece = &self.pieces[piece_index];
+        let pending = self.take_piece(piece_index)?;
+        self.finish_piece(pending.hash())
+    }
 
-        // Verify SHA1 hash
-        let mut hasher = Sha1::new();
-        hasher.update(&piece_data);
-        let hash = hasher.finalize();
+    /// Take a fully downloaded piece out to be hashed
+    ///
+    /// The piece stays Downloading, so it isn't picked again, until the
+    /// result is handed to [`PieceManager::finish_piece`].
+    pub fn take_piece(&mut self, piece_index: usize) -> Result<PendingPiece> {
+        let buffer = self
+            .downloading
+            .remove(&piece_index)
+            .ok_or_else(|| BittorrentError::PieceError("Piece not being downloaded".to_string()))?;
+        self.inflight_bytes -= buffer.data.len() as u64;
+        self.session_bytes += buffer.data.len() as u64;
+
+        Ok(PendingPiece {
+            index: piece_index,
+            elapsed: buffer.started.elapsed(),
+            buffer,
+            expected: *self.pieces[piece_index].hash.as_bytes(),
+        })
+    }
 
-        if hash.as_slice() != piece.hash {
+    /// Mark a hashed piece complete, or missing again if it failed
+    pub fn finish_piece(&mut self, piece: HashedPiece) -> Result<Vec<u8>> {
+        let piece_index = piece.index;
+        if !piece.valid {
             warn!("Piece {} failed verification", piece_index);
+            self.wasted_bytes += piece.data.len() as u64;
             self.pieces[piece_index].state = PieceState::Missing;
-            return Err(BittorrentError::PieceError(
-                "Piece hash verification failed".to_string(),
-            ));
+            return Err(BittorrentError::HashMismatch(format!(
+                "piece {}",
+                piece_index
+            )));
         }
 
         self.pieces[piece_index].state = PieceState::Complete;
-        info!("Piece {} verified and complete", piece_index);
+        self.download_times.insert(piece_index, piece.elapsed);
+        debug!(
+            "Piece {} verified and complete in {:?}",
+            piece_index, piece.elapsed
+        );
+
+        Ok(piece.data)
+    }
+
+    /// Give up on a piece taken by [`PieceManager::take_piece`] that will
+    /// never be finished
+    pub fn abandon_piece(&mut self, piece_index: usize) {
+        if self.pieces[piece_index].state == PieceState::Downloading
+            && !self.downloading.contains_key(&piece_index)
+        {
+            self.pieces[piece_index].state = PieceState::Missing;
+        }
+    }
+
+    /// Mark a verified piece missing again, as when storing its data failed
+    pub fn forget_piece(&mut self, piece_index: usize) {
+        if let Some(piece) = self.pieces.get_mut(piece_index) {
+            if piece.state == PieceState::Complete {
+                piece.state = PieceState::Missing;
+                self.download_times.remove(&piece_index);
+                debug!("Forgot piece {}", piece_index);
+            }
+        }
+    }
+
+    /// How long a piece completed this session took from `start_piece` to
+    /// passing verification
+    pub fn download_time(&self, piece_index: usize) -> Option<Duration> {
+        self.download_times.get(&piece_index).copied()
+    }
+
+    /// Abandon an in-progress piece so it can be picked again
+    pub fn reset_piece(&mut self, piece_index: usize) {
+        if let Some(buffer) = self.downloading.remove(&piece_index) {
+            self.inflight_bytes -= buffer.data.len() as u64;
+            self.wasted_bytes += buffer.received_overlap(0, buffer.data.len());
+            self.pieces[piece_index].state = PieceState::Missing;
+            debug!("Reset piece {}", piece_index);
+        }
+    }
+
+    /// Blocks of a piece already held, or None if it isn't downloading
+    pub fn received_blocks(&self, piece_index: usize) -> Option<&[bool]> {
+        self.downloading
+            .get(&piece_index)
+            .map(|buffer| buffer.received.as_slice())
+    }
+
+    /// In-progress pieces with at least one block, with their data
+    pub fn partial_pieces(&self) -> Vec<(usize, Vec<bool>, Vec<u8>)> {
+        let mut partial: Vec<_> = self
+            .downloading
+            .iter()
+            .filter(|(_, buffer)| buffer.received.contains(&true))
+            .map(|(index, buffer)| (*index, buffer.received.clone(), buffer.data.clone()))
+            .collect();
+        partial.sort_by_key(|(index, _, _)| *index);
+        partial
+    }
+
+    /// Keep blocks saved by a previous session for when the piece is started
+    ///
+    /// `data` is the whole piece as read back from disk; only the blocks
+    /// flagged in `received` are trusted. The piece is still hash-checked
+    /// once the rest arrives.
+    pub fn restore_partial(
+        &mut self,
+        piece_index: usize,
+        received: Vec<bool>,
+        data: Vec<u8>,
+    ) -> Result<()> {
+        let piece = self
+            .pieces
+            .get(piece_index)
+            .ok_or_else(|| BittorrentError::PieceError("Invalid piece index".to_string()))?;
+        if piece.state != PieceState::Missing
+            || data.len() as u64 != piece.length
+            || received.len() != self.blocks_in_piece(piece_index)
+        {
+            return Err(BittorrentError::PieceError(format!(
+                "Saved blocks don't fit piece {}",
+                piece_index
+            )));
+        }
+
+        self.restored.insert(piece_index, (received, data));
+        Ok(())
+    }
 
-        Ok(piece_data)
+    /// Mark a piece found intact on disk as complete
+    pub fn mark_verified(&mut self, piece_index: usize) {
+        if let Some(piece) = self.pieces.get_mut(piece_index) {
+            if piece.state == PieceState::Missing {
+                piece.state = PieceState::Complete;
+            }
+        }
+    }
+
+    /// Which pieces are complete, by index
+    pub fn completed_pieces(&self) -> Vec<bool> {
+        self.pieces
+            .iter()
+            .map(|p| p.state == PieceState::Complete)
+            .collect()
+    }
+
+    /// Bytes of each file that lie in verified pieces
+    ///
+    /// A piece straddling two files counts toward both once verified, and
+    /// toward neither before, so a file is only reported complete when every
+    /// byte of it has been checked.
+    pub fn file_completed_bytes(&self, files: &[FileInfo]) -> Vec<u64> {
+        let mut file_start = 0;
+
+        files
+            .iter()
+            .map(|file| {
+                let file_end = file_start + file.length;
+                let first_piece = (file_start / self.piece_length) as usize;
+
+                let completed = self.pieces[first_piece.min(self.pieces.len())..]
+                    .iter()
+                    .take_while(|piece| (piece.index as u64) * self.piece_length < file_end)
+                    .filter(|piece| piece.state == PieceState::Complete)
+                    .map(|piece| {
+                        let piece_start = piece.index as u64 * self.piece_length;
+                        let piece_end = piece_start + piece.length;
+                        piece_end.min(file_end) - piece_start.max(file_start)
+                    })
+                    .sum();
+
+                file_start = file_end;
+                completed
+            })
+            .collect()
     }
 
     /// Get the number of blocks in a piece
@@ -125,7 +464,7 @@ impl PieceManager {
         }
 
         let piece_length = self.pieces[piece_index].length;
-        ((piece_length + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64) as usize
+        piece_length.div_ceil(BLOCK_SIZE as u64) as usize
     }
 
     /// Get block info for a piece
@@ -141,20 +480,64 @@ impl PieceManager {
             return None;
         }
 
-        let length = std::cmp::min(
-            BLOCK_SIZE,
-            piece.length as u32 - offset,
-        );
+        let length = std::cmp::min(BLOCK_SIZE, piece.length as u32 - offset);
 
         Some((offset, length))
     }
 
+    /// Get the length of a piece in bytes
+    #[cfg(test)]
+    pub fn piece_size(&self, piece_index: usize) -> Option<u64> {
+        self.pieces.get(piece_index).map(|p| p.length)
+    }
+
+    /// Bytes currently allocated for in-progress pieces
+    #[cfg(test)]
+    pub fn inflight_bytes(&self) -> u64 {
+        self.inflight_bytes
+    }
+
+    /// Bytes of pieces downloaded this session, including ones that
+    /// failed verification
+    pub fn session_bytes(&self) -> u64 {
+        self.session_bytes
+    }
+
+    /// Block data received this session, wasted or not
+    pub fn received_bytes(&self) -> u64 {
+        self.received_bytes
+    }
+
+    /// Received bytes that were thrown away rather than kept
+    pub fn wasted_bytes(&self) -> u64 {
+        self.wasted_bytes
+    }
+
+    /// Whether the download quota is used up, counting pieces in flight,
+    /// so no new piece should be started
+    pub fn quota_reached(&self) -> bool {
+        self.download_quota
+            .is_some_and(|quota| self.session_bytes + self.inflight_bytes >= quota)
+    }
+
     pub fn piece_count(&self) -> usize {
         self.pieces.len()
     }
 
+    /// Bytes in verified pieces
+    pub fn completed_bytes(&self) -> u64 {
+        self.pieces
+            .iter()
+            .filter(|p| p.state == PieceState::Complete)
+            .map(|p| p.length)
+            .sum()
+    }
+
     pub fn complete_count(&self) -> usize {
-        self.pieces.iter().filter(|p| p.state == PieceState::Complete).count()
+        self.pieces
+            .iter()
+            .filter(|p| p.state == PieceState::Complete)
+            .count()
     }
 
     pub fn progress(&self) -> f64 {
@@ -169,3 +552,309 @@ impl PieceManager {
         self.pieces.get(piece_index).map(|p| p.state)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::make_info;
+
+    fn manager(piece_length: u64, total_length: u64) -> PieceManager {
+        PieceManager::new(&make_info(piece_length, total_length))
+    }
+
+    #[test]
+    fn test_last_piece_exact_multiple() {
+        let pm = manager(16384, 16384 * 3);
+        assert_eq!(pm.piece_size(0), Some(16384));
+        assert_eq!(pm.piece_size(2), Some(16384));
+    }
+
+    #[test]
+    fn test_last_piece_smaller_than_piece_length() {
+        let pm = manager(16384, 1000);
+        assert_eq!(pm.piece_size(0), Some(1000));
+        assert_eq!(pm.blocks_in_piece(0), 1);
+    }
+
+    #[test]
+    fn test_last_piece_partial() {
+        let pm = manager(16384, 16384 * 2 + 100);
+        assert_eq!(pm.piece_size(1), Some(16384));
+        assert_eq!(pm.piece_size(2), Some(100));
+        assert_eq!(pm.get_block_info(2, 0), Some((0, 100)));
+    }
+
+    #[test]
+    fn test_saved_blocks_are_restored_and_verified() {
+        let content = crate::testutil::random_content(16384 * 3);
+        let mut info = make_info(16384 * 3, 16384 * 3);
+        info.pieces = crate::torrent::Pieces::from_bytes(&Sha1::digest(&content)).unwrap();
+
+        let mut pm = PieceManager::new(&info);
+        pm.start_piece(0).unwrap();
+        // Covers block 1 entirely but only the tail of block 0
+        pm.add_block(0, 100, &content[100..32768]).unwrap();
+        assert_eq!(pm.received_blocks(0), Some(&[false, true, false][..]));
+        let (index, received, data) = pm.partial_pieces().remove(0);
+        assert_eq!(index, 0);
+
+        let mut restarted = PieceManager::new(&info);
+        restarted.restore_partial(0, received, data).unwrap();
+        restarted.start_piece(0).unwrap();
+        assert_eq!(
+            restarted.received_blocks(0),
+            Some(&[false, true, false][..])
+        );
+        restarted.add_block(0, 0, &content[..16384]).unwrap();
+        restarted.add_block(0, 32768, &content[32768..]).unwrap();
+        assert_eq!(restarted.complete_piece(0).unwrap(), content);
+
+        // Saved blocks that don't match the piece's layout are refused
+        assert!(restarted
+            .restore_partial(0, vec![true], vec![0; 10])
+            .is_err());
+    }
+
+    #[test]
+    fn test_redownloaded_piece_counts_as_wasted_not_verified() {
+        let content = crate::testutil::random_content(32768);
+        let mut info = make_info(32768, 32768);
+        info.pieces = crate::torrent::Pieces::from_bytes(&Sha1::digest(&content)).unwrap();
+        let mut pm = PieceManager::new(&info);
+
+        // A corrupt copy is received in full, then thrown away
+        pm.start_piece(0).unwrap();
+        pm.add_block(0, 0, &[0u8; 32768]).unwrap();
+        assert!(pm.complete_piece(0).is_err());
+        assert_eq!(pm.received_bytes(), 32768);
+        assert_eq!(pm.wasted_bytes(), 32768);
+        assert_eq!(pm.completed_bytes(), 0);
+
+        // The good copy, with its first block arriving twice
+        pm.start_piece(0).unwrap();
+        pm.add_block(0, 0, &content[..16384]).unwrap();
+        pm.add_block(0, 0, &content[..16384]).unwrap();
+        pm.add_block(0, 16384, &content[16384..]).unwrap();
+        pm.complete_piece(0).unwrap();
+        assert_eq!(pm.received_bytes(), 32768 * 2 + 16384);
+        assert_eq!(pm.wasted_bytes(), 32768 + 16384);
+        assert_eq!(pm.completed_bytes(), 32768);
+
+        // Abandoning a piece wastes what it had received
+        let mut pm = PieceManager::new(&info);
+        pm.start_piece(0).unwrap();
+        pm.add_block(0, 0, &content[..16384]).unwrap();
+        pm.reset_piece(0);
+        assert_eq!(pm.wasted_bytes(), 16384);
+    }
+
+    #[test]
+    fn test_memory_budget_caps_start_piece() {
+        let mut pm = manager(1000, 5000).with_max_inflight_bytes(Some(2500));
+
+        pm.start_piece(0).unwrap();
+        pm.start_piece(1).unwrap();
+        assert!(matches!(
+            pm.start_piece(2),
+            Err(BittorrentError::MemoryBudgetExceeded(_))
+        ));
+        assert_eq!(pm.inflight_bytes(), 2000);
+        assert_eq!(pm.get_piece_state(2), Some(PieceState::Missing));
+    }
+
+    #[test]
+    fn test_single_piece_allowed_over_budget() {
+        let mut pm = manager(1000, 2000).with_max_inflight_bytes(Some(500));
+        pm.start_piece(0).unwrap();
+        assert!(pm.start_piece(1).is_err());
+    }
+
+    #[test]
+    fn test_reset_piece_frees_buffer() {
+        let mut pm = manager(1000, 3000).with_max_inflight_bytes(Some(1000));
+        pm.start_piece(1).unwrap();
+        pm.add_block(1, 0, &[7u8; 100]).unwrap();
+
+        pm.reset_piece(1);
+        assert_eq!(pm.get_piece_state(1), Some(PieceState::Missing));
+        assert_eq!(pm.inflight_bytes(), 0);
+        assert!(pm.add_block(1, 0, &[7u8; 100]).is_err());
+
+        // The freed budget lets another piece start
+        pm.start_piece(2).unwrap();
+        pm.start_piece(1).unwrap_err();
+    }
+
+    #[test]
+    fn test_quota_counts_inflight_and_downloaded_bytes() {
+        let mut pm = manager(1000, 5000).with_download_quota(Some(2500));
+        pm.start_piece(0).unwrap();
+        pm.start_piece(1).unwrap();
+        assert!(!pm.quota_reached());
+
+        pm.start_piece(2).unwrap();
+        assert!(pm.quota_reached());
+
+        // Abandoned pieces give their share back; failed ones don't
+        pm.reset_piece(2);
+        assert!(!pm.quota_reached());
+        pm.complete_piece(0).unwrap_err();
+        assert_eq!(pm.session_bytes(), 1000);
+    }
+
+    /// Manager for one 3-block piece of `content`
+    fn hashed_manager(content: &[u8]) -> PieceManager {
+        let mut info = make_info(BLOCK_SIZE as u64 * 3, content.len() as u64);
+        info.pieces = crate::torrent::Pieces::from_bytes(&Sha1::digest(content)).unwrap();
+        let mut pm = PieceManager::new(&info);
+        pm.start_piece(0).unwrap();
+        pm
+    }
+
+    #[test]
+    fn test_rolling_and_fallback_hashes_agree() {
+        let content = crate::testutil::random_content(BLOCK_SIZE as usize * 3 - 100);
+        let blocks: Vec<(u32, &[u8])> = content
+            .chunks(BLOCK_SIZE as usize)
+            .enumerate()
+            .map(|(i, block)| (i as u32 * BLOCK_SIZE, block))
+            .collect();
+
+        // In order: the rolling hash covers the whole piece
+        let mut pm = hashed_manager(&content);
+        for &(offset, block) in &blocks {
+            pm.add_block(0, offset, block).unwrap();
+        }
+        assert!(pm.downloading[&0].hasher.is_some());
+        assert_eq!(pm.complete_piece(0).unwrap(), content);
+
+        // Out of order: falls back to hashing the buffer
+        let mut pm = hashed_manager(&content);
+        for &(offset, block) in blocks.iter().rev() {
+            pm.add_block(0, offset, block).unwrap();
+        }
+        assert!(pm.downloading[&0].hasher.is_none());
+        assert_eq!(pm.complete_piece(0).unwrap(), content);
+
+        // A block rewritten after being hashed also falls back
+        let mut pm = hashed_manager(&content);
+        pm.add_block(0, 0, &vec![0u8; BLOCK_SIZE as usize]).unwrap();
+        for &(offset, block) in &blocks {
+            pm.add_block(0, offset, block).unwrap();
+        }
+        assert_eq!(pm.complete_piece(0).unwrap(), content);
+    }
+
+    #[test]
+    fn test_rolling_hash_rejects_bad_data() {
+        let content = crate::testutil::random_content(BLOCK_SIZE as usize * 3);
+        let mut pm = hashed_manager(&content);
+        for (i, block) in content.chunks(BLOCK_SIZE as usize).enumerate() {
+            let mut block = block.to_vec();
+            block[0] ^= (i == 1) as u8;
+            pm.add_block(0, i as u32 * BLOCK_SIZE, &block).unwrap();
+        }
+        assert!(pm.complete_piece(0).is_err());
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+    }
+
+    #[test]
+    fn test_download_time_is_recorded_for_verified_pieces() {
+        let content = crate::testutil::random_content(BLOCK_SIZE as usize * 2);
+        let mut pm = hashed_manager(&content);
+        assert_eq!(pm.download_time(0), None);
+
+        std::thread::sleep(Duration::from_millis(20));
+        pm.add_block(0, 0, &content).unwrap();
+        pm.complete_piece(0).unwrap();
+        assert!(pm.download_time(0).unwrap() >= Duration::from_millis(20));
+
+        // Pieces that fail verification aren't timed
+        let mut pm = hashed_manager(&content);
+        pm.add_block(0, 0, &[0u8; BLOCK_SIZE as usize * 2]).unwrap();
+        pm.complete_piece(0).unwrap_err();
+        assert_eq!(pm.download_time(0), None);
+    }
+
+    #[test]
+    fn test_taken_piece_is_finished_after_hashing() {
+        let content = crate::testutil::random_content(BLOCK_SIZE as usize * 2);
+        let mut pm = hashed_manager(&content);
+        pm.add_block(0, 0, &content).unwrap();
+
+        // Out of the manager but not up for picking while it hashes
+        let pending = pm.take_piece(0).unwrap();
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Downloading));
+        assert_eq!(pm.inflight_bytes(), 0);
+        assert!(pm.take_piece(0).is_err());
+
+        let hashed = std::thread::spawn(move || pending.hash()).join().unwrap();
+        assert_eq!(pm.finish_piece(hashed).unwrap(), content);
+        assert!(pm.is_complete());
+
+        let mut pm = hashed_manager(&content);
+        pm.add_block(0, 0, &[0u8; BLOCK_SIZE as usize * 2]).unwrap();
+        let hashed = pm.take_piece(0).unwrap().hash();
+        assert!(matches!(
+            pm.finish_piece(hashed),
+            Err(BittorrentError::HashMismatch(_))
+        ));
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+        assert_eq!(pm.wasted_bytes(), BLOCK_SIZE as u64 * 2);
+
+        // A piece whose hash never comes back can be picked again
+        let mut pm = hashed_manager(&content);
+        pm.add_block(0, 0, &content).unwrap();
+        drop(pm.take_piece(0).unwrap());
+        pm.abandon_piece(0);
+        assert_eq!(pm.get_piece_state(0), Some(PieceState::Missing));
+    }
+
+    #[test]
+    fn test_file_completed_bytes_with_middle_file_complete() {
+        // Files at [0, 10000), [10000, 30000), [30000, 40000) in 8 KiB pieces
+        let mut info = make_info(8192, 40000);
+        info.files = [("a", 10000), ("b", 20000), ("c", 10000)]
+            .into_iter()
+            .map(|(name, length)| FileInfo {
+                path: vec![name.to_string()],
+                length,
+            })
+            .collect();
+        let mut pm = PieceManager::new(&info);
+
+        // Pieces 1..=3 cover all of "b" and the edges of "a" and "c"
+        for index in 1..=3 {
+            pm.mark_verified(index);
+        }
+
+        assert_eq!(
+            pm.file_completed_bytes(&info.files),
+            vec![10000 - 8192, 20000, 32768 - 30000]
+        );
+
+        pm.mark_verified(0);
+        pm.mark_verified(4);
+        assert_eq!(
+            pm.file_completed_bytes(&info.files),
+            vec![10000, 20000, 10000]
+        );
+    }
+
+    #[test]
+    fn test_file_completed_bytes_with_empty_file() {
+        let mut info = make_info(8192, 8192);
+        info.files = vec![
+            FileInfo {
+                path: vec!["empty".to_string()],
+                length: 0,
+            },
+            FileInfo {
+                path: vec!["data".to_string()],
+                length: 8192,
+            },
+        ];
+        let pm = PieceManager::new(&info);
+        assert_eq!(pm.file_completed_bytes(&info.files), vec![0, 0]);
+    }
+}
@@ -1,8 +1,15 @@
 mod manager;
 mod picker;
+mod super_seed;
 
 pub use manager::PieceManager;
-pub use picker::PiecePicker;
+pub use picker::{
+    PickStrategy, PiecePicker, DEFAULT_ENDGAME_MIN_PIECES, DEFAULT_ENDGAME_RATIO,
+    DEFAULT_MAX_CONCURRENT_PIECES, DEFAULT_RANDOM_FIRST_PIECES,
+};
+pub use super_seed::SuperSeeder;
+
+use crate::torrent::PieceHash;
 
 /// Standard block size (16 KB)
 pub const BLOCK_SIZE: u32 = 16 * 1024;
@@ -24,5 +31,5 @@ pub struct PieceInfo {
     pub index: usize,
     pub length: u64,
     pub state: PieceState,
-    pub hash: [u8; 20],
+    pub hash: PieceHash,
 }
@@ -1,5 +1,59 @@
 use super::PieceState;
 use rand::seq::SliceRandom;
+use std::time::{Duration, Instant};
+
+/// Every Nth pick goes to the rarest piece, even during random-first
+const RAREST_PICK_INTERVAL: usize = 4;
+
+/// A piece's effective availability drops by one for every interval since
+/// a peer first offered it, so pieces left waiting win eventually
+const STARVATION_BOOST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Default share of all pieces that may remain when endgame starts
+pub const DEFAULT_ENDGAME_RATIO: f64 = 0.02;
+
+/// Default number of remaining pieces that always triggers endgame
+pub const DEFAULT_ENDGAME_MIN_PIECES: usize = 5;
+
+/// Default number of pieces fetched at random before switching to
+/// rarest-first
+pub const DEFAULT_RANDOM_FIRST_PIECES: usize = 4;
+
+/// Default cap on pieces being assembled at once, well above what the
+/// default peer count keeps busy
+pub const DEFAULT_MAX_CONCURRENT_PIECES: usize = 128;
+
+/// How the next piece to download is chosen
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PickStrategy {
+    /// Lowest index first, so a file can be played while it downloads
+    Sequential,
+    /// Least available first, to keep rare pieces alive in the swarm
+    Rarest,
+    /// Random until a few pieces are complete, so there is something to
+    /// trade quickly, then rarest-first
+    #[default]
+    RandomFirstThenRarest,
+    /// Uniformly random, mostly useful for testing swarm behavior
+    Random,
+}
+
+impl std::str::FromStr for PickStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sequential" => Ok(Self::Sequential),
+            "rarest" => Ok(Self::Rarest),
+            "random-first" => Ok(Self::RandomFirstThenRarest),
+            "random" => Ok(Self::Random),
+            _ => Err(format!(
+                "unknown piece strategy \"{}\" (expected sequential, rarest, random-first or random)",
+                s
+            )),
+        }
+    }
+}
 
 /// Selects which pieces to download next
 pub struct PiecePicker {
@@ -7,12 +61,30 @@ pub struct PiecePicker {
     piece_states: Vec<PieceState>,
     /// Tracks how many peers have each piece (for rarest-first)
     piece_availability: Vec<u32>,
-    /// Whether to use random first piece strategy
-    random_first: bool,
+    strategy: PickStrategy,
+    /// Pieces fetched at random before `RandomFirstThenRarest` switches
+    random_first_pieces: usize,
     /// Number of pieces downloaded (for switching strategies)
     downloaded_count: usize,
     /// Whether we're in endgame mode
     endgame_mode: bool,
+    /// Enter endgame once at most this share of pieces is missing...
+    endgame_ratio: f64,
+    /// ...or this many, whichever is larger
+    endgame_min_pieces: usize,
+    /// When some peer first had each piece, for starvation boosting
+    available_since: Vec<Option<Instant>>,
+    /// Number of picks made, for reserving rarest-first picks
+    picks: usize,
+    /// Pieces picked ahead of all others, e.g. file boundaries for preview
+    boosted: Vec<bool>,
+    /// Most pieces being downloaded at once (None = unlimited)
+    max_concurrent_pieces: Option<usize>,
+    /// Pieces set aside because the peer they were picked for lacks them,
+    /// left until endgame
+    deferred: Vec<bool>,
+    /// Hand out no pieces at all until resumed
+    paused: bool,
 }
 
 impl PiecePicker {
@@ -21,22 +93,124 @@ impl PiecePicker {
             total_pieces,
             piece_states: vec![PieceState::Missing; total_pieces],
             piece_availability: vec![0; total_pieces],
-            random_first: true,
+            strategy: PickStrategy::default(),
+            random_first_pieces: DEFAULT_RANDOM_FIRST_PIECES,
             downloaded_count: 0,
             endgame_mode: false,
+            endgame_ratio: DEFAULT_ENDGAME_RATIO,
+            endgame_min_pieces: DEFAULT_ENDGAME_MIN_PIECES,
+            available_since: vec![None; total_pieces],
+            picks: 0,
+            boosted: vec![false; total_pieces],
+            max_concurrent_pieces: None,
+            deferred: vec![false; total_pieces],
+            paused: false,
+        }
+    }
+
+    /// Start no new piece while `limit` are being downloaded
+    pub fn with_max_concurrent_pieces(mut self, limit: Option<usize>) -> Self {
+        self.max_concurrent_pieces = limit.map(|limit| limit.max(1));
+        self
+    }
+
+    /// Enter endgame once at most `ratio` of all pieces, or `min_pieces`,
+    /// whichever is larger, are still missing
+    pub fn with_endgame_threshold(mut self, ratio: f64, min_pieces: usize) -> Self {
+        self.endgame_ratio = ratio.clamp(0.0, 1.0);
+        self.endgame_min_pieces = min_pieces;
+        self
+    }
+
+    /// Choose pieces with `strategy`; `random_first_pieces` only matters
+    /// for [`PickStrategy::RandomFirstThenRarest`]
+    pub fn with_strategy(mut self, strategy: PickStrategy, random_first_pieces: usize) -> Self {
+        self.strategy = strategy;
+        self.random_first_pieces = random_first_pieces;
+        self
+    }
+
+    /// Stop or start handing out pieces; piece states are kept either way
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Pick these pieces before any other, in the order the strategy
+    /// prefers among them
+    pub fn boost_pieces(&mut self, pieces: impl IntoIterator<Item = usize>) {
+        for piece_index in pieces {
+            if let Some(boosted) = self.boosted.get_mut(piece_index) {
+                *boosted = true;
+            }
         }
     }
 
+    /// Number of missing pieces at or below which endgame starts
+    pub fn endgame_threshold(&self) -> usize {
+        let scaled = (self.total_pieces as f64 * self.endgame_ratio).ceil() as usize;
+        scaled.max(self.endgame_min_pieces)
+    }
+
     /// Update peer's bitfield
     pub fn update_peer_pieces(&mut self, bitfield: &[u8]) {
+        let now = Instant::now();
         for piece_index in 0..self.total_pieces {
             if self.has_piece_in_bitfield(bitfield, piece_index) {
                 self.piece_availability[piece_index] += 1;
+                self.available_since[piece_index].get_or_insert(now);
             }
         }
     }
 
+    /// Count one more peer offering `piece_index`, announced with Have
+    pub fn add_peer_piece(&mut self, piece_index: usize) {
+        if piece_index < self.total_pieces {
+            self.piece_availability[piece_index] += 1;
+            self.available_since[piece_index].get_or_insert(Instant::now());
+        }
+    }
+
+    /// Stop counting a peer's pieces, once it's gone or sent a new bitfield
+    ///
+    /// Returns the pieces we still need that no connected peer has any more.
+    pub fn remove_peer_pieces(&mut self, bitfield: &[u8]) -> Vec<usize> {
+        let mut lost = Vec::new();
+        for piece_index in 0..self.total_pieces {
+            if !self.has_piece_in_bitfield(bitfield, piece_index) {
+                continue;
+            }
+            let availability = &mut self.piece_availability[piece_index];
+            if *availability == 0 {
+                continue;
+            }
+            *availability -= 1;
+            if *availability == 0 {
+                self.available_since[piece_index] = None;
+                if self.piece_states[piece_index] != PieceState::Complete {
+                    lost.push(piece_index);
+                }
+            }
+        }
+        lost
+    }
+
+    /// Per-piece peer counts
+    pub fn availability(&self) -> &[u32] {
+        &self.piece_availability
+    }
+
+    /// Seed availability from a saved snapshot
+    ///
+    /// Peers that come and go later add to and take from these counts, so
+    /// the snapshot only steers the first picks of a resumed session.
+    pub fn restore_availability(&mut self, availability: &[u32]) {
+        if availability.len() == self.total_pieces {
+            self.piece_availability.copy_from_slice(availability);
+        }
+    }
+
     /// Mark a piece as being downloaded
+    #[allow(dead_code, reason = "`pick_piece` marks the pieces it returns")]
     pub fn mark_downloading(&mut self, piece_index: usize) {
         if piece_index < self.total_pieces {
             self.piece_states[piece_index] = PieceState::Downloading;
@@ -48,31 +222,43 @@ impl PiecePicker {
         if piece_index < self.total_pieces {
             self.piece_states[piece_index] = PieceState::Complete;
             self.downloaded_count += 1;
-
-            // Switch to rarest-first after downloading first 4 pieces
-            if self.downloaded_count >= 4 {
-                self.random_first = false;
-            }
         }
     }
 
     /// Mark a piece as missing (e.g., after failed verification)
+    ///
+    /// Completed pieces stay complete; a redundant endgame attempt failing
+    /// must not undo another peer's success.
     pub fn mark_missing(&mut self, piece_index: usize) {
-        if piece_index < self.total_pieces {
+        if piece_index < self.total_pieces && self.piece_states[piece_index] != PieceState::Complete
+        {
             self.piece_states[piece_index] = PieceState::Missing;
         }
     }
 
-    /// Pick the next piece to download using rarest-first strategy
+    /// Set aside a picked piece the chosen peer turned out not to have
+    ///
+    /// Like an active piece it isn't picked again before endgame, but it
+    /// doesn't count toward the active piece limit.
+    pub fn defer_piece(&mut self, piece_index: usize) {
+        if self.piece_states.get(piece_index) == Some(&PieceState::Downloading) {
+            self.piece_states[piece_index] = PieceState::Missing;
+            self.deferred[piece_index] = true;
+        }
+    }
+
+    /// Pick the next piece to download according to the strategy
     pub fn pick_piece(&mut self, piece_manager: &super::PieceManager) -> Option<usize> {
+        if self.must_wait(piece_manager) {
+            return None;
+        }
+
         // Check if we should enter endgame mode
-        let missing_count = self
-            .piece_states
-            .iter()
-            .filter(|&&s| s == PieceState::Missing)
+        let missing_count = (0..self.total_pieces)
+            .filter(|&idx| self.piece_states[idx] == PieceState::Missing && !self.deferred[idx])
             .count();
 
-        if !self.endgame_mode && missing_count > 0 && missing_count <= 5 {
+        if !self.endgame_mode && missing_count > 0 && missing_count <= self.endgame_threshold() {
             self.endgame_mode = true;
             tracing::info!(
                 "Entering endgame mode with {} pieces remaining",
@@ -94,7 +280,10 @@ impl PiecePicker {
             }
 
             // In endgame mode, allow downloading pieces even if already in progress
-            if !self.endgame_mode && self.piece_states[piece_index] == PieceState::Downloading {
+            if !self.endgame_mode
+                && (self.piece_states[piece_index] == PieceState::Downloading
+                    || self.deferred[piece_index])
+            {
                 continue;
             }
 
@@ -105,16 +294,37 @@ impl PiecePicker {
             return None;
         }
 
-        // Use random first strategy for the first few pieces
-        let selected_piece = if self.random_first {
-            // Random selection
-            let mut rng = rand::thread_rng();
-            *available_pieces.choose(&mut rng)?
-        } else {
-            // Rarest-first strategy
-            available_pieces
+        // Boosted pieces go first, unless no peer has them but some peer
+        // has something else
+        let boosted: Vec<usize> = available_pieces
+            .iter()
+            .copied()
+            .filter(|&idx| self.boosted[idx])
+            .collect();
+        let has = |idx: &usize| self.piece_availability[*idx] > 0;
+        if boosted.iter().any(has) || (!boosted.is_empty() && !available_pieces.iter().any(has)) {
+            available_pieces = boosted;
+        }
+
+        let selected_piece = match self.strategy {
+            // Pieces no peer has yet would only hold up the stream
+            PickStrategy::Sequential => available_pieces
                 .into_iter()
-                .min_by_key(|&idx| self.piece_availability[idx])?
+                .min_by_key(|&idx| (self.piece_availability[idx] == 0, idx))?,
+            PickStrategy::Rarest => self.rarest(available_pieces)?,
+            PickStrategy::Random => *available_pieces.choose(&mut rand::thread_rng())?,
+            PickStrategy::RandomFirstThenRarest => {
+                // Reserve some picks for the rarest piece even while picking
+                // at random, so fast peers can't starve it
+                let reserved = self.picks.is_multiple_of(RAREST_PICK_INTERVAL);
+                self.picks += 1;
+
+                if self.downloaded_count < self.random_first_pieces && !reserved {
+                    *available_pieces.choose(&mut rand::thread_rng())?
+                } else {
+                    self.rarest(available_pieces)?
+                }
+            }
         };
 
         // Mark as downloading and return (except in endgame mode)
@@ -124,12 +334,58 @@ impl PiecePicker {
         Some(selected_piece)
     }
 
+    /// Pieces picked or started and not yet complete or given up
+    pub fn active_pieces(&self, piece_manager: &super::PieceManager) -> usize {
+        (0..self.total_pieces)
+            .filter(|&idx| {
+                self.piece_states[idx] == PieceState::Downloading
+                    || piece_manager.get_piece_state(idx) == Some(PieceState::Downloading)
+            })
+            .count()
+    }
+
+    /// Whether `pick_piece` is refusing new pieces until an active one
+    /// completes or is abandoned, as opposed to having nothing left
+    pub fn at_piece_limit(&self, piece_manager: &super::PieceManager) -> bool {
+        self.max_concurrent_pieces
+            .is_some_and(|limit| self.active_pieces(piece_manager) >= limit)
+    }
+
+    /// Whether `pick_piece` returning None means "not now" (paused or at
+    /// the piece limit) rather than "nothing left"
+    pub fn must_wait(&self, piece_manager: &super::PieceManager) -> bool {
+        self.paused || self.at_piece_limit(piece_manager)
+    }
+
+    /// The rarest of `candidates`, preferring pieces some peer is known to
+    /// have
+    fn rarest(&self, candidates: Vec<usize>) -> Option<usize> {
+        let now = Instant::now();
+        candidates.into_iter().min_by_key(|&idx| {
+            let availability = self.piece_availability[idx];
+            (availability == 0, self.priority(idx, now))
+        })
+    }
+
+    /// Effective availability of a piece: lower is picked sooner
+    ///
+    /// Not clamped at zero, so boosting every piece equally keeps the
+    /// rarest-first order intact.
+    fn priority(&self, piece_index: usize, now: Instant) -> i64 {
+        let waited = self.available_since[piece_index]
+            .map_or(Duration::ZERO, |since| now.saturating_duration_since(since));
+        let boost = waited.as_secs() / STARVATION_BOOST_INTERVAL.as_secs();
+        self.piece_availability[piece_index] as i64 - boost as i64
+    }
+
     /// Check if we're in endgame mode
+    #[allow(dead_code, reason = "`pick_piece` switches to endgame on its own")]
     pub fn is_endgame(&self) -> bool {
         self.endgame_mode
     }
 
     /// Pick the next piece to download from a peer's bitfield using rarest-first strategy
+    #[allow(dead_code, reason = "the client picks through `pick_piece`")]
     pub fn pick_piece_from_peer(&self, peer_bitfield: &[u8]) -> Option<usize> {
         let mut best_piece = None;
         let mut lowest_availability = u32::MAX;
@@ -168,7 +424,37 @@ impl PiecePicker {
         }
     }
 
+    /// Summarize how well the connected peers cover the torrent
+    ///
+    /// Returns the minimum availability among pieces we still need, how many
+    /// of those no connected peer has, and the swarm's distributed copies
+    /// (full copies plus the fraction of pieces above that minimum).
+    pub fn availability_summary(&self) -> (u32, usize, f64) {
+        let missing: Vec<u32> = (0..self.total_pieces)
+            .filter(|&idx| self.piece_states[idx] != PieceState::Complete)
+            .map(|idx| self.piece_availability[idx])
+            .collect();
+
+        let min_missing = missing.iter().min().copied().unwrap_or(0);
+        let zero_count = missing.iter().filter(|&&a| a == 0).count();
+
+        let overall_min = self.piece_availability.iter().min().copied().unwrap_or(0);
+        let above_min = self
+            .piece_availability
+            .iter()
+            .filter(|&&a| a > overall_min)
+            .count();
+        let distributed_copies = if self.total_pieces == 0 {
+            0.0
+        } else {
+            overall_min as f64 + above_min as f64 / self.total_pieces as f64
+        };
+
+        (min_missing, zero_count, distributed_copies)
+    }
+
     /// Get the number of complete pieces
+    #[allow(dead_code, reason = "the client reads progress from `PieceManager`")]
     pub fn complete_count(&self) -> usize {
         self.piece_states
             .iter()
@@ -177,12 +463,238 @@ impl PiecePicker {
     }
 
     /// Check if all pieces are complete
+    #[allow(dead_code, reason = "the client reads progress from `PieceManager`")]
     pub fn is_complete(&self) -> bool {
         self.complete_count() == self.total_pieces
     }
 
     /// Get progress as a percentage
+    #[allow(dead_code, reason = "the client reads progress from `PieceManager`")]
     pub fn progress(&self) -> f64 {
         (self.complete_count() as f64 / self.total_pieces as f64) * 100.0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::PieceManager;
+    use super::*;
+    use crate::testutil::make_info;
+    use crate::torrent::FileInfo;
+
+    /// Availability for 8 pieces: piece 7 is held by one peer, the rest by two
+    fn picker_with_rare_piece() -> PiecePicker {
+        let mut picker = PiecePicker::new(8);
+        picker.update_peer_pieces(&[0b1111_1110]);
+        picker.update_peer_pieces(&[0b1111_1110]);
+        picker.update_peer_pieces(&[0b0000_0001]);
+        picker
+    }
+
+    #[test]
+    fn test_active_pieces_stay_within_limit() {
+        let info = make_info(16384, 16384 * 8);
+        let mut pm = PieceManager::new(&info);
+        let mut picker = PiecePicker::new(8)
+            .with_strategy(PickStrategy::Sequential, 0)
+            .with_max_concurrent_pieces(Some(3));
+        picker.update_peer_pieces(&[0xff]);
+
+        let mut started = Vec::new();
+        while let Some(piece_index) = picker.pick_piece(&pm) {
+            pm.start_piece(piece_index).unwrap();
+            started.push(piece_index);
+        }
+        assert_eq!(started, vec![0, 1, 2]);
+        assert!(picker.at_piece_limit(&pm));
+
+        // Finishing or abandoning one frees its slot
+        pm.add_block(1, 0, &[0u8; 16384]).unwrap();
+        let _ = pm.complete_piece(1);
+        picker.mark_missing(1);
+        assert!(!picker.at_piece_limit(&pm));
+        assert_eq!(picker.pick_piece(&pm), Some(1));
+        assert_eq!(picker.pick_piece(&pm), None);
+
+        // Finish the oldest active piece each round; never more than three
+        // are in flight
+        while !pm.is_complete() {
+            while let Some(piece_index) = picker.pick_piece(&pm) {
+                pm.start_piece(piece_index).unwrap();
+            }
+            assert!(picker.active_pieces(&pm) <= 3);
+            let oldest = (0..8)
+                .find(|&idx| pm.get_piece_state(idx) == Some(PieceState::Downloading))
+                .unwrap();
+            pm.reset_piece(oldest);
+            pm.mark_verified(oldest);
+            picker.mark_complete(oldest);
+        }
+        assert!(pm.is_complete());
+    }
+
+    #[test]
+    fn test_rare_piece_is_picked_during_random_first() {
+        let pm = PieceManager::new(&make_info(16384, 16384 * 8));
+
+        for _ in 0..20 {
+            let mut picker = picker_with_rare_piece();
+            let picks: Vec<usize> = (0..RAREST_PICK_INTERVAL)
+                .filter_map(|_| picker.pick_piece(&pm))
+                .collect();
+            assert!(picks.contains(&7), "{:?}", picks);
+        }
+    }
+
+    #[test]
+    fn test_starved_piece_is_boosted() {
+        let pm = PieceManager::new(&make_info(16384, 16384 * 8));
+        let mut picker = picker_with_rare_piece();
+        picker.strategy = PickStrategy::Rarest;
+
+        // Piece 3 is common, but has been waiting far longer than the rest
+        picker.piece_availability[3] = 3;
+        picker.available_since[3] = Some(Instant::now() - STARVATION_BOOST_INTERVAL * 3);
+        assert_eq!(picker.pick_piece(&pm), Some(3));
+
+        // Then the rare piece comes next
+        assert_eq!(picker.pick_piece(&pm), Some(7));
+    }
+
+    #[test]
+    fn test_sequential_picks_in_order() {
+        let mut pm = PieceManager::new(&make_info(16384, 16384 * 8));
+        let mut picker = picker_with_rare_piece()
+            .with_strategy(PickStrategy::Sequential, 0)
+            .with_endgame_threshold(0.0, 0);
+        pm.mark_verified(2);
+        picker.mark_complete(2);
+
+        let picks: Vec<usize> = std::iter::from_fn(|| picker.pick_piece(&pm)).collect();
+        assert_eq!(picks, vec![0, 1, 3, 4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn test_rarest_prefers_lowest_availability() {
+        let pm = PieceManager::new(&make_info(16384, 16384 * 8));
+        let mut picker = PiecePicker::new(8).with_strategy(PickStrategy::Rarest, 0);
+        picker.update_peer_pieces(&[0b1111_1111]);
+        picker.update_peer_pieces(&[0b1101_1111]);
+        picker.update_peer_pieces(&[0b1101_1011]);
+
+        // Piece 2 is on one peer, piece 5 on two, the rest on three
+        assert_eq!(picker.pick_piece(&pm), Some(2));
+        assert_eq!(picker.pick_piece(&pm), Some(5));
+    }
+
+    #[test]
+    fn test_boosted_pieces_are_picked_first() {
+        let mut info = make_info(16384, 16384 * 8);
+        info.files = vec![
+            FileInfo {
+                path: vec!["a.mkv".to_string()],
+                length: 16384 * 3 + 100,
+            },
+            FileInfo {
+                path: vec!["b.mkv".to_string()],
+                length: 16384 * 5 - 100,
+            },
+        ];
+        let boundaries = info.file_boundary_pieces();
+        assert_eq!(boundaries, vec![0, 3, 7]);
+
+        let pm = PieceManager::new(&info);
+        let mut picker = picker_with_rare_piece()
+            .with_strategy(PickStrategy::Rarest, 0)
+            .with_endgame_threshold(0.0, 0);
+        picker.boost_pieces(boundaries);
+
+        let mut picks: Vec<usize> = std::iter::from_fn(|| picker.pick_piece(&pm)).collect();
+        let interior = picks.split_off(3);
+        picks.sort_unstable();
+        assert_eq!(picks, vec![0, 3, 7]);
+        assert!(interior.iter().all(|idx| ![0, 3, 7].contains(idx)));
+        assert_eq!(interior.len(), 5);
+    }
+
+    #[test]
+    fn test_pick_strategy_from_str() {
+        assert_eq!("sequential".parse(), Ok(PickStrategy::Sequential));
+        assert_eq!(
+            "random-first".parse(),
+            Ok(PickStrategy::RandomFirstThenRarest)
+        );
+        assert!("fastest".parse::<PickStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_endgame_threshold_scales_with_piece_count() {
+        // Small torrents fall back to the absolute minimum
+        assert_eq!(PiecePicker::new(10).endgame_threshold(), 5);
+        assert_eq!(PiecePicker::new(100).endgame_threshold(), 5);
+
+        // Large ones use the ratio
+        assert_eq!(PiecePicker::new(1000).endgame_threshold(), 20);
+        assert_eq!(PiecePicker::new(10_000).endgame_threshold(), 200);
+
+        let custom = PiecePicker::new(1000).with_endgame_threshold(0.1, 2);
+        assert_eq!(custom.endgame_threshold(), 100);
+        let custom = PiecePicker::new(10).with_endgame_threshold(0.0, 2);
+        assert_eq!(custom.endgame_threshold(), 2);
+    }
+
+    #[test]
+    fn test_endgame_starts_at_threshold() {
+        let mut pm = PieceManager::new(&make_info(16384, 16384 * 1000));
+        let mut picker = PiecePicker::new(1000);
+
+        for index in 0..979 {
+            pm.mark_verified(index);
+            picker.mark_complete(index);
+        }
+        picker.pick_piece(&pm);
+        assert!(!picker.is_endgame());
+
+        // 21 pieces were missing; the one just picked leaves 20, 2% of 1000
+        picker.pick_piece(&pm);
+        assert!(picker.is_endgame());
+    }
+
+    #[test]
+    fn test_availability_summary() {
+        let mut picker = PiecePicker::new(4);
+        picker.update_peer_pieces(&[0b1110_0000]);
+        picker.update_peer_pieces(&[0b1100_0000]);
+
+        // Availability: [2, 2, 1, 0]
+        let (min, zero, copies) = picker.availability_summary();
+        assert_eq!(min, 0);
+        assert_eq!(zero, 1);
+        assert!((copies - 0.75).abs() < f64::EPSILON);
+
+        // Completing the unavailable piece removes it from the summary
+        picker.mark_complete(3);
+        let (min, zero, _) = picker.availability_summary();
+        assert_eq!(min, 1);
+        assert_eq!(zero, 0);
+    }
+
+    #[test]
+    fn test_departing_peers_reduce_availability() {
+        let mut picker = PiecePicker::new(4);
+        picker.update_peer_pieces(&[0b1110_0000]);
+        picker.update_peer_pieces(&[0b1000_0000]);
+        picker.add_peer_piece(3);
+        picker.mark_complete(2);
+        assert_eq!(picker.availability(), &[2, 1, 1, 1]);
+
+        // Piece 0 still has a holder and piece 2 is no longer needed
+        assert_eq!(picker.remove_peer_pieces(&[0b1110_0000]), vec![1]);
+        assert_eq!(picker.availability(), &[1, 0, 0, 1]);
+        assert_eq!(picker.remove_peer_pieces(&[0b0001_0000]), vec![3]);
+
+        // A peer counted twice can't take a piece below zero
+        assert!(picker.remove_peer_pieces(&[0b0100_0000]).is_empty());
+        assert_eq!(picker.availability(), &[1, 0, 0, 0]);
+    }
+}
@@ -0,0 +1,127 @@
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+
+/// Per-peer piece advertisement for super-seeding (BEP 16)
+///
+/// Instead of sending a full bitfield, an initial seeder reveals one piece
+/// at a time to each peer with a `Have` message. A peer is only offered a
+/// new piece once the previous one has been seen on *another* peer, which
+/// shows it was actually passed on rather than hoarded. Pieces are chosen
+/// least-advertised first so each peer carries something different into
+/// the swarm.
+pub struct SuperSeeder {
+    /// Number of times each piece has been offered to a peer
+    advert_counts: Vec<u32>,
+    /// The piece currently offered to each peer
+    offered: HashMap<SocketAddr, usize>,
+    /// Pieces each peer has announced having
+    peer_pieces: HashMap<SocketAddr, HashSet<usize>>,
+}
+
+impl SuperSeeder {
+    pub fn new(num_pieces: usize) -> Self {
+        Self {
+            advert_counts: vec![0; num_pieces],
+            offered: HashMap::new(),
+            peer_pieces: HashMap::new(),
+        }
+    }
+
+    /// Pick the piece to advertise to `peer`, if it's due a new one
+    ///
+    /// Returns `None` while the peer's current piece hasn't spread yet, or
+    /// when the peer already has every piece.
+    pub fn next_piece(&mut self, peer: SocketAddr) -> Option<usize> {
+        if self.offered.contains_key(&peer) {
+            return None;
+        }
+
+        let held = self.peer_pieces.get(&peer);
+        let piece_index = (0..self.advert_counts.len())
+            .filter(|idx| !held.is_some_and(|pieces| pieces.contains(idx)))
+            .min_by_key(|&idx| self.advert_counts[idx])?;
+
+        self.advert_counts[piece_index] += 1;
+        self.offered.insert(peer, piece_index);
+        Some(piece_index)
+    }
+
+    /// Record a `Have` (or bitfield entry) from `peer`
+    ///
+    /// Any other peer that was offered this piece has now shared it and
+    /// becomes eligible for its next one.
+    pub fn on_have(&mut self, peer: SocketAddr, piece_index: usize) {
+        self.peer_pieces
+            .entry(peer)
+            .or_default()
+            .insert(piece_index);
+
+        self.offered
+            .retain(|&other, &mut offered| other == peer || offered != piece_index);
+    }
+
+    /// The piece currently offered to `peer`
+    #[cfg(test)]
+    pub fn offered_piece(&self, peer: SocketAddr) -> Option<usize> {
+        self.offered.get(&peer).copied()
+    }
+
+    /// Forget a disconnected peer
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        self.offered.remove(&peer);
+        self.peer_pieces.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn test_peers_get_different_pieces() {
+        let mut seeder = SuperSeeder::new(3);
+
+        let a = seeder.next_piece(addr(1)).unwrap();
+        let b = seeder.next_piece(addr(2)).unwrap();
+        let c = seeder.next_piece(addr(3)).unwrap();
+
+        let mut offered = vec![a, b, c];
+        offered.sort();
+        assert_eq!(offered, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_advances_only_after_piece_is_shared() {
+        let mut seeder = SuperSeeder::new(4);
+        let first = seeder.next_piece(addr(1)).unwrap();
+
+        // Still waiting on the first piece
+        assert_eq!(seeder.next_piece(addr(1)), None);
+
+        // The peer downloading it from us doesn't count as sharing
+        seeder.on_have(addr(1), first);
+        assert_eq!(seeder.offered_piece(addr(1)), Some(first));
+        assert_eq!(seeder.next_piece(addr(1)), None);
+
+        // Another peer having it does
+        seeder.on_have(addr(2), first);
+        let second = seeder.next_piece(addr(1)).unwrap();
+        assert_ne!(second, first);
+    }
+
+    #[test]
+    fn test_skips_pieces_the_peer_has() {
+        let mut seeder = SuperSeeder::new(2);
+        seeder.on_have(addr(1), 0);
+        seeder.on_have(addr(1), 1);
+
+        assert_eq!(seeder.next_piece(addr(1)), None);
+
+        seeder.remove_peer(addr(1));
+        assert_eq!(seeder.next_piece(addr(1)), Some(0));
+    }
+}
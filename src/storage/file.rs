@@ -0,0 +1,963 @@
+use super::StorageBackend;
+use crate::error::{BittorrentError, Result};
+use crate::torrent::TorrentInfo;
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+use tracing::{debug, info};
+
+/// Where the file of a multi-file torrent with a single entry goes
+///
+/// Some torrent creators write one file using the multi-file `files`
+/// structure. Read by the book that's a directory named after the torrent
+/// holding the file, which is what most clients create and what cross-seeding
+/// the same data needs; the creator may well have meant a plain single file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SingleEntryLayout {
+    /// `<download dir>/<name>/<file>`, as for any multi-file torrent
+    #[default]
+    Directory,
+    /// `<download dir>/<file>`, as for a single-file torrent
+    Flat,
+    /// Flat if the entry is a bare file name, a directory if its path has
+    /// subdirectories of its own
+    Auto,
+}
+
+impl std::str::FromStr for SingleEntryLayout {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "directory" => Ok(Self::Directory),
+            "flat" => Ok(Self::Flat),
+            "auto" => Ok(Self::Auto),
+            _ => Err(format!(
+                "unknown single-entry layout \"{}\" (expected directory, flat or auto)",
+                s
+            )),
+        }
+    }
+}
+
+impl SingleEntryLayout {
+    /// Whether `torrent_info` is saved without a directory of its own
+    pub fn is_flat(self, torrent_info: &TorrentInfo) -> bool {
+        if torrent_info.is_single_file() {
+            return true;
+        }
+        let [only] = torrent_info.files.as_slice() else {
+            return false;
+        };
+        match self {
+            Self::Directory => false,
+            Self::Flat => true,
+            Self::Auto => only.path.len() == 1,
+        }
+    }
+}
+
+/// Path of what a torrent downloads: its file, or the directory holding
+/// its files
+pub fn content_path(
+    download_dir: &Path,
+    torrent_info: &TorrentInfo,
+    layout: SingleEntryLayout,
+) -> PathBuf {
+    if !layout.is_flat(torrent_info) {
+        download_dir.join(&torrent_info.name)
+    } else {
+        torrent_info.files[0]
+            .path
+            .iter()
+            .fold(download_dir.to_path_buf(), |path, part| path.join(part))
+    }
+}
+
+/// Manages file I/O for downloaded pieces
+pub struct StorageManager {
+    /// Base directory for downloads
+    #[allow(dead_code, reason = "file paths are resolved against it up front")]
+    download_dir: PathBuf,
+    /// Files in the torrent
+    files: Vec<FileEntry>,
+    /// Layout of the torrent's pieces
+    torrent_info: TorrentInfo,
+    /// Sequential writes held back to be flushed in larger chunks
+    write_buffer: Option<Mutex<WriteBuffer>>,
+    /// Sync file data to disk after every write
+    fsync: bool,
+}
+
+struct FileEntry {
+    path: PathBuf,
+    length: u64,
+    offset: u64, // Global offset in the torrent
+}
+
+/// Write-back buffer that coalesces sequential writes within each file
+struct WriteBuffer {
+    /// Flush everything once this many bytes are buffered
+    capacity: usize,
+    /// Bytes currently buffered across all files
+    buffered: usize,
+    /// One contiguous pending run per file, keyed by file index
+    runs: HashMap<usize, PendingRun>,
+}
+
+struct PendingRun {
+    offset: u64,
+    data: Vec<u8>,
+}
+
+impl StorageManager {
+    /// Create a new storage manager
+    #[allow(dead_code, reason = "the client always passes a layout")]
+    pub async fn new<P: AsRef<Path>>(download_dir: P, torrent_info: &TorrentInfo) -> Result<Self> {
+        Self::new_with_layout(download_dir, torrent_info, SingleEntryLayout::default()).await
+    }
+
+    /// Create a storage manager, placing a one-entry multi-file torrent's
+    /// file according to `layout`
+    pub async fn new_with_layout<P: AsRef<Path>>(
+        download_dir: P,
+        torrent_info: &TorrentInfo,
+        layout: SingleEntryLayout,
+    ) -> Result<Self> {
+        let download_dir = download_dir.as_ref().to_path_buf();
+
+        // Check every path up front so a bad torrent creates nothing on disk
+        let relative_paths = torrent_info
+            .files
+            .iter()
+            .map(|file_info| sanitize_path(&file_info.path))
+            .collect::<Result<Vec<_>>>()?;
+
+        // A single file goes straight into the download directory; the files
+        // of a multi-file torrent go into a directory named after it
+        let root = if layout.is_flat(torrent_info) {
+            download_dir.clone()
+        } else {
+            download_dir.join(sanitize_path(std::slice::from_ref(&torrent_info.name))?)
+        };
+
+        // Create download directory, and make sure we can actually write
+        // there before any peer is contacted
+        fs::create_dir_all(&download_dir)
+            .await
+            .map_err(|e| storage_io_error(&download_dir, e))?;
+        probe_writable(&download_dir).await?;
+
+        let mut files = Vec::new();
+        let mut offset = 0u64;
+
+        for (file_info, relative_path) in torrent_info.files.iter().zip(relative_paths) {
+            let file_path = root.join(relative_path);
+
+            // Create parent directories
+            if let Some(parent) = file_path.parent() {
+                fs::create_dir_all(parent)
+                    .await
+                    .map_err(|e| storage_io_error(parent, e))?;
+            }
+
+            // No piece data ever lands in an empty file, so writes would
+            // never create it
+            if file_info.length == 0 {
+                OpenOptions::new()
+                    .create(true)
+                    .truncate(false)
+                    .write(true)
+                    .open(&file_path)
+                    .await
+                    .map_err(|e| storage_io_error(&file_path, e))?;
+            }
+
+            files.push(FileEntry {
+                path: file_path,
+                length: file_info.length,
+                offset,
+            });
+
+            offset += file_info.length;
+        }
+
+        info!(
+            "Storage initialized: {} files, {} bytes total",
+            files.len(),
+            torrent_info.total_length
+        );
+
+        Ok(Self {
+            download_dir,
+            files,
+            torrent_info: torrent_info.clone(),
+            write_buffer: None,
+            fsync: true,
+        })
+    }
+
+    /// Coalesce sequential writes and flush them in chunks of up to
+    /// `capacity` bytes (0 writes straight through)
+    pub fn with_write_buffer(mut self, capacity: usize) -> Self {
+        self.write_buffer = (capacity > 0).then(|| {
+            Mutex::new(WriteBuffer {
+                capacity,
+                buffered: 0,
+                runs: HashMap::new(),
+            })
+        });
+        self
+    }
+
+    /// Choose whether writes are synced to disk before they count as done
+    ///
+    /// Syncing (the default) means a piece that was written survives a
+    /// crash or power loss, at the cost of waiting on the disk for every
+    /// write. Without it data may sit in the OS page cache for a while, and
+    /// pieces lost that way are re-downloaded after verification on the
+    /// next run. With a write buffer the sync happens once per flush.
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Write out everything held in the write buffer
+    pub async fn flush_all(&self) -> Result<()> {
+        let Some(buffer) = &self.write_buffer else {
+            return Ok(());
+        };
+
+        let mut buffer = buffer.lock().await;
+        self.flush_runs(&mut buffer).await
+    }
+
+    /// Write out every run, keeping those that fail in the buffer so a
+    /// later flush can retry them
+    async fn flush_runs(&self, buffer: &mut WriteBuffer) -> Result<()> {
+        let mut failure = None;
+        let mut file_indices: Vec<usize> = buffer.runs.keys().copied().collect();
+        file_indices.sort_unstable();
+
+        for file_index in file_indices {
+            let run = &buffer.runs[&file_index];
+            let path = &self.files[file_index].path;
+            if let Err(e) = write_to_file(path, run.offset, &run.data, self.fsync).await {
+                failure.get_or_insert(e);
+                continue;
+            }
+            if let Some(run) = buffer.runs.remove(&file_index) {
+                buffer.buffered -= run.data.len();
+            }
+        }
+
+        match failure {
+            Some(e) => Err(self.not_durable(e, buffer)),
+            None => Ok(()),
+        }
+    }
+
+    /// Name the pieces still held in `buffer` in the error for a failed
+    /// flush, since they are not on disk
+    fn not_durable(&self, error: BittorrentError, buffer: &WriteBuffer) -> BittorrentError {
+        let mut pieces: Vec<usize> = buffer
+            .runs
+            .iter()
+            .filter(|(_, run)| !run.data.is_empty())
+            .flat_map(|(&file_index, run)| {
+                let start = self.files[file_index].offset + run.offset;
+                let end = start + run.data.len() as u64 - 1;
+                let piece_length = self.torrent_info.piece_length;
+                (start / piece_length) as usize..=(end / piece_length) as usize
+            })
+            .collect();
+        pieces.sort_unstable();
+        pieces.dedup();
+
+        let pieces = pieces
+            .iter()
+            .map(|piece| piece.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        let reason = match error {
+            BittorrentError::StorageError(reason) => reason,
+            other => other.to_string(),
+        };
+        BittorrentError::StorageError(format!("{}; pieces not on disk: {}", reason, pieces))
+    }
+
+    /// Write a piece to disk
+    pub async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+        let global_offset = (piece_index as u64) * self.torrent_info.piece_length;
+
+        debug!(
+            "Writing piece {} at global offset {} ({} bytes)",
+            piece_index,
+            global_offset,
+            data.len()
+        );
+
+        self.write_at_offset(global_offset, data).await?;
+
+        info!("Piece {} written to disk", piece_index);
+        Ok(())
+    }
+
+    /// Read a piece from disk
+    pub async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+        // Buffered data must reach the files before they can be read back
+        self.flush_all().await?;
+
+        let global_offset = (piece_index as u64) * self.torrent_info.piece_length;
+        let piece_length = self.torrent_info.piece_size(piece_index);
+
+        self.read_at_offset(global_offset, piece_length as usize)
+            .await
+    }
+
+    /// Write data at a global offset (spans multiple files if needed)
+    async fn write_at_offset(&self, mut offset: u64, mut data: &[u8]) -> Result<()> {
+        for (file_index, file_entry) in self.files.iter().enumerate() {
+            if offset >= file_entry.offset + file_entry.length {
+                continue; // This file is before our offset
+            }
+
+            if offset < file_entry.offset {
+                break; // We've passed our offset
+            }
+
+            let file_offset = offset - file_entry.offset;
+            let bytes_to_write =
+                std::cmp::min(data.len() as u64, file_entry.length - file_offset) as usize;
+
+            self.write_segment(file_index, file_offset, &data[..bytes_to_write])
+                .await?;
+
+            // Move to next file
+            offset += bytes_to_write as u64;
+            data = &data[bytes_to_write..];
+
+            if data.is_empty() {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write data within one file, through the write buffer if enabled
+    async fn write_segment(&self, file_index: usize, offset: u64, data: &[u8]) -> Result<()> {
+        let Some(buffer) = &self.write_buffer else {
+            return write_to_file(&self.files[file_index].path, offset, data, self.fsync).await;
+        };
+
+        let mut buffer = buffer.lock().await;
+
+        // A gap ends the file's run: write it out and start a new one
+        let contiguous = buffer
+            .runs
+            .get(&file_index)
+            .is_some_and(|run| run.offset + run.data.len() as u64 == offset);
+        if !contiguous {
+            if let Some(run) = buffer.runs.get(&file_index) {
+                let path = &self.files[file_index].path;
+                if let Err(e) = write_to_file(path, run.offset, &run.data, self.fsync).await {
+                    return Err(self.not_durable(e, &buffer));
+                }
+                if let Some(run) = buffer.runs.remove(&file_index) {
+                    buffer.buffered -= run.data.len();
+                }
+            }
+        }
+
+        buffer
+            .runs
+            .entry(file_index)
+            .or_insert_with(|| PendingRun {
+                offset,
+                data: Vec::new(),
+            })
+            .data
+            .extend_from_slice(data);
+        buffer.buffered += data.len();
+
+        if buffer.buffered >= buffer.capacity {
+            self.flush_runs(&mut buffer).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Read data from a global offset (spans multiple files if needed)
+    async fn read_at_offset(&self, mut offset: u64, mut length: usize) -> Result<Vec<u8>> {
+        let mut result = Vec::with_capacity(length);
+
+        for file_entry in &self.files {
+            if offset >= file_entry.offset + file_entry.length {
+                continue;
+            }
+
+            if offset < file_entry.offset {
+                break;
+            }
+
+            let file_offset = offset - file_entry.offset;
+            let bytes_to_read =
+                std::cmp::min(length as u64, file_entry.length - file_offset) as usize;
+
+            // Open file and read
+            let mut file = File::open(&file_entry.path).await?;
+            file.seek(std::io::SeekFrom::Start(file_offset)).await?;
+
+            let mut buffer = vec![0u8; bytes_to_read];
+            file.read_exact(&mut buffer).await?;
+
+            result.extend_from_slice(&buffer);
+
+            offset += bytes_to_read as u64;
+            length -= bytes_to_read;
+
+            if length == 0 {
+                break;
+            }
+        }
+
+        Ok(result)
+    }
+}
+
+/// Turn a torrent file path into a relative path that stays inside the
+/// download directory
+///
+/// Each component must be a single plain name: empty components, `.`, `..`,
+/// separators and anything that would make the path absolute are rejected,
+/// as are names Windows treats as devices or drives when running there.
+fn sanitize_path(components: &[String]) -> Result<PathBuf> {
+    let invalid = |reason: &str| {
+        BittorrentError::InvalidTorrent(format!(
+            "Unsafe file path {:?}: {}",
+            components.join("/"),
+            reason
+        ))
+    };
+
+    if components.is_empty() {
+        return Err(invalid("empty path"));
+    }
+
+    let mut path = PathBuf::new();
+    for component in components {
+        if component.is_empty() {
+            return Err(invalid("empty component"));
+        }
+        if component.contains(['/', '\\', '\0']) {
+            return Err(invalid("component contains a separator"));
+        }
+
+        let mut parsed = Path::new(component).components();
+        match (parsed.next(), parsed.next()) {
+            (Some(Component::Normal(name)), None) if name == component.as_str() => {}
+            (Some(Component::ParentDir), _) => return Err(invalid("parent directory reference")),
+            (Some(Component::CurDir), _) => return Err(invalid("current directory reference")),
+            _ => return Err(invalid("not a plain file name")),
+        }
+
+        if cfg!(windows) && is_windows_reserved(component) {
+            return Err(invalid("reserved name on Windows"));
+        }
+
+        path.push(component);
+    }
+
+    Ok(path)
+}
+
+/// Whether Windows would treat a file name as a device, drive or stream
+/// rather than an ordinary file
+fn is_windows_reserved(component: &str) -> bool {
+    const DEVICES: [&str; 22] = [
+        "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+        "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+    ];
+
+    // Device names are reserved with any extension, e.g. `nul.txt`
+    let stem = component.split('.').next().unwrap_or(component).trim_end();
+    if DEVICES
+        .iter()
+        .any(|device| stem.eq_ignore_ascii_case(device))
+    {
+        return true;
+    }
+
+    // `C:` drive prefixes and `file:stream` alternate data streams
+    if component.contains(':') {
+        return true;
+    }
+
+    // Windows silently strips trailing dots and spaces, so `..` could hide as `.. `
+    component.ends_with(['.', ' '])
+}
+
+/// Write data at an offset in one file, creating it if needed, and
+/// optionally wait for it to reach the disk
+async fn write_to_file(path: &Path, offset: u64, data: &[u8], sync: bool) -> Result<()> {
+    let write = async {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .await?;
+
+        file.seek(std::io::SeekFrom::Start(offset)).await?;
+        file.write_all(data).await?;
+        if sync {
+            file.sync_data().await?;
+        }
+        Ok(())
+    };
+    write.await.map_err(|e| storage_io_error(path, e))?;
+
+    debug!(
+        "Wrote {} bytes to {:?} at offset {}",
+        data.len(),
+        path,
+        offset
+    );
+    Ok(())
+}
+
+/// Write and remove a scratch file, so a read-only download directory is
+/// reported before the download starts rather than on the first piece
+async fn probe_writable(dir: &Path) -> Result<()> {
+    let probe = dir.join(format!(".bittorrent-rs-probe-{}", std::process::id()));
+    fs::write(&probe, b"")
+        .await
+        .map_err(|e| storage_io_error(dir, e))?;
+    fs::remove_file(&probe).await?;
+    Ok(())
+}
+
+/// Explain an I/O error the user has to fix on their side, such as a
+/// read-only or full disk; anything else is passed through as is
+fn storage_io_error(path: &Path, error: std::io::Error) -> BittorrentError {
+    match error.kind() {
+        ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => {
+            BittorrentError::StorageError(format!(
+                "{} is not writable ({}); fix its permissions or choose another \
+                 download directory with --output",
+                path.display(),
+                error
+            ))
+        }
+        ErrorKind::StorageFull | ErrorKind::QuotaExceeded => {
+            BittorrentError::StorageError(format!(
+                "no space left on the device holding {} ({}); free some space and \
+                 run the download again to resume it",
+                path.display(),
+                error
+            ))
+        }
+        _ => error.into(),
+    }
+}
+
+#[async_trait]
+impl StorageBackend for StorageManager {
+    async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+        StorageManager::write_piece(self, piece_index, data).await
+    }
+
+    async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+        StorageManager::read_piece(self, piece_index).await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.flush_all().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_info, random_content};
+
+    fn test_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_unwritable_and_full_disks_explain_themselves() {
+        let path = Path::new("/mnt/downloads");
+
+        let denied = storage_io_error(path, ErrorKind::PermissionDenied.into());
+        assert!(
+            matches!(&denied, BittorrentError::StorageError(reason) if reason.contains("--output"))
+        );
+
+        let full = storage_io_error(path, ErrorKind::StorageFull.into());
+        assert!(
+            matches!(&full, BittorrentError::StorageError(reason) if reason.contains("no space"))
+        );
+
+        // Errors the user can't do much about stay I/O errors
+        let other = storage_io_error(path, ErrorKind::UnexpectedEof.into());
+        assert!(matches!(other, BittorrentError::IoError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_buffered_writes_are_durable_after_flush_all() {
+        let dir = test_dir("write-buffer");
+        let info = make_info(16384, 16384 * 4);
+        let content = random_content(16384 * 4);
+        let storage = StorageManager::new(&dir, &info)
+            .await
+            .unwrap()
+            .with_write_buffer(1024 * 1024);
+
+        for (index, piece) in content.chunks(16384).enumerate() {
+            storage.write_piece(index, piece).await.unwrap();
+        }
+
+        // Still below the buffer size, so nothing has been written yet
+        let path = dir.join(&info.name);
+        assert!(std::fs::read(&path).map_or(true, |data| data.is_empty()));
+
+        storage.flush_all().await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_keeps_unwritten_pieces_buffered() {
+        let dir = test_dir("flush-failure");
+        let mut info = make_info(16384, 16384 * 2);
+        info.name = "pair".to_string();
+        info.multi_file = true;
+        info.files = ["a", "b"]
+            .iter()
+            .map(|name| crate::torrent::FileInfo {
+                path: vec![name.to_string()],
+                length: 16384,
+            })
+            .collect();
+        let content = random_content(16384 * 2);
+        let storage = StorageManager::new(&dir, &info)
+            .await
+            .unwrap()
+            .with_write_buffer(1024 * 1024);
+        for (index, piece) in content.chunks(16384).enumerate() {
+            storage.write_piece(index, piece).await.unwrap();
+        }
+
+        // A directory where file b should be makes its write fail
+        let blocked = dir.join("pair").join("b");
+        std::fs::remove_file(&blocked).ok();
+        std::fs::create_dir(&blocked).unwrap();
+        let err = storage.flush_all().await.unwrap_err();
+        assert!(
+            err.to_string().ends_with("pieces not on disk: 1"),
+            "{}",
+            err
+        );
+        assert_eq!(
+            std::fs::read(dir.join("pair").join("a")).unwrap(),
+            &content[..16384]
+        );
+
+        // Piece 1 is still buffered and lands once the file is writable
+        std::fs::remove_dir(&blocked).unwrap();
+        storage.flush_all().await.unwrap();
+        assert_eq!(std::fs::read(&blocked).unwrap(), &content[16384..]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_synced_pieces_are_read_back_by_a_new_manager() {
+        let dir = test_dir("fsync");
+        let info = make_info(16384, 16384 * 2);
+        let content = random_content(16384 * 2);
+
+        let storage = StorageManager::new(&dir, &info).await.unwrap();
+        for (index, piece) in content.chunks(16384).enumerate() {
+            storage.write_piece(index, piece).await.unwrap();
+        }
+        drop(storage);
+
+        // A fresh manager has no state of its own, only what's on disk
+        let reopened = StorageManager::new(&dir, &info).await.unwrap();
+        for (index, piece) in content.chunks(16384).enumerate() {
+            assert_eq!(reopened.read_piece(index).await.unwrap(), piece);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn multi_file_info(paths: &[&[&str]]) -> TorrentInfo {
+        let mut info = make_info(16384, 100 * paths.len() as u64);
+        info.name = "album".to_string();
+        info.multi_file = true;
+        info.files = paths
+            .iter()
+            .map(|path| crate::torrent::FileInfo {
+                path: path.iter().map(|c| c.to_string()).collect(),
+                length: 100,
+            })
+            .collect();
+        info
+    }
+
+    #[tokio::test]
+    async fn test_traversal_path_is_rejected_before_creating_files() {
+        let dir = test_dir("traversal");
+        let data = crate::bencode::encode(&crate::bencode::BencodeValue::Dict(
+            [
+                (
+                    b"announce".to_vec(),
+                    crate::bencode::BencodeValue::String(b"http://t".to_vec()),
+                ),
+                (b"info".to_vec(), traversal_info_dict()),
+            ]
+            .into_iter()
+            .collect(),
+        ));
+        let metainfo = crate::torrent::parse_torrent(&data).unwrap();
+
+        let result = StorageManager::new(&dir, &metainfo.info).await;
+        assert!(matches!(result, Err(BittorrentError::InvalidTorrent(_))));
+        assert!(!dir.exists());
+        assert!(!dir.join("../escaped.txt").exists());
+    }
+
+    fn traversal_info_dict() -> crate::bencode::BencodeValue {
+        use crate::bencode::BencodeValue;
+
+        let file = |path: &[&str]| {
+            BencodeValue::Dict(
+                [
+                    (b"length".to_vec(), BencodeValue::Integer(50)),
+                    (
+                        b"path".to_vec(),
+                        BencodeValue::List(
+                            path.iter()
+                                .map(|c| BencodeValue::String(c.as_bytes().to_vec()))
+                                .collect(),
+                        ),
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            )
+        };
+
+        BencodeValue::Dict(
+            [
+                (b"name".to_vec(), BencodeValue::String(b"evil".to_vec())),
+                (b"piece length".to_vec(), BencodeValue::Integer(16384)),
+                (b"pieces".to_vec(), BencodeValue::String(vec![0u8; 20])),
+                (
+                    b"files".to_vec(),
+                    BencodeValue::List(vec![file(&["ok.txt"]), file(&["..", "escaped.txt"])]),
+                ),
+            ]
+            .into_iter()
+            .collect(),
+        )
+    }
+
+    #[test]
+    fn test_sanitize_path() {
+        let path = |components: &[&str]| {
+            sanitize_path(&components.iter().map(|c| c.to_string()).collect::<Vec<_>>())
+        };
+
+        assert_eq!(
+            path(&["dir", "file.txt"]).unwrap(),
+            Path::new("dir/file.txt")
+        );
+        assert!(path(&[]).is_err());
+        assert!(path(&["dir", ""]).is_err());
+        assert!(path(&[".", "file"]).is_err());
+        assert!(path(&["..", "file"]).is_err());
+        assert!(path(&["/etc", "passwd"]).is_err());
+        assert!(path(&["a/../../b"]).is_err());
+        assert!(path(&["a\\..\\b"]).is_err());
+    }
+
+    #[test]
+    fn test_windows_reserved_names() {
+        for name in [
+            "CON",
+            "nul.txt",
+            "com1",
+            "Lpt9.log",
+            "C:",
+            "file:stream",
+            "name.",
+        ] {
+            assert!(is_windows_reserved(name), "{}", name);
+        }
+        for name in ["console", "file.txt", "com10", ".hidden"] {
+            assert!(!is_windows_reserved(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_multi_file_torrent_gets_its_own_directory() {
+        let dir = test_dir("nested-paths");
+        let info = multi_file_info(&[&["a", "b", "one.bin"], &["two.bin"]]);
+
+        let storage = StorageManager::new(&dir, &info).await.unwrap();
+        let root = dir.join("album");
+        assert_eq!(
+            storage.files[0].path,
+            root.join("a").join("b").join("one.bin")
+        );
+        assert_eq!(storage.files[1].path, root.join("two.bin"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_empty_files_are_created() {
+        let dir = test_dir("empty-files");
+        let mut info = multi_file_info(&[&["one.bin"], &["empty", "a"], &["b"], &["two.bin"]]);
+        info.files[1].length = 0;
+        info.files[2].length = 0;
+        info.total_length = 200;
+        let content = random_content(200);
+
+        let storage = StorageManager::new(&dir, &info).await.unwrap();
+        storage.write_piece(0, &content).await.unwrap();
+
+        // The empty files take up no room between their neighbours
+        let root = dir.join("album");
+        assert_eq!(std::fs::read(root.join("one.bin")).unwrap(), content[..100]);
+        assert_eq!(std::fs::read(root.join("two.bin")).unwrap(), content[100..]);
+        assert_eq!(storage.read_piece(0).await.unwrap(), content);
+        for path in [root.join("empty").join("a"), root.join("b")] {
+            assert_eq!(std::fs::metadata(&path).unwrap().len(), 0, "{:?}", path);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_single_file_torrent_goes_straight_into_download_dir() {
+        let dir = test_dir("single-file-layout");
+        let info = make_info(16384, 100);
+        assert!(info.is_single_file());
+
+        let storage = StorageManager::new(&dir, &info).await.unwrap();
+        storage.write_piece(0, &[1u8; 100]).await.unwrap();
+
+        assert_eq!(storage.files[0].path, dir.join("test.bin"));
+        assert!(dir.join("test.bin").is_file());
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_single_entry_layouts() {
+        let bare = multi_file_info(&[&["track.flac"]]);
+        let nested = multi_file_info(&[&["disc1", "track.flac"]]);
+        let cases = [
+            (
+                &bare,
+                SingleEntryLayout::Directory,
+                &["album", "track.flac"][..],
+            ),
+            (&bare, SingleEntryLayout::Flat, &["track.flac"][..]),
+            (&bare, SingleEntryLayout::Auto, &["track.flac"][..]),
+            (
+                &nested,
+                SingleEntryLayout::Flat,
+                &["disc1", "track.flac"][..],
+            ),
+            (
+                &nested,
+                SingleEntryLayout::Auto,
+                &["album", "disc1", "track.flac"][..],
+            ),
+        ];
+
+        for (i, (info, layout, expected)) in cases.into_iter().enumerate() {
+            let dir = test_dir(&format!("single-entry-{}", i));
+            let storage = StorageManager::new_with_layout(&dir, info, layout)
+                .await
+                .unwrap();
+            storage.write_piece(0, &[1u8; 100]).await.unwrap();
+
+            let path = expected
+                .iter()
+                .fold(dir.clone(), |path, part| path.join(part));
+            assert_eq!(storage.files[0].path, path, "{:?}", layout);
+            assert!(path.is_file(), "{:?}", layout);
+            let content = if expected[0] == "album" {
+                dir.join("album")
+            } else {
+                path
+            };
+            assert_eq!(content_path(&dir, info, layout), content);
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_single_file_torrent_ignores_single_entry_layout() {
+        let info = make_info(16384, 100);
+
+        for layout in [SingleEntryLayout::Directory, SingleEntryLayout::Auto] {
+            let dir = test_dir("single-file-any-layout");
+            let storage = StorageManager::new_with_layout(&dir, &info, layout)
+                .await
+                .unwrap();
+            assert_eq!(storage.files[0].path, dir.join("test.bin"));
+            assert_eq!(content_path(&dir, &info, layout), dir.join("test.bin"));
+
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsafe_torrent_name_is_rejected_for_multi_file() {
+        let dir = test_dir("unsafe-name");
+        let mut info = multi_file_info(&[&["one.bin"]]);
+        info.name = "..".to_string();
+
+        let result = StorageManager::new(&dir, &info).await;
+        assert!(matches!(result, Err(BittorrentError::InvalidTorrent(_))));
+        assert!(!dir.exists());
+    }
+
+    #[tokio::test]
+    async fn test_read_sees_buffered_writes() {
+        let dir = test_dir("write-buffer-read");
+        let info = make_info(16384, 16384 * 4);
+        let content = random_content(16384 * 4);
+        let storage = StorageManager::new(&dir, &info)
+            .await
+            .unwrap()
+            .with_write_buffer(1024 * 1024);
+
+        // Out of order, so runs are broken up and flushed on gaps
+        for index in [2, 0, 1, 3] {
+            let piece = &content[index * 16384..(index + 1) * 16384];
+            storage.write_piece(index, piece).await.unwrap();
+        }
+
+        for (index, piece) in content.chunks(16384).enumerate() {
+            assert_eq!(storage.read_piece(index).await.unwrap(), piece);
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,98 @@
+use super::StorageBackend;
+use crate::error::{BittorrentError, Result};
+use async_trait::async_trait;
+use std::sync::Mutex;
+
+/// Storage backend that keeps the whole torrent in a memory buffer
+pub struct MemoryStorage {
+    data: Mutex<Vec<u8>>,
+    piece_length: u64,
+    total_length: u64,
+}
+
+impl MemoryStorage {
+    pub fn new(piece_length: u64, total_length: u64) -> Self {
+        Self {
+            data: Mutex::new(vec![0u8; total_length as usize]),
+            piece_length,
+            total_length,
+        }
+    }
+
+    /// Get a copy of the full buffer
+    pub fn contents(&self) -> Vec<u8> {
+        self.data.lock().unwrap().clone()
+    }
+
+    /// Byte range covered by a piece
+    fn piece_range(&self, piece_index: usize) -> Result<std::ops::Range<usize>> {
+        let start = piece_index as u64 * self.piece_length;
+        if start >= self.total_length {
+            return Err(BittorrentError::StorageError(format!(
+                "Piece {} is out of range",
+                piece_index
+            )));
+        }
+
+        let end = std::cmp::min(start + self.piece_length, self.total_length);
+        Ok(start as usize..end as usize)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+        let range = self.piece_range(piece_index)?;
+        if data.len() != range.len() {
+            return Err(BittorrentError::StorageError(format!(
+                "Piece {} has {} bytes, expected {}",
+                piece_index,
+                data.len(),
+                range.len()
+            )));
+        }
+
+        self.data.lock().unwrap()[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+        let range = self.piece_range(piece_index)?;
+        Ok(self.data.lock().unwrap()[range].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::Pieces;
+    use sha1::{Digest, Sha1};
+
+    #[tokio::test]
+    async fn test_write_and_read_pieces() {
+        let storage = MemoryStorage::new(4, 10);
+
+        storage.write_piece(0, b"abcd").await.unwrap();
+        storage.write_piece(2, b"ij").await.unwrap();
+
+        assert_eq!(storage.read_piece(0).await.unwrap(), b"abcd");
+        assert_eq!(storage.read_piece(2).await.unwrap(), b"ij");
+        assert!(storage.write_piece(1, b"toolong").await.is_err());
+        assert!(storage.read_piece(3).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_verify_existing() {
+        let storage = MemoryStorage::new(4, 8);
+        storage.write_piece(1, b"efgh").await.unwrap();
+
+        let mut hashes = Vec::new();
+        for chunk in [b"abcd", b"efgh"] {
+            hashes.extend_from_slice(&Sha1::digest(chunk));
+        }
+        let pieces = Pieces::from_bytes(&hashes).unwrap();
+
+        let verified = storage.verify_existing(&pieces).await.unwrap();
+        assert_eq!(verified, vec![false, true]);
+    }
+}
@@ -0,0 +1,33 @@
+use super::StorageBackend;
+use crate::error::{BittorrentError, Result};
+use async_trait::async_trait;
+use tracing::debug;
+
+/// Storage backend that discards all writes (used for dry runs)
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NullStorage;
+
+impl NullStorage {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+#[async_trait]
+impl StorageBackend for NullStorage {
+    async fn write_piece(&self, piece_index: usize, data: &[u8]) -> Result<()> {
+        debug!(
+            "Dry run: discarding piece {} ({} bytes)",
+            piece_index,
+            data.len()
+        );
+        Ok(())
+    }
+
+    async fn read_piece(&self, piece_index: usize) -> Result<Vec<u8>> {
+        Err(BittorrentError::StorageError(format!(
+            "Piece {} is not stored in dry-run mode",
+            piece_index
+        )))
+    }
+}
@@ -0,0 +1,292 @@
+//! Session state saved next to a download so a restart picks up where it
+//! left off
+//!
+//! The file is a bencoded dictionary:
+//!
+//! ```text
+//! version       i1e
+//! info hash     20-byte string
+//! pieces        piece count
+//! completed     bitfield of verified pieces
+//! availability  list of per-piece peer counts
+//! uploaded      total bytes uploaded
+//! downloaded    total bytes downloaded
+//! partial       optional list of [piece, block count, block bitfield] for
+//!               pieces left half-downloaded; their blocks are on disk
+//! ```
+
+use crate::bencode::{decode, encode, BencodeValue};
+use crate::error::{BittorrentError, Result};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+
+/// Format version written to and expected from resume files
+pub const RESUME_VERSION: i64 = 1;
+
+/// Snapshot of a download session
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeData {
+    pub info_hash: [u8; 20],
+    /// Pieces verified and written to disk
+    pub completed: Vec<bool>,
+    /// How many peers had each piece when the snapshot was taken
+    pub availability: Vec<u32>,
+    /// Bytes uploaded across all sessions
+    pub uploaded: u64,
+    /// Bytes downloaded across all sessions
+    pub downloaded: u64,
+    /// Received blocks of unfinished pieces, whose data was written in place
+    pub partial: Vec<(usize, Vec<bool>)>,
+}
+
+/// Location of the resume file for a torrent
+pub fn resume_path(download_dir: impl AsRef<Path>, name: &str) -> PathBuf {
+    download_dir.as_ref().join(format!("{}.resume", name))
+}
+
+impl ResumeData {
+    /// Start a snapshot for a torrent with nothing downloaded
+    pub fn new(info_hash: [u8; 20], num_pieces: usize) -> Self {
+        Self {
+            info_hash,
+            completed: vec![false; num_pieces],
+            availability: vec![0; num_pieces],
+            uploaded: 0,
+            downloaded: 0,
+            partial: Vec::new(),
+        }
+    }
+
+    /// Encode as a bencoded dictionary
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let availability = self
+            .availability
+            .iter()
+            .map(|&count| BencodeValue::from(count as i64))
+            .collect::<Vec<_>>();
+
+        let partial = self
+            .partial
+            .iter()
+            .map(|(index, blocks)| {
+                BencodeValue::List(vec![
+                    (*index as i64).into(),
+                    (blocks.len() as i64).into(),
+                    pack_bits(blocks).into(),
+                ])
+            })
+            .collect::<Vec<_>>();
+
+        encode(&BencodeValue::dict([
+            ("version", RESUME_VERSION.into()),
+            ("info hash", self.info_hash.to_vec().into()),
+            ("pieces", (self.completed.len() as i64).into()),
+            ("completed", pack_bits(&self.completed).into()),
+            ("availability", availability.into()),
+            ("uploaded", (self.uploaded as i64).into()),
+            ("downloaded", (self.downloaded as i64).into()),
+            ("partial", partial.into()),
+        ]))
+    }
+
+    /// Decode a resume file, checking it belongs to this torrent
+    pub fn from_bytes(data: &[u8], info_hash: [u8; 20], num_pieces: usize) -> Result<Self> {
+        let invalid =
+            |reason: &str| BittorrentError::StorageError(format!("Resume file {}", reason));
+
+        let value = decode(data)?;
+        let dict = value.as_dict().ok_or_else(|| invalid("is not a dict"))?;
+        let integer = |key: &[u8]| {
+            dict.get(key)
+                .and_then(|v| v.as_integer())
+                .filter(|&n| n >= 0)
+                .map(|n| n as u64)
+        };
+
+        if dict.get(b"version".as_ref()).and_then(|v| v.as_integer()) != Some(RESUME_VERSION) {
+            return Err(invalid("has an unsupported version"));
+        }
+        if dict.get(b"info hash".as_ref()).and_then(|v| v.as_bytes()) != Some(&info_hash[..]) {
+            return Err(invalid("is for a different torrent"));
+        }
+        if integer(b"pieces") != Some(num_pieces as u64) {
+            return Err(invalid("has the wrong piece count"));
+        }
+
+        let completed = dict
+            .get(b"completed".as_ref())
+            .and_then(|v| v.as_bytes())
+            .and_then(|bits| unpack_bits(bits, num_pieces))
+            .ok_or_else(|| invalid("has an invalid completed bitfield"))?;
+
+        let availability = dict
+            .get(b"availability".as_ref())
+            .and_then(|v| v.as_list())
+            .filter(|list| list.len() == num_pieces)
+            .and_then(|list| {
+                list.iter()
+                    .map(|v| v.as_integer().and_then(|n| u32::try_from(n).ok()))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid("has an invalid availability list"))?;
+
+        // Absent from files written before partial pieces were saved
+        let partial = match dict.get(b"partial".as_ref()) {
+            None => Vec::new(),
+            Some(value) => value
+                .as_list()
+                .and_then(|list| {
+                    list.iter()
+                        .map(|entry| partial_entry(entry, num_pieces))
+                        .collect()
+                })
+                .ok_or_else(|| invalid("has an invalid partial list"))?,
+        };
+
+        Ok(Self {
+            info_hash,
+            completed,
+            availability,
+            uploaded: integer(b"uploaded").ok_or_else(|| invalid("is missing 'uploaded'"))?,
+            downloaded: integer(b"downloaded").ok_or_else(|| invalid("is missing 'downloaded'"))?,
+            partial,
+        })
+    }
+
+    /// Write the snapshot atomically: a temporary file renamed over the old one
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let tmp = PathBuf::from(tmp);
+
+        tokio::fs::write(&tmp, self.to_bytes()).await?;
+        tokio::fs::rename(&tmp, path).await?;
+
+        debug!("Saved resume data to {}", path.display());
+        Ok(())
+    }
+
+    /// Load a snapshot, or `None` if there is no usable one
+    ///
+    /// A corrupt or mismatched file is ignored with a warning so the caller
+    /// can fall back to verifying what's on disk.
+    pub async fn load(path: &Path, info_hash: [u8; 20], num_pieces: usize) -> Option<Self> {
+        let data = tokio::fs::read(path).await.ok()?;
+
+        match Self::from_bytes(&data, info_hash, num_pieces) {
+            Ok(resume) => Some(resume),
+            Err(e) => {
+                warn!("Ignoring resume file {}: {}", path.display(), e);
+                None
+            }
+        }
+    }
+}
+
+/// Pack flags into a bitfield, high bit first
+fn pack_bits(flags: &[bool]) -> Vec<u8> {
+    let mut bitfield = vec![0u8; flags.len().div_ceil(8)];
+    for (index, _) in flags.iter().enumerate().filter(|(_, &set)| set) {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    bitfield
+}
+
+/// Unpack `count` flags, or None if the bitfield is the wrong size
+fn unpack_bits(bitfield: &[u8], count: usize) -> Option<Vec<bool>> {
+    if bitfield.len() != count.div_ceil(8) {
+        return None;
+    }
+    Some(
+        (0..count)
+            .map(|index| bitfield[index / 8] & (0x80 >> (index % 8)) != 0)
+            .collect(),
+    )
+}
+
+/// Decode one `[piece, block count, bitfield]` entry of the partial list
+fn partial_entry(entry: &BencodeValue, num_pieces: usize) -> Option<(usize, Vec<bool>)> {
+    match entry.as_list()? {
+        [index, count, bits] => {
+            let index = usize::try_from(index.as_integer()?).ok()?;
+            let count = usize::try_from(count.as_integer()?).ok()?;
+            if index >= num_pieces {
+                return None;
+            }
+            Some((index, unpack_bits(bits.as_bytes()?, count)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> ResumeData {
+        ResumeData {
+            info_hash: [3u8; 20],
+            completed: vec![true, false, false, true, false, false, false, false, true],
+            availability: vec![1, 2, 3, 4, 5, 6, 7, 8, 0],
+            uploaded: 1000,
+            downloaded: 123_456,
+            partial: vec![(2, vec![true, true, false, true]), (5, vec![false; 9])],
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let resume = sample();
+        let decoded = ResumeData::from_bytes(&resume.to_bytes(), [3u8; 20], 9).unwrap();
+        assert_eq!(decoded, resume);
+    }
+
+    #[test]
+    fn test_mismatched_file_is_rejected() {
+        let bytes = sample().to_bytes();
+        assert!(ResumeData::from_bytes(&bytes, [4u8; 20], 9).is_err());
+        assert!(ResumeData::from_bytes(&bytes, [3u8; 20], 10).is_err());
+        assert!(ResumeData::from_bytes(b"garbage", [3u8; 20], 9).is_err());
+
+        let mut old_version = bytes.clone();
+        let at = bytes
+            .windows(12)
+            .position(|w| w == b"7:versioni1e")
+            .unwrap();
+        old_version[at + 10] = b'0';
+        assert!(ResumeData::from_bytes(&old_version, [3u8; 20], 9).is_err());
+    }
+
+    #[test]
+    fn test_file_without_partial_pieces_loads() {
+        let resume = ResumeData {
+            partial: Vec::new(),
+            ..sample()
+        };
+        let bytes = resume.to_bytes();
+        let at = bytes.windows(10).position(|w| w == b"7:partiall").unwrap();
+        let old = [&bytes[..at], &bytes[at + 11..]].concat();
+        assert_eq!(ResumeData::from_bytes(&old, [3u8; 20], 9).unwrap(), resume);
+
+        let mut bad = sample();
+        bad.partial = vec![(9, vec![true])];
+        assert!(ResumeData::from_bytes(&bad.to_bytes(), [3u8; 20], 9).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("resume-test-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await.unwrap();
+        let path = resume_path(&dir, "file.bin");
+
+        assert_eq!(ResumeData::load(&path, [3u8; 20], 9).await, None);
+
+        sample().save(&path).await.unwrap();
+        assert_eq!(ResumeData::load(&path, [3u8; 20], 9).await, Some(sample()));
+
+        tokio::fs::write(&path, b"d7:versioni99ee").await.unwrap();
+        assert_eq!(ResumeData::load(&path, [3u8; 20], 9).await, None);
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+//! Loopback test doubles for exercising the download path without a network
+
+mod peer;
+mod tracker;
+mod webseed;
+
+pub use peer::{MockBehavior, MockPeer, PieceFixture};
+pub use tracker::MockTracker;
+pub use webseed::{MockHttpSeed, MockWebSeed};
+
+use crate::bencode::{encode, BencodeValue};
+use crate::torrent::{FileInfo, Pieces, TorrentInfo};
+use rand::{Rng, RngCore};
+use sha1::{Digest, Sha1};
+
+/// Generate `len` bytes of random content
+pub fn random_content(len: usize) -> Vec<u8> {
+    let mut content = vec![0u8; len];
+    rand::thread_rng().fill_bytes(&mut content);
+    content
+}
+
+/// Keys a torrent parser looks for, so random dicts reach deep code paths
+const TORRENT_KEYS: &[&str] = &[
+    "announce",
+    "announce-list",
+    "nodes",
+    "url-list",
+    "httpseeds",
+    "info",
+    "piece layers",
+    "name",
+    "piece length",
+    "pieces",
+    "length",
+    "files",
+    "path",
+    "meta version",
+    "file tree",
+    "pieces root",
+    "",
+];
+
+/// Integers at the edges of what parsers convert and multiply
+const EDGE_INTEGERS: &[i64] = &[0, 1, -1, 20, 16384, u32::MAX as i64, i64::MAX, i64::MIN];
+
+/// A random bencode value nested at most `depth` levels, biased toward
+/// torrent-like keys and awkward integers
+pub fn random_bencode(rng: &mut impl Rng, depth: usize) -> BencodeValue {
+    let kind = if depth == 0 {
+        rng.gen_range(0..2)
+    } else {
+        rng.gen_range(0..4)
+    };
+    match kind {
+        0 if rng.gen_bool(0.5) => {
+            BencodeValue::Integer(EDGE_INTEGERS[rng.gen_range(0..EDGE_INTEGERS.len())])
+        }
+        0 => BencodeValue::Integer(rng.gen_range(-100..100_000)),
+        1 => {
+            // Often a whole number of hashes or a socket address length
+            let len = match rng.gen_range(0..3) {
+                0 => 20 * rng.gen_range(0..4),
+                1 => 6,
+                _ => rng.gen_range(0..40),
+            };
+            let mut bytes = vec![0u8; len];
+            rng.fill_bytes(&mut bytes);
+            BencodeValue::String(bytes)
+        }
+        2 => BencodeValue::List(
+            (0..rng.gen_range(0..4))
+                .map(|_| random_bencode(rng, depth - 1))
+                .collect(),
+        ),
+        _ => BencodeValue::Dict(
+            (0..rng.gen_range(0..6))
+                .map(|_| {
+                    let key = TORRENT_KEYS[rng.gen_range(0..TORRENT_KEYS.len())];
+                    (key.as_bytes().to_vec(), random_bencode(rng, depth - 1))
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Corrupt `data` by truncating it, flipping bytes or splicing in bencode
+/// delimiters
+pub fn mutate_bytes(rng: &mut impl Rng, data: &mut Vec<u8>) {
+    for _ in 0..rng.gen_range(1..4) {
+        if data.is_empty() {
+            data.push(b'd');
+            continue;
+        }
+        let at = rng.gen_range(0..data.len());
+        match rng.gen_range(0..3) {
+            0 => data.truncate(at),
+            1 => data[at] = rng.gen(),
+            _ => data.insert(at, b"deil:9-"[rng.gen_range(0..7)]),
+        }
+    }
+}
+
+/// Build a single-file `TorrentInfo` with placeholder piece hashes
+pub fn make_info(piece_length: u64, total_length: u64) -> TorrentInfo {
+    let num_pieces = total_length.div_ceil(piece_length) as usize;
+
+    TorrentInfo {
+        name: "test.bin".to_string(),
+        piece_length,
+        pieces: Pieces::from_bytes(&vec![0u8; num_pieces * 20]).unwrap(),
+        files: vec![FileInfo {
+            path: vec!["test.bin".to_string()],
+            length: total_length,
+        }],
+        total_length,
+        multi_file: false,
+        meta_version: 1,
+        v2_files: Vec::new(),
+    }
+}
+
+/// Build a single-file .torrent for `content`
+pub fn make_torrent(name: &str, content: &[u8], piece_length: u64, announce: &str) -> Vec<u8> {
+    let pieces: Vec<u8> = content
+        .chunks(piece_length as usize)
+        .flat_map(|chunk| Sha1::digest(chunk).to_vec())
+        .collect();
+
+    let info = BencodeValue::dict([
+        ("name", name.into()),
+        ("piece length", (piece_length as i64).into()),
+        ("pieces", pieces.into()),
+        ("length", (content.len() as i64).into()),
+    ]);
+
+    encode(&BencodeValue::dict([
+        ("announce", announce.into()),
+        ("info", info),
+    ]))
+}
@@ -0,0 +1,284 @@
+use super::make_torrent;
+use crate::peer::{BlockInfo, Handshake, PeerConnection, PeerMessage};
+use crate::piece::PieceManager;
+use crate::storage::MemoryStorage;
+use crate::torrent::{parse_torrent, Metainfo};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Knobs for making a mock peer misbehave
+#[derive(Debug, Clone, Default)]
+pub struct MockBehavior {
+    /// Delay before answering each block request
+    pub block_delay: Duration,
+    /// Choke the leecher once this many blocks have been served
+    pub choke_after_blocks: Option<usize>,
+    /// How long to stay choked, ignoring requests, before unchoking again
+    pub choke_for: Duration,
+    /// Ignore Interested and keep the leecher choked for good
+    pub never_unchoke: bool,
+    /// Start with an empty bitfield and announce every piece with Have
+    pub announce_with_have: bool,
+    /// Hang up instead of answering once this many blocks have been served
+    pub disconnect_after_blocks: Option<usize>,
+    /// Flip the first byte of the nth block served, counting from zero
+    pub corrupt_block: Option<usize>,
+    /// Messages sent just before the reply to the nth block, counting from
+    /// zero
+    pub extra_before_block: Vec<(usize, PeerMessage)>,
+    /// Collect this many requests before answering, then answer them as
+    /// one span of the piece
+    pub batch_requests: usize,
+    /// Piece offsets at which a batched reply is cut into separate Piece
+    /// messages, regardless of block boundaries
+    pub split_batch_at: Vec<u32>,
+}
+
+/// A peer that seeds in-memory content over loopback
+pub struct MockPeer {
+    addr: SocketAddr,
+    /// Block requests received over every connection
+    requests: Arc<Mutex<Vec<BlockInfo>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockPeer {
+    /// Start serving `content` for `info_hash` on an ephemeral port
+    pub async fn spawn(info_hash: [u8; 20], content: Vec<u8>, piece_length: u64) -> Self {
+        Self::spawn_with(info_hash, content, piece_length, MockBehavior::default()).await
+    }
+
+    /// Start a peer with custom behavior
+    pub async fn spawn_with(
+        info_hash: [u8; 20],
+        content: Vec<u8>,
+        piece_length: u64,
+        behavior: MockBehavior,
+    ) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let content = Arc::new(content);
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let log = requests.clone();
+
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let content = content.clone();
+                let behavior = behavior.clone();
+                let log = log.clone();
+                tokio::spawn(async move {
+                    let _ = serve(socket, info_hash, &content, piece_length, &behavior, &log).await;
+                });
+            }
+        });
+
+        Self {
+            addr,
+            requests,
+            task,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Number of block requests received so far
+    pub fn requests(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+
+    /// Every block requested so far, in arrival order
+    pub fn requested_blocks(&self) -> Vec<BlockInfo> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl Drop for MockPeer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A mock peer seeding `content`, a connection to it, and an empty piece
+/// manager and storage to download into
+pub struct PieceFixture {
+    pub metainfo: Metainfo,
+    pub seed: MockPeer,
+    pub peer: PeerConnection,
+    pub piece_manager: Arc<tokio::sync::Mutex<PieceManager>>,
+    pub storage: Arc<MemoryStorage>,
+}
+
+impl PieceFixture {
+    /// Serve `content` in pieces of `piece_length` from a peer behaving
+    /// as `behavior` says
+    pub async fn new(content: &[u8], piece_length: u64, behavior: MockBehavior) -> Self {
+        let torrent = make_torrent("fixture.bin", content, piece_length, "http://x");
+        let metainfo = parse_torrent(&torrent).unwrap();
+        let seed =
+            MockPeer::spawn_with(metainfo.info_hash, content.to_vec(), piece_length, behavior)
+                .await;
+
+        let mut peer = PeerConnection::connect(seed.addr(), metainfo.info_hash, [1u8; 20])
+            .await
+            .unwrap();
+        peer.set_piece_count(metainfo.info.piece_count());
+        let piece_manager = Arc::new(tokio::sync::Mutex::new(PieceManager::new(&metainfo.info)));
+        let storage = Arc::new(MemoryStorage::new(piece_length, content.len() as u64));
+
+        Self {
+            metainfo,
+            seed,
+            peer,
+            piece_manager,
+            storage,
+        }
+    }
+
+    /// Serve `content` as a single piece
+    pub async fn single_piece(content: &[u8], behavior: MockBehavior) -> Self {
+        Self::new(content, content.len() as u64, behavior).await
+    }
+}
+
+/// Handle one leecher connection until it hangs up
+async fn serve(
+    mut socket: TcpStream,
+    info_hash: [u8; 20],
+    content: &[u8],
+    piece_length: u64,
+    behavior: &MockBehavior,
+    requests: &Mutex<Vec<BlockInfo>>,
+) -> std::io::Result<()> {
+    let mut handshake = [0u8; 68];
+    socket.read_exact(&mut handshake).await?;
+    socket
+        .write_all(&Handshake::new(info_hash, *b"-MK0001-mockpeer0000").to_bytes())
+        .await?;
+
+    let num_pieces = content.len().div_ceil(piece_length as usize);
+    if behavior.announce_with_have {
+        let empty = PeerMessage::Bitfield {
+            bitfield: vec![0; num_pieces.div_ceil(8)],
+        };
+        socket.write_all(&empty.to_bytes()).await?;
+        for piece_index in 0..num_pieces as u32 {
+            let have = PeerMessage::Have { piece_index };
+            socket.write_all(&have.to_bytes()).await?;
+        }
+    } else {
+        let full = PeerMessage::Bitfield {
+            bitfield: full_bitfield(num_pieces),
+        };
+        socket.write_all(&full.to_bytes()).await?;
+    }
+
+    let mut served = 0;
+    let mut batch = Vec::new();
+    loop {
+        match read_message(&mut socket).await? {
+            PeerMessage::Interested if !behavior.never_unchoke => {
+                socket.write_all(&PeerMessage::Unchoke.to_bytes()).await?;
+            }
+            PeerMessage::Request { block } => {
+                requests.lock().unwrap().push(block);
+                if behavior.disconnect_after_blocks == Some(served) {
+                    return Ok(());
+                }
+                batch.push(block);
+                if batch.len() < behavior.batch_requests {
+                    continue;
+                }
+
+                tokio::time::sleep(behavior.block_delay).await;
+                for (_, extra) in behavior
+                    .extra_before_block
+                    .iter()
+                    .filter(|(at, _)| *at == served)
+                {
+                    socket.write_all(&extra.to_bytes()).await?;
+                }
+
+                let start = batch.iter().map(|block| block.offset).min().unwrap();
+                let end = batch
+                    .iter()
+                    .map(|block| block.offset + block.length)
+                    .max()
+                    .unwrap();
+                let mut cuts = vec![start];
+                cuts.extend(
+                    behavior
+                        .split_batch_at
+                        .iter()
+                        .filter(|&&at| at > start && at < end),
+                );
+                cuts.push(end);
+
+                let piece_start = block.piece_index as usize * piece_length as usize;
+                for span in cuts.windows(2) {
+                    let from = piece_start + span[0] as usize;
+                    let to = std::cmp::min(piece_start + span[1] as usize, content.len());
+                    let mut data = content[from..to].to_vec();
+                    if behavior.corrupt_block == Some(served) {
+                        if let Some(byte) = data.first_mut() {
+                            *byte ^= 0xff;
+                        }
+                    }
+                    let reply = PeerMessage::Piece {
+                        piece_index: block.piece_index,
+                        offset: span[0],
+                        data,
+                    };
+                    socket.write_all(&reply.to_bytes()).await?;
+                }
+
+                served += batch.len();
+                batch.clear();
+                if behavior.choke_after_blocks == Some(served) {
+                    choke_for(&mut socket, behavior.choke_for).await?;
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Choke the leecher, drop whatever it requests for `duration`, then
+/// unchoke it again
+async fn choke_for(socket: &mut TcpStream, duration: Duration) -> std::io::Result<()> {
+    socket.write_all(&PeerMessage::Choke.to_bytes()).await?;
+
+    let deadline = tokio::time::Instant::now() + duration;
+    while let Ok(message) = tokio::time::timeout_at(deadline, read_message(socket)).await {
+        message?;
+    }
+
+    socket.write_all(&PeerMessage::Unchoke.to_bytes()).await
+}
+
+/// Bitfield advertising every one of `num_pieces` pieces
+pub fn full_bitfield(num_pieces: usize) -> Vec<u8> {
+    let mut bitfield = vec![0u8; num_pieces.div_ceil(8)];
+    for index in 0..num_pieces {
+        bitfield[index / 8] |= 0x80 >> (index % 8);
+    }
+    bitfield
+}
+
+/// Read one length-prefixed peer message
+pub async fn read_message(socket: &mut TcpStream) -> std::io::Result<PeerMessage> {
+    let mut length = [0u8; 4];
+    socket.read_exact(&mut length).await?;
+
+    let mut frame = length.to_vec();
+    frame.resize(4 + u32::from_be_bytes(length) as usize, 0);
+    socket.read_exact(&mut frame[4..]).await?;
+
+    PeerMessage::from_bytes(&frame)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()))
+}
@@ -0,0 +1,253 @@
+use crate::bencode::{encode, BencodeValue};
+use crate::tracker::Peer;
+use sha2::{Digest, Sha256};
+use std::net::SocketAddr;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::task::JoinHandle;
+
+/// A minimal HTTP or UDP tracker that answers every announce with a fixed
+/// peer list
+pub struct MockTracker {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+    /// DER certificate when serving HTTPS
+    certificate: Option<Vec<u8>>,
+    /// Whether this speaks the UDP tracker protocol
+    udp: bool,
+}
+
+impl MockTracker {
+    /// Start a tracker returning `peers` in compact form
+    pub async fn spawn(peers: Vec<SocketAddr>) -> Self {
+        let peers: Vec<Peer> = peers
+            .into_iter()
+            .map(|addr| Peer::new(addr.ip(), addr.port()))
+            .collect();
+        let body = encode(&BencodeValue::dict([
+            ("interval", 1800.into()),
+            ("peers", Peer::to_compact_list(&peers).into()),
+        ]));
+
+        Self::with_body(body).await
+    }
+
+    /// Start a tracker that replies with a raw response body
+    pub async fn with_body(body: Vec<u8>) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(respond(socket, body.clone()));
+            }
+        });
+
+        Self {
+            addr,
+            task,
+            certificate: None,
+            udp: false,
+        }
+    }
+
+    /// Start an HTTP tracker that answers every announce with a 301 to
+    /// `location`
+    pub async fn redirect_to(location: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let status = format!("301 Moved Permanently\r\nLocation: {}", location);
+
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                tokio::spawn(reply(socket, status.clone(), Vec::new()));
+            }
+        });
+
+        Self {
+            addr,
+            task,
+            certificate: None,
+            udp: false,
+        }
+    }
+
+    /// Start a UDP tracker (BEP 15) returning `peers`, which must be IPv4
+    pub async fn spawn_udp(peers: Vec<SocketAddr>) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                if len < 16 {
+                    continue;
+                }
+                let action = u32::from_be_bytes(buf[8..12].try_into().unwrap());
+                // Action and transaction ID are echoed back
+                let mut reply = buf[8..16].to_vec();
+                match action {
+                    0 => reply.extend_from_slice(&0x5eed_u64.to_be_bytes()),
+                    1 => {
+                        reply.extend_from_slice(&1800u32.to_be_bytes());
+                        reply.extend_from_slice(&0u32.to_be_bytes());
+                        reply.extend_from_slice(&(peers.len() as u32).to_be_bytes());
+                        for peer in &peers {
+                            reply.extend(Peer::new(peer.ip(), peer.port()).to_compact().unwrap());
+                        }
+                    }
+                    _ => continue,
+                }
+                let _ = socket.send_to(&reply, from).await;
+            }
+        });
+
+        Self {
+            addr,
+            task,
+            certificate: None,
+            udp: true,
+        }
+    }
+
+    /// Start an HTTPS tracker with a fresh self-signed certificate for
+    /// 127.0.0.1
+    pub async fn with_tls(body: Vec<u8>) -> Self {
+        let (cert, key) = self_signed_certificate();
+        let identity = tokio_native_tls::native_tls::Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let acceptor = tokio_native_tls::TlsAcceptor::from(
+            tokio_native_tls::native_tls::TlsAcceptor::new(identity).unwrap(),
+        );
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let task = tokio::spawn(async move {
+            while let Ok((socket, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                let body = body.clone();
+                tokio::spawn(async move {
+                    // Clients that reject the certificate hang up here
+                    if let Ok(stream) = acceptor.accept(socket).await {
+                        respond(stream, body).await;
+                    }
+                });
+            }
+        });
+
+        Self {
+            addr,
+            task,
+            certificate: Some(cert.to_der().unwrap()),
+            udp: false,
+        }
+    }
+
+    /// Announce URL for this tracker
+    pub fn announce_url(&self) -> String {
+        let scheme = if self.udp {
+            "udp"
+        } else if self.certificate.is_some() {
+            "https"
+        } else {
+            "http"
+        };
+        format!("{}://{}/announce", scheme, self.addr)
+    }
+
+    /// PEM certificate of an HTTPS tracker
+    pub fn certificate_pem(&self) -> Vec<u8> {
+        let der = self.certificate.as_ref().expect("not an HTTPS tracker");
+        openssl::x509::X509::from_der(der)
+            .unwrap()
+            .to_pem()
+            .unwrap()
+    }
+
+    /// SHA-256 fingerprint of an HTTPS tracker's certificate
+    pub fn certificate_fingerprint(&self) -> [u8; 32] {
+        let der = self.certificate.as_ref().expect("not an HTTPS tracker");
+        Sha256::digest(der).into()
+    }
+}
+
+impl Drop for MockTracker {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Read one request and answer it with `body`
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(socket: S, body: Vec<u8>) {
+    reply(socket, "200 OK".to_string(), body).await
+}
+
+/// Read one request and answer it with `status` (which may carry extra
+/// header lines) and `body`
+async fn reply<S: AsyncRead + AsyncWrite + Unpin>(mut socket: S, status: String, body: Vec<u8>) {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        match socket.read(&mut buf).await {
+            Ok(0) | Err(_) => return,
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+        }
+    }
+
+    let header = format!(
+        "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    );
+    let _ = socket.write_all(header.as_bytes()).await;
+    let _ = socket.write_all(&body).await;
+    let _ = socket.shutdown().await;
+}
+
+/// A one-day self-signed certificate for 127.0.0.1 and its key
+fn self_signed_certificate() -> (
+    openssl::x509::X509,
+    openssl::pkey::PKey<openssl::pkey::Private>,
+) {
+    use openssl::asn1::Asn1Time;
+    use openssl::bn::BigNum;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::x509::extension::SubjectAlternativeName;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "127.0.0.1").unwrap();
+    let name = name.build();
+
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+    builder.set_serial_number(&serial).unwrap();
+    builder.set_subject_name(&name).unwrap();
+    builder.set_issuer_name(&name).unwrap();
+    builder.set_pubkey(&key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    let san = SubjectAlternativeName::new()
+        .ip("127.0.0.1")
+        .build(&builder.x509v3_context(None, None))
+        .unwrap();
+    builder.append_extension(san).unwrap();
+    builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+    (builder.build(), key)
+}
@@ -0,0 +1,167 @@
+use crate::tracker::urlencode_bytes;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+
+/// A minimal HTTP server answering `Range` requests for fixed files
+pub struct MockWebSeed {
+    addr: SocketAddr,
+    task: JoinHandle<()>,
+}
+
+impl MockWebSeed {
+    /// Serve each `(path, content)` pair, e.g. `("/data.bin", bytes)`
+    pub async fn spawn(files: Vec<(&str, Vec<u8>)>) -> Self {
+        Self::spawn_with(files, true).await
+    }
+
+    /// Like [`spawn`](Self::spawn), but answer every request with the whole
+    /// file unless `honor_ranges` is set
+    pub async fn spawn_with(files: Vec<(&str, Vec<u8>)>, honor_ranges: bool) -> Self {
+        let files: HashMap<String, Vec<u8>> = files
+            .into_iter()
+            .map(|(path, content)| (path.to_string(), content))
+            .collect();
+
+        let (addr, task) = serve_http(move |request| {
+            let path = request.split_whitespace().nth(1).unwrap_or("");
+            let range = request.lines().filter(|_| honor_ranges).find_map(|line| {
+                let (start, end) = line
+                    .to_ascii_lowercase()
+                    .strip_prefix("range: bytes=")?
+                    .split_once('-')
+                    .map(|(s, e)| (s.to_string(), e.to_string()))?;
+                Some((start.parse::<usize>().ok()?, end.parse::<usize>().ok()?))
+            });
+
+            match (files.get(path), range) {
+                (Some(content), Some((start, end))) if end < content.len() => {
+                    ("206 Partial Content", content[start..=end].to_vec())
+                }
+                (Some(content), None) => ("200 OK", content.clone()),
+                (Some(_), Some(_)) => ("416 Range Not Satisfiable", Vec::new()),
+                (None, _) => ("404 Not Found", Vec::new()),
+            }
+        })
+        .await;
+
+        Self { addr, task }
+    }
+
+    /// Base URL of the server, with a trailing slash
+    pub fn url(&self) -> String {
+        format!("http://{}/", self.addr)
+    }
+}
+
+impl Drop for MockWebSeed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// A BEP 17 HTTP seed serving one torrent's pieces at `/seed`
+pub struct MockHttpSeed {
+    addr: SocketAddr,
+    /// Seconds to send with a 503 instead of serving, while set
+    busy: Arc<Mutex<Option<u64>>>,
+    task: JoinHandle<()>,
+}
+
+impl MockHttpSeed {
+    pub async fn spawn(info_hash: [u8; 20], content: Vec<u8>, piece_length: u64) -> Self {
+        let busy: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+        let expected_hash = urlencode_bytes(&info_hash);
+        let seconds = busy.clone();
+
+        let (addr, task) = serve_http(move |request| {
+            if let Some(seconds) = *seconds.lock().unwrap() {
+                return ("503 Service Unavailable", seconds.to_string().into_bytes());
+            }
+            let target = request.split_whitespace().nth(1).unwrap_or("");
+            let Some(query) = target.strip_prefix("/seed?") else {
+                return ("404 Not Found", Vec::new());
+            };
+            let params: HashMap<&str, &str> = query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .collect();
+            let piece = params
+                .get("piece")
+                .and_then(|piece| piece.parse::<usize>().ok());
+
+            let piece_length = piece_length as usize;
+            match piece {
+                Some(piece)
+                    if params.get("info_hash") == Some(&expected_hash.as_str())
+                        && piece * piece_length < content.len() =>
+                {
+                    let end = std::cmp::min((piece + 1) * piece_length, content.len());
+                    ("200 OK", content[piece * piece_length..end].to_vec())
+                }
+                _ => ("400 Bad Request", Vec::new()),
+            }
+        })
+        .await;
+
+        Self { addr, busy, task }
+    }
+
+    /// Answer every request with a 503 asking for `seconds` of patience,
+    /// or serve again with None
+    pub fn set_busy(&self, seconds: Option<u64>) {
+        *self.busy.lock().unwrap() = seconds;
+    }
+
+    pub fn url(&self) -> String {
+        format!("http://{}/seed", self.addr)
+    }
+}
+
+impl Drop for MockHttpSeed {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Answer each HTTP request on a loopback port with `handler`'s status line
+/// and body, given the request head
+async fn serve_http<F>(handler: F) -> (SocketAddr, JoinHandle<()>)
+where
+    F: Fn(&str) -> (&'static str, Vec<u8>) + Send + Sync + 'static,
+{
+    let handler = Arc::new(handler);
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let task = tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            let handler = handler.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buf = [0u8; 1024];
+                while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                    match socket.read(&mut buf).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(n) => request.extend_from_slice(&buf[..n]),
+                    }
+                }
+
+                let (status, body) = handler(&String::from_utf8_lossy(&request));
+
+                let header = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                let _ = socket.write_all(header.as_bytes()).await;
+                let _ = socket.write_all(&body).await;
+            });
+        }
+    });
+
+    (addr, task)
+}
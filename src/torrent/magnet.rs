@@ -0,0 +1,194 @@
+use super::v2::Sha256Hash;
+use super::Metainfo;
+use crate::error::{BittorrentError, Result};
+use url::form_urlencoded;
+
+/// `xt` prefix for a hex or base32 SHA-1 info hash
+const BTIH_PREFIX: &str = "urn:btih:";
+
+/// `xt` prefix for a v2 info hash: multihash code 0x12 (SHA-256), length 0x20
+const BTMH_PREFIX: &str = "urn:btmh:1220";
+
+/// The parts of a magnet link we understand
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MagnetLink {
+    /// v1 info hash (`urn:btih`)
+    pub info_hash: Option<[u8; 20]>,
+    /// v2 info hash (`urn:btmh`)
+    pub v2_info_hash: Option<Sha256Hash>,
+    /// Suggested name (`dn`)
+    pub display_name: Option<String>,
+    /// Trackers (`tr`), in link order
+    pub trackers: Vec<String>,
+}
+
+impl MagnetLink {
+    /// Describe a parsed torrent as a magnet link
+    pub fn from_metainfo(metainfo: &Metainfo) -> Self {
+        Self {
+            info_hash: (!metainfo.info.is_v2_only()).then_some(metainfo.info_hash),
+            v2_info_hash: metainfo.v2_info_hash,
+            display_name: Some(metainfo.info.name.clone()),
+            trackers: metainfo.announce_tiers().into_iter().flatten().collect(),
+        }
+    }
+
+    /// Parse a `magnet:?` URI
+    ///
+    /// Unknown parameters are ignored; at least one info hash is required.
+    #[allow(
+        dead_code,
+        reason = "magnet links can't be downloaded until metadata exchange (BEP 9) exists"
+    )]
+    pub fn parse(uri: &str) -> Result<Self> {
+        let query = uri
+            .get(..8)
+            .filter(|prefix| prefix.eq_ignore_ascii_case("magnet:?"))
+            .map(|_| &uri[8..])
+            .ok_or_else(|| BittorrentError::InvalidMagnet(format!("not a magnet URI: {}", uri)))?;
+
+        let mut link = MagnetLink::default();
+        for (key, value) in form_urlencoded::parse(query.as_bytes()) {
+            // Repeated parameters may be numbered, as in `tr.1`
+            let key = key.split('.').next().unwrap_or_default();
+            match key {
+                "xt" => link.parse_exact_topic(&value)?,
+                "dn" => link.display_name = Some(value.into_owned()),
+                "tr" => link.trackers.push(value.into_owned()),
+                _ => {}
+            }
+        }
+
+        if link.info_hash.is_none() && link.v2_info_hash.is_none() {
+            return Err(BittorrentError::InvalidMagnet(
+                "no urn:btih or urn:btmh info hash".to_string(),
+            ));
+        }
+        Ok(link)
+    }
+
+    /// Read one `xt` value; topics other than BitTorrent hashes are skipped
+    fn parse_exact_topic(&mut self, topic: &str) -> Result<()> {
+        let invalid = || BittorrentError::InvalidMagnet(format!("bad info hash: {}", topic));
+
+        if let Some(hash) = topic.strip_prefix(BTIH_PREFIX) {
+            let bytes = match hash.len() {
+                40 => hex::decode(hash).map_err(|_| invalid())?,
+                32 => decode_base32(hash).ok_or_else(invalid)?,
+                _ => return Err(invalid()),
+            };
+            self.info_hash = Some(bytes.try_into().map_err(|_| invalid())?);
+        } else if let Some(hash) = topic.strip_prefix(BTMH_PREFIX) {
+            let bytes = hex::decode(hash).map_err(|_| invalid())?;
+            self.v2_info_hash = Some(bytes.try_into().map_err(|_| invalid())?);
+        }
+        Ok(())
+    }
+
+    /// Format as a `magnet:?` URI
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if let Some(info_hash) = &self.info_hash {
+            params.push(format!("xt={}{}", BTIH_PREFIX, hex::encode(info_hash)));
+        }
+        if let Some(v2_info_hash) = &self.v2_info_hash {
+            params.push(format!("xt={}{}", BTMH_PREFIX, hex::encode(v2_info_hash)));
+        }
+        if let Some(name) = &self.display_name {
+            params.push(format!("dn={}", encode(name)));
+        }
+        for tracker in &self.trackers {
+            params.push(format!("tr={}", encode(tracker)));
+        }
+
+        format!("magnet:?{}", params.join("&"))
+    }
+}
+
+fn encode(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Decode RFC 4648 base32 without padding, as used by old-style magnets
+fn decode_base32(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer = 0u64;
+    let mut bits = 0;
+
+    for c in text.bytes() {
+        let value = match c.to_ascii_uppercase() {
+            c @ b'A'..=b'Z' => c - b'A',
+            c @ b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::make_torrent;
+    use crate::torrent::parse_torrent;
+
+    #[test]
+    fn test_magnet_link_round_trip() {
+        let torrent = make_torrent(
+            "my file & more.bin",
+            &[7u8; 1000],
+            16384,
+            "http://tracker.example/announce?key=a&b=c",
+        );
+        let mut metainfo = parse_torrent(&torrent).unwrap();
+        metainfo.announce_list = Some(vec![
+            vec!["http://tracker.example/announce?key=a&b=c".to_string()],
+            vec![
+                "udp://backup.example:6969/announce".to_string(),
+                "https://third.example/announce".to_string(),
+            ],
+        ]);
+
+        let uri = metainfo.magnet_link();
+        assert!(uri.starts_with(&format!(
+            "magnet:?xt=urn:btih:{}&dn=my+file+%26+more.bin&tr=",
+            metainfo.info_hash_hex()
+        )));
+
+        let parsed = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(parsed.info_hash, Some(metainfo.info_hash));
+        assert_eq!(parsed.display_name.as_deref(), Some("my file & more.bin"));
+        assert_eq!(
+            parsed.trackers,
+            vec![
+                "http://tracker.example/announce?key=a&b=c",
+                "udp://backup.example:6969/announce",
+                "https://third.example/announce",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_base32_and_numbered_params() {
+        let hash = [0xab; 20];
+        let base32 = "VOV2XK5LVOV2XK5LVOV2XK5LVOV2XK5L";
+        let uri = format!("MAGNET:?xt.1=urn:btih:{}&tr.1=udp%3A%2F%2Fa%3A1", base32);
+
+        let parsed = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(parsed.info_hash, Some(hash));
+        assert_eq!(parsed.trackers, vec!["udp://a:1"]);
+    }
+
+    #[test]
+    fn test_parse_rejects_links_without_hash() {
+        assert!(MagnetLink::parse("magnet:?dn=name").is_err());
+        assert!(MagnetLink::parse("magnet:?xt=urn:btih:1234").is_err());
+        assert!(MagnetLink::parse("http://example.com/?xt=urn:btih:").is_err());
+    }
+}
@@ -1,8 +1,12 @@
-use crate::bencode::{encode, BencodeValue};
-use crate::error::{BittorrentError, Result};
+use super::v2::{parse_file_tree, parse_piece_layers, Sha256Hash, V2File};
 use super::Pieces;
+use crate::bencode::{find_dict_value, BencodeValue};
+use crate::error::{BittorrentError, Result};
 use sha1::{Digest, Sha1};
-use std::collections::BTreeMap;
+use sha2::Sha256;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
 
 /// Represents a file in a multi-file torrent
 #[derive(Debug, Clone)]
@@ -24,6 +28,12 @@ pub struct TorrentInfo {
     pub files: Vec<FileInfo>,
     /// Total length of all files
     pub total_length: u64,
+    /// Whether the torrent uses the multi-file (`files`) layout
+    pub multi_file: bool,
+    /// `meta version`: 1 for classic torrents, 2 for BEP 52 (v2 or hybrid)
+    pub meta_version: u8,
+    /// Files from the v2 `file tree` (empty for v1-only torrents)
+    pub v2_files: Vec<V2File>,
 }
 
 impl TorrentInfo {
@@ -45,78 +55,154 @@ impl TorrentInfo {
             .and_then(|v| v.as_integer())
             .ok_or_else(|| {
                 BittorrentError::InvalidTorrent("Missing 'piece length' field".to_string())
-            })? as u64;
+            })?;
+        let piece_length = validate_piece_length(piece_length)?;
+
+        // Parse meta version (absent in v1 torrents)
+        let meta_version = match dict.get(b"meta version".as_ref()) {
+            None => 1,
+            Some(value) => match value.as_integer() {
+                Some(1) => 1,
+                Some(2) => 2,
+                _ => {
+                    return Err(BittorrentError::InvalidTorrent(
+                        "Unsupported 'meta version'".to_string(),
+                    ))
+                }
+            },
+        };
 
-        // Parse pieces
-        let pieces_bytes = dict
-            .get(b"pieces".as_ref())
-            .and_then(|v| v.as_bytes())
-            .ok_or_else(|| BittorrentError::InvalidTorrent("Missing 'pieces' field".to_string()))?;
+        // Parse the v2 file tree
+        let v2_files = if meta_version == 2 {
+            let tree = dict.get(b"file tree".as_ref()).ok_or_else(|| {
+                BittorrentError::InvalidTorrent("Missing 'file tree' field".to_string())
+            })?;
+            parse_file_tree(tree)?
+        } else {
+            Vec::new()
+        };
 
-        let pieces = Pieces::from_bytes(pieces_bytes)?;
+        // Parse pieces (v2-only torrents have none; hybrids carry both)
+        let pieces = match dict.get(b"pieces".as_ref()) {
+            Some(value) => {
+                let pieces_bytes = value.as_bytes().ok_or_else(|| {
+                    BittorrentError::InvalidTorrent("Invalid 'pieces' field".to_string())
+                })?;
+                Pieces::from_bytes(pieces_bytes)?
+            }
+            None if meta_version == 2 => Pieces::from_bytes(&[])?,
+            None => {
+                return Err(BittorrentError::InvalidTorrent(
+                    "Missing 'pieces' field".to_string(),
+                ))
+            }
+        };
 
         // Parse files (single-file or multi-file mode)
-        let (files, total_length) = if let Some(length_value) = dict.get(b"length".as_ref()) {
-            // Single-file mode
-            let length = length_value.as_integer().ok_or_else(|| {
-                BittorrentError::InvalidTorrent("Invalid 'length' field".to_string())
-            })? as u64;
-
-            let file = FileInfo {
-                path: vec![name.clone()],
-                length,
-            };
-
-            (vec![file], length)
-        } else if let Some(files_value) = dict.get(b"files".as_ref()) {
-            // Multi-file mode
-            let files_list = files_value.as_list().ok_or_else(|| {
-                BittorrentError::InvalidTorrent("Invalid 'files' field".to_string())
-            })?;
+        let (files, total_length, multi_file) =
+            if let Some(length_value) = dict.get(b"length".as_ref()) {
+                // Single-file mode
+                let length = length_value
+                    .as_integer()
+                    .filter(|&length| length >= 0)
+                    .ok_or_else(|| {
+                        BittorrentError::InvalidTorrent("Invalid 'length' field".to_string())
+                    })? as u64;
 
-            let mut files = Vec::new();
-            let mut total = 0u64;
+                let file = FileInfo {
+                    path: vec![name.clone()],
+                    length,
+                };
 
-            for file_value in files_list {
-                let file_dict = file_value.as_dict().ok_or_else(|| {
-                    BittorrentError::InvalidTorrent("File entry must be a dict".to_string())
+                (vec![file], length, false)
+            } else if let Some(files_value) = dict.get(b"files".as_ref()) {
+                // Multi-file mode
+                let files_list = files_value.as_list().ok_or_else(|| {
+                    BittorrentError::InvalidTorrent("Invalid 'files' field".to_string())
                 })?;
 
-                let length = file_dict
-                    .get(b"length".as_ref())
-                    .and_then(|v| v.as_integer())
-                    .ok_or_else(|| {
-                        BittorrentError::InvalidTorrent("Missing file 'length'".to_string())
-                    })? as u64;
+                let mut files = Vec::new();
+                let mut total = 0u64;
 
-                let path_list = file_dict
-                    .get(b"path".as_ref())
-                    .and_then(|v| v.as_list())
-                    .ok_or_else(|| {
-                        BittorrentError::InvalidTorrent("Missing file 'path'".to_string())
+                for file_value in files_list {
+                    let file_dict = file_value.as_dict().ok_or_else(|| {
+                        BittorrentError::InvalidTorrent("File entry must be a dict".to_string())
                     })?;
 
-                let path = path_list
+                    let length = file_dict
+                        .get(b"length".as_ref())
+                        .and_then(|v| v.as_integer())
+                        .filter(|&length| length >= 0)
+                        .ok_or_else(|| {
+                            BittorrentError::InvalidTorrent(
+                                "Missing or invalid file 'length'".to_string(),
+                            )
+                        })? as u64;
+
+                    let path_list = file_dict
+                        .get(b"path".as_ref())
+                        .and_then(|v| v.as_list())
+                        .ok_or_else(|| {
+                            BittorrentError::InvalidTorrent("Missing file 'path'".to_string())
+                        })?;
+
+                    let path = path_list
+                        .iter()
+                        .map(|v| {
+                            v.as_str()
+                                .ok_or_else(|| {
+                                    BittorrentError::InvalidTorrent(
+                                        "Invalid path component".to_string(),
+                                    )
+                                })
+                                .map(String::from)
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+
+                    total = total.checked_add(length).ok_or_else(|| {
+                        BittorrentError::InvalidTorrent("File lengths overflow".to_string())
+                    })?;
+                    files.push(FileInfo { path, length });
+                }
+
+                (files, total, true)
+            } else if !v2_files.is_empty() {
+                // v2-only: take the layout from the file tree
+                let files: Vec<FileInfo> = v2_files
                     .iter()
-                    .map(|v| {
-                        v.as_str()
-                            .ok_or_else(|| {
-                                BittorrentError::InvalidTorrent("Invalid path component".to_string())
-                            })
-                            .map(String::from)
+                    .map(|file| FileInfo {
+                        path: file.path.clone(),
+                        length: file.length,
                     })
-                    .collect::<Result<Vec<_>>>()?;
+                    .collect();
+                let total = files
+                    .iter()
+                    .try_fold(0u64, |total, file| total.checked_add(file.length))
+                    .ok_or_else(|| {
+                        BittorrentError::InvalidTorrent("File lengths overflow".to_string())
+                    })?;
+                let multi_file = !(files.len() == 1 && files[0].path == [name.clone()]);
 
-                total += length;
-                files.push(FileInfo { path, length });
-            }
+                (files, total, multi_file)
+            } else {
+                return Err(BittorrentError::InvalidTorrent(
+                    "Missing 'length' or 'files' field".to_string(),
+                ));
+            };
 
-            (files, total)
-        } else {
-            return Err(BittorrentError::InvalidTorrent(
-                "Missing 'length' or 'files' field".to_string(),
-            ));
-        };
+        // Every hash must cover at least one byte, or the surplus pieces
+        // would be zero-length and could never complete
+        if !(meta_version == 2 && pieces.is_empty()) {
+            let expected = total_length.div_ceil(piece_length);
+            if pieces.len() as u64 != expected {
+                return Err(BittorrentError::InvalidTorrent(format!(
+                    "'pieces' holds {} hashes but {} bytes need {}",
+                    pieces.len(),
+                    total_length,
+                    expected
+                )));
+            }
+        }
 
         Ok(TorrentInfo {
             name,
@@ -124,37 +210,152 @@ impl TorrentInfo {
             pieces,
             files,
             total_length,
+            multi_file,
+            meta_version,
+            v2_files,
         })
     }
+
+    /// Whether the torrent is a single file rather than a directory of
+    /// files
+    ///
+    /// `files` always holds at least one entry; for a single-file torrent
+    /// it is just `[name]`.
+    pub fn is_single_file(&self) -> bool {
+        !self.multi_file
+    }
+
+    /// Whether this is a hybrid torrent carrying both v1 and v2 metadata
+    pub fn is_hybrid(&self) -> bool {
+        self.meta_version == 2 && !self.pieces.is_empty()
+    }
+
+    /// Whether this is a v2-only torrent with no v1 piece hashes
+    pub fn is_v2_only(&self) -> bool {
+        self.meta_version == 2 && self.pieces.is_empty()
+    }
+
+    /// Number of pieces in the torrent
+    pub fn piece_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// First and last piece of every non-empty file, in ascending order
+    ///
+    /// These hold container headers and footers, so fetching them early
+    /// lets media players open files that are still downloading.
+    pub fn file_boundary_pieces(&self) -> Vec<usize> {
+        let mut pieces = Vec::new();
+        let mut offset = 0;
+        for file in &self.files {
+            if file.length > 0 {
+                pieces.push((offset / self.piece_length) as usize);
+                pieces.push(((offset + file.length - 1) / self.piece_length) as usize);
+            }
+            offset += file.length;
+        }
+        pieces.sort_unstable();
+        pieces.dedup();
+        pieces
+    }
+
+    /// Pieces holding any byte of file `index`; empty for empty files and
+    /// `None` past the last file
+    pub fn file_piece_range(&self, index: usize) -> Option<std::ops::Range<usize>> {
+        let file = self.files.get(index)?;
+        let offset: u64 = self.files[..index].iter().map(|f| f.length).sum();
+        let first = (offset / self.piece_length) as usize;
+        if file.length == 0 {
+            return Some(first..first);
+        }
+        let last = ((offset + file.length - 1) / self.piece_length) as usize;
+        Some(first..last + 1)
+    }
+
+    /// Length of a piece in bytes
+    ///
+    /// Every piece is `piece_length` long except the last, which holds
+    /// whatever remains. Indices past the end have a size of 0.
+    pub fn piece_size(&self, index: usize) -> u64 {
+        let offset = index as u64 * self.piece_length;
+        std::cmp::min(self.piece_length, self.total_length.saturating_sub(offset))
+    }
+}
+
+/// Smallest piece length considered standard (16 KiB)
+const MIN_STANDARD_PIECE_LENGTH: u64 = 16 * 1024;
+/// Largest piece length considered standard (64 MiB)
+const MAX_STANDARD_PIECE_LENGTH: u64 = 64 * 1024 * 1024;
+/// Piece lengths above this are rejected outright to bound allocations (512 MiB)
+const MAX_PIECE_LENGTH: u64 = 512 * 1024 * 1024;
+
+/// Reject unusable piece lengths and warn about non-standard ones
+fn validate_piece_length(piece_length: i64) -> Result<u64> {
+    if piece_length <= 0 {
+        return Err(BittorrentError::InvalidTorrent(format!(
+            "'piece length' must be positive, got {}",
+            piece_length
+        )));
+    }
+
+    let piece_length = piece_length as u64;
+    if piece_length > MAX_PIECE_LENGTH {
+        return Err(BittorrentError::InvalidTorrent(format!(
+            "'piece length' of {} bytes exceeds the {} byte limit",
+            piece_length, MAX_PIECE_LENGTH
+        )));
+    }
+
+    if !piece_length.is_power_of_two() {
+        warn!(
+            "Non-standard piece length {} (not a power of two)",
+            piece_length
+        );
+    }
+    if !(MIN_STANDARD_PIECE_LENGTH..=MAX_STANDARD_PIECE_LENGTH).contains(&piece_length) {
+        warn!(
+            "Unusual piece length {} (expected {} to {} bytes)",
+            piece_length, MIN_STANDARD_PIECE_LENGTH, MAX_STANDARD_PIECE_LENGTH
+        );
+    }
+
+    Ok(piece_length)
 }
 
 /// Top-level metainfo structure from a .torrent file
 #[derive(Debug, Clone)]
 pub struct Metainfo {
-    /// URL of the tracker
-    pub announce: String,
+    /// URL of the tracker (absent in trackerless torrents)
+    pub announce: Option<String>,
     /// Additional tracker URLs (optional)
     pub announce_list: Option<Vec<Vec<String>>>,
+    /// DHT bootstrap nodes for trackerless torrents
+    pub nodes: Vec<SocketAddr>,
+    /// Web seed URLs (BEP 19 `url-list`)
+    pub url_list: Vec<String>,
+    /// HTTP seed URLs (BEP 17 `httpseeds`), asked for whole pieces by index
+    pub httpseeds: Vec<String>,
     /// Information about the torrent contents
     pub info: TorrentInfo,
     /// SHA1 hash of the bencoded info dictionary
     pub info_hash: [u8; 20],
+    /// SHA-256 hash of the info dictionary, for v2 and hybrid torrents
+    pub v2_info_hash: Option<Sha256Hash>,
+    /// v2 `piece layers`: per-file piece hashes keyed by pieces root
+    pub piece_layers: HashMap<Sha256Hash, Vec<Sha256Hash>>,
 }
 
 impl Metainfo {
     pub fn from_bencode(value: BencodeValue, raw_data: &[u8]) -> Result<Self> {
-        let dict = value.as_dict().ok_or_else(|| {
-            BittorrentError::InvalidTorrent("Torrent must be a dict".to_string())
-        })?;
+        let dict = value
+            .as_dict()
+            .ok_or_else(|| BittorrentError::InvalidTorrent("Torrent must be a dict".to_string()))?;
 
-        // Parse announce
+        // Parse announce (optional for trackerless torrents)
         let announce = dict
             .get(b"announce".as_ref())
             .and_then(|v| v.as_str())
-            .ok_or_else(|| {
-                BittorrentError::InvalidTorrent("Missing 'announce' field".to_string())
-            })?
-            .to_string();
+            .map(String::from);
 
         // Parse announce-list (optional)
         let announce_list = dict.get(b"announce-list".as_ref()).and_then(|v| {
@@ -171,6 +372,33 @@ impl Metainfo {
             })
         });
 
+        // Parse DHT nodes (optional)
+        let nodes_value = dict.get(b"nodes".as_ref());
+        let nodes = nodes_value
+            .and_then(|v| v.as_list())
+            .map(parse_nodes)
+            .unwrap_or_default();
+
+        // Parse url-list and httpseeds (optional)
+        let url_list = parse_url_list(dict.get(b"url-list".as_ref()));
+        let httpseeds = parse_url_list(dict.get(b"httpseeds".as_ref()));
+
+        let has_announce_list = announce_list
+            .as_ref()
+            .is_some_and(|tiers: &Vec<Vec<String>>| tiers.iter().any(|tier| !tier.is_empty()));
+
+        if announce.is_none()
+            && !has_announce_list
+            && nodes_value.is_none()
+            && url_list.is_empty()
+            && httpseeds.is_empty()
+        {
+            return Err(BittorrentError::InvalidTorrent(
+                "Missing 'announce', 'announce-list', 'nodes', 'url-list' or 'httpseeds' field"
+                    .to_string(),
+            ));
+        }
+
         // Parse info
         let info_value = dict
             .get(b"info".as_ref())
@@ -178,85 +406,144 @@ impl Metainfo {
 
         let info = TorrentInfo::from_bencode(info_value)?;
 
-        // Calculate info_hash from raw bencoded info dict
-        let info_hash = calculate_info_hash(raw_data)?;
+        // Parse piece layers (v2 only)
+        let piece_layers = match dict.get(b"piece layers".as_ref()) {
+            Some(layers) if info.meta_version == 2 => parse_piece_layers(layers)?,
+            _ => HashMap::new(),
+        };
+
+        // Calculate info hashes from raw bencoded info dict
+        let info_bytes = &raw_data[find_dict_value(raw_data, b"info")?];
+        let info_hash = Sha1::digest(info_bytes).into();
+        let v2_info_hash = (info.meta_version == 2).then(|| Sha256::digest(info_bytes).into());
 
         Ok(Metainfo {
             announce,
             announce_list,
+            nodes,
+            url_list,
+            httpseeds,
             info,
             info_hash,
+            v2_info_hash,
+            piece_layers,
+        })
+    }
+
+    /// Number of web seeds of either kind (BEP 19 and BEP 17)
+    pub fn web_seed_count(&self) -> usize {
+        self.url_list.len() + self.httpseeds.len()
+    }
+
+    /// Get the tracker to announce to: `announce`, or else the first
+    /// entry of `announce-list`
+    #[cfg(test)]
+    pub fn primary_tracker(&self) -> Option<&str> {
+        self.announce.as_deref().or_else(|| {
+            self.announce_list
+                .as_ref()?
+                .iter()
+                .flatten()
+                .next()
+                .map(String::as_str)
         })
     }
 
+    /// Every tracker in announce order: `announce` first, then each
+    /// `announce-list` tier, skipping repeats and empty tiers
+    pub fn announce_tiers(&self) -> Vec<Vec<String>> {
+        let mut seen = HashSet::new();
+        let mut tiers = Vec::new();
+
+        let announce = self.announce.iter().cloned().map(|url| vec![url]);
+        for tier in announce.chain(self.announce_list.iter().flatten().cloned()) {
+            let tier: Vec<String> = tier
+                .into_iter()
+                .filter(|url| seen.insert(url.clone()))
+                .collect();
+            if !tier.is_empty() {
+                tiers.push(tier);
+            }
+        }
+
+        tiers
+    }
+
     /// Get the info hash as a hex string
     pub fn info_hash_hex(&self) -> String {
         hex::encode(self.info_hash)
     }
 
-    /// Get the info hash as a URL-encoded string for tracker requests
-    pub fn info_hash_urlencoded(&self) -> String {
-        self.info_hash
-            .iter()
-            .map(|b| format!("%{:02x}", b))
-            .collect()
+    /// Get the v2 info hash as a hex string
+    pub fn v2_info_hash_hex(&self) -> Option<String> {
+        self.v2_info_hash.map(hex::encode)
     }
-}
 
-/// Calculate the info_hash from the raw torrent data
-fn calculate_info_hash(raw_data: &[u8]) -> Result<[u8; 20]> {
-    // Find the info dictionary in the raw data
-    // We need to find "4:info" and then extract the bencoded dict that follows
-    let info_key = b"4:info";
-    let info_start = raw_data
-        .windows(info_key.len())
-        .position(|window| window == info_key)
-        .ok_or_else(|| BittorrentError::InvalidTorrent("Info dict not found".to_string()))?
-        + info_key.len();
-
-    // Parse the info dict to find its end
-    let info_dict_bytes = extract_info_dict(&raw_data[info_start..])?;
-
-    // Calculate SHA1 hash
-    let mut hasher = Sha1::new();
-    hasher.update(info_dict_bytes);
-    let hash = hasher.finalize();
-
-    let mut result = [0u8; 20];
-    result.copy_from_slice(&hash);
-    Ok(result)
-}
+    /// The v2 info hash truncated to 20 bytes, as used in handshakes and
+    /// tracker announces on v2 swarms
+    pub fn v2_info_hash_truncated(&self) -> Option<[u8; 20]> {
+        let mut truncated = [0u8; 20];
+        truncated.copy_from_slice(&self.v2_info_hash?[..20]);
+        Some(truncated)
+    }
 
-/// Extract the bencoded info dictionary bytes
-fn extract_info_dict(data: &[u8]) -> Result<&[u8]> {
-    if data.is_empty() || data[0] != b'd' {
-        return Err(BittorrentError::InvalidTorrent(
-            "Info dict must start with 'd'".to_string(),
-        ));
+    /// Whether the 20-byte info hash in a peer's handshake identifies this
+    /// torrent, through either its v1 or (truncated) v2 hash
+    pub fn matches_info_hash(&self, info_hash: &[u8; 20]) -> bool {
+        *info_hash == self.info_hash || self.v2_info_hash_truncated() == Some(*info_hash)
     }
 
-    let mut pos = 0;
-    let mut depth = 0;
-
-    for (i, &byte) in data.iter().enumerate() {
-        match byte {
-            b'd' | b'l' => depth += 1,
-            b'e' => {
-                depth -= 1;
-                if depth == 0 {
-                    pos = i + 1;
-                    break;
-                }
-            }
-            _ => {}
-        }
+    /// A magnet link carrying the info hash(es), name and every tracker,
+    /// for sharing the torrent without the .torrent file
+    pub fn magnet_link(&self) -> String {
+        super::MagnetLink::from_metainfo(self).to_uri()
+    }
+
+    /// Get the info hash as a URL-encoded string for tracker requests
+    #[allow(dead_code, reason = "`TrackerRequest` encodes the hash itself")]
+    pub fn info_hash_urlencoded(&self) -> String {
+        crate::tracker::urlencode_bytes(&self.info_hash)
     }
+}
 
-    if pos == 0 {
-        return Err(BittorrentError::InvalidTorrent(
-            "Unterminated info dict".to_string(),
-        ));
+/// URLs from a `url-list` or `httpseeds` value, either a single URL or a
+/// list of them
+fn parse_url_list(value: Option<&BencodeValue>) -> Vec<String> {
+    match value {
+        Some(BencodeValue::List(urls)) => urls
+            .iter()
+            .filter_map(|u| u.as_str())
+            .filter(|u| !u.is_empty())
+            .map(String::from)
+            .collect(),
+        Some(value) => value
+            .as_str()
+            .filter(|u| !u.is_empty())
+            .map(|u| vec![u.to_string()])
+            .unwrap_or_default(),
+        None => Vec::new(),
     }
+}
 
-    Ok(&data[..pos])
+/// Parse the `nodes` list of `[host, port]` pairs
+///
+/// Only IP literals can be represented as socket addresses; hostname
+/// entries are skipped.
+fn parse_nodes(list: &[BencodeValue]) -> Vec<SocketAddr> {
+    list.iter()
+        .filter_map(|node| {
+            let pair = node.as_list()?;
+            let host = pair.first()?.as_str()?;
+            let port = pair.get(1)?.as_integer()?;
+            let port = u16::try_from(port).ok()?;
+
+            match host.parse::<IpAddr>() {
+                Ok(ip) => Some(SocketAddr::new(ip, port)),
+                Err(_) => {
+                    warn!("Skipping DHT node with non-IP host: {}", host);
+                    None
+                }
+            }
+        })
+        .collect()
 }
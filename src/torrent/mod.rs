@@ -1,22 +1,485 @@
+mod magnet;
 mod metainfo;
 mod piece;
+mod v2;
 
-pub use metainfo::{FileInfo, Metainfo, TorrentInfo};
+pub use magnet::MagnetLink;
+pub use metainfo::FileInfo;
+pub use metainfo::{Metainfo, TorrentInfo};
 pub use piece::{PieceHash, Pieces};
 
-use crate::bencode::{decode, BencodeValue};
+use crate::bencode::decode;
 use crate::error::{BittorrentError, Result};
 use std::path::Path;
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 
 /// Load and parse a .torrent file
+#[allow(
+    dead_code,
+    reason = "the CLI also takes URLs and stdin, via `load_torrent`"
+)]
 pub async fn load_torrent_file<P: AsRef<Path>>(path: P) -> Result<Metainfo> {
     let data = fs::read(path).await?;
     parse_torrent(&data)
 }
 
+/// Load and parse a torrent from a path, an http(s) URL, or `-` for stdin
+pub async fn load_torrent(source: &str) -> Result<Metainfo> {
+    let data = read_torrent_source(source).await?;
+    parse_torrent(&data)
+}
+
+/// Read the raw .torrent bytes from a path, an http(s) URL, or `-` for stdin
+pub async fn read_torrent_source(source: &str) -> Result<Vec<u8>> {
+    if source == "-" {
+        let mut data = Vec::new();
+        tokio::io::stdin().read_to_end(&mut data).await?;
+        return Ok(data);
+    }
+
+    if source.starts_with("http://") || source.starts_with("https://") {
+        return fetch_torrent(source).await;
+    }
+
+    Ok(fs::read(source).await?)
+}
+
+/// Download .torrent bytes over HTTP
+async fn fetch_torrent(url: &str) -> Result<Vec<u8>> {
+    let response = reqwest::get(url)
+        .await
+        .map_err(|e| BittorrentError::FetchError(format!("{}: {}", url, e)))?;
+
+    let status = response.status();
+    if !status.is_success() {
+        return Err(BittorrentError::FetchError(format!(
+            "{}: HTTP {}",
+            url, status
+        )));
+    }
+
+    let body = response
+        .bytes()
+        .await
+        .map_err(|e| BittorrentError::FetchError(format!("{}: {}", url, e)))?;
+
+    Ok(body.to_vec())
+}
+
 /// Parse torrent data from bytes
 pub fn parse_torrent(data: &[u8]) -> Result<Metainfo> {
     let value = decode(data)?;
     Metainfo::from_bencode(value, data)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::{encode, BencodeValue};
+    use sha1::{Digest, Sha1};
+    use std::collections::BTreeMap;
+
+    fn info_dict() -> BencodeValue {
+        info_dict_with_piece_length(16384)
+    }
+
+    fn info_dict_with_piece_length(piece_length: i64) -> BencodeValue {
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BencodeValue::String(b"file.txt".to_vec()));
+        info.insert(
+            b"piece length".to_vec(),
+            BencodeValue::Integer(piece_length),
+        );
+        info.insert(b"pieces".to_vec(), BencodeValue::String(vec![0u8; 20]));
+        info.insert(b"length".to_vec(), BencodeValue::Integer(100));
+        BencodeValue::Dict(info)
+    }
+
+    fn torrent_bytes(fields: Vec<(&[u8], BencodeValue)>) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        root.insert(b"info".to_vec(), info_dict());
+        for (key, value) in fields {
+            root.insert(key.to_vec(), value);
+        }
+        encode(&BencodeValue::Dict(root))
+    }
+
+    #[test]
+    fn test_random_torrents_never_panic() {
+        use crate::testutil::{mutate_bytes, random_bencode};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        for seed in 0..2000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+
+            // A real info dict with random extras, or a random one outright
+            let mut root = match random_bencode(&mut rng, 4) {
+                BencodeValue::Dict(root) => root,
+                _ => BTreeMap::new(),
+            };
+            if seed % 2 == 0 {
+                let mut info = match info_dict() {
+                    BencodeValue::Dict(info) => info,
+                    _ => unreachable!(),
+                };
+                if let BencodeValue::Dict(extra) = random_bencode(&mut rng, 3) {
+                    info.extend(extra);
+                }
+                root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+            }
+            let data = encode(&BencodeValue::Dict(root));
+
+            let result = std::panic::catch_unwind(|| parse_torrent(&data).map(|_| ()));
+            assert!(result.is_ok(), "seed {} panicked on {:?}", seed, data);
+
+            let mut corrupted = data;
+            mutate_bytes(&mut rng, &mut corrupted);
+            let result = std::panic::catch_unwind(|| parse_torrent(&corrupted).map(|_| ()));
+            assert!(result.is_ok(), "seed {} panicked on {:?}", seed, corrupted);
+        }
+    }
+
+    #[test]
+    fn test_negative_or_overflowing_lengths_are_rejected() {
+        let file = |length: i64| {
+            let mut file = BTreeMap::new();
+            file.insert(b"length".to_vec(), BencodeValue::Integer(length));
+            file.insert(
+                b"path".to_vec(),
+                BencodeValue::List(vec![BencodeValue::String(b"a".to_vec())]),
+            );
+            BencodeValue::Dict(file)
+        };
+        // The info dict with its `length` replaced by `key`
+        let torrent = |key: &[u8], value: BencodeValue| {
+            let mut info = match info_dict() {
+                BencodeValue::Dict(info) => info,
+                _ => unreachable!(),
+            };
+            info.remove(b"length".as_ref());
+            info.insert(key.to_vec(), value);
+            let mut root = BTreeMap::new();
+            root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+            encode(&BencodeValue::Dict(root))
+        };
+
+        let negative_single = torrent(b"length", BencodeValue::Integer(-1));
+        let negative_file = torrent(b"files", BencodeValue::List(vec![file(-1), file(2)]));
+        let overflowing = torrent(
+            b"files",
+            BencodeValue::List(vec![file(i64::MAX), file(i64::MAX), file(i64::MAX)]),
+        );
+
+        for data in [negative_single, negative_file, overflowing] {
+            assert!(matches!(
+                parse_torrent(&data),
+                Err(BittorrentError::InvalidTorrent(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_info_hash_covers_exact_info_bytes() {
+        // The pieces hash is full of bytes that look like bencode delimiters
+        let mut info = match info_dict() {
+            BencodeValue::Dict(info) => info,
+            _ => unreachable!(),
+        };
+        info.insert(b"pieces".to_vec(), b"e4:infodeeeeeeeeeeee".to_vec().into());
+        let info = BencodeValue::Dict(info);
+
+        // A comment sorts before info and mentions it too
+        let data = encode(&BencodeValue::dict([
+            ("comment", "4:infod".into()),
+            ("info", info.clone()),
+            ("announce", "http://tracker/announce".into()),
+        ]));
+
+        let metainfo = parse_torrent(&data).unwrap();
+        let expected: [u8; 20] = Sha1::digest(encode(&info)).into();
+        assert_eq!(metainfo.info_hash, expected);
+    }
+
+    #[test]
+    fn test_info_hash_with_e_in_piece_hash() {
+        // A piece hash with an 'e' byte, plus integers whose closing 'e'
+        // a byte scanner would count as ending a dict
+        let mut hash = [0x42u8; 20];
+        hash[3] = b'e';
+        let info = BencodeValue::dict([
+            ("length", 100.into()),
+            ("name", "file.txt".into()),
+            ("piece length", 16384.into()),
+            ("pieces", hash.to_vec().into()),
+            ("private", 1.into()),
+        ]);
+        let data = encode(&BencodeValue::dict([
+            ("announce", "http://tracker/announce".into()),
+            ("info", info.clone()),
+            ("url-list", "http://seed/file.txt".into()),
+        ]));
+
+        let metainfo = parse_torrent(&data).unwrap();
+        let expected: [u8; 20] = Sha1::digest(encode(&info)).into();
+        assert_eq!(metainfo.info_hash, expected);
+    }
+
+    #[test]
+    fn test_trackerless_torrent_with_nodes() {
+        let node = BencodeValue::List(vec![
+            BencodeValue::String(b"127.0.0.1".to_vec()),
+            BencodeValue::Integer(6881),
+        ]);
+        let data = torrent_bytes(vec![(b"nodes", BencodeValue::List(vec![node]))]);
+
+        let metainfo = parse_torrent(&data).unwrap();
+        assert!(metainfo.announce.is_none());
+        assert!(metainfo.primary_tracker().is_none());
+        assert_eq!(metainfo.nodes, vec!["127.0.0.1:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_announce_tiers_start_with_announce() {
+        let string = |s: &str| BencodeValue::String(s.as_bytes().to_vec());
+        let data = torrent_bytes(vec![
+            (b"announce", string("http://a.example/announce")),
+            (
+                b"announce-list",
+                BencodeValue::List(vec![
+                    BencodeValue::List(vec![string("http://a.example/announce")]),
+                    BencodeValue::List(vec![
+                        string("udp://b.example:80"),
+                        string("udp://c.example:80"),
+                    ]),
+                ]),
+            ),
+        ]);
+
+        let metainfo = parse_torrent(&data).unwrap();
+        assert_eq!(
+            metainfo.announce_tiers(),
+            vec![
+                vec!["http://a.example/announce".to_string()],
+                vec![
+                    "udp://b.example:80".to_string(),
+                    "udp://c.example:80".to_string()
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_url_list_as_string_or_list() {
+        let single = torrent_bytes(vec![(
+            b"url-list",
+            BencodeValue::String(b"http://seed.example/file.txt".to_vec()),
+        )]);
+        let metainfo = parse_torrent(&single).unwrap();
+        assert_eq!(metainfo.url_list, vec!["http://seed.example/file.txt"]);
+
+        let list = torrent_bytes(vec![(
+            b"url-list",
+            BencodeValue::List(vec![
+                BencodeValue::String(b"http://a.example/".to_vec()),
+                BencodeValue::String(b"http://b.example/".to_vec()),
+            ]),
+        )]);
+        let metainfo = parse_torrent(&list).unwrap();
+        assert_eq!(
+            metainfo.url_list,
+            vec!["http://a.example/", "http://b.example/"]
+        );
+    }
+
+    #[test]
+    fn test_httpseeds_are_a_peer_source() {
+        let data = torrent_bytes(vec![(
+            b"httpseeds",
+            BencodeValue::List(vec![BencodeValue::String(
+                b"http://seed.example/seed.php".to_vec(),
+            )]),
+        )]);
+        let metainfo = parse_torrent(&data).unwrap();
+        assert_eq!(metainfo.httpseeds, vec!["http://seed.example/seed.php"]);
+        assert!(metainfo.url_list.is_empty());
+        assert_eq!(metainfo.web_seed_count(), 1);
+    }
+
+    #[test]
+    fn test_torrent_without_any_peer_source_is_rejected() {
+        let data = torrent_bytes(vec![]);
+        assert!(parse_torrent(&data).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_load_torrent_from_url() {
+        let torrent = torrent_bytes(vec![(
+            b"announce",
+            BencodeValue::String(b"http://tracker/announce".to_vec()),
+        )]);
+        let server = crate::testutil::MockTracker::with_body(torrent).await;
+
+        let metainfo = load_torrent(&server.announce_url()).await.unwrap();
+        assert_eq!(metainfo.info.name, "file.txt");
+    }
+
+    #[tokio::test]
+    async fn test_load_torrent_distinguishes_fetch_errors() {
+        let server = crate::testutil::MockTracker::with_body(b"not bencode".to_vec()).await;
+        assert!(matches!(
+            load_torrent(&server.announce_url()).await,
+            Err(BittorrentError::BencodeError(_))
+        ));
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/x.torrent", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(matches!(
+            load_torrent(&url).await,
+            Err(BittorrentError::FetchError(_))
+        ));
+    }
+
+    fn torrent_with_piece_length(piece_length: i64) -> Vec<u8> {
+        let mut root = BTreeMap::new();
+        root.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://t".to_vec()),
+        );
+        root.insert(b"info".to_vec(), info_dict_with_piece_length(piece_length));
+        encode(&BencodeValue::Dict(root))
+    }
+
+    #[test]
+    fn test_zero_piece_length_is_rejected() {
+        assert!(parse_torrent(&torrent_with_piece_length(0)).is_err());
+        assert!(parse_torrent(&torrent_with_piece_length(-16384)).is_err());
+    }
+
+    #[test]
+    fn test_enormous_piece_length_is_rejected() {
+        assert!(parse_torrent(&torrent_with_piece_length(1 << 40)).is_err());
+    }
+
+    #[test]
+    fn test_non_standard_piece_length_is_accepted() {
+        let metainfo = parse_torrent(&torrent_with_piece_length(1000)).unwrap();
+        assert_eq!(metainfo.info.piece_length, 1000);
+    }
+
+    #[test]
+    fn test_piece_count_must_match_length() {
+        let torrent_with_pieces = |count: usize| {
+            let mut info = match info_dict() {
+                BencodeValue::Dict(info) => info,
+                _ => unreachable!(),
+            };
+            info.insert(b"pieces".to_vec(), vec![0u8; 20 * count].into());
+            encode(&BencodeValue::dict([
+                ("announce", "http://tracker/announce".into()),
+                ("info", BencodeValue::Dict(info)),
+            ]))
+        };
+
+        // 100 bytes in 16 KiB pieces need exactly one hash
+        assert!(parse_torrent(&torrent_with_pieces(1)).is_ok());
+        assert!(parse_torrent(&torrent_with_pieces(0)).is_err());
+        assert!(parse_torrent(&torrent_with_pieces(2)).is_err());
+    }
+
+    #[test]
+    fn test_hybrid_torrent_has_both_info_hashes() {
+        let mut props = BTreeMap::new();
+        props.insert(b"length".to_vec(), BencodeValue::Integer(100));
+        props.insert(b"pieces root".to_vec(), BencodeValue::String(vec![1u8; 32]));
+        let mut leaf = BTreeMap::new();
+        leaf.insert(Vec::new(), BencodeValue::Dict(props));
+        let mut tree = BTreeMap::new();
+        tree.insert(b"file.txt".to_vec(), BencodeValue::Dict(leaf));
+
+        let mut info = match info_dict() {
+            BencodeValue::Dict(info) => info,
+            _ => unreachable!(),
+        };
+        info.insert(b"meta version".to_vec(), BencodeValue::Integer(2));
+        info.insert(b"file tree".to_vec(), BencodeValue::Dict(tree));
+        let info = BencodeValue::Dict(info);
+
+        let mut root = BTreeMap::new();
+        root.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://t".to_vec()),
+        );
+        root.insert(b"info".to_vec(), info);
+        let metainfo = parse_torrent(&encode(&BencodeValue::Dict(root))).unwrap();
+
+        let v1 = metainfo.info_hash;
+        let v2 = metainfo.v2_info_hash.unwrap();
+
+        assert!(metainfo.info.is_hybrid());
+        assert_ne!(v1[..], v2[..20]);
+        assert!(metainfo.matches_info_hash(&v1));
+        assert!(metainfo.matches_info_hash(&metainfo.v2_info_hash_truncated().unwrap()));
+        assert!(!metainfo.matches_info_hash(&[0u8; 20]));
+    }
+
+    #[test]
+    fn test_parse_v2_only_torrent() {
+        let mut props = BTreeMap::new();
+        props.insert(b"length".to_vec(), BencodeValue::Integer(20000));
+        props.insert(b"pieces root".to_vec(), BencodeValue::String(vec![1u8; 32]));
+        let mut leaf = BTreeMap::new();
+        leaf.insert(Vec::new(), BencodeValue::Dict(props));
+        let mut tree = BTreeMap::new();
+        tree.insert(b"v2.bin".to_vec(), BencodeValue::Dict(leaf));
+
+        let mut info = BTreeMap::new();
+        info.insert(b"name".to_vec(), BencodeValue::String(b"v2.bin".to_vec()));
+        info.insert(b"piece length".to_vec(), BencodeValue::Integer(16384));
+        info.insert(b"meta version".to_vec(), BencodeValue::Integer(2));
+        info.insert(b"file tree".to_vec(), BencodeValue::Dict(tree));
+
+        let mut layers = BTreeMap::new();
+        layers.insert(vec![1u8; 32], BencodeValue::String(vec![7u8; 64]));
+
+        let mut root = BTreeMap::new();
+        root.insert(
+            b"announce".to_vec(),
+            BencodeValue::String(b"http://t".to_vec()),
+        );
+        root.insert(b"info".to_vec(), BencodeValue::Dict(info));
+        root.insert(b"piece layers".to_vec(), BencodeValue::Dict(layers));
+
+        let metainfo = parse_torrent(&encode(&BencodeValue::Dict(root))).unwrap();
+        assert!(metainfo.info.is_v2_only());
+        assert!(!metainfo.info.multi_file);
+        assert_eq!(metainfo.info.total_length, 20000);
+        assert_eq!(metainfo.info.files[0].path, vec!["v2.bin"]);
+        assert!(metainfo.v2_info_hash.is_some());
+        assert_eq!(metainfo.piece_layers[&[1u8; 32]].len(), 2);
+    }
+
+    #[test]
+    fn test_piece_size_last_piece() {
+        // 100-byte file in 16 KiB pieces: one short piece
+        let info = parse_torrent(&torrent_bytes(vec![(
+            b"announce",
+            BencodeValue::String(b"http://x".to_vec()),
+        )]))
+        .unwrap()
+        .info;
+        assert_eq!(info.piece_count(), 1);
+        assert_eq!(info.piece_size(0), 100);
+        assert_eq!(info.piece_size(1), 0);
+
+        let exact = crate::testutil::make_info(16384, 16384 * 2);
+        assert_eq!(exact.piece_count(), 2);
+        assert_eq!(exact.piece_size(1), 16384);
+
+        let partial = crate::testutil::make_info(16384, 16384 * 2 + 1);
+        assert_eq!(partial.piece_count(), 3);
+        assert_eq!(partial.piece_size(1), 16384);
+        assert_eq!(partial.piece_size(2), 1);
+    }
+}
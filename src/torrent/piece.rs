@@ -1,10 +1,12 @@
 use crate::error::{BittorrentError, Result};
+use sha1::{Digest, Sha1};
 
 /// A 20-byte SHA1 hash representing a piece
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct PieceHash([u8; 20]);
 
 impl PieceHash {
+    #[allow(dead_code, reason = "hashes are read from torrents with `from_slice`")]
     pub fn new(hash: [u8; 20]) -> Self {
         Self(hash)
     }
@@ -23,6 +25,13 @@ impl PieceHash {
     pub fn as_bytes(&self) -> &[u8; 20] {
         &self.0
     }
+
+    /// Check whether the SHA1 of `data` matches this hash
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().as_slice() == self.0
+    }
 }
 
 impl AsRef<[u8]> for PieceHash {
@@ -40,7 +49,7 @@ pub struct Pieces {
 impl Pieces {
     /// Parse pieces from concatenated SHA1 hashes
     pub fn from_bytes(data: &[u8]) -> Result<Self> {
-        if data.len() % 20 != 0 {
+        if !data.len().is_multiple_of(20) {
             return Err(BittorrentError::InvalidTorrent(
                 "Pieces length must be multiple of 20".to_string(),
             ));
@@ -69,4 +78,27 @@ impl Pieces {
     pub fn iter(&self) -> impl Iterator<Item = &PieceHash> {
         self.hashes.iter()
     }
+
+    /// Check whether `data` matches the hash of the piece at `index`
+    ///
+    /// Returns `false` for an out-of-range index.
+    pub fn verify_hash(&self, index: usize, data: &[u8]) -> bool {
+        self.get(index).is_some_and(|hash| hash.matches(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_verify_hash() {
+        // SHA1("abc")
+        let hash = hex::decode("a9993e364706816aba3e25717850c26c9cd0d89d").unwrap();
+        let pieces = Pieces::from_bytes(&hash).unwrap();
+
+        assert!(pieces.verify_hash(0, b"abc"));
+        assert!(!pieces.verify_hash(0, b"abd"));
+        assert!(!pieces.verify_hash(1, b"abc"));
+    }
 }
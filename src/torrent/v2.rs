@@ -0,0 +1,203 @@
+//! BitTorrent v2 metadata (BEP 52)
+//!
+//! v2 torrents describe their contents with a nested `file tree` and hash
+//! each file separately with SHA-256 merkle trees. Only parsing is
+//! supported so far; downloading still relies on the v1 `pieces` string.
+
+use crate::bencode::BencodeValue;
+use crate::error::{BittorrentError, Result};
+use std::collections::{BTreeMap, HashMap};
+
+/// A SHA-256 hash from v2 metadata
+pub type Sha256Hash = [u8; 32];
+
+/// A file from a v2 `file tree`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct V2File {
+    /// Path components below the torrent's root directory
+    pub path: Vec<String>,
+    pub length: u64,
+    /// Root of the file's merkle tree (absent for empty files)
+    pub pieces_root: Option<Sha256Hash>,
+}
+
+/// Flatten a `file tree` dictionary into its files, in tree order
+pub fn parse_file_tree(value: &BencodeValue) -> Result<Vec<V2File>> {
+    let tree = value
+        .as_dict()
+        .ok_or_else(|| BittorrentError::InvalidTorrent("'file tree' must be a dict".to_string()))?;
+
+    let mut files = Vec::new();
+    walk_file_tree(tree, &mut Vec::new(), &mut files)?;
+
+    if files.is_empty() {
+        return Err(BittorrentError::InvalidTorrent(
+            "'file tree' contains no files".to_string(),
+        ));
+    }
+
+    Ok(files)
+}
+
+fn walk_file_tree(
+    tree: &BTreeMap<Vec<u8>, BencodeValue>,
+    path: &mut Vec<String>,
+    files: &mut Vec<V2File>,
+) -> Result<()> {
+    for (name, node) in tree {
+        let name = String::from_utf8(name.clone()).map_err(|_| {
+            BittorrentError::InvalidTorrent("Invalid path component in 'file tree'".to_string())
+        })?;
+        let node = node.as_dict().ok_or_else(|| {
+            BittorrentError::InvalidTorrent(format!("'file tree' entry {} must be a dict", name))
+        })?;
+
+        path.push(name);
+
+        // A file is a node with a single empty key holding its properties
+        match node.get(b"".as_ref()) {
+            Some(leaf) => files.push(parse_leaf(leaf, path)?),
+            None => walk_file_tree(node, path, files)?,
+        }
+
+        path.pop();
+    }
+
+    Ok(())
+}
+
+fn parse_leaf(leaf: &BencodeValue, path: &[String]) -> Result<V2File> {
+    let display = path.join("/");
+    let leaf = leaf.as_dict().ok_or_else(|| {
+        BittorrentError::InvalidTorrent(format!("File {} must be a dict", display))
+    })?;
+
+    let length = leaf
+        .get(b"length".as_ref())
+        .and_then(|v| v.as_integer())
+        .filter(|&length| length >= 0)
+        .ok_or_else(|| {
+            BittorrentError::InvalidTorrent(format!("Missing or invalid length for {}", display))
+        })? as u64;
+
+    let pieces_root = match leaf.get(b"pieces root".as_ref()) {
+        Some(root) => Some(
+            to_sha256(root.as_bytes().unwrap_or_default()).ok_or_else(|| {
+                BittorrentError::InvalidTorrent(format!(
+                    "'pieces root' of {} must be 32 bytes",
+                    display
+                ))
+            })?,
+        ),
+        None if length > 0 => {
+            return Err(BittorrentError::InvalidTorrent(format!(
+                "Missing 'pieces root' for {}",
+                display
+            )))
+        }
+        None => None,
+    };
+
+    Ok(V2File {
+        path: path.to_vec(),
+        length,
+        pieces_root,
+    })
+}
+
+/// Parse the top-level `piece layers` dictionary, keyed by pieces root
+pub fn parse_piece_layers(value: &BencodeValue) -> Result<HashMap<Sha256Hash, Vec<Sha256Hash>>> {
+    let layers = value.as_dict().ok_or_else(|| {
+        BittorrentError::InvalidTorrent("'piece layers' must be a dict".to_string())
+    })?;
+
+    layers
+        .iter()
+        .map(|(root, hashes)| {
+            let root = to_sha256(root).ok_or_else(|| {
+                BittorrentError::InvalidTorrent("'piece layers' key must be 32 bytes".to_string())
+            })?;
+            let hashes = hashes
+                .as_bytes()
+                .filter(|bytes| bytes.len().is_multiple_of(32))
+                .ok_or_else(|| {
+                    BittorrentError::InvalidTorrent(
+                        "'piece layers' value must be a multiple of 32 bytes".to_string(),
+                    )
+                })?;
+
+            let hashes = hashes.chunks_exact(32).filter_map(to_sha256).collect();
+            Ok((root, hashes))
+        })
+        .collect()
+}
+
+fn to_sha256(bytes: &[u8]) -> Option<Sha256Hash> {
+    bytes.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(length: i64, root: Option<[u8; 32]>) -> BencodeValue {
+        let mut props = BTreeMap::new();
+        props.insert(b"length".to_vec(), BencodeValue::Integer(length));
+        if let Some(root) = root {
+            props.insert(b"pieces root".to_vec(), BencodeValue::String(root.to_vec()));
+        }
+
+        let mut node = BTreeMap::new();
+        node.insert(Vec::new(), BencodeValue::Dict(props));
+        BencodeValue::Dict(node)
+    }
+
+    #[test]
+    fn test_parse_nested_file_tree() {
+        let mut dir = BTreeMap::new();
+        dir.insert(b"b.txt".to_vec(), leaf(20000, Some([2u8; 32])));
+
+        let mut tree = BTreeMap::new();
+        tree.insert(b"a.txt".to_vec(), leaf(0, None));
+        tree.insert(b"dir".to_vec(), BencodeValue::Dict(dir));
+
+        let files = parse_file_tree(&BencodeValue::Dict(tree)).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                V2File {
+                    path: vec!["a.txt".to_string()],
+                    length: 0,
+                    pieces_root: None,
+                },
+                V2File {
+                    path: vec!["dir".to_string(), "b.txt".to_string()],
+                    length: 20000,
+                    pieces_root: Some([2u8; 32]),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_non_empty_file_requires_pieces_root() {
+        let mut tree = BTreeMap::new();
+        tree.insert(b"a.txt".to_vec(), leaf(10, None));
+        assert!(parse_file_tree(&BencodeValue::Dict(tree)).is_err());
+    }
+
+    #[test]
+    fn test_parse_piece_layers() {
+        let mut layers = BTreeMap::new();
+        layers.insert(
+            vec![1u8; 32],
+            BencodeValue::String([[3u8; 32], [4u8; 32]].concat()),
+        );
+
+        let parsed = parse_piece_layers(&BencodeValue::Dict(layers.clone())).unwrap();
+        assert_eq!(parsed[&[1u8; 32]], vec![[3u8; 32], [4u8; 32]]);
+
+        layers.insert(vec![5u8; 32], BencodeValue::String(vec![0u8; 31]));
+        assert!(parse_piece_layers(&BencodeValue::Dict(layers)).is_err());
+    }
+}
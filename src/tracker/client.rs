@@ -1,58 +1,442 @@
-use super::{TrackerRequest, TrackerResponse};
+use super::{TrackerRequest, TrackerResponse, UdpTracker};
 use crate::bencode::decode;
-use crate::error::Result;
-use reqwest::Client;
-use tracing::{debug, info};
+use crate::error::{BittorrentError, Result};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, LOCATION};
+use reqwest::redirect::Policy;
+use reqwest::tls::TlsInfo;
+use reqwest::{Certificate, Client};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+/// Default User-Agent sent to trackers
+pub const DEFAULT_USER_AGENT: &str = concat!("bittorrent-rs/", env!("CARGO_PKG_VERSION"));
+
+/// How long an announce may take before the tracker counts as down
+pub const DEFAULT_ANNOUNCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Redirects followed for one HTTP announce
+pub const DEFAULT_MAX_REDIRECTS: usize = 5;
+
+/// Options for building the HTTP client used for announces
+#[derive(Debug, Clone)]
+pub struct TrackerOptions {
+    /// User-Agent header sent with every announce
+    pub user_agent: String,
+    /// Additional headers sent with every announce
+    pub headers: Vec<(String, String)>,
+    /// Certificate handling for HTTPS trackers
+    pub tls: TrackerTls,
+    /// Limit on a whole announce, from connecting to reading the body
+    pub timeout: Duration,
+    /// HTTP redirects followed per announce (0 = treat them as errors)
+    pub max_redirects: usize,
+    /// Retry with `compact=0` when a compact announce gives no usable
+    /// peer list
+    pub compact_fallback: bool,
+}
+
+impl Default for TrackerOptions {
+    fn default() -> Self {
+        Self {
+            user_agent: DEFAULT_USER_AGENT.to_string(),
+            headers: Vec::new(),
+            tls: TrackerTls::default(),
+            timeout: DEFAULT_ANNOUNCE_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            compact_fallback: true,
+        }
+    }
+}
+
+/// Certificate handling for HTTPS trackers
+#[derive(Debug, Clone, Default)]
+pub struct TrackerTls {
+    /// Extra PEM-encoded root certificates to trust, e.g. a private
+    /// tracker's self-signed certificate
+    pub root_certificates: Vec<Vec<u8>>,
+    /// SHA-256 fingerprints of the DER certificates HTTPS trackers may
+    /// present. When set, responses from any other certificate are refused.
+    pub pinned_certificates: Vec<[u8; 32]>,
+    /// Hosts whose certificates aren't checked at all. Anyone on the path
+    /// to these trackers can read and forge announces.
+    pub insecure_hosts: Vec<String>,
+}
 
 /// Client for communicating with BitTorrent trackers
+///
+/// Announces go over HTTP(S) or UDP depending on the tracker URL's scheme.
+#[derive(Clone)]
 pub struct TrackerClient {
     client: Client,
+    /// Client that skips certificate checks, used only for insecure hosts
+    insecure_client: Option<Client>,
+    insecure_hosts: Vec<String>,
+    pinned_certificates: Vec<[u8; 32]>,
+    timeout: Duration,
+    max_redirects: usize,
+    compact_fallback: bool,
+    /// HTTP trackers that only gave peers once asked with `compact=0`
+    non_compact_trackers: Arc<std::sync::Mutex<HashSet<String>>>,
+    /// UDP trackers by announce URL, kept so re-announces reuse the
+    /// connection ID
+    udp_trackers: Arc<Mutex<HashMap<String, UdpTracker>>>,
 }
 
 impl TrackerClient {
     pub fn new() -> Self {
         Self {
-            client: Client::new(),
+            client: Client::builder()
+                .redirect(Policy::none())
+                .build()
+                .expect("HTTP client with default settings"),
+            insecure_client: None,
+            insecure_hosts: Vec::new(),
+            pinned_certificates: Vec::new(),
+            timeout: DEFAULT_ANNOUNCE_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            compact_fallback: true,
+            non_compact_trackers: Arc::default(),
+            udp_trackers: Arc::default(),
         }
     }
 
-    /// Send a request to a tracker and get the peer list
-    pub async fn announce(&self, tracker_url: &str, request: &TrackerRequest) -> Result<TrackerResponse> {
-        info!("Announcing to tracker: {}", tracker_url);
+    /// Create a tracker client with a custom User-Agent and headers
+    pub fn with_options(options: &TrackerOptions) -> Result<Self> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &options.headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|_| {
+                BittorrentError::TrackerError(format!("Invalid header name: {}", name))
+            })?;
+            let value = HeaderValue::from_str(value).map_err(|_| {
+                BittorrentError::TrackerError(format!("Invalid value for header {}", name))
+            })?;
+            headers.insert(name, value);
+        }
 
-        // Build URL with query parameters
-        let url = reqwest::Url::parse_with_params(
-            tracker_url,
-            &request.to_query_params(),
-        )?;
+        // Redirects are followed by hand in `announce_http`, which knows
+        // to carry the announce parameters over
+        let base = || {
+            Client::builder()
+                .redirect(Policy::none())
+                .user_agent(options.user_agent.as_str())
+                .default_headers(headers.clone())
+                .tls_info(!options.tls.pinned_certificates.is_empty())
+        };
+
+        let mut builder = base();
+        for pem in &options.tls.root_certificates {
+            let cert = Certificate::from_pem(pem).map_err(|e| {
+                BittorrentError::TrackerError(format!("Invalid root certificate: {}", e))
+            })?;
+            builder = builder.add_root_certificate(cert);
+        }
 
-        debug!("Tracker request URL: {}", url);
+        let insecure_client = if options.tls.insecure_hosts.is_empty() {
+            None
+        } else {
+            for host in &options.tls.insecure_hosts {
+                warn!(
+                    "TLS certificate checks are DISABLED for tracker {}; announces to it can be intercepted",
+                    host
+                );
+            }
+            Some(base().danger_accept_invalid_certs(true).build()?)
+        };
 
-        // Send GET request
-        let response = self.client.get(url).send().await?;
+        Ok(Self {
+            client: builder.build()?,
+            insecure_client,
+            insecure_hosts: options.tls.insecure_hosts.clone(),
+            pinned_certificates: options.tls.pinned_certificates.clone(),
+            timeout: options.timeout,
+            max_redirects: options.max_redirects,
+            compact_fallback: options.compact_fallback,
+            non_compact_trackers: Arc::default(),
+            udp_trackers: Arc::default(),
+        })
+    }
 
-        let status = response.status();
-        let body = response.bytes().await?;
+    /// Pick the HTTP client for a tracker URL
+    fn client_for(&self, url: &reqwest::Url) -> &Client {
+        let insecure = url
+            .host_str()
+            .is_some_and(|host| self.insecure_hosts.iter().any(|h| h == host));
 
-        debug!("Tracker response status: {}, body length: {}", status, body.len());
+        match &self.insecure_client {
+            Some(client) if insecure => client,
+            _ => &self.client,
+        }
+    }
 
-        if !status.is_success() {
-            return Err(crate::error::BittorrentError::TrackerError(
-                format!("HTTP error: {}", status)
-            ));
+    /// Refuse a response whose certificate isn't pinned
+    fn check_pinned(&self, response: &reqwest::Response) -> Result<()> {
+        if self.pinned_certificates.is_empty() || response.url().scheme() != "https" {
+            return Ok(());
         }
 
-        // Decode bencoded response
-        let decoded = decode(&body)?;
-        let tracker_response = TrackerResponse::from_bencode(decoded)?;
+        let fingerprint = response
+            .extensions()
+            .get::<TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(|der| <[u8; 32]>::from(Sha256::digest(der)));
 
+        match fingerprint {
+            Some(fingerprint) if self.pinned_certificates.contains(&fingerprint) => Ok(()),
+            Some(fingerprint) => Err(BittorrentError::TrackerError(format!(
+                "Tracker certificate {} is not pinned",
+                hex::encode(fingerprint)
+            ))),
+            None => Err(BittorrentError::TrackerError(
+                "Tracker presented no certificate to check against the pins".to_string(),
+            )),
+        }
+    }
+
+    /// Whether `tracker_url` has a scheme we can announce to
+    pub fn supports(tracker_url: &str) -> bool {
+        let scheme = scheme(tracker_url);
+        matches!(scheme.as_deref(), Some("http" | "https" | "udp"))
+            || (cfg!(feature = "webtorrent") && matches!(scheme.as_deref(), Some("ws" | "wss")))
+    }
+
+    /// Send a request to a tracker and get the peer list
+    ///
+    /// `http://` and `https://` trackers are announced to over HTTP and
+    /// `udp://` trackers with BEP 15. Any other scheme is an error, so
+    /// callers trying a tier move on to the next tracker. That includes
+    /// `ws://` and `wss://`: with the `webtorrent` feature we announce to
+    /// them, but their peers need WebRTC, which isn't implemented.
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        match scheme(tracker_url).as_deref() {
+            Some("http") | Some("https") => self.announce_http(tracker_url, request).await,
+            Some("udp") => self.announce_udp(tracker_url, request).await,
+            #[cfg(feature = "webtorrent")]
+            Some("ws") | Some("wss") => self.announce_websocket(tracker_url, request).await,
+            #[cfg(not(feature = "webtorrent"))]
+            Some("ws") | Some("wss") => Err(BittorrentError::TrackerError(format!(
+                "{}: WebSocket trackers need the webtorrent feature",
+                tracker_url
+            ))),
+            _ => Err(BittorrentError::TrackerError(format!(
+                "Unsupported tracker scheme: {}",
+                tracker_url
+            ))),
+        }
+    }
+
+    /// Announce to a UDP tracker, reusing its connection ID if we talked to
+    /// it before
+    async fn announce_udp(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        info!("Announcing to tracker: {}", tracker_url);
+
+        // Taken out of the cache so other trackers aren't held up meanwhile
+        let cached = self.udp_trackers.lock().await.remove(tracker_url);
+        let exchange = async {
+            let mut tracker = match cached {
+                Some(tracker) => tracker,
+                None => UdpTracker::connect(tracker_url)
+                    .await?
+                    .with_total_timeout(self.timeout),
+            };
+            let response = tracker.announce(request).await?;
+            Ok::<_, BittorrentError>((tracker, response))
+        };
+        let (tracker, response) = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| {
+                BittorrentError::TrackerError(format!(
+                    "Tracker {} did not answer within {}s",
+                    tracker_url,
+                    self.timeout.as_secs_f64()
+                ))
+            })??;
+
+        self.udp_trackers
+            .lock()
+            .await
+            .insert(tracker_url.to_string(), tracker);
         info!(
             "Received {} peers from tracker (interval: {}s)",
-            tracker_response.peers.len(),
-            tracker_response.interval
+            response.peers.len(),
+            response.interval
         );
+        Ok(response)
+    }
+
+    /// Announce to a WebTorrent tracker
+    ///
+    /// Always an error: even when the tracker answers, the peers it knows
+    /// are only reachable over WebRTC.
+    #[cfg(feature = "webtorrent")]
+    async fn announce_websocket(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        info!("Announcing to tracker: {}", tracker_url);
+
+        let exchange = async {
+            let mut tracker = super::WebSocketTracker::connect(tracker_url).await?;
+            tracker.announce(request).await
+        };
+        let response = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| {
+                BittorrentError::TrackerError(format!(
+                    "Tracker {} did not answer within {}s",
+                    tracker_url,
+                    self.timeout.as_secs_f64()
+                ))
+            })??;
+
+        Err(BittorrentError::TrackerError(format!(
+            "Tracker {} knows {} seeders and {} leechers, but connecting to WebRTC peers is not implemented",
+            tracker_url,
+            response.complete.unwrap_or(0),
+            response.incomplete.unwrap_or(0)
+        )))
+    }
+
+    /// Announce to an HTTP(S) tracker
+    ///
+    /// With the compact fallback on, a `compact=1` announce whose reply
+    /// can't be read or lists no peers is retried once with `compact=0`,
+    /// for legacy trackers that only give the dictionary peer list. A
+    /// tracker the retry worked for is asked with `compact=0` from then on.
+    async fn announce_http(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        info!("Announcing to tracker: {}", tracker_url);
+
+        let fallback = self.compact_fallback && request.compact;
+        let non_compact = TrackerRequest {
+            compact: false,
+            ..request.clone()
+        };
+        let known_non_compact = fallback
+            && self
+                .non_compact_trackers
+                .lock()
+                .unwrap()
+                .contains(tracker_url);
+        if known_non_compact {
+            return parse_announce(&self.fetch_announce(tracker_url, &non_compact).await?);
+        }
+
+        // Trackers that don't answer at all aren't asked again
+        let response = parse_announce(&self.fetch_announce(tracker_url, request).await?);
+        if !fallback || response.as_ref().is_ok_and(|r| !r.peers.is_empty()) {
+            return response;
+        }
 
-        Ok(tracker_response)
+        debug!(
+            "No usable compact peer list from {}, retrying with compact=0",
+            tracker_url
+        );
+        let retried = self
+            .fetch_announce(tracker_url, &non_compact)
+            .await
+            .and_then(|body| parse_announce(&body));
+        match retried {
+            Ok(retried) if !retried.peers.is_empty() || response.is_err() => {
+                info!(
+                    "Tracker {} only answers non-compact announces, using compact=0 for it",
+                    tracker_url
+                );
+                self.non_compact_trackers
+                    .lock()
+                    .unwrap()
+                    .insert(tracker_url.to_string());
+                Ok(retried)
+            }
+            _ => response,
+        }
+    }
+
+    /// Send one announce over HTTP(S), following redirects, and return the
+    /// body of a successful reply
+    async fn fetch_announce(&self, tracker_url: &str, request: &TrackerRequest) -> Result<Vec<u8>> {
+        // Build URL with query parameters
+        let mut url = request.announce_url(tracker_url)?;
+
+        // A tracker that accepts the connection but never answers would
+        // otherwise hold up the download forever
+        let exchange = async {
+            let mut redirects = 0;
+            loop {
+                debug!("Tracker request URL: {}", url);
+                let response = self.client_for(&url).get(url.clone()).send().await?;
+                self.check_pinned(&response)?;
+                let status = response.status();
+                if !status.is_redirection() {
+                    return Ok::<_, BittorrentError>((status, response.bytes().await?));
+                }
+
+                let location = response
+                    .headers()
+                    .get(LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .ok_or_else(|| {
+                        BittorrentError::TrackerError(format!(
+                            "Tracker {} sent {} without a Location",
+                            tracker_url, status
+                        ))
+                    })?;
+                if redirects == self.max_redirects {
+                    return Err(BittorrentError::TrackerError(format!(
+                        "Tracker {} redirected more than {} times",
+                        tracker_url, self.max_redirects
+                    )));
+                }
+                redirects += 1;
+                url = redirect_target(&url, location, request)?;
+
+                let mut moved_to = url.clone();
+                moved_to.set_query(None);
+                warn!(
+                    "Tracker {} redirected the announce ({}) to {}; consider updating the torrent",
+                    tracker_url, status, moved_to
+                );
+            }
+        };
+        let (status, body) = tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| {
+                BittorrentError::TrackerError(format!(
+                    "Tracker {} did not answer within {}s",
+                    tracker_url,
+                    self.timeout.as_secs_f64()
+                ))
+            })??;
+
+        debug!(
+            "Tracker response status: {}, body length: {}",
+            status,
+            body.len()
+        );
+
+        if !status.is_success() {
+            return Err(BittorrentError::TrackerError(format!(
+                "HTTP error: {}",
+                status
+            )));
+        }
+
+        Ok(body.to_vec())
     }
 }
 
@@ -61,3 +445,297 @@ impl Default for TrackerClient {
         Self::new()
     }
 }
+
+/// Decode an HTTP announce reply
+fn parse_announce(body: &[u8]) -> Result<TrackerResponse> {
+    let tracker_response = TrackerResponse::from_bencode(decode(body)?)?;
+
+    info!(
+        "Received {} peers from tracker (interval: {}s)",
+        tracker_response.peers.len(),
+        tracker_response.interval
+    );
+
+    Ok(tracker_response)
+}
+
+/// Where to announce after a redirect from `from` to `location`
+///
+/// `location` may be relative. A target that dropped the announce
+/// parameters, e.g. one pointing at the tracker's new bare announce URL,
+/// gets them added back.
+fn redirect_target(
+    from: &reqwest::Url,
+    location: &str,
+    request: &TrackerRequest,
+) -> Result<reqwest::Url> {
+    let target = from.join(location)?;
+    if target.query_pairs().any(|(key, _)| key == "info_hash") {
+        Ok(target)
+    } else {
+        request.announce_url(target.as_str())
+    }
+}
+
+/// Lowercased scheme of a tracker URL
+fn scheme(tracker_url: &str) -> Option<String> {
+    tracker_url
+        .split_once("://")
+        .map(|(scheme, _)| scheme.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::MockTracker;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_announce_to_silent_tracker_times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move {
+            // Read the request, then sit on the connection
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let _ = socket.read(&mut [0u8; 1024]).await;
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let client = TrackerClient::with_options(&TrackerOptions {
+            timeout: Duration::from_millis(200),
+            ..Default::default()
+        })
+        .unwrap();
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+
+        let started = std::time::Instant::now();
+        let result = client.announce(&url, &request).await;
+        assert!(
+            matches!(&result, Err(BittorrentError::TrackerError(msg)) if msg.contains("did not answer")),
+            "{:?}",
+            result.err()
+        );
+        assert!(started.elapsed() < Duration::from_secs(5));
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_announce_sends_user_agent_and_headers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+
+            let body = b"d8:intervali1800e5:peers0:e";
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.write_all(body).await.unwrap();
+
+            String::from_utf8(request).unwrap().to_lowercase()
+        });
+
+        let options = TrackerOptions {
+            user_agent: "test-agent/1.0".to_string(),
+            headers: vec![("X-Passkey".to_string(), "secret".to_string())],
+            ..Default::default()
+        };
+        let client = TrackerClient::with_options(&options).unwrap();
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+        client.announce(&url, &request).await.unwrap();
+
+        let sent = server.await.unwrap();
+        assert!(sent.contains("user-agent: test-agent/1.0"));
+        assert!(sent.contains("x-passkey: secret"));
+    }
+
+    #[tokio::test]
+    async fn test_redirected_announce_still_gets_peers() {
+        let peer: std::net::SocketAddr = "10.0.0.7:51413".parse().unwrap();
+        let tracker = MockTracker::spawn(vec![peer]).await;
+        // The new announce URL has none of our parameters on it
+        let moved = MockTracker::redirect_to(&tracker.announce_url()).await;
+        // A relative redirect on the same host
+        let relative = MockTracker::redirect_to("/elsewhere").await;
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+
+        let response = TrackerClient::new()
+            .announce(&moved.announce_url(), &request)
+            .await
+            .unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr, peer);
+
+        // Endless redirects give up instead of looping
+        let looping = TrackerClient::new()
+            .announce(&relative.announce_url(), &request)
+            .await;
+        assert!(
+            matches!(&looping, Err(BittorrentError::TrackerError(msg)) if msg.contains("redirected more than")),
+            "{:?}",
+            looping.err()
+        );
+
+        let no_redirects = TrackerClient::with_options(&TrackerOptions {
+            max_redirects: 0,
+            ..Default::default()
+        })
+        .unwrap()
+        .announce(&moved.announce_url(), &request)
+        .await;
+        assert!(no_redirects.is_err());
+    }
+
+    /// A legacy tracker: compact requests get an empty peer string, others
+    /// the dictionary peer list. Returns the request lines it served.
+    async fn legacy_tracker(listener: TcpListener, announces: usize) -> Vec<String> {
+        let mut served = Vec::new();
+        for _ in 0..announces {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = socket.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            let request_line = String::from_utf8_lossy(&request)
+                .lines()
+                .next()
+                .unwrap()
+                .to_string();
+
+            let body: &[u8] = if request_line.contains("compact=1") {
+                b"d8:intervali1800e5:peers0:e"
+            } else {
+                b"d8:intervali1800e5:peersld2:ip8:10.0.0.74:porti6881eed2:ip8:10.0.0.84:porti6882eeee"
+            };
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                body.len()
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+            socket.write_all(body).await.unwrap();
+            served.push(request_line);
+        }
+        served
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_non_compact_peer_list() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let server = tokio::spawn(legacy_tracker(listener, 3));
+
+        let client = TrackerClient::new();
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+        for _ in 0..2 {
+            let response = client.announce(&url, &request).await.unwrap();
+            let peers: Vec<String> = response.peers.iter().map(|p| p.addr.to_string()).collect();
+            assert_eq!(peers, vec!["10.0.0.7:6881", "10.0.0.8:6882"]);
+        }
+
+        // Once the fallback worked, the tracker isn't asked for compact again
+        let served = server.await.unwrap();
+        assert!(served[0].contains("compact=1"));
+        assert!(served[1].contains("compact=0"));
+        assert!(served[2].contains("compact=0"));
+    }
+
+    #[tokio::test]
+    async fn test_compact_fallback_can_be_disabled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let server = tokio::spawn(legacy_tracker(listener, 1));
+
+        let client = TrackerClient::with_options(&TrackerOptions {
+            compact_fallback: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+        let response = client.announce(&url, &request).await.unwrap();
+        assert!(response.peers.is_empty());
+        assert_eq!(server.await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_header_is_rejected() {
+        let options = TrackerOptions {
+            headers: vec![("bad header".to_string(), "value".to_string())],
+            ..Default::default()
+        };
+        assert!(TrackerClient::with_options(&options).is_err());
+    }
+
+    fn tls_options(tls: TrackerTls) -> TrackerOptions {
+        TrackerOptions {
+            tls,
+            ..Default::default()
+        }
+    }
+
+    async fn announce_with(tls: TrackerTls, tracker: &MockTracker) -> Result<TrackerResponse> {
+        let client = TrackerClient::with_options(&tls_options(tls)).unwrap();
+        let request = TrackerRequest::new([0u8; 20], [1u8; 20], 6881, 0);
+        client.announce(&tracker.announce_url(), &request).await
+    }
+
+    #[tokio::test]
+    async fn test_self_signed_tracker_needs_explicit_trust() {
+        let tracker = MockTracker::with_tls(b"d8:intervali1800e5:peers0:e".to_vec()).await;
+
+        assert!(announce_with(TrackerTls::default(), &tracker)
+            .await
+            .is_err());
+
+        let trusted = TrackerTls {
+            root_certificates: vec![tracker.certificate_pem()],
+            ..Default::default()
+        };
+        assert!(announce_with(trusted, &tracker).await.is_ok());
+
+        let insecure = TrackerTls {
+            insecure_hosts: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        };
+        assert!(announce_with(insecure, &tracker).await.is_ok());
+
+        let other_host = TrackerTls {
+            insecure_hosts: vec!["tracker.example".to_string()],
+            ..Default::default()
+        };
+        assert!(announce_with(other_host, &tracker).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_pinned_certificate() {
+        let tracker = MockTracker::with_tls(b"d8:intervali1800e5:peers0:e".to_vec()).await;
+
+        let pinned = TrackerTls {
+            root_certificates: vec![tracker.certificate_pem()],
+            pinned_certificates: vec![tracker.certificate_fingerprint()],
+            ..Default::default()
+        };
+        assert!(announce_with(pinned, &tracker).await.is_ok());
+
+        let wrong_pin = TrackerTls {
+            root_certificates: vec![tracker.certificate_pem()],
+            pinned_certificates: vec![[0u8; 32]],
+            ..Default::default()
+        };
+        assert!(matches!(
+            announce_with(wrong_pin, &tracker).await,
+            Err(BittorrentError::TrackerError(_))
+        ));
+    }
+}
@@ -0,0 +1,91 @@
+use url::Url;
+
+/// Which trackers may be contacted
+///
+/// Each pattern is either a full announce URL, matched exactly, or a piece
+/// of a hostname, matched case-insensitively against the tracker's host.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerFilter {
+    /// If non-empty, only trackers matching one of these are contacted
+    pub allow: Vec<String>,
+    /// Trackers matching any of these are never contacted
+    pub deny: Vec<String>,
+}
+
+impl TrackerFilter {
+    /// Whether a tracker passes both lists
+    pub fn permits(&self, url: &str) -> bool {
+        let matches = |pattern: &String| pattern_matches(pattern, url);
+        !self.deny.iter().any(matches) && (self.allow.is_empty() || self.allow.iter().any(matches))
+    }
+
+    /// Drop filtered trackers from announce tiers, and tiers left empty
+    pub fn apply(&self, tiers: &[Vec<String>]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| {
+                tier.iter()
+                    .filter(|url| self.permits(url))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            })
+            .filter(|tier| !tier.is_empty())
+            .collect()
+    }
+}
+
+fn pattern_matches(pattern: &str, url: &str) -> bool {
+    if pattern.contains("://") {
+        return pattern == url;
+    }
+
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_ascii_lowercase))
+        .is_some_and(|host| host.contains(&pattern.to_ascii_lowercase()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiers(tiers: &[&[&str]]) -> Vec<Vec<String>> {
+        tiers
+            .iter()
+            .map(|tier| tier.iter().map(|url| url.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_deny_by_host_and_url() {
+        let filter = TrackerFilter {
+            allow: Vec::new(),
+            deny: vec![
+                "Flaky.example".to_string(),
+                "udp://tracker.example:6969/announce".to_string(),
+            ],
+        };
+
+        let kept = filter.apply(&tiers(&[
+            &["http://tracker.flaky.example/announce"],
+            &[
+                "udp://tracker.example:6969/announce",
+                "udp://tracker.example:1337/announce",
+            ],
+        ]));
+        assert_eq!(kept, tiers(&[&["udp://tracker.example:1337/announce"]]));
+    }
+
+    #[test]
+    fn test_allow_list_restricts_and_deny_wins() {
+        let filter = TrackerFilter {
+            allow: vec!["private.example".to_string()],
+            deny: vec!["old.private.example".to_string()],
+        };
+
+        assert!(filter.permits("https://private.example/announce?passkey=x"));
+        assert!(!filter.permits("https://old.private.example/announce"));
+        assert!(!filter.permits("udp://public.example:80/announce"));
+        assert!(!filter.permits("not a url"));
+    }
+}
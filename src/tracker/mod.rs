@@ -1,25 +1,53 @@
 mod client;
+mod filter;
+mod overrides;
 mod peer;
 mod request;
 mod response;
+mod schedule;
+mod udp;
+#[cfg(feature = "webtorrent")]
+mod websocket;
 
-pub use client::TrackerClient;
+pub use client::{
+    TrackerClient, TrackerOptions, TrackerTls, DEFAULT_ANNOUNCE_TIMEOUT, DEFAULT_MAX_REDIRECTS,
+    DEFAULT_USER_AGENT,
+};
+pub use filter::TrackerFilter;
+pub use overrides::{check_tracker_url, TrackerOverrides};
 pub use peer::Peer;
-pub use request::{TrackerEvent, TrackerRequest};
+pub use request::{urlencode_bytes, TrackerEvent, TrackerRequest};
 pub use response::TrackerResponse;
+pub use schedule::AnnounceSchedule;
+pub use udp::UdpTracker;
+#[cfg(feature = "webtorrent")]
+pub use websocket::WebSocketTracker;
 
-use crate::error::Result;
 use rand::Rng;
 
+/// Azureus-style client identifier at the start of our peer IDs
+pub const DEFAULT_PEER_ID_PREFIX: [u8; 8] = *b"-RS0001-";
+
+/// Characters used for the random part of a peer ID; all of them are
+/// unreserved in URLs, so the ID never needs escaping
+const PEER_ID_CHARSET: &[u8] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
 /// Generate a random peer ID
 /// Format: -RS0001-<12 random chars>
+#[allow(dead_code, reason = "the client takes its prefix from the config")]
 pub fn generate_peer_id() -> [u8; 20] {
+    generate_peer_id_with_prefix(&DEFAULT_PEER_ID_PREFIX)
+}
+
+/// Generate a peer ID starting with `prefix`, followed by 12 random
+/// alphanumeric characters
+pub fn generate_peer_id_with_prefix(prefix: &[u8; 8]) -> [u8; 20] {
     let mut peer_id = [0u8; 20];
-    peer_id[0..8].copy_from_slice(b"-RS0001-");
+    peer_id[0..8].copy_from_slice(prefix);
 
     let mut rng = rand::thread_rng();
     for byte in &mut peer_id[8..] {
-        *byte = rng.gen_range(b'0'..=b'z');
+        *byte = PEER_ID_CHARSET[rng.gen_range(0..PEER_ID_CHARSET.len())];
     }
 
     peer_id
@@ -35,4 +63,25 @@ mod tests {
         assert_eq!(peer_id.len(), 20);
         assert_eq!(&peer_id[0..8], b"-RS0001-");
     }
+
+    #[test]
+    fn test_peer_id_prefix_is_honored() {
+        let peer_id = generate_peer_id_with_prefix(b"-qB4650-");
+        assert_eq!(peer_id.len(), 20);
+        assert_eq!(&peer_id[0..8], b"-qB4650-");
+    }
+
+    #[test]
+    fn test_peer_id_suffix_is_alphanumeric() {
+        // 12 random bytes per ID, so 1000 IDs cover the alphabet many times over
+        for _ in 0..1000 {
+            let peer_id = generate_peer_id();
+            assert!(
+                peer_id[8..].iter().all(u8::is_ascii_alphanumeric),
+                "{:?}",
+                String::from_utf8_lossy(&peer_id)
+            );
+        }
+        assert_eq!(PEER_ID_CHARSET.len(), 62);
+    }
 }
@@ -0,0 +1,137 @@
+use super::TrackerClient;
+use crate::error::{BittorrentError, Result};
+use url::Url;
+
+/// Trackers given at download time, on top of or instead of the torrent's
+///
+/// The torrent's metainfo is left alone, so the info hash doesn't change;
+/// the session announces to [`TrackerOverrides::apply`]'s result instead.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerOverrides {
+    /// Extra announce URLs, tried after the torrent's own trackers
+    pub add: Vec<String>,
+    /// Ignore the torrent's trackers and use only `add`
+    pub replace: bool,
+}
+
+impl TrackerOverrides {
+    /// Effective announce tiers for a torrent whose own tiers are `tiers`
+    ///
+    /// Added trackers form one extra tier at the end, minus any the torrent
+    /// already lists.
+    pub fn apply(&self, tiers: &[Vec<String>]) -> Vec<Vec<String>> {
+        let mut tiers = if self.replace {
+            Vec::new()
+        } else {
+            tiers.to_vec()
+        };
+
+        let mut added: Vec<String> = Vec::new();
+        for url in &self.add {
+            if !tiers.iter().flatten().any(|known| known == url) && !added.contains(url) {
+                added.push(url.clone());
+            }
+        }
+        if !added.is_empty() {
+            tiers.push(added);
+        }
+        tiers
+    }
+
+    /// Check that every added tracker is a URL we can announce to
+    pub fn validate(&self) -> Result<()> {
+        for url in &self.add {
+            check_tracker_url(url).map_err(BittorrentError::ConfigError)?;
+        }
+        if self.replace && self.add.is_empty() {
+            return Err(BittorrentError::ConfigError(
+                "replace_trackers needs at least one added tracker".to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Reason `url` can't be used as a tracker, if any
+pub fn check_tracker_url(url: &str) -> std::result::Result<(), String> {
+    let parsed = Url::parse(url).map_err(|e| format!("invalid tracker URL {}: {}", url, e))?;
+    if !TrackerClient::supports(url) {
+        return Err(format!(
+            "unsupported tracker scheme {}:// in {}",
+            parsed.scheme(),
+            url
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_tiers() -> Vec<Vec<String>> {
+        vec![
+            vec!["http://original.example/announce".to_string()],
+            vec!["udp://backup.example:6969/announce".to_string()],
+        ]
+    }
+
+    #[test]
+    fn test_added_trackers_follow_the_torrents_own() {
+        let overrides = TrackerOverrides {
+            add: vec![
+                "https://private.example/announce?passkey=x".to_string(),
+                "udp://backup.example:6969/announce".to_string(),
+            ],
+            replace: false,
+        };
+
+        let mut expected = torrent_tiers();
+        expected.push(vec![
+            "https://private.example/announce?passkey=x".to_string()
+        ]);
+        assert_eq!(overrides.apply(&torrent_tiers()), expected);
+        assert_eq!(
+            TrackerOverrides::default().apply(&torrent_tiers()),
+            torrent_tiers()
+        );
+    }
+
+    #[test]
+    fn test_replace_drops_the_torrents_trackers() {
+        let overrides = TrackerOverrides {
+            add: vec!["udp://working.example:1337/announce".to_string()],
+            replace: true,
+        };
+
+        assert_eq!(
+            overrides.apply(&torrent_tiers()),
+            vec![vec!["udp://working.example:1337/announce".to_string()]]
+        );
+    }
+
+    #[test]
+    fn test_validate_rejects_unsupported_trackers() {
+        let valid = TrackerOverrides {
+            add: vec![
+                "http://a.example/announce".to_string(),
+                "udp://b.example:80".to_string(),
+            ],
+            replace: true,
+        };
+        assert!(valid.validate().is_ok());
+
+        for url in ["ftp://a.example/announce", "not a url"] {
+            let overrides = TrackerOverrides {
+                add: vec![url.to_string()],
+                replace: false,
+            };
+            assert!(overrides.validate().is_err(), "{}", url);
+        }
+        let empty = TrackerOverrides {
+            add: Vec::new(),
+            replace: true,
+        };
+        assert!(empty.validate().is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 /// Represents a peer in the swarm
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
@@ -22,22 +22,89 @@ impl Peer {
         }
     }
 
-    /// Parse a peer from compact format (6 bytes: 4 IP + 2 port)
+    /// Parse a peer from compact format
+    /// (6 bytes: 4 IP + 2 port, or 18 bytes: 16 IPv6 + 2 port)
     pub fn from_compact(data: &[u8]) -> Option<Self> {
-        if data.len() != 6 {
-            return None;
-        }
+        let (ip, port) = match data.len() {
+            6 => (
+                IpAddr::V4(Ipv4Addr::new(data[0], data[1], data[2], data[3])),
+                [data[4], data[5]],
+            ),
+            18 => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&data[..16]);
+                (IpAddr::V6(Ipv6Addr::from(octets)), [data[16], data[17]])
+            }
+            _ => return None,
+        };
+
+        Some(Self::new(ip, u16::from_be_bytes(port)))
+    }
 
-        let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
-        let port = u16::from_be_bytes([data[4], data[5]]);
+    /// Encode this peer in compact format
+    /// (6 bytes for IPv4, 18 bytes for IPv6)
+    #[cfg(test)]
+    pub fn to_compact(&self) -> Option<Vec<u8>> {
+        let mut data = match self.addr.ip() {
+            IpAddr::V4(ip) => ip.octets().to_vec(),
+            IpAddr::V6(ip) => ip.octets().to_vec(),
+        };
+        data.extend_from_slice(&self.addr.port().to_be_bytes());
+        Some(data)
+    }
 
-        Some(Self::new(IpAddr::V4(ip), port))
+    /// Encode multiple IPv4 peers into a compact peer list
+    #[cfg(test)]
+    pub fn to_compact_list(peers: &[Peer]) -> Vec<u8> {
+        peers
+            .iter()
+            .filter(|peer| peer.addr.is_ipv4())
+            .filter_map(Peer::to_compact)
+            .flatten()
+            .collect()
     }
 
     /// Parse multiple peers from compact format
+    #[allow(
+        dead_code,
+        reason = "responses are parsed with IPv6 and dedup handling"
+    )]
     pub fn from_compact_list(data: &[u8]) -> Vec<Self> {
         data.chunks_exact(6)
             .filter_map(Self::from_compact)
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compact_roundtrip_v4() {
+        let peer = Peer::new("192.168.1.20".parse().unwrap(), 6881);
+        let compact = peer.to_compact().unwrap();
+
+        assert_eq!(compact, vec![192, 168, 1, 20, 0x1a, 0xe1]);
+        assert_eq!(Peer::from_compact(&compact), Some(peer));
+    }
+
+    #[test]
+    fn test_compact_roundtrip_v6() {
+        let peer = Peer::new("2001:db8::1".parse().unwrap(), 51413);
+        let compact = peer.to_compact().unwrap();
+
+        assert_eq!(compact.len(), 18);
+        assert_eq!(Peer::from_compact(&compact), Some(peer));
+    }
+
+    #[test]
+    fn test_compact_list_roundtrip() {
+        let peers = vec![
+            Peer::new("10.0.0.1".parse().unwrap(), 1),
+            Peer::new("10.0.0.2".parse().unwrap(), 2),
+        ];
+        let compact = Peer::to_compact_list(&peers);
+        assert_eq!(Peer::from_compact_list(&compact), peers);
+    }
+}
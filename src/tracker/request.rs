@@ -1,8 +1,12 @@
+use crate::error::Result;
+use url::Url;
+
 /// Events sent to the tracker
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TrackerEvent {
     Started,
     Stopped,
+    #[allow(dead_code, reason = "a finished download exits without announcing")]
     Completed,
 }
 
@@ -27,7 +31,8 @@ pub struct TrackerRequest {
     pub port: u16,
     /// Total amount uploaded
     pub uploaded: u64,
-    /// Total amount downloaded
+    /// Bytes downloaded and verified this session. Data thrown away after
+    /// failing verification isn't counted, as trackers expect
     pub downloaded: u64,
     /// Number of bytes left to download
     pub left: u64,
@@ -54,13 +59,16 @@ impl TrackerRequest {
     /// Build query parameters for HTTP request
     pub fn to_query_params(&self) -> Vec<(String, String)> {
         let mut params = vec![
-            ("info_hash".to_string(), urlencoded_hash(&self.info_hash)),
-            ("peer_id".to_string(), urlencoded_hash(&self.peer_id)),
+            ("info_hash".to_string(), urlencode_bytes(&self.info_hash)),
+            ("peer_id".to_string(), urlencode_bytes(&self.peer_id)),
             ("port".to_string(), self.port.to_string()),
             ("uploaded".to_string(), self.uploaded.to_string()),
             ("downloaded".to_string(), self.downloaded.to_string()),
             ("left".to_string(), self.left.to_string()),
-            ("compact".to_string(), if self.compact { "1" } else { "0" }.to_string()),
+            (
+                "compact".to_string(),
+                if self.compact { "1" } else { "0" }.to_string(),
+            ),
         ];
 
         if let Some(event) = &self.event {
@@ -69,11 +77,84 @@ impl TrackerRequest {
 
         params
     }
+
+    /// Announce URL for `tracker_url` carrying this request
+    ///
+    /// Values from [`Self::to_query_params`] are already percent-encoded, so
+    /// they are appended verbatim; encoding them again would turn every
+    /// `%` of the hashes into `%25`. Any query the tracker URL already has
+    /// (such as a passkey) is kept in front.
+    pub fn announce_url(&self, tracker_url: &str) -> Result<Url> {
+        let mut url = Url::parse(tracker_url)?;
+        let params = self
+            .to_query_params()
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect::<Vec<_>>()
+            .join("&");
+
+        let query = match url.query() {
+            Some(existing) if !existing.is_empty() => format!("{}&{}", existing, params),
+            _ => params,
+        };
+        url.set_query(Some(&query));
+        Ok(url)
+    }
+}
+
+/// Percent-encode raw bytes for a tracker query string
+///
+/// RFC 3986 unreserved characters (`A-Za-z0-9-_.~`) stay literal and every
+/// other byte becomes `%XX` with uppercase hex, matching reference clients
+/// byte for byte.
+pub fn urlencode_bytes(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 3);
+    for &byte in bytes {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
 }
 
-/// URL-encode a hash for tracker requests
-fn urlencoded_hash(hash: &[u8; 20]) -> String {
-    hash.iter()
-        .map(|b| format!("%{:02x}", b))
-        .collect()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The 20-byte hash from the BitTorrent protocol specification's
+    /// tracker request example
+    const SPEC_HASH: [u8; 20] = [
+        0x12, 0x34, 0x56, 0x78, 0x9a, 0xbc, 0xde, 0xf1, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef,
+        0x12, 0x34, 0x56, 0x78, 0x9a,
+    ];
+
+    #[test]
+    fn test_urlencode_matches_spec_example() {
+        assert_eq!(
+            urlencode_bytes(&SPEC_HASH),
+            "%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A"
+        );
+        assert_eq!(
+            urlencode_bytes(b"-RS0001-aZ.~_ /%"),
+            "-RS0001-aZ.~_%20%2F%25"
+        );
+    }
+
+    #[test]
+    fn test_announce_url_is_not_encoded_twice() {
+        let request = TrackerRequest::new(SPEC_HASH, *b"-RS0001-abcdefghijkl", 6881, 100);
+        let url = request
+            .announce_url("http://tracker.example/announce?passkey=abc")
+            .unwrap();
+
+        assert_eq!(
+            url.as_str(),
+            "http://tracker.example/announce?passkey=abc\
+             &info_hash=%124Vx%9A%BC%DE%F1%23Eg%89%AB%CD%EF%124Vx%9A\
+             &peer_id=-RS0001-abcdefghijkl&port=6881&uploaded=0&downloaded=0&left=100\
+             &compact=1&event=started"
+        );
+    }
 }
@@ -1,7 +1,15 @@
+use super::Peer;
 use crate::bencode::BencodeValue;
 use crate::error::{BittorrentError, Result};
-use super::Peer;
-use std::net::IpAddr;
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+/// Bytes per peer in a compact `peers` string (IPv4 + port)
+const COMPACT_PEER_LEN: usize = 6;
+
+/// Bytes per peer in a compact `peers6` string (IPv6 + port, BEP 7)
+const COMPACT_PEER6_LEN: usize = 18;
 
 /// Response from a tracker
 #[derive(Debug, Clone)]
@@ -11,6 +19,10 @@ pub struct TrackerResponse {
     /// Minimum announce interval (optional)
     pub min_interval: Option<u64>,
     /// Tracker ID (optional)
+    #[allow(
+        dead_code,
+        reason = "parsed, but not yet echoed back on later announces"
+    )]
     pub tracker_id: Option<String>,
     /// Number of seeders (optional)
     pub complete: Option<u64>,
@@ -20,18 +32,32 @@ pub struct TrackerResponse {
     pub peers: Vec<Peer>,
 }
 
+/// Swarm counts for one torrent, as reported by a scrape
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrapeStats {
+    /// Number of seeders
+    pub complete: u64,
+    /// Number of times the torrent was fully downloaded
+    pub downloaded: u64,
+    /// Number of leechers
+    pub incomplete: u64,
+}
+
+/// Response to a scrape request, keyed by info hash
+#[derive(Debug, Clone, Default)]
+pub struct ScrapeResponse {
+    pub files: HashMap<[u8; 20], ScrapeStats>,
+}
+
 impl TrackerResponse {
     pub fn from_bencode(value: BencodeValue) -> Result<Self> {
-        let dict = value.as_dict().ok_or_else(|| {
-            BittorrentError::TrackerError("Response must be a dict".to_string())
-        })?;
+        let dict = value
+            .as_dict()
+            .ok_or_else(|| BittorrentError::TrackerError("Response must be a dict".to_string()))?;
 
         // Check for failure reason
         if let Some(failure) = dict.get(b"failure reason".as_ref()) {
-            let reason = failure
-                .as_str()
-                .unwrap_or("Unknown failure")
-                .to_string();
+            let reason = failure.as_str().unwrap_or("Unknown failure").to_string();
             return Err(BittorrentError::TrackerError(reason));
         }
 
@@ -39,15 +65,21 @@ impl TrackerResponse {
         let interval = dict
             .get(b"interval".as_ref())
             .and_then(|v| v.as_integer())
-            .ok_or_else(|| {
-                BittorrentError::TrackerError("Missing 'interval' field".to_string())
-            })? as u64;
+            .ok_or_else(|| BittorrentError::TrackerError("Missing 'interval' field".to_string()))?;
+        let interval = u64::try_from(interval).map_err(|_| {
+            BittorrentError::TrackerError(format!("Negative 'interval': {}", interval))
+        })?;
 
         // Parse optional fields
         let min_interval = dict
             .get(b"min interval".as_ref())
             .and_then(|v| v.as_integer())
-            .map(|i| i as u64);
+            .map(|i| {
+                u64::try_from(i).map_err(|_| {
+                    BittorrentError::TrackerError(format!("Negative 'min interval': {}", i))
+                })
+            })
+            .transpose()?;
 
         let tracker_id = dict
             .get(b"tracker id".as_ref())
@@ -64,24 +96,7 @@ impl TrackerResponse {
             .and_then(|v| v.as_integer())
             .map(|i| i as u64);
 
-        // Parse peers
-        let peers = if let Some(peers_value) = dict.get(b"peers".as_ref()) {
-            // Try compact format first (binary string)
-            if let Some(compact_peers) = peers_value.as_bytes() {
-                Peer::from_compact_list(compact_peers)
-            } else if let Some(peer_list) = peers_value.as_list() {
-                // Dictionary model
-                parse_peer_list(peer_list)?
-            } else {
-                return Err(BittorrentError::TrackerError(
-                    "Invalid 'peers' format".to_string(),
-                ));
-            }
-        } else {
-            return Err(BittorrentError::TrackerError(
-                "Missing 'peers' field".to_string(),
-            ));
-        };
+        let peers = parse_all_peers(dict)?;
 
         Ok(TrackerResponse {
             interval,
@@ -94,13 +109,148 @@ impl TrackerResponse {
     }
 }
 
+/// Collect peers from every form the tracker sent, `peers` first and
+/// then `peers6`.
+///
+/// Each key may hold a compact string or a dictionary list, and some
+/// trackers send several at once. A form that fails to parse is skipped
+/// as long as another one succeeds; the first error is only returned when
+/// nothing usable is left. Duplicate addresses keep their first
+/// occurrence, picking up a peer id from a later copy if it had none.
+fn parse_all_peers(dict: &BTreeMap<Vec<u8>, BencodeValue>) -> Result<Vec<Peer>> {
+    let forms = [("peers", COMPACT_PEER_LEN), ("peers6", COMPACT_PEER6_LEN)];
+
+    let mut peers: Vec<Peer> = Vec::new();
+    let mut index: HashMap<SocketAddr, usize> = HashMap::new();
+    let mut first_error = None;
+    let mut parsed = 0;
+
+    for (key, width) in forms {
+        let Some(value) = dict.get(key.as_bytes()) else {
+            continue;
+        };
+
+        match parse_peers(key, value, width) {
+            Ok(found) => {
+                parsed += 1;
+                for peer in found {
+                    match index.get(&peer.addr) {
+                        Some(&i) => {
+                            if peers[i].peer_id.is_none() {
+                                peers[i].peer_id = peer.peer_id;
+                            }
+                        }
+                        None => {
+                            index.insert(peer.addr, peers.len());
+                            peers.push(peer);
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                if first_error.is_none() {
+                    first_error = Some(e);
+                }
+            }
+        }
+    }
+
+    if parsed == 0 {
+        return Err(first_error.unwrap_or_else(|| {
+            BittorrentError::TrackerError("Missing 'peers' field".to_string())
+        }));
+    }
+
+    // Compact `peers` plus compact `peers6` is the normal BEP 7 reply
+    if let Some(e) = &first_error {
+        warn!(
+            "Tracker sent a mixed peer response ({}); using the usable part: {}",
+            describe_forms(dict),
+            e
+        );
+    } else if is_mixed(dict) {
+        warn!(
+            "Tracker sent a mixed peer response ({}); merged {} unique peers",
+            describe_forms(dict),
+            peers.len()
+        );
+    }
+
+    Ok(peers)
+}
+
+/// Whether `peers` and `peers6` use different models, such as a
+/// dictionary list next to a compact string
+fn is_mixed(dict: &BTreeMap<Vec<u8>, BencodeValue>) -> bool {
+    match (dict.get(b"peers".as_ref()), dict.get(b"peers6".as_ref())) {
+        (Some(peers), Some(peers6)) => form_kind(peers) != form_kind(peers6),
+        _ => false,
+    }
+}
+
+/// The peer forms present, e.g. "peers as compact string, peers6 as
+/// dictionary list"
+fn describe_forms(dict: &BTreeMap<Vec<u8>, BencodeValue>) -> String {
+    ["peers", "peers6"]
+        .iter()
+        .filter_map(|key| {
+            let value = dict.get(key.as_bytes())?;
+            Some(format!("{} as {}", key, form_kind(value)))
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn form_kind(value: &BencodeValue) -> &'static str {
+    match value {
+        BencodeValue::String(_) => "compact string",
+        BencodeValue::List(_) => "dictionary list",
+        BencodeValue::Integer(_) => "integer",
+        BencodeValue::Dict(_) => "dictionary",
+    }
+}
+
+/// Parse a `peers` or `peers6` value in either the compact or the
+/// dictionary model
+fn parse_peers(key: &str, value: &BencodeValue, width: usize) -> Result<Vec<Peer>> {
+    match value {
+        // An empty string is how some trackers say "no peers"
+        BencodeValue::String(compact) => Ok(parse_compact_peers(compact, width)),
+        BencodeValue::List(list) => parse_peer_list(list),
+        BencodeValue::Integer(n) => Err(BittorrentError::TrackerError(format!(
+            "'{}' must be a string or a list, got integer {}",
+            key, n
+        ))),
+        BencodeValue::Dict(_) => Err(BittorrentError::TrackerError(format!(
+            "'{}' must be a string or a list, got a dictionary",
+            key
+        ))),
+    }
+}
+
+/// Parse fixed-width compact peers, ignoring a truncated trailing entry
+fn parse_compact_peers(data: &[u8], width: usize) -> Vec<Peer> {
+    let trailing = data.len() % width;
+    if trailing != 0 {
+        warn!(
+            "Ignoring {} trailing bytes of a {} byte compact peer list",
+            trailing,
+            data.len()
+        );
+    }
+
+    data.chunks_exact(width)
+        .filter_map(Peer::from_compact)
+        .collect()
+}
+
 fn parse_peer_list(list: &[BencodeValue]) -> Result<Vec<Peer>> {
     let mut peers = Vec::new();
 
     for peer_value in list {
-        let peer_dict = peer_value.as_dict().ok_or_else(|| {
-            BittorrentError::TrackerError("Peer must be a dict".to_string())
-        })?;
+        let peer_dict = peer_value
+            .as_dict()
+            .ok_or_else(|| BittorrentError::TrackerError("Peer must be a dict".to_string()))?;
 
         // Parse IP
         let ip_str = peer_dict
@@ -108,9 +258,9 @@ fn parse_peer_list(list: &[BencodeValue]) -> Result<Vec<Peer>> {
             .and_then(|v| v.as_str())
             .ok_or_else(|| BittorrentError::TrackerError("Missing peer 'ip'".to_string()))?;
 
-        let ip: IpAddr = ip_str.parse().map_err(|_| {
-            BittorrentError::TrackerError("Invalid peer IP address".to_string())
-        })?;
+        let ip: IpAddr = ip_str
+            .parse()
+            .map_err(|_| BittorrentError::TrackerError("Invalid peer IP address".to_string()))?;
 
         // Parse port
         let port = peer_dict
@@ -136,3 +286,181 @@ fn parse_peer_list(list: &[BencodeValue]) -> Result<Vec<Peer>> {
 
     Ok(peers)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bencode::decode;
+
+    fn parse(body: &[u8]) -> Result<TrackerResponse> {
+        TrackerResponse::from_bencode(decode(body).unwrap())
+    }
+
+    #[test]
+    fn test_empty_compact_peers() {
+        let response = parse(b"d8:intervali1800e5:peers0:e").unwrap();
+        assert!(response.peers.is_empty());
+    }
+
+    #[test]
+    fn test_integer_peers_is_a_clear_error() {
+        let err = parse(b"d8:intervali1800e5:peersi0ee").unwrap_err();
+        assert!(err.to_string().contains("got integer 0"), "{}", err);
+    }
+
+    #[test]
+    fn test_negative_intervals_are_rejected() {
+        let err = parse(b"d8:intervali-1e5:peers0:e").unwrap_err();
+        assert!(err.to_string().contains("Negative 'interval'"), "{}", err);
+
+        let err = parse(b"d8:intervali1800e12:min intervali-5e5:peers0:e").unwrap_err();
+        assert!(
+            err.to_string().contains("Negative 'min interval'"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn test_truncated_compact_peers() {
+        let response =
+            parse(b"d8:intervali1800e5:peers8:\x7f\x00\x00\x01\x1a\xe1\x0a\x00e").unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr, "127.0.0.1:6881".parse().unwrap());
+    }
+
+    #[test]
+    fn test_compact_peers6() {
+        let mut body = b"d8:intervali1800e5:peers0:6:peers618:".to_vec();
+        body.extend_from_slice(&"::1".parse::<std::net::Ipv6Addr>().unwrap().octets());
+        body.extend_from_slice(&[0x1a, 0xe1, b'e']);
+
+        let response = parse(&body).unwrap();
+        assert_eq!(response.peers.len(), 1);
+        assert_eq!(response.peers[0].addr, "[::1]:6881".parse().unwrap());
+    }
+
+    fn ipv6_compact(ip: &str, port: u16) -> Vec<u8> {
+        let mut entry = ip.parse::<std::net::Ipv6Addr>().unwrap().octets().to_vec();
+        entry.extend_from_slice(&port.to_be_bytes());
+        entry
+    }
+
+    fn addrs(response: &TrackerResponse) -> Vec<SocketAddr> {
+        response.peers.iter().map(|p| p.addr).collect()
+    }
+
+    #[test]
+    fn test_dual_stack_compact_response_is_not_mixed() {
+        let mut body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe16:peers618:".to_vec();
+        body.extend(ipv6_compact("::1", 6882));
+        body.push(b'e');
+
+        let value = decode(&body).unwrap();
+        assert!(!is_mixed(value.as_dict().unwrap()));
+
+        let response = TrackerResponse::from_bencode(value).unwrap();
+        assert_eq!(
+            addrs(&response),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+
+        let mixed = decode(b"d5:peers0:6:peers6lee").unwrap();
+        assert!(is_mixed(mixed.as_dict().unwrap()));
+    }
+
+    #[test]
+    fn test_dict_peers_with_compact_peers6_are_merged() {
+        let mut body = b"d8:intervali1800e5:peersl\
+            d2:ip8:10.0.0.14:porti6881ee\
+            d2:ip3:::14:porti6882ee\
+            e6:peers636:"
+            .to_vec();
+        body.extend(ipv6_compact("::1", 6882));
+        body.extend(ipv6_compact("::2", 6883));
+        body.push(b'e');
+
+        let response = parse(&body).unwrap();
+        assert_eq!(
+            addrs(&response),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+                "[::2]:6883".parse().unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compact_peers_with_dict_peers6_are_merged() {
+        let body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1\
+            6:peers6ld2:ip3:::17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+            d2:ip3:::24:porti6882eeee";
+
+        let response = parse(body).unwrap();
+        assert_eq!(
+            addrs(&response),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6881".parse().unwrap(),
+                "[::2]:6882".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            response.peers[1].peer_id.as_deref(),
+            Some(b"-XX0001-0123456789ab".as_ref())
+        );
+    }
+
+    #[test]
+    fn test_duplicate_keeps_first_and_fills_peer_id() {
+        let body = b"d8:intervali1800e5:peers6:\x0a\x00\x00\x01\x1a\xe1\
+            6:peers6ld2:ip8:10.0.0.17:peer id20:-XX0001-0123456789ab4:porti6881ee\
+            d2:ip3:::14:porti6882eeee";
+        // The IPv4 peer is listed twice: compact in `peers`, as a dict in `peers6`
+        let response = parse(body).unwrap();
+        assert_eq!(
+            addrs(&response),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "[::1]:6882".parse().unwrap(),
+            ]
+        );
+        assert_eq!(
+            response.peers[0].peer_id.as_deref(),
+            Some(b"-XX0001-0123456789ab".as_ref())
+        );
+    }
+
+    #[test]
+    fn test_unusable_peers_falls_back_to_peers6() {
+        let mut body = b"d8:intervali1800e5:peersi0e6:peers618:".to_vec();
+        body.extend(ipv6_compact("::1", 6881));
+        body.push(b'e');
+
+        let response = parse(&body).unwrap();
+        assert_eq!(addrs(&response), vec!["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_peers6_without_peers() {
+        let mut body = b"d8:intervali1800e6:peers618:".to_vec();
+        body.extend(ipv6_compact("::1", 6881));
+        body.push(b'e');
+
+        let response = parse(&body).unwrap();
+        assert_eq!(addrs(&response), vec!["[::1]:6881".parse().unwrap()]);
+    }
+
+    #[test]
+    fn test_no_usable_form_reports_first_error() {
+        let err = parse(b"d8:intervali1800e5:peersi0e6:peers6dee").unwrap_err();
+        assert!(err.to_string().contains("'peers' must be"), "{}", err);
+
+        let err = parse(b"d8:intervali1800ee").unwrap_err();
+        assert!(err.to_string().contains("Missing 'peers'"), "{}", err);
+    }
+}
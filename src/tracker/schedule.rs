@@ -0,0 +1,140 @@
+use super::TrackerResponse;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Floor for early re-announces when the tracker sends no `min interval`
+pub const DEFAULT_MIN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Longest wait between announces, whatever the tracker asks for
+pub const MAX_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Decides when the next announce to a tracker may go out
+///
+/// Regular announces wait `max(interval, min interval)` after the last one.
+/// Forced ones (e.g. after losing peers) may come sooner, but never inside
+/// the `min interval` window: trackers ban clients that announce too often.
+#[derive(Debug, Clone)]
+pub struct AnnounceSchedule {
+    last_announce: Instant,
+    interval: Duration,
+    min_interval: Duration,
+}
+
+impl AnnounceSchedule {
+    /// Schedule following an announce sent at `now`
+    pub fn new(response: &TrackerResponse, now: Instant) -> Self {
+        let mut schedule = Self {
+            last_announce: now,
+            interval: Duration::ZERO,
+            min_interval: Duration::ZERO,
+        };
+        schedule.record(response, now);
+        schedule
+    }
+
+    /// Note an announce sent at `now` and adopt the intervals it returned
+    pub fn record(&mut self, response: &TrackerResponse, now: Instant) {
+        self.last_announce = now;
+        self.interval = Duration::from_secs(response.interval.max(1)).min(MAX_INTERVAL);
+        self.min_interval = match response.min_interval {
+            Some(secs) => Duration::from_secs(secs).min(MAX_INTERVAL),
+            // Without one, forced announces still can't beat the regular pace
+            None => self.interval.min(DEFAULT_MIN_INTERVAL),
+        };
+    }
+
+    /// Note an announce sent at `now` that failed, keeping the old intervals
+    pub fn record_failure(&mut self, now: Instant) {
+        self.last_announce = now;
+    }
+
+    /// When the next regular announce is due
+    pub fn next_regular(&self) -> Instant {
+        self.after_last(self.interval.max(self.min_interval))
+    }
+
+    /// When a re-announce requested at `now` may go out
+    pub fn next_forced(&self, now: Instant) -> Instant {
+        let earliest = self.after_last(self.min_interval);
+        if earliest > now {
+            info!(
+                "Re-announce throttled for {:.0}s by the tracker's min interval",
+                (earliest - now).as_secs_f64()
+            );
+        }
+        earliest.max(now).min(self.next_regular())
+    }
+
+    /// `delay` after the last announce; should that not fit in an
+    /// `Instant`, announcing early beats panicking
+    fn after_last(&self, delay: Duration) -> Instant {
+        self.last_announce
+            .checked_add(delay)
+            .unwrap_or(self.last_announce)
+    }
+
+    #[cfg(test)]
+    pub fn min_interval(&self) -> Duration {
+        self.min_interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(interval: u64, min_interval: Option<u64>) -> TrackerResponse {
+        TrackerResponse {
+            interval,
+            min_interval,
+            tracker_id: None,
+            complete: None,
+            incomplete: None,
+            peers: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_regular_announce_honors_min_interval() {
+        let now = Instant::now();
+        let schedule = AnnounceSchedule::new(&response(10, Some(300)), now);
+        assert_eq!(schedule.next_regular(), now + Duration::from_secs(300));
+
+        let schedule = AnnounceSchedule::new(&response(1800, Some(300)), now);
+        assert_eq!(schedule.next_regular(), now + Duration::from_secs(1800));
+    }
+
+    #[test]
+    fn test_forced_announce_is_deferred_within_min_interval() {
+        let start = Instant::now();
+        let schedule = AnnounceSchedule::new(&response(1800, Some(120)), start);
+
+        let soon = start + Duration::from_secs(30);
+        assert_eq!(schedule.next_forced(soon), start + Duration::from_secs(120));
+
+        let later = start + Duration::from_secs(200);
+        assert_eq!(schedule.next_forced(later), later);
+    }
+
+    #[test]
+    fn test_missing_min_interval_uses_default_floor() {
+        let start = Instant::now();
+        let schedule = AnnounceSchedule::new(&response(1800, None), start);
+        assert_eq!(schedule.min_interval(), DEFAULT_MIN_INTERVAL);
+        assert_eq!(schedule.next_forced(start), start + DEFAULT_MIN_INTERVAL);
+
+        // A short regular interval is never beaten by a forced announce
+        let schedule = AnnounceSchedule::new(&response(5, None), start);
+        assert_eq!(schedule.next_forced(start), start + Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_enormous_intervals_are_capped() {
+        let start = Instant::now();
+        let schedule = AnnounceSchedule::new(&response(u64::MAX, Some(u64::MAX)), start);
+        assert_eq!(schedule.min_interval(), MAX_INTERVAL);
+        assert_eq!(schedule.next_regular(), start + MAX_INTERVAL);
+        assert_eq!(schedule.next_forced(start), start + MAX_INTERVAL);
+    }
+}
@@ -0,0 +1,520 @@
+use super::response::{ScrapeResponse, ScrapeStats};
+use super::{Peer, TrackerEvent, TrackerRequest, TrackerResponse};
+use crate::error::{BittorrentError, Result};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tracing::debug;
+
+/// Magic constant identifying the UDP tracker protocol (BEP 15)
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// Most info hashes a single scrape packet may carry
+pub const MAX_SCRAPE_HASHES: usize = 74;
+
+/// How long a connection ID may be reused before connecting again
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Wait for the first attempt; it doubles with every retry
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Requests sent before giving up on a silent tracker
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Largest reply a UDP tracker can send
+const MAX_REPLY_LEN: usize = 65_507;
+
+/// Client for one UDP tracker
+pub struct UdpTracker {
+    socket: UdpSocket,
+    url: String,
+    /// Connection ID from the last connect and when it was issued
+    connection: Option<(u64, Instant)>,
+    timeout: Duration,
+    /// Sent with every announce so the tracker can tell us apart if our
+    /// address changes
+    key: u32,
+}
+
+impl UdpTracker {
+    /// Resolve a `udp://host:port` tracker URL and open a socket to it
+    pub async fn connect(url: &str) -> Result<Self> {
+        let parsed = url::Url::parse(url)?;
+        if parsed.scheme() != "udp" {
+            return Err(BittorrentError::TrackerError(format!(
+                "{}: not a udp:// tracker",
+                url
+            )));
+        }
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| BittorrentError::TrackerError(format!("{}: missing host", url)))?;
+        let port = parsed
+            .port()
+            .ok_or_else(|| BittorrentError::TrackerError(format!("{}: missing port", url)))?;
+
+        let addr = tokio::net::lookup_host((host, port))
+            .await?
+            .next()
+            .ok_or_else(|| {
+                BittorrentError::TrackerError(format!("{}: host did not resolve", url))
+            })?;
+
+        let bind_addr = if addr.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        socket.connect(addr).await?;
+
+        Ok(Self {
+            socket,
+            url: url.to_string(),
+            connection: None,
+            timeout: DEFAULT_TIMEOUT,
+            key: rand::random(),
+        })
+    }
+
+    /// Change how long the first attempt waits for a reply
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Split `total` over all attempts, so a silent tracker is given up on
+    /// after about that long
+    pub fn with_total_timeout(self, total: Duration) -> Self {
+        self.with_timeout(total / (2u32.pow(MAX_ATTEMPTS) - 1))
+    }
+
+    /// Announce to the tracker and get the peer list
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<TrackerResponse> {
+        let connection_id = self.connection_id().await?;
+        let transaction_id = rand::random();
+        let packet = announce_request(connection_id, transaction_id, self.key, request);
+
+        let reply = self
+            .request(&packet, ACTION_ANNOUNCE, transaction_id)
+            .await?;
+        // Peers come in the address family we talk to the tracker over
+        let ipv6 = self.socket.peer_addr()?.is_ipv6();
+        let response = parse_announce_response(&reply, ipv6)?;
+
+        debug!(
+            "Received {} peers from {} (interval: {}s)",
+            response.peers.len(),
+            self.url,
+            response.interval
+        );
+        Ok(response)
+    }
+
+    /// Ask for seeder, leecher and completed counts of the given torrents
+    ///
+    /// Large requests are split into packets of [`MAX_SCRAPE_HASHES`].
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> Result<ScrapeResponse> {
+        let mut response = ScrapeResponse::default();
+
+        for chunk in info_hashes.chunks(MAX_SCRAPE_HASHES) {
+            let connection_id = self.connection_id().await?;
+            let transaction_id = rand::random();
+            let request = scrape_request(connection_id, transaction_id, chunk);
+
+            let reply = self
+                .request(&request, ACTION_SCRAPE, transaction_id)
+                .await?;
+            let stats = parse_scrape_response(&reply, chunk.len())?;
+            response.files.extend(chunk.iter().copied().zip(stats));
+        }
+
+        debug!(
+            "Scraped {} torrents from {}",
+            response.files.len(),
+            self.url
+        );
+        Ok(response)
+    }
+
+    /// Current connection ID, connecting first if there is none or it expired
+    async fn connection_id(&mut self) -> Result<u64> {
+        if let Some((id, issued)) = self.connection {
+            if issued.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+
+        let transaction_id = rand::random();
+        let reply = self
+            .request(
+                &connect_request(transaction_id),
+                ACTION_CONNECT,
+                transaction_id,
+            )
+            .await?;
+        let id = parse_connect_response(&reply)?;
+
+        self.connection = Some((id, Instant::now()));
+        Ok(id)
+    }
+
+    /// Send a request and wait for the matching reply, retrying with a
+    /// doubling timeout
+    ///
+    /// Returns the reply body after the action and transaction ID.
+    async fn request(&self, packet: &[u8], action: u32, transaction_id: u32) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; MAX_REPLY_LEN];
+
+        for attempt in 0..MAX_ATTEMPTS {
+            self.socket.send(packet).await?;
+
+            let deadline = tokio::time::Instant::now() + self.timeout * 2u32.pow(attempt);
+            loop {
+                let received =
+                    match tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await {
+                        Ok(received) => received?,
+                        Err(_) => break,
+                    };
+
+                // Stray replies to earlier, timed out requests are skipped
+                if let Some(body) = check_header(&buf[..received], action, transaction_id)? {
+                    return Ok(body.to_vec());
+                }
+            }
+
+            debug!("No reply from {} (attempt {})", self.url, attempt + 1);
+        }
+
+        Err(BittorrentError::Timeout(format!(
+            "{}: no reply after {} attempts",
+            self.url, MAX_ATTEMPTS
+        )))
+    }
+}
+
+fn connect_request(transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+fn announce_request(
+    connection_id: u64,
+    transaction_id: u32,
+    key: u32,
+    request: &TrackerRequest,
+) -> Vec<u8> {
+    let event: u32 = match request.event {
+        None => 0,
+        Some(TrackerEvent::Completed) => 1,
+        Some(TrackerEvent::Started) => 2,
+        Some(TrackerEvent::Stopped) => 3,
+    };
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(&request.info_hash);
+    packet.extend_from_slice(&request.peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&event.to_be_bytes());
+    // IP address: 0 lets the tracker use the packet's source
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&key.to_be_bytes());
+    // Number of peers wanted: -1 for the tracker's default
+    packet.extend_from_slice(&(-1i32).to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    packet
+}
+
+fn scrape_request(connection_id: u64, transaction_id: u32, info_hashes: &[[u8; 20]]) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_SCRAPE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    for info_hash in info_hashes {
+        packet.extend_from_slice(info_hash);
+    }
+    packet
+}
+
+/// Match a reply against the request it should answer
+///
+/// Returns the body for a matching reply, `None` for a reply to some other
+/// transaction, and the tracker's message if it answered with an error.
+fn check_header(data: &[u8], action: u32, transaction_id: u32) -> Result<Option<&[u8]>> {
+    if data.len() < 8 {
+        return Err(BittorrentError::TrackerError(format!(
+            "UDP reply too short: {} bytes",
+            data.len()
+        )));
+    }
+
+    let reply_action = u32::from_be_bytes(data[0..4].try_into().unwrap());
+    let reply_transaction = u32::from_be_bytes(data[4..8].try_into().unwrap());
+    if reply_transaction != transaction_id {
+        return Ok(None);
+    }
+
+    match reply_action {
+        ACTION_ERROR => Err(BittorrentError::TrackerError(
+            String::from_utf8_lossy(&data[8..]).into_owned(),
+        )),
+        a if a == action => Ok(Some(&data[8..])),
+        other => Err(BittorrentError::TrackerError(format!(
+            "Expected UDP action {}, got {}",
+            action, other
+        ))),
+    }
+}
+
+fn parse_connect_response(body: &[u8]) -> Result<u64> {
+    let id = body
+        .get(..8)
+        .ok_or_else(|| BittorrentError::TrackerError("UDP connect reply too short".to_string()))?;
+    Ok(u64::from_be_bytes(id.try_into().unwrap()))
+}
+
+/// Parse the interval, swarm counts and compact peers of an announce reply
+fn parse_announce_response(body: &[u8], ipv6: bool) -> Result<TrackerResponse> {
+    if body.len() < 12 {
+        return Err(BittorrentError::TrackerError(format!(
+            "UDP announce reply too short: {} bytes",
+            body.len()
+        )));
+    }
+
+    let field = |index: usize| {
+        u32::from_be_bytes(body[index * 4..index * 4 + 4].try_into().unwrap()) as u64
+    };
+    let peer_len = if ipv6 { 18 } else { 6 };
+
+    Ok(TrackerResponse {
+        interval: field(0),
+        min_interval: None,
+        tracker_id: None,
+        complete: Some(field(2)),
+        incomplete: Some(field(1)),
+        peers: body[12..]
+            .chunks_exact(peer_len)
+            .filter_map(Peer::from_compact)
+            .collect(),
+    })
+}
+
+/// Parse the seeders/completed/leechers triples of a scrape reply, in
+/// request order
+fn parse_scrape_response(body: &[u8], count: usize) -> Result<Vec<ScrapeStats>> {
+    if body.len() < count * 12 {
+        return Err(BittorrentError::TrackerError(format!(
+            "UDP scrape reply has {} bytes for {} torrents",
+            body.len(),
+            count
+        )));
+    }
+
+    let field = |entry: &[u8], index: usize| {
+        u32::from_be_bytes(entry[index * 4..index * 4 + 4].try_into().unwrap()) as u64
+    };
+
+    Ok(body
+        .chunks_exact(12)
+        .take(count)
+        .map(|entry| ScrapeStats {
+            complete: field(entry, 0),
+            downloaded: field(entry, 1),
+            incomplete: field(entry, 2),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Scrape reply for transaction 0x0000beef with two torrents
+    const CAPTURED_SCRAPE_REPLY: [u8; 32] = [
+        0x00, 0x00, 0x00, 0x02, // action: scrape
+        0x00, 0x00, 0xbe, 0xef, // transaction id
+        0x00, 0x00, 0x00, 0x2a, // seeders: 42
+        0x00, 0x00, 0x01, 0x00, // completed: 256
+        0x00, 0x00, 0x00, 0x07, // leechers: 7
+        0x00, 0x00, 0x00, 0x00, // seeders: 0
+        0x00, 0x00, 0x00, 0x01, // completed: 1
+        0x00, 0x01, 0x00, 0x00, // leechers: 65536
+    ];
+
+    #[test]
+    fn test_parse_captured_scrape_reply() {
+        let body = check_header(&CAPTURED_SCRAPE_REPLY, ACTION_SCRAPE, 0xbeef)
+            .unwrap()
+            .unwrap();
+        let stats = parse_scrape_response(body, 2).unwrap();
+
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats {
+                    complete: 42,
+                    downloaded: 256,
+                    incomplete: 7,
+                },
+                ScrapeStats {
+                    complete: 0,
+                    downloaded: 1,
+                    incomplete: 65536,
+                },
+            ]
+        );
+
+        // Too few entries for what was asked
+        assert!(parse_scrape_response(body, 3).is_err());
+    }
+
+    #[test]
+    fn test_reply_headers() {
+        // Another transaction's reply is skipped
+        assert!(check_header(&CAPTURED_SCRAPE_REPLY, ACTION_SCRAPE, 1)
+            .unwrap()
+            .is_none());
+
+        let mut error = vec![0, 0, 0, 3, 0, 0, 0, 9];
+        error.extend_from_slice(b"unknown torrent");
+        match check_header(&error, ACTION_SCRAPE, 9) {
+            Err(BittorrentError::TrackerError(message)) => assert_eq!(message, "unknown torrent"),
+            other => panic!("expected tracker error, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_request_layout() {
+        let connect = connect_request(0x01020304);
+        assert_eq!(
+            connect,
+            [0, 0, 0x04, 0x17, 0x27, 0x10, 0x19, 0x80, 0, 0, 0, 0, 1, 2, 3, 4]
+        );
+
+        let mut request = TrackerRequest::new([0xaa; 20], [0xbb; 20], 6881, 1000);
+        request.downloaded = 5;
+        let announce = announce_request(7, 9, 0x0102_0304, &request);
+        assert_eq!(announce.len(), 98);
+        assert_eq!(&announce[8..12], &ACTION_ANNOUNCE.to_be_bytes());
+        assert_eq!(&announce[16..36], &[0xaa; 20]);
+        assert_eq!(&announce[56..64], &5u64.to_be_bytes());
+        assert_eq!(&announce[64..72], &1000u64.to_be_bytes());
+        // Started
+        assert_eq!(&announce[80..84], &2u32.to_be_bytes());
+        assert_eq!(&announce[88..92], &[1, 2, 3, 4]);
+        assert_eq!(&announce[96..], &6881u16.to_be_bytes());
+
+        let scrape = scrape_request(7, 9, &[[0xaa; 20], [0xbb; 20]]);
+        assert_eq!(scrape.len(), 16 + 40);
+        assert_eq!(&scrape[..8], &7u64.to_be_bytes());
+        assert_eq!(&scrape[8..12], &ACTION_SCRAPE.to_be_bytes());
+        assert_eq!(&scrape[16..36], &[0xaa; 20]);
+    }
+
+    /// Minimal UDP tracker answering every scrape with the hash's first
+    /// byte as seeder count, counting scrape packets
+    async fn spawn_tracker() -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let counter = scrapes.clone();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            while let Ok((len, from)) = socket.recv_from(&mut buf).await {
+                let packet = &buf[..len];
+                let action = u32::from_be_bytes(packet[8..12].try_into().unwrap());
+                let mut reply = packet[8..16].to_vec();
+
+                if action == ACTION_CONNECT {
+                    reply.extend_from_slice(&0x1122_3344_5566_7788u64.to_be_bytes());
+                } else {
+                    assert_eq!(&packet[..8], &0x1122_3344_5566_7788u64.to_be_bytes());
+                    let hashes = &packet[16..];
+                    assert!(hashes.len() / 20 <= MAX_SCRAPE_HASHES);
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    for hash in hashes.chunks(20) {
+                        reply.extend_from_slice(&(hash[0] as u32).to_be_bytes());
+                        reply.extend_from_slice(&0u32.to_be_bytes());
+                        reply.extend_from_slice(&1u32.to_be_bytes());
+                    }
+                }
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        (url, scrapes)
+    }
+
+    #[tokio::test]
+    async fn test_scrape_is_chunked() {
+        let (url, scrapes) = spawn_tracker().await;
+        let hashes: Vec<[u8; 20]> = (0..100u8).map(|i| [i; 20]).collect();
+
+        let mut tracker = UdpTracker::connect(&url)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_secs(2));
+        let response = tracker.scrape(&hashes).await.unwrap();
+
+        assert_eq!(scrapes.load(Ordering::SeqCst), 2);
+        assert_eq!(response.files.len(), 100);
+        assert_eq!(response.files[&[99; 20]].complete, 99);
+        assert_eq!(response.files[&[0; 20]].incomplete, 1);
+    }
+
+    #[test]
+    fn test_parse_announce_reply() {
+        let body = [
+            0x00, 0x00, 0x07, 0x08, // interval: 1800
+            0x00, 0x00, 0x00, 0x03, // leechers: 3
+            0x00, 0x00, 0x00, 0x01, // seeders: 1
+            10, 0, 0, 1, 0x1a, 0xe1, // 10.0.0.1:6881
+            10, 0, 0, 2, 0x1a, 0xe2, // 10.0.0.2:6882
+        ];
+        let response = parse_announce_response(&body, false).unwrap();
+
+        assert_eq!(response.interval, 1800);
+        assert_eq!(response.complete, Some(1));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(
+            response.peers,
+            vec![
+                Peer::new("10.0.0.1".parse().unwrap(), 6881),
+                Peer::new("10.0.0.2".parse().unwrap(), 6882),
+            ]
+        );
+
+        assert!(parse_announce_response(&body[..8], false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_silent_tracker_times_out() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}", socket.local_addr().unwrap());
+
+        let mut tracker = UdpTracker::connect(&url)
+            .await
+            .unwrap()
+            .with_timeout(Duration::from_millis(20));
+        assert!(matches!(
+            tracker.scrape(&[[1; 20]]).await,
+            Err(BittorrentError::Timeout(_))
+        ));
+    }
+}
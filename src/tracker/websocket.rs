@@ -0,0 +1,475 @@
+use super::TrackerRequest;
+use crate::error::{BittorrentError, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Appended to the handshake key before hashing it for the accept header
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_TEXT: u8 = 0x1;
+const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xa;
+
+/// Largest message accepted from a tracker
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A WebRTC offer another peer sent through the tracker
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebRtcOffer {
+    /// The offering peer's ID
+    pub peer_id: Vec<u8>,
+    /// Must be echoed in our answer so the tracker can route it
+    pub offer_id: Vec<u8>,
+    /// Session description to answer
+    pub sdp: String,
+}
+
+/// Answer to an announce on a WebSocket tracker
+#[derive(Debug, Clone, Default)]
+pub struct WebSocketAnnounce {
+    /// Interval in seconds to wait before next request
+    #[allow(
+        dead_code,
+        reason = "the announce is reported as failed, so no re-announce follows"
+    )]
+    pub interval: u64,
+    /// Number of seeders (optional)
+    pub complete: Option<u64>,
+    /// Number of leechers (optional)
+    pub incomplete: Option<u64>,
+    /// Offers relayed from other peers while we waited for the answer
+    #[allow(dead_code, reason = "answering an offer needs WebRTC")]
+    pub offers: Vec<WebRtcOffer>,
+}
+
+/// Client for one WebTorrent tracker (`ws://` or `wss://`)
+///
+/// Peers on these trackers are only reachable over WebRTC, which isn't
+/// implemented: this discovers them but can't connect to them.
+pub struct WebSocketTracker {
+    stream: BufReader<Box<dyn Stream>>,
+    url: String,
+}
+
+impl WebSocketTracker {
+    /// Connect to a tracker and complete the WebSocket handshake
+    pub async fn connect(url: &str) -> Result<Self> {
+        let parsed = url::Url::parse(url)?;
+        let secure = match parsed.scheme() {
+            "ws" => false,
+            "wss" => true,
+            _ => {
+                return Err(BittorrentError::TrackerError(format!(
+                    "{}: not a WebSocket tracker",
+                    url
+                )))
+            }
+        };
+        let host = parsed
+            .host_str()
+            .ok_or_else(|| BittorrentError::TrackerError(format!("{}: missing host", url)))?;
+        let port = parsed.port_or_known_default().unwrap_or(80);
+
+        let tcp = TcpStream::connect((host, port)).await?;
+        let stream: Box<dyn Stream> = if secure {
+            let connector = tokio_native_tls::native_tls::TlsConnector::new()
+                .map_err(|e| BittorrentError::TrackerError(format!("{}: {}", url, e)))?;
+            let tls = tokio_native_tls::TlsConnector::from(connector)
+                .connect(host, tcp)
+                .await
+                .map_err(|e| BittorrentError::TrackerError(format!("{}: {}", url, e)))?;
+            Box::new(tls)
+        } else {
+            Box::new(tcp)
+        };
+
+        let mut tracker = Self {
+            stream: BufReader::new(stream),
+            url: url.to_string(),
+        };
+        let path = match parsed.query() {
+            Some(query) => format!("{}?{}", parsed.path(), query),
+            None => parsed.path().to_string(),
+        };
+        tracker.handshake(&host_header(&parsed), &path).await?;
+        Ok(tracker)
+    }
+
+    /// Announce without offers of our own and wait for the tracker's answer
+    pub async fn announce(&mut self, request: &TrackerRequest) -> Result<WebSocketAnnounce> {
+        let info_hash = binary_string(&request.info_hash);
+        self.send_text(&announce_message(request).to_string())
+            .await?;
+
+        let mut offers = Vec::new();
+        loop {
+            let message = self.read_text().await?;
+            let value: Value = serde_json::from_str(&message).map_err(|e| {
+                BittorrentError::TrackerError(format!("{}: invalid JSON: {}", self.url, e))
+            })?;
+
+            if let Some(reason) = value.get("failure reason").and_then(Value::as_str) {
+                return Err(BittorrentError::TrackerError(reason.to_string()));
+            }
+            if value.get("action").and_then(Value::as_str) != Some("announce")
+                || value.get("info_hash").and_then(Value::as_str) != Some(info_hash.as_str())
+            {
+                continue;
+            }
+
+            if let Some(offer) = parse_offer(&value) {
+                offers.push(offer);
+            }
+            if let Some(interval) = value.get("interval").and_then(Value::as_u64) {
+                debug!("{} relayed {} WebRTC offers", self.url, offers.len());
+                return Ok(WebSocketAnnounce {
+                    interval,
+                    complete: value.get("complete").and_then(Value::as_u64),
+                    incomplete: value.get("incomplete").and_then(Value::as_u64),
+                    offers,
+                });
+            }
+        }
+    }
+
+    /// Send the upgrade request and check the server's accept key
+    async fn handshake(&mut self, host: &str, path: &str) -> Result<()> {
+        let key = BASE64.encode(rand::random::<[u8; 16]>());
+        let request = format!(
+            "GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+            path, host, key
+        );
+        self.stream.get_mut().write_all(request.as_bytes()).await?;
+
+        let mut status = String::new();
+        self.stream.read_line(&mut status).await?;
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(BittorrentError::TrackerError(format!(
+                "{}: WebSocket upgrade refused: {}",
+                self.url,
+                status.trim_end()
+            )));
+        }
+
+        let mut accept = None;
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(BittorrentError::TrackerError(format!(
+                    "{}: connection closed during handshake",
+                    self.url
+                )));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("sec-websocket-accept") {
+                    accept = Some(value.trim().to_string());
+                }
+            }
+        }
+
+        if accept.as_deref() != Some(accept_key(&key).as_str()) {
+            return Err(BittorrentError::TrackerError(format!(
+                "{}: bad Sec-WebSocket-Accept",
+                self.url
+            )));
+        }
+        Ok(())
+    }
+
+    async fn send_text(&mut self, text: &str) -> Result<()> {
+        self.send_frame(OPCODE_TEXT, text.as_bytes()).await
+    }
+
+    async fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> Result<()> {
+        let frame = encode_frame(opcode, payload, rand::random());
+        self.stream.get_mut().write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Read the next complete text message, answering pings on the way
+    async fn read_text(&mut self) -> Result<String> {
+        let mut message = Vec::new();
+        loop {
+            let (fin, opcode, payload) = read_frame(&mut self.stream).await?;
+            match opcode {
+                OPCODE_TEXT | OPCODE_CONTINUATION => {
+                    message.extend_from_slice(&payload);
+                    if message.len() > MAX_MESSAGE_LEN {
+                        return Err(BittorrentError::TrackerError(format!(
+                            "{}: message over {} bytes",
+                            self.url, MAX_MESSAGE_LEN
+                        )));
+                    }
+                    if fin {
+                        return String::from_utf8(message).map_err(|_| {
+                            BittorrentError::TrackerError(format!(
+                                "{}: message is not UTF-8",
+                                self.url
+                            ))
+                        });
+                    }
+                }
+                OPCODE_PING => self.send_frame(OPCODE_PONG, &payload).await?,
+                OPCODE_CLOSE => {
+                    return Err(BittorrentError::TrackerError(format!(
+                        "{}: tracker closed the connection",
+                        self.url
+                    )))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// `Host` header value, with the port only when it isn't the default
+fn host_header(url: &url::Url) -> String {
+    let host = url.host_str().unwrap_or_default();
+    match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    }
+}
+
+/// Value the server must send back for handshake key `key`
+fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(WEBSOCKET_GUID.as_bytes());
+    BASE64.encode(hasher.finalize())
+}
+
+/// Encode a single, final, masked client frame
+fn encode_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(0x80 | len as u8),
+        len @ 126..=0xffff => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(
+        payload
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| byte ^ mask[i % 4]),
+    );
+    frame
+}
+
+/// Read one frame, returning its FIN bit, opcode and unmasked payload
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> Result<(bool, u8, Vec<u8>)> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header).await?;
+    let fin = header[0] & 0x80 != 0;
+    let opcode = header[0] & 0x0f;
+    let masked = header[1] & 0x80 != 0;
+
+    let len = match header[1] & 0x7f {
+        126 => reader.read_u16().await? as usize,
+        127 => reader.read_u64().await? as usize,
+        len => len as usize,
+    };
+    if len > MAX_MESSAGE_LEN {
+        return Err(BittorrentError::TrackerError(format!(
+            "WebSocket frame of {} bytes is too large",
+            len
+        )));
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        reader.read_exact(&mut mask).await?;
+    }
+    let mut payload = vec![0u8; len];
+    reader.read_exact(&mut payload).await?;
+    if masked {
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+    }
+
+    Ok((fin, opcode, payload))
+}
+
+/// WebTorrent sends 20-byte IDs as strings with one char per byte
+fn binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn from_binary_string(s: &str) -> Option<Vec<u8>> {
+    s.chars().map(|c| u8::try_from(c).ok()).collect()
+}
+
+fn announce_message(request: &TrackerRequest) -> Value {
+    let mut message = json!({
+        "action": "announce",
+        "info_hash": binary_string(&request.info_hash),
+        "peer_id": binary_string(&request.peer_id),
+        "uploaded": request.uploaded,
+        "downloaded": request.downloaded,
+        "left": request.left,
+        // Without WebRTC we have no offers to hand out
+        "numwant": 0,
+        "offers": [],
+    });
+    if let Some(event) = request.event {
+        message["event"] = event.as_str().into();
+    }
+    message
+}
+
+/// An offer relayed by the tracker, if `value` carries one
+fn parse_offer(value: &Value) -> Option<WebRtcOffer> {
+    let offer = value.get("offer")?;
+    Some(WebRtcOffer {
+        peer_id: from_binary_string(value.get("peer_id")?.as_str()?)?,
+        offer_id: from_binary_string(value.get("offer_id")?.as_str()?)?,
+        sdp: offer.get("sdp")?.as_str()?.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_accept_key() {
+        // Example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[tokio::test]
+    async fn test_frame_roundtrip() {
+        for len in [0, 125, 126, 70_000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = encode_frame(OPCODE_TEXT, &payload, [1, 2, 3, 4]);
+            let (fin, opcode, decoded) = read_frame(&mut frame.as_slice()).await.unwrap();
+            assert!(fin);
+            assert_eq!(opcode, OPCODE_TEXT);
+            assert_eq!(decoded, payload);
+        }
+    }
+
+    #[test]
+    fn test_binary_strings() {
+        let bytes = [0u8, 0x7f, 0x80, 0xff];
+        assert_eq!(
+            from_binary_string(&binary_string(&bytes)),
+            Some(bytes.to_vec())
+        );
+        assert_eq!(from_binary_string("\u{100}"), None);
+    }
+
+    /// Server side of a single tracker session: accept the handshake,
+    /// check the announce, relay one offer and then answer
+    async fn serve_announce(listener: TcpListener, info_hash: [u8; 20]) {
+        let (socket, _) = listener.accept().await.unwrap();
+        let mut socket = BufReader::new(socket);
+
+        let mut key = String::new();
+        loop {
+            let mut line = String::new();
+            socket.read_line(&mut line).await.unwrap();
+            if line.trim_end().is_empty() {
+                break;
+            }
+            if let Some(value) = line.strip_prefix("Sec-WebSocket-Key:") {
+                key = value.trim().to_string();
+            }
+        }
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\
+             Connection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept_key(&key)
+        );
+        socket
+            .get_mut()
+            .write_all(response.as_bytes())
+            .await
+            .unwrap();
+
+        let (_, opcode, payload) = read_frame(&mut socket).await.unwrap();
+        assert_eq!(opcode, OPCODE_TEXT);
+        let announce: Value = serde_json::from_slice(&payload).unwrap();
+        assert_eq!(announce["action"], "announce");
+        assert_eq!(announce["event"], "started");
+        let hash = binary_string(&info_hash);
+        assert_eq!(announce["info_hash"], hash.as_str());
+
+        // Servers don't mask their frames
+        let send = |message: Value| {
+            let text = message.to_string();
+            let mut frame = vec![0x80 | OPCODE_TEXT, 126];
+            frame.extend_from_slice(&(text.len() as u16).to_be_bytes());
+            frame.extend_from_slice(text.as_bytes());
+            frame
+        };
+        let offer = send(json!({
+            "action": "announce",
+            "info_hash": hash,
+            "peer_id": binary_string(&[7; 20]),
+            "offer_id": binary_string(&[9; 20]),
+            "offer": {"type": "offer", "sdp": "v=0"},
+        }));
+        let answer = send(json!({
+            "action": "announce",
+            "info_hash": hash,
+            "interval": 120,
+            "complete": 2,
+            "incomplete": 5,
+        }));
+        socket.get_mut().write_all(&offer).await.unwrap();
+        socket.get_mut().write_all(&answer).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_announce_collects_offers() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/announce", listener.local_addr().unwrap());
+        let server = tokio::spawn(serve_announce(listener, [0xab; 20]));
+
+        let mut tracker = WebSocketTracker::connect(&url).await.unwrap();
+        let request = TrackerRequest::new([0xab; 20], [1; 20], 6881, 1000);
+        let response = tracker.announce(&request).await.unwrap();
+        server.await.unwrap();
+
+        assert_eq!(response.interval, 120);
+        assert_eq!(response.complete, Some(2));
+        assert_eq!(response.incomplete, Some(5));
+        assert_eq!(
+            response.offers,
+            vec![WebRtcOffer {
+                peer_id: vec![7; 20],
+                offer_id: vec![9; 20],
+                sdp: "v=0".to_string(),
+            }]
+        );
+    }
+}
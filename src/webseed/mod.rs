@@ -0,0 +1,447 @@
+//! HTTP seeding (BEP 19 and BEP 17)
+//!
+//! A BEP 19 web seed is a plain HTTP server hosting the torrent's files.
+//! Pieces are fetched with `Range` requests. A BEP 17 HTTP seed is a script
+//! that is asked for a piece by info hash and index. Either way the data is
+//! verified like any peer-sourced piece.
+
+use crate::error::{BittorrentError, Result};
+use crate::torrent::TorrentInfo;
+use crate::tracker::urlencode_bytes;
+use reqwest::header::RANGE;
+use reqwest::{Client, StatusCode, Url};
+use std::time::Duration;
+use tracing::debug;
+
+/// Consecutive failures after which a web seed is abandoned
+pub const MAX_WEBSEED_FAILURES: u32 = 3;
+
+/// Longest a busy HTTP seed's retry delay is honoured
+pub const MAX_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// Which protocol a web seed speaks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WebSeedStyle {
+    /// BEP 19 `url-list`: files fetched with `Range` requests
+    UrlList,
+    /// BEP 17 `httpseeds`: `?info_hash=...&piece=N` per piece
+    HttpSeed { info_hash: [u8; 20] },
+}
+
+/// A byte range within a single file of the torrent
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileRange {
+    /// Index into `TorrentInfo::files`
+    pub file_index: usize,
+    /// Offset of the range within the file
+    pub offset: u64,
+    /// Number of bytes in the range
+    pub length: u64,
+}
+
+/// Map a piece onto the file ranges it covers, in order
+pub fn piece_file_ranges(info: &TorrentInfo, piece_index: usize) -> Vec<FileRange> {
+    let piece_start = piece_index as u64 * info.piece_length;
+    let piece_end = piece_start + info.piece_size(piece_index);
+
+    let mut ranges = Vec::new();
+    let mut file_start = 0u64;
+
+    for (file_index, file) in info.files.iter().enumerate() {
+        let file_end = file_start + file.length;
+
+        let start = std::cmp::max(piece_start, file_start);
+        let end = std::cmp::min(piece_end, file_end);
+        if start < end {
+            ranges.push(FileRange {
+                file_index,
+                offset: start - file_start,
+                length: end - start,
+            });
+        }
+
+        if file_end >= piece_end {
+            break;
+        }
+        file_start = file_end;
+    }
+
+    ranges
+}
+
+/// An HTTP server listed in the torrent's `url-list` or `httpseeds`
+pub struct WebSeed {
+    url: String,
+    client: Client,
+    style: WebSeedStyle,
+    failures: u32,
+    /// Delay a busy HTTP seed asked for with its last answer
+    retry_after: Option<Duration>,
+}
+
+impl WebSeed {
+    /// A BEP 19 web seed
+    pub fn new(url: impl Into<String>, client: Client) -> Self {
+        Self {
+            url: url.into(),
+            client,
+            style: WebSeedStyle::UrlList,
+            failures: 0,
+            retry_after: None,
+        }
+    }
+
+    /// A BEP 17 HTTP seed for the torrent with `info_hash`
+    pub fn http_seed(url: impl Into<String>, info_hash: [u8; 20], client: Client) -> Self {
+        Self {
+            style: WebSeedStyle::HttpSeed { info_hash },
+            ..Self::new(url, client)
+        }
+    }
+
+    /// Base URL of the web seed
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// How long the seed asked us to wait after its last answer, if it was
+    /// busy. Cleared by reading it
+    pub fn take_retry_after(&mut self) -> Option<Duration> {
+        self.retry_after.take()
+    }
+
+    /// Record a failed fetch
+    pub fn record_failure(&mut self) {
+        self.failures += 1;
+    }
+
+    /// Record a successful fetch
+    pub fn record_success(&mut self) {
+        self.failures = 0;
+    }
+
+    /// Whether the web seed has failed too often to keep using
+    pub fn is_dead(&self) -> bool {
+        self.failures >= MAX_WEBSEED_FAILURES
+    }
+
+    /// Build the URL of a file following BEP 19
+    ///
+    /// Single-file torrents use the URL as-is unless it ends in `/`, in
+    /// which case the torrent name is appended. Multi-file torrents always
+    /// append the name followed by the file's path components.
+    pub fn file_url(&self, info: &TorrentInfo, file_index: usize) -> Result<Url> {
+        let file = info.files.get(file_index).ok_or_else(|| {
+            BittorrentError::WebSeedError(format!("File {} is out of range", file_index))
+        })?;
+
+        let mut url = Url::parse(&self.url)?;
+        if !info.multi_file && !self.url.ends_with('/') {
+            return Ok(url);
+        }
+
+        {
+            let mut segments = url.path_segments_mut().map_err(|_| {
+                BittorrentError::WebSeedError(format!("Cannot append a path to {}", self.url))
+            })?;
+            segments.pop_if_empty().push(&info.name);
+            if info.multi_file {
+                segments.extend(&file.path);
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// Build the BEP 17 URL asking for one piece
+    ///
+    /// The parameters are appended to any query the seed's URL already has.
+    pub fn piece_url(&self, info_hash: &[u8; 20], piece_index: usize) -> Result<Url> {
+        let separator = if self.url.contains('?') { '&' } else { '?' };
+        Ok(Url::parse(&format!(
+            "{}{}info_hash={}&piece={}",
+            self.url,
+            separator,
+            urlencode_bytes(info_hash),
+            piece_index
+        ))?)
+    }
+
+    /// Fetch the bytes of a piece
+    ///
+    /// The data is not verified here; callers hash it like any other piece.
+    pub async fn fetch_piece(&mut self, info: &TorrentInfo, piece_index: usize) -> Result<Vec<u8>> {
+        match self.style {
+            WebSeedStyle::UrlList => self.fetch_file_ranges(info, piece_index).await,
+            WebSeedStyle::HttpSeed { info_hash } => {
+                self.fetch_http_seed_piece(info, &info_hash, piece_index)
+                    .await
+            }
+        }
+    }
+
+    /// Fetch a piece from a BEP 17 seed
+    ///
+    /// A seed too busy to answer replies 503 with the seconds to wait as
+    /// its body, which is kept for [`WebSeed::take_retry_after`].
+    async fn fetch_http_seed_piece(
+        &mut self,
+        info: &TorrentInfo,
+        info_hash: &[u8; 20],
+        piece_index: usize,
+    ) -> Result<Vec<u8>> {
+        let url = self.piece_url(info_hash, piece_index)?;
+        debug!("HTTP seed request {}", url);
+
+        let response = self.client.get(url.clone()).send().await?;
+        let status = response.status();
+        let body = response.bytes().await?;
+
+        if status == StatusCode::SERVICE_UNAVAILABLE {
+            let seconds = std::str::from_utf8(&body)
+                .ok()
+                .and_then(|body| body.trim().parse::<u64>().ok())
+                .unwrap_or(MAX_RETRY_AFTER.as_secs());
+            let delay = Duration::from_secs(seconds).min(MAX_RETRY_AFTER);
+            self.retry_after = Some(delay);
+            return Err(BittorrentError::WebSeedError(format!(
+                "{} is busy, retry in {:?}",
+                self.url, delay
+            )));
+        }
+        if status != StatusCode::OK {
+            return Err(BittorrentError::WebSeedError(format!(
+                "{} answered {} for piece {}",
+                self.url, status, piece_index
+            )));
+        }
+
+        let expected = info.piece_size(piece_index);
+        if body.len() as u64 != expected {
+            return Err(BittorrentError::WebSeedError(format!(
+                "{} sent {} bytes for piece {}, expected {}",
+                self.url,
+                body.len(),
+                piece_index,
+                expected
+            )));
+        }
+
+        Ok(body.to_vec())
+    }
+
+    /// Fetch a piece from a BEP 19 seed, spanning files as needed
+    async fn fetch_file_ranges(&self, info: &TorrentInfo, piece_index: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        for range in piece_file_ranges(info, piece_index) {
+            let url = self.file_url(info, range.file_index)?;
+            data.extend(self.fetch_range(url, range.offset, range.length).await?);
+        }
+
+        Ok(data)
+    }
+
+    /// Fetch `length` bytes at `offset` of a single file
+    async fn fetch_range(&self, url: Url, offset: u64, length: u64) -> Result<Vec<u8>> {
+        debug!("Web seed request {} bytes={}+{}", url, offset, length);
+
+        let mut response = self
+            .client
+            .get(url.clone())
+            .header(RANGE, format!("bytes={}-{}", offset, offset + length - 1))
+            .send()
+            .await?;
+
+        let status = response.status();
+        let data = match status {
+            StatusCode::PARTIAL_CONTENT => response.bytes().await?.to_vec(),
+            // Server ignored the range and is sending the whole file: keep
+            // only the range and hang up once it has arrived
+            StatusCode::OK => {
+                let mut data = Vec::with_capacity(length as usize);
+                let mut position = 0u64;
+                while position < offset + length {
+                    let Some(chunk) = response.chunk().await? else {
+                        break;
+                    };
+                    let chunk_end = position + chunk.len() as u64;
+                    let start = offset.clamp(position, chunk_end) - position;
+                    let end = (offset + length).clamp(position, chunk_end) - position;
+                    data.extend_from_slice(&chunk[start as usize..end as usize]);
+                    position = chunk_end;
+                }
+                data
+            }
+            _ => {
+                return Err(BittorrentError::WebSeedError(format!(
+                    "{} answered {} for bytes {}+{}",
+                    url, status, offset, length
+                )))
+            }
+        };
+
+        if data.len() as u64 != length {
+            return Err(BittorrentError::WebSeedError(format!(
+                "{} sent {} bytes, expected {}",
+                url,
+                data.len(),
+                length
+            )));
+        }
+
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{make_torrent, random_content, MockHttpSeed, MockWebSeed};
+    use crate::torrent::{parse_torrent, FileInfo, Pieces};
+
+    fn multi_file_info() -> TorrentInfo {
+        TorrentInfo {
+            name: "album".to_string(),
+            piece_length: 8,
+            pieces: Pieces::from_bytes(&[0u8; 40]).unwrap(),
+            files: vec![
+                FileInfo {
+                    path: vec!["a.txt".to_string()],
+                    length: 5,
+                },
+                FileInfo {
+                    path: vec!["sub dir".to_string(), "b.txt".to_string()],
+                    length: 12,
+                },
+            ],
+            total_length: 17,
+            multi_file: true,
+            meta_version: 1,
+            v2_files: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_piece_spanning_files() {
+        let info = multi_file_info();
+
+        assert_eq!(
+            piece_file_ranges(&info, 0),
+            vec![
+                FileRange {
+                    file_index: 0,
+                    offset: 0,
+                    length: 5
+                },
+                FileRange {
+                    file_index: 1,
+                    offset: 0,
+                    length: 3
+                },
+            ]
+        );
+        assert_eq!(
+            piece_file_ranges(&info, 2),
+            vec![FileRange {
+                file_index: 1,
+                offset: 11,
+                length: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn test_file_url_construction() {
+        let info = multi_file_info();
+        let seed = WebSeed::new("http://seed.example/files", Client::new());
+        assert_eq!(
+            seed.file_url(&info, 1).unwrap().as_str(),
+            "http://seed.example/files/album/sub%20dir/b.txt"
+        );
+
+        let mut single = multi_file_info();
+        single.multi_file = false;
+        single.files.truncate(1);
+        let exact = WebSeed::new("http://seed.example/a.txt", Client::new());
+        assert_eq!(
+            exact.file_url(&single, 0).unwrap().as_str(),
+            "http://seed.example/a.txt"
+        );
+        let dir = WebSeed::new("http://seed.example/pub/", Client::new());
+        assert_eq!(
+            dir.file_url(&single, 0).unwrap().as_str(),
+            "http://seed.example/pub/album"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_piece_with_range_requests() {
+        let content = random_content(40_000);
+        let server = MockWebSeed::spawn(vec![("/data.bin", content.clone())]).await;
+        let metainfo =
+            parse_torrent(&make_torrent("data.bin", &content, 16384, "http://x")).unwrap();
+
+        let mut seed = WebSeed::new(server.url(), Client::new());
+        let piece = seed.fetch_piece(&metainfo.info, 2).await.unwrap();
+        assert_eq!(piece, &content[32768..]);
+
+        let mut missing = WebSeed::new(format!("{}missing/", server.url()), Client::new());
+        assert!(missing.fetch_piece(&metainfo.info, 0).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_piece_from_server_ignoring_ranges() {
+        let content = random_content(40_000);
+        let server = MockWebSeed::spawn_with(vec![("/data.bin", content.clone())], false).await;
+        let metainfo =
+            parse_torrent(&make_torrent("data.bin", &content, 16384, "http://x")).unwrap();
+
+        let mut seed = WebSeed::new(server.url(), Client::new());
+        let piece = seed.fetch_piece(&metainfo.info, 1).await.unwrap();
+        assert_eq!(piece, &content[16384..32768]);
+        let piece = seed.fetch_piece(&metainfo.info, 2).await.unwrap();
+        assert_eq!(piece, &content[32768..]);
+    }
+
+    #[test]
+    fn test_http_seed_piece_url() {
+        let info_hash = [0xab; 20];
+        let seed = WebSeed::http_seed("http://seed.example/seed.php", info_hash, Client::new());
+        assert_eq!(
+            seed.piece_url(&info_hash, 7).unwrap().as_str(),
+            format!(
+                "http://seed.example/seed.php?info_hash={}&piece=7",
+                "%AB".repeat(20)
+            )
+        );
+
+        let keyed = WebSeed::http_seed("http://seed.example/s?key=1", info_hash, Client::new());
+        assert!(keyed
+            .piece_url(&info_hash, 0)
+            .unwrap()
+            .as_str()
+            .starts_with("http://seed.example/s?key=1&info_hash="));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_piece_from_http_seed() {
+        let content = random_content(40_000);
+        let metainfo =
+            parse_torrent(&make_torrent("data.bin", &content, 16384, "http://x")).unwrap();
+        let server = MockHttpSeed::spawn(metainfo.info_hash, content.clone(), 16384).await;
+
+        let mut seed = WebSeed::http_seed(server.url(), metainfo.info_hash, Client::new());
+        let piece = seed.fetch_piece(&metainfo.info, 2).await.unwrap();
+        assert_eq!(piece, &content[32768..]);
+        assert_eq!(seed.take_retry_after(), None);
+
+        let mut wrong = WebSeed::http_seed(server.url(), [0u8; 20], Client::new());
+        assert!(wrong.fetch_piece(&metainfo.info, 0).await.is_err());
+
+        // Busy seeds say how long to wait
+        server.set_busy(Some(7));
+        assert!(seed.fetch_piece(&metainfo.info, 0).await.is_err());
+        assert_eq!(seed.take_retry_after(), Some(Duration::from_secs(7)));
+    }
+}